@@ -0,0 +1,183 @@
+// Benchmarks of the dispatcher: the throughput of tiny jobs, the
+// latency of tiny jobs queued behind large ones, and how submitting
+// from many threads scales from 1 to 64 workers. Run them with
+// `cargo bench`, or `cargo bench --features bench` to compare each
+// workload with a baseline pool built on a Mutex<Receiver>, the queue
+// design rpools started with.
+
+#[cfg(feature = "bench")]
+use std::sync::{mpsc, Arc, Mutex};
+use std::{
+    hint::black_box,
+    thread,
+    time::{Duration, Instant},
+};
+
+use rpools::{pool::WorkerPool, sync::WaitGroup};
+
+// The runs of each benchmark, the median is reported.
+const RUNS: usize = 11;
+const TINY_JOBS: usize = 20_000;
+const SUBMITTERS: usize = 8;
+
+// A pool the workloads run on.
+trait Dispatch: Sync {
+    fn name(&self) -> &'static str;
+    fn spawn(&self, job: Box<dyn FnOnce() + Send>);
+}
+
+impl Dispatch for WorkerPool {
+    fn name(&self) -> &'static str {
+        "rpools"
+    }
+
+    fn spawn(&self, job: Box<dyn FnOnce() + Send>) {
+        self.execute(job).expect("the pool accepts jobs");
+    }
+}
+
+// The baseline pool: workers taking turns on a shared receiver.
+#[cfg(feature = "bench")]
+struct MutexReceiver {
+    sender: Option<mpsc::Sender<Box<dyn FnOnce() + Send>>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+#[cfg(feature = "bench")]
+impl MutexReceiver {
+    fn new(size: usize) -> MutexReceiver {
+        let (sender, receiver) = mpsc::channel::<Box<dyn FnOnce() + Send>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    let job = receiver.lock().expect("Cant acquire lock").recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return,
+                    }
+                })
+            })
+            .collect();
+        MutexReceiver {
+            sender: Some(sender),
+            workers,
+        }
+    }
+}
+
+#[cfg(feature = "bench")]
+impl Dispatch for MutexReceiver {
+    fn name(&self) -> &'static str {
+        "mutex-receiver"
+    }
+
+    fn spawn(&self, job: Box<dyn FnOnce() + Send>) {
+        let sender = self.sender.as_ref().expect("the pool accepts jobs");
+        sender.send(job).expect("the workers are running");
+    }
+}
+
+#[cfg(feature = "bench")]
+impl Drop for MutexReceiver {
+    fn drop(&mut self) {
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+// Returns the pools to compare, with the given workers.
+fn pools(size: usize) -> Vec<Box<dyn Dispatch>> {
+    #[allow(unused_mut)]
+    let mut pools: Vec<Box<dyn Dispatch>> = vec![Box::new(WorkerPool::new(size))];
+    #[cfg(feature = "bench")]
+    pools.push(Box::new(MutexReceiver::new(size)));
+    pools
+}
+
+// Runs a workload RUNS times and prints the median time it took.
+fn bench(group: &str, pool: &dyn Dispatch, workload: impl Fn(&dyn Dispatch) -> Duration) {
+    workload(pool);
+    let mut times: Vec<Duration> = (0..RUNS).map(|_| workload(pool)).collect();
+    times.sort_unstable();
+    println!("{:<32} {:<16} {:>12?}", group, pool.name(), times[RUNS / 2]);
+}
+
+// Sends TINY_JOBS empty jobs from one thread and waits for them.
+fn tiny_jobs(pool: &dyn Dispatch) -> Duration {
+    let start = Instant::now();
+    let wg = WaitGroup::default();
+    for _ in 0..TINY_JOBS {
+        let wg = wg.clone();
+        pool.spawn(Box::new(move || drop(black_box(wg))));
+    }
+    wg.wait();
+    start.elapsed()
+}
+
+// Queues large jobs, one per worker, then tiny ones, and returns how
+// long the tiny ones took to finish.
+fn large_job_fairness(pool: &dyn Dispatch, workers: usize) -> Duration {
+    let large = WaitGroup::default();
+    for _ in 0..workers {
+        let large = large.clone();
+        pool.spawn(Box::new(move || {
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_millis(2) {
+                black_box(start);
+            }
+            drop(large);
+        }));
+    }
+    let start = Instant::now();
+    let tiny = WaitGroup::default();
+    for _ in 0..TINY_JOBS / 10 {
+        let tiny = tiny.clone();
+        pool.spawn(Box::new(move || drop(black_box(tiny))));
+    }
+    tiny.wait();
+    let elapsed = start.elapsed();
+    large.wait();
+    elapsed
+}
+
+// Sends TINY_JOBS empty jobs from SUBMITTERS threads at once.
+fn contention(pool: &dyn Dispatch) -> Duration {
+    let start = Instant::now();
+    let wg = WaitGroup::default();
+    thread::scope(|scope| {
+        for _ in 0..SUBMITTERS {
+            let wg = wg.clone();
+            scope.spawn(move || {
+                for _ in 0..TINY_JOBS / SUBMITTERS {
+                    let wg = wg.clone();
+                    pool.spawn(Box::new(move || drop(black_box(wg))));
+                }
+            });
+        }
+    });
+    wg.wait();
+    start.elapsed()
+}
+
+fn main() {
+    // cargo bench passes --bench, cargo test --benches only smoke tests
+    if std::env::args().all(|arg| arg != "--bench") {
+        return;
+    }
+    for pool in pools(4) {
+        bench("tiny jobs, 4 workers", &*pool, tiny_jobs);
+        bench("large job fairness, 4 workers", &*pool, |pool| {
+            large_job_fairness(pool, 4)
+        });
+    }
+    for workers in [1, 2, 4, 8, 16, 32, 64] {
+        let group = format!("contention, {} workers", workers);
+        for pool in pools(workers) {
+            bench(&group, &*pool, contention);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! ## Actor
+//!
+//! This module has a minimal actor layer on top of a pool. An actor is
+//! a state and a handler spawned with `WorkerPool::spawn_actor`, and
+//! the Addr it returns sends it messages. The messages of an actor are
+//! handled one at a time, in the order they were sent, so the handler
+//! owns the state without locks, while different actors handle their
+//! messages in parallel on the workers of the pool.
+//!
+//! ### Examples
+//! ```
+//! use rpools::pool::WorkerPool;
+//! use std::sync::mpsc;
+//!
+//! enum Account {
+//!     Deposit(u64),
+//!     Balance(mpsc::Sender<u64>),
+//! }
+//!
+//! let pool = WorkerPool::new(4);
+//! let account = pool.spawn_actor(0, |balance: &mut u64, msg| match msg {
+//!     Account::Deposit(amount) => *balance += amount,
+//!     Account::Balance(reply) => reply.send(*balance).unwrap(),
+//! });
+//!
+//! for amount in 1..=10 {
+//!     let account = account.clone();
+//!     pool.execute(move || account.send(Account::Deposit(amount)).unwrap())
+//!         .unwrap();
+//! }
+//! pool.wait();
+//!
+//! let (tx, rx) = mpsc::channel();
+//! account.send(Account::Balance(tx)).unwrap();
+//! assert_eq!(55, rx.recv().unwrap());
+//! ```
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use crate::pool::{ExecuteError, SerialKey, WeakPool, WorkerPool};
+
+// The ids of the actors of the process.
+static NEXT_ACTOR: AtomicU64 = AtomicU64::new(0);
+
+/// The address of an actor, made by `WorkerPool::spawn_actor`. Clones
+/// send to the same actor. An address doesn't keep the pool alive, and
+/// the actor and its state are dropped with its last address and its
+/// last pending message.
+pub struct Addr<M> {
+    mailbox: Arc<Mailbox<M>>,
+}
+
+// The state of an actor, behind its handler, and where it runs.
+struct Mailbox<M> {
+    id: u64,
+    pool: WeakPool,
+    // only locked by the message being handled, as the messages of an
+    // actor are serialized
+    handler: Mutex<Box<dyn FnMut(M) + Send>>,
+}
+
+impl<M: Send + 'static> Addr<M> {
+    /// Sends a message to the actor. It is handled after the messages
+    /// sent before, once a worker is free.
+    ///
+    /// **msg**: M - the message. \
+    /// **returns**: Ok if the message was queued, or an ExecuteError.
+    pub fn send(&self, msg: M) -> Result<(), ExecuteError> {
+        let mailbox = Arc::clone(&self.mailbox);
+        self.mailbox.pool.execute_serial(
+            SerialKey::Actor(self.mailbox.id),
+            Box::new(move || {
+                // a handler that panicked leaves the state as it was, and
+                // the next messages are still handled
+                let mut handler = mailbox
+                    .handler
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                handler(msg);
+            }),
+        )
+    }
+}
+
+impl<M> Clone for Addr<M> {
+    fn clone(&self) -> Self {
+        Addr {
+            mailbox: Arc::clone(&self.mailbox),
+        }
+    }
+}
+
+// Implements Debug for Addr, showing the id of the actor.
+impl<M> std::fmt::Debug for Addr<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Addr")
+            .field("actor", &self.mailbox.id)
+            .finish()
+    }
+}
+
+impl WorkerPool {
+    /// Spawns an actor on the pool: a state changed by a handler, one
+    /// message at a time.
+    ///
+    /// **state**: S - the initial state of the actor. \
+    /// **handler**: A FnMut closure that takes the state and a message. \
+    /// **returns**: the address sending messages to the actor.
+    pub fn spawn_actor<S, M, H>(&self, state: S, mut handler: H) -> Addr<M>
+    where
+        S: Send + 'static,
+        M: Send + 'static,
+        H: FnMut(&mut S, M) + Send + 'static,
+    {
+        let mut state = state;
+        Addr {
+            mailbox: Arc::new(Mailbox {
+                id: NEXT_ACTOR.fetch_add(1, Ordering::Relaxed),
+                pool: self.downgrade(),
+                handler: Mutex::new(Box::new(move |msg| handler(&mut state, msg))),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use std::{
+        sync::{atomic::AtomicBool, mpsc},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn actor_should_handle_its_messages_in_order_one_at_a_time() {
+        let pool = WorkerPool::new(4);
+        let (tx, rx) = mpsc::channel();
+        let busy = Arc::new(AtomicBool::new(false));
+        let actor = pool.spawn_actor(Vec::new(), move |seen: &mut Vec<u32>, msg: u32| {
+            assert!(!busy.swap(true, Ordering::SeqCst), "overlapping messages");
+            thread::sleep(Duration::from_micros(100));
+            seen.push(msg);
+            busy.store(false, Ordering::SeqCst);
+            if msg == 3 {
+                panic!("bad message");
+            }
+            if seen.len() == 20 {
+                tx.send(seen.clone()).unwrap();
+            }
+        });
+        let other = pool.spawn_actor((), |_, msg: u32| assert!(msg < 20));
+        for msg in 0..20 {
+            actor.send(msg).unwrap();
+            other.send(msg).unwrap();
+        }
+        let seen = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!((0..20).collect::<Vec<_>>(), seen);
+
+        drop(pool);
+        assert!(matches!(actor.send(0), Err(ExecuteError::Shutdown)));
+    }
+}
@@ -0,0 +1,169 @@
+//! ## Audit
+//!
+//! This module has the audit trail of a pool: one record for each job
+//! that ends, with the thread that submitted it, its name and label,
+//! when it ran and how it ended. Records are written to the sinks
+//! installed with `Builder::audit`, so every background job is logged
+//! without wrapping each closure.
+//!
+//! ### Examples
+//! ```
+//! use rpools::audit::{AuditRecord, AuditSink, Outcome};
+//! use rpools::pool::Builder;
+//! use std::sync::{Arc, Mutex};
+//!
+//! #[derive(Default)]
+//! struct Trail(Mutex<Vec<AuditRecord>>);
+//!
+//! impl AuditSink for Trail {
+//!     fn record(&self, record: &AuditRecord) {
+//!         self.0.lock().unwrap().push(record.clone());
+//!     }
+//! }
+//!
+//! let trail = Arc::new(Trail::default());
+//! let pool = Builder::new(2).audit(trail.clone()).build();
+//!
+//! pool.job(|| {}).name("nightly-export").spawn().unwrap();
+//! pool.wait();
+//!
+//! let records = trail.0.lock().unwrap();
+//! assert_eq!(Some("nightly-export"), records[0].name.as_deref());
+//! assert_eq!(Outcome::Completed, records[0].outcome);
+//! assert_eq!(std::thread::current().id(), records[0].submitted_by);
+//! ```
+
+use std::{
+    fmt::Display,
+    io::Write,
+    sync::Mutex,
+    thread::ThreadId,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How a job ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Outcome {
+    /// The job returned.
+    Completed,
+    /// The job panicked.
+    Panicked,
+    /// The job never ran, as it was cancelled or missed its deadline.
+    Skipped,
+}
+
+/// The audit record of a job.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct AuditRecord {
+    /// The thread that sent the job to the pool.
+    pub submitted_by: ThreadId,
+    /// The name of the job, if it was sent with one.
+    pub name: Option<String>,
+    /// The label of the job, if it was sent with one.
+    pub label: Option<String>,
+    /// The trace id of the job.
+    pub trace: u64,
+    /// When the job started, or None if it was skipped.
+    pub started: Option<SystemTime>,
+    /// When the job ended, or was skipped.
+    pub finished: SystemTime,
+    /// How the job ended.
+    pub outcome: Outcome,
+}
+
+// Implements Display for AuditRecord as a single line of key=value
+// pairs, times in milliseconds since the unix epoch.
+impl Display for AuditRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let millis = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis())
+        };
+        write!(
+            f,
+            "outcome={:?} name={} label={} trace={} submitted_by={:?}",
+            self.outcome,
+            self.name.as_deref().unwrap_or("-"),
+            self.label.as_deref().unwrap_or("-"),
+            self.trace,
+            self.submitted_by
+        )?;
+        if let Some(started) = self.started {
+            write!(f, " started={}", millis(started))?;
+        }
+        write!(f, " finished={}", millis(self.finished))
+    }
+}
+
+/// Where audit records are written. Sinks are called inline, in the
+/// worker thread that ran the job, so slow sinks should hand records
+/// to another thread.
+pub trait AuditSink: Send + Sync {
+    /// Writes the record of a job that ended.
+    fn record(&self, record: &AuditRecord);
+}
+
+/// A sink writing each record as a line to a writer, like a file or
+/// the standard error. Write errors are ignored, so auditing never
+/// fails a job.
+pub struct WriterSink<W> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send> WriterSink<W> {
+    /// Constructs a new WriterSink.
+    ///
+    /// **writer**: W - where the lines are written.
+    pub fn new(writer: W) -> WriterSink<W> {
+        WriterSink {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Returns the writer, after the pool is done with the sink.
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().expect("Cant acquire lock")
+    }
+}
+
+impl<W: Write + Send> AuditSink for WriterSink<W> {
+    fn record(&self, record: &AuditRecord) {
+        let mut writer = self.writer.lock().expect("Cant acquire lock");
+        let _ = writeln!(writer, "{}", record);
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::pool::Builder;
+    use crate::sync::CancellationToken;
+    use std::sync::Arc;
+
+    #[test]
+    fn writer_sink_should_write_a_line_per_job() {
+        let sink = Arc::new(WriterSink::new(Vec::new()));
+        let pool = Builder::new(1).audit(sink.clone()).build();
+        let token = CancellationToken::new();
+        token.cancel();
+        pool.job(|| {}).label("report").spawn().unwrap();
+        pool.job(|| {}).token(&token).spawn().unwrap();
+        let _ = pool
+            .submit(|| -> u8 { panic!("audited boom") })
+            .unwrap()
+            .join();
+        pool.shutdown();
+        drop(pool);
+
+        let sink = Arc::try_unwrap(sink).ok().unwrap();
+        let lines = String::from_utf8(sink.into_inner()).unwrap();
+        let lines: Vec<_> = lines.lines().collect();
+        assert_eq!(3, lines.len());
+        assert!(lines[0].starts_with("outcome=Completed name=- label=report"));
+        assert!(lines[0].contains(" started="));
+        assert!(lines[1].starts_with("outcome=Skipped"));
+        assert!(!lines[1].contains(" started="));
+        assert!(lines[2].starts_with("outcome=Panicked"));
+    }
+}
@@ -0,0 +1,356 @@
+//! ## Backend
+//!
+//! This module has the JobQueue trait, which decides the order the
+//! queued jobs of a pool are picked in. The pool keeps one JobQueue per
+//! priority lane and does the locking, blocking, bounds and closing
+//! around it, so a backend only stores and orders jobs. The default
+//! backend is a FIFO VecDeque, PriorityHeap picks jobs by a key, and
+//! RoundRobin takes turns between the FIFOs of different keys.
+//! Pools pick a backend with `Builder::queue_backend`.
+//!
+//! There is no crossbeam channel backend. Workers don't share a
+//! Mutex<Receiver> anymore, so a lock free channel has no lock to
+//! remove, and the crate takes no dependencies.
+//!
+//! ### Examples
+//! ```
+//! use rpools::backend::{JobQueue, PriorityHeap};
+//! use rpools::pool::{Builder, QueuedJob};
+//! use std::sync::mpsc;
+//!
+//! // the jobs with the shortest names first
+//! let pool = Builder::new(1)
+//!     .queue_backend(|| {
+//!         let heap = PriorityHeap::new(|job: &QueuedJob| job.info().name().map(str::len));
+//!         Box::new(heap) as Box<dyn JobQueue<QueuedJob>>
+//!     })
+//!     .build();
+//!
+//! let (tx, rx) = mpsc::channel();
+//! pool.execute(move || {
+//!     std::thread::sleep(std::time::Duration::from_millis(20));
+//! })
+//! .unwrap();
+//! for name in ["three", "two", "one!"] {
+//!     let tx = tx.clone();
+//!     pool.job(move || tx.send(name).unwrap()).name(name).submit().unwrap();
+//! }
+//! pool.wait();
+//! assert_eq!(vec!["two", "one!", "three"], rx.try_iter().collect::<Vec<_>>());
+//! ```
+
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap, VecDeque},
+    hash::Hash,
+};
+
+/// The storage of a queue lane. Calls are made with the lane locked, so
+/// they must not block.
+pub trait JobQueue<T>: Send {
+    /// Stores an item.
+    ///
+    /// **item**: T - the item to store.
+    fn push(&mut self, item: T);
+
+    /// Removes the next item to pick, if any.
+    fn pop(&mut self) -> Option<T>;
+
+    /// Removes the item stored first, if any, for the DropOldest
+    /// overflow policy. The default removes the next item to pick, so
+    /// backends that don't pick items in the order they were stored
+    /// should override it.
+    fn pop_oldest(&mut self) -> Option<T> {
+        self.pop()
+    }
+
+    /// Returns how many items are stored.
+    fn len(&self) -> usize;
+
+    /// Returns true if no item is stored.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes the items for which remove returns true. The default
+    /// pops every item and pushes back the ones kept, in the order they
+    /// were popped.
+    ///
+    /// **remove**: the predicate choosing the items to remove. \
+    /// **returns**: the removed items, in the order they were popped.
+    fn remove_if(&mut self, remove: &mut dyn FnMut(&T) -> bool) -> Vec<T> {
+        let mut kept = Vec::new();
+        let mut removed = Vec::new();
+        while let Some(item) = self.pop() {
+            if remove(&item) {
+                removed.push(item);
+            } else {
+                kept.push(item);
+            }
+        }
+        kept.into_iter().for_each(|item| self.push(item));
+        removed
+    }
+}
+
+// Implements JobQueue for VecDeque, picking the items in FIFO order.
+impl<T: Send> JobQueue<T> for VecDeque<T> {
+    fn push(&mut self, item: T) {
+        self.push_back(item);
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        self.pop_front()
+    }
+
+    fn len(&self) -> usize {
+        VecDeque::len(self)
+    }
+
+    fn remove_if(&mut self, remove: &mut dyn FnMut(&T) -> bool) -> Vec<T> {
+        let mut removed = Vec::new();
+        for item in std::mem::take(self) {
+            if remove(&item) {
+                removed.push(item);
+            } else {
+                self.push_back(item);
+            }
+        }
+        removed
+    }
+}
+
+/// A JobQueue picking the item with the lowest key first, and the items
+/// with equal keys in FIFO order. Keys are computed once, on push.
+pub struct PriorityHeap<T, K> {
+    heap: BinaryHeap<Entry<T, K>>,
+    key: Box<dyn Fn(&T) -> K + Send>,
+    pushed: u64,
+}
+
+impl<T, K: Ord> PriorityHeap<T, K> {
+    /// Constructs a new empty PriorityHeap.
+    ///
+    /// **key**: A Fn closure that returns the key of an item.
+    pub fn new<F>(key: F) -> PriorityHeap<T, K>
+    where
+        F: Fn(&T) -> K + Send + 'static,
+    {
+        PriorityHeap {
+            heap: BinaryHeap::new(),
+            key: Box::new(key),
+            pushed: 0,
+        }
+    }
+}
+
+impl<T: Send, K: Ord + Send> JobQueue<T> for PriorityHeap<T, K> {
+    fn push(&mut self, item: T) {
+        let key = (self.key)(&item);
+        self.pushed += 1;
+        self.heap.push(Entry {
+            key,
+            seq: self.pushed,
+            item,
+        });
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        self.heap.pop().map(|entry| entry.item)
+    }
+
+    fn pop_oldest(&mut self) -> Option<T> {
+        if self.heap.is_empty() {
+            return None;
+        }
+        let mut entries = std::mem::take(&mut self.heap).into_vec();
+        let oldest = (0..entries.len()).min_by_key(|&i| entries[i].seq)?;
+        let entry = entries.swap_remove(oldest);
+        self.heap = BinaryHeap::from(entries);
+        Some(entry.item)
+    }
+
+    fn len(&self) -> usize {
+        self.heap.len()
+    }
+}
+
+/// A JobQueue keeping a FIFO per key, and picking the items from each
+/// key in turn, so a key with many items doesn't starve the others.
+pub struct RoundRobin<T, K> {
+    // the items of each key, with the sequence they were pushed in
+    queues: HashMap<K, VecDeque<(u64, T)>>,
+    // the keys with items, the next one to pick from first
+    turns: VecDeque<K>,
+    key: Box<dyn Fn(&T) -> K + Send>,
+    len: usize,
+    pushed: u64,
+}
+
+impl<T, K: Hash + Eq + Clone> RoundRobin<T, K> {
+    /// Constructs a new empty RoundRobin.
+    ///
+    /// **key**: A Fn closure that returns the key of an item.
+    pub fn new<F>(key: F) -> RoundRobin<T, K>
+    where
+        F: Fn(&T) -> K + Send + 'static,
+    {
+        RoundRobin {
+            queues: HashMap::new(),
+            turns: VecDeque::new(),
+            key: Box::new(key),
+            len: 0,
+            pushed: 0,
+        }
+    }
+}
+
+impl<T: Send, K: Hash + Eq + Clone + Send> JobQueue<T> for RoundRobin<T, K> {
+    fn push(&mut self, item: T) {
+        let key = (self.key)(&item);
+        let queue = self.queues.entry(key.clone()).or_default();
+        if queue.is_empty() {
+            self.turns.push_back(key);
+        }
+        self.pushed += 1;
+        queue.push_back((self.pushed, item));
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let key = self.turns.pop_front()?;
+        let queue = self.queues.get_mut(&key)?;
+        let item = queue.pop_front();
+        if queue.is_empty() {
+            self.queues.remove(&key);
+        } else {
+            self.turns.push_back(key);
+        }
+        self.len -= 1;
+        item.map(|(_, item)| item)
+    }
+
+    fn pop_oldest(&mut self) -> Option<T> {
+        let key = self
+            .queues
+            .iter()
+            .filter_map(|(key, queue)| Some((queue.front()?.0, key)))
+            .min_by_key(|&(seq, _)| seq)?
+            .1
+            .clone();
+        let queue = self.queues.get_mut(&key)?;
+        let item = queue.pop_front();
+        if queue.is_empty() {
+            self.queues.remove(&key);
+            self.turns.retain(|turn| *turn != key);
+        }
+        self.len -= 1;
+        item.map(|(_, item)| item)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+// An item of a PriorityHeap, ordered so the max heap pops the lowest
+// key, then the lowest push sequence.
+struct Entry<T, K> {
+    key: K,
+    seq: u64,
+    item: T,
+}
+
+impl<T, K: Ord> Ord for Entry<T, K> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .key
+            .cmp(&self.key)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl<T, K: Ord> PartialOrd for Entry<T, K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T, K: Ord> PartialEq for Entry<T, K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T, K: Ord> Eq for Entry<T, K> {}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn priority_heap_should_pop_lowest_keys_in_fifo_order() {
+        let mut heap = PriorityHeap::new(|item: &(u8, char)| item.0);
+        for item in [(2, 'a'), (1, 'b'), (2, 'c'), (1, 'd')] {
+            heap.push(item);
+        }
+        assert_eq!(4, JobQueue::len(&heap));
+        let order: Vec<char> = std::iter::from_fn(|| heap.pop()).map(|i| i.1).collect();
+        assert_eq!(vec!['b', 'd', 'a', 'c'], order);
+        assert!(heap.is_empty());
+    }
+
+    #[test]
+    fn round_robin_should_take_turns_between_keys() {
+        let mut queue = RoundRobin::new(|item: &(char, u8)| item.0);
+        for item in [('a', 1), ('a', 2), ('a', 3), ('b', 1), ('c', 1), ('b', 2)] {
+            queue.push(item);
+        }
+        let mut order = vec![queue.pop().unwrap()];
+        queue.push(('c', 2));
+        order.extend(std::iter::from_fn(|| queue.pop()));
+        let expected = [
+            ('a', 1),
+            ('b', 1),
+            ('c', 1),
+            ('a', 2),
+            ('b', 2),
+            ('c', 2),
+            ('a', 3),
+        ];
+        assert_eq!(expected.to_vec(), order);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn pop_oldest_should_remove_the_item_pushed_first() {
+        let mut heap = PriorityHeap::new(|item: &(u8, char)| item.0);
+        let mut queue = RoundRobin::new(|item: &(u8, char)| item.0);
+        for item in [(2, 'a'), (1, 'b'), (1, 'c')] {
+            heap.push(item);
+            queue.push(item);
+        }
+        assert_eq!(Some((2, 'a')), heap.pop_oldest());
+        assert_eq!(Some((1, 'b')), heap.pop());
+        assert_eq!(Some((2, 'a')), queue.pop_oldest());
+        assert_eq!(Some((1, 'b')), queue.pop());
+        assert_eq!(Some((1, 'c')), queue.pop_oldest());
+        assert!(queue.is_empty() && queue.pop().is_none());
+        assert_eq!(Some(1), VecDeque::from(vec![1, 2]).pop_oldest());
+    }
+
+    #[test]
+    fn remove_if_should_keep_the_order_of_the_other_items() {
+        let mut fifo: VecDeque<u8> = (0..6).collect();
+        assert_eq!(vec![1, 3, 5], fifo.remove_if(&mut |i| i % 2 == 1));
+        assert_eq!(vec![0, 2, 4], fifo.into_iter().collect::<Vec<_>>());
+
+        let mut heap = PriorityHeap::new(|item: &(u8, char)| item.0);
+        for item in [(2, 'a'), (1, 'b'), (2, 'c'), (1, 'd')] {
+            heap.push(item);
+        }
+        assert_eq!(vec![(1, 'd')], heap.remove_if(&mut |i| i.1 == 'd'));
+        let order: Vec<char> = std::iter::from_fn(|| heap.pop()).map(|i| i.1).collect();
+        assert_eq!(vec!['b', 'a', 'c'], order);
+    }
+}
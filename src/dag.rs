@@ -0,0 +1,391 @@
+//! ## Dag
+//!
+//! This module runs a graph of tasks with dependencies on a WorkerPool.
+//! Tasks are registered with the names of the tasks they depend on, and
+//! `WorkerPool::run` starts each task as soon as all its dependencies
+//! succeeded, so independent branches run in parallel. When a task
+//! fails, the graph either stops starting new tasks, or goes on with
+//! the tasks that don't depend on the failed one.
+//!
+//! ### Examples
+//! ```
+//! use rpools::dag::{Graph, TaskState};
+//! use rpools::pool::WorkerPool;
+//! use std::sync::{Arc, Mutex};
+//!
+//! let pool = WorkerPool::new(4);
+//! let log = Arc::new(Mutex::new(Vec::new()));
+//! let mut graph = Graph::<String>::new();
+//!
+//! for (name, deps) in [
+//!     ("fetch", vec![]),
+//!     ("parse", vec!["fetch"]),
+//!     ("thumbnails", vec!["fetch"]),
+//!     ("publish", vec!["parse", "thumbnails"]),
+//! ] {
+//!     let log = log.clone();
+//!     graph.add_task(name, &deps, move || {
+//!         log.lock().unwrap().push(name);
+//!         Ok(())
+//!     });
+//! }
+//!
+//! let report = pool.run(graph).unwrap();
+//! assert!(report.is_success());
+//! let log = log.lock().unwrap();
+//! assert_eq!("fetch", log[0]);
+//! assert_eq!("publish", log[3]);
+//! assert!(matches!(report.state("parse"), Some(TaskState::Succeeded)));
+//! ```
+
+use std::{collections::HashMap, fmt::Display, sync::mpsc};
+
+use crate::pool::{catch, panic_message, ExecuteError, WorkerPool};
+
+// The closure of a task.
+type Run<E> = Box<dyn FnOnce() -> Result<(), E> + Send + 'static>;
+
+// A task of a graph, with the names of its dependencies.
+struct Node<E> {
+    name: String,
+    deps: Vec<String>,
+    run: Run<E>,
+}
+
+/// A graph of named tasks and their dependencies, run with
+/// `WorkerPool::run`. Tasks return an error of type E to fail.
+pub struct Graph<E> {
+    nodes: Vec<Node<E>>,
+    fail_fast: bool,
+}
+
+impl<E: Send + 'static> Graph<E> {
+    /// Constructs a new empty Graph, failing fast.
+    pub fn new() -> Graph<E> {
+        Graph {
+            nodes: Vec::new(),
+            fail_fast: true,
+        }
+    }
+
+    /// Adds a task to the graph. Dependencies may be added after the
+    /// tasks depending on them.
+    ///
+    /// **name**: &str - the unique name of the task. \
+    /// **deps**: &[&str] - the names of the tasks that must succeed
+    /// before this one starts. \
+    /// **f**: A FnOnce closure returning Ok, or Err to fail the task.
+    pub fn add_task<F>(&mut self, name: &str, deps: &[&str], f: F) -> &mut Graph<E>
+    where
+        F: FnOnce() -> Result<(), E> + Send + 'static,
+    {
+        self.nodes.push(Node {
+            name: name.to_string(),
+            deps: deps.iter().map(|dep| dep.to_string()).collect(),
+            run: Box::new(f),
+        });
+        self
+    }
+
+    /// Sets what happens when a task fails or panics. Failing fast, the
+    /// default, no other task is started and the ones running finish.
+    /// Otherwise only the tasks depending on the failed one are skipped.
+    ///
+    /// **fail_fast**: bool - whether to stop at the first failure.
+    pub fn fail_fast(&mut self, fail_fast: bool) -> &mut Graph<E> {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    // Returns the dependencies of each task as indexes, or why the
+    // graph can't run.
+    fn resolve(&self) -> Result<Vec<Vec<usize>>, GraphError> {
+        let mut index = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            if index.insert(node.name.as_str(), i).is_some() {
+                return Err(GraphError::Duplicate(node.name.clone()));
+            }
+        }
+        let deps = self
+            .nodes
+            .iter()
+            .map(|node| {
+                node.deps
+                    .iter()
+                    .map(|dep| {
+                        index.get(dep.as_str()).copied().ok_or_else(|| {
+                            GraphError::UnknownDependency {
+                                task: node.name.clone(),
+                                dependency: dep.clone(),
+                            }
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Kahn's algorithm, the tasks left unsorted are in a cycle
+        let mut pending: Vec<usize> = deps.iter().map(Vec::len).collect();
+        let dependents = dependents(&deps);
+        let mut ready: Vec<usize> = (0..deps.len()).filter(|&i| pending[i] == 0).collect();
+        let mut sorted = 0;
+        while let Some(i) = ready.pop() {
+            sorted += 1;
+            for &dependent in &dependents[i] {
+                pending[dependent] -= 1;
+                if pending[dependent] == 0 {
+                    ready.push(dependent);
+                }
+            }
+        }
+        if sorted < deps.len() {
+            let cycle = (0..deps.len())
+                .filter(|&i| pending[i] > 0)
+                .map(|i| self.nodes[i].name.clone())
+                .collect();
+            return Err(GraphError::Cycle(cycle));
+        }
+        Ok(deps)
+    }
+}
+
+// Implements Default for Graph, same as Graph::new.
+impl<E: Send + 'static> Default for Graph<E> {
+    fn default() -> Graph<E> {
+        Graph::new()
+    }
+}
+
+// Returns the tasks depending on each task.
+fn dependents(deps: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    let mut dependents = vec![Vec::new(); deps.len()];
+    for (i, deps) in deps.iter().enumerate() {
+        for &dep in deps {
+            dependents[dep].push(i);
+        }
+    }
+    dependents
+}
+
+/// Why a graph can't run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GraphError {
+    /// Two tasks have this name.
+    Duplicate(String),
+    /// A task depends on a task that isn't in the graph.
+    UnknownDependency {
+        /// The task with the dependency.
+        task: String,
+        /// The name of the missing task.
+        dependency: String,
+    },
+    /// These tasks depend on each other in a cycle, or on a cycle.
+    Cycle(Vec<String>),
+    /// The pool didn't accept a task.
+    Execute(ExecuteError),
+}
+
+// Implements Display for GraphError with a summary of the problem.
+impl Display for GraphError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GraphError::Duplicate(task) => write!(f, "the task {} was added twice", task),
+            GraphError::UnknownDependency { task, dependency } => {
+                write!(f, "the task {} depends on the unknown {}", task, dependency)
+            }
+            GraphError::Cycle(tasks) => write!(f, "the tasks {} form a cycle", tasks.join(", ")),
+            GraphError::Execute(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
+
+/// How a task of a graph ended.
+#[derive(Debug)]
+pub enum TaskState<E> {
+    /// The task returned Ok.
+    Succeeded,
+    /// The task returned this error.
+    Failed(E),
+    /// The task panicked, with this message if it was a string.
+    Panicked(Option<String>),
+    /// The task didn't run, as a dependency didn't succeed or the graph
+    /// failed fast.
+    Skipped,
+}
+
+/// The states of the tasks of a graph after it ran.
+#[derive(Debug)]
+pub struct Report<E> {
+    states: Vec<(String, TaskState<E>)>,
+}
+
+impl<E> Report<E> {
+    /// Returns true if every task succeeded.
+    pub fn is_success(&self) -> bool {
+        self.states
+            .iter()
+            .all(|(_, state)| matches!(state, TaskState::Succeeded))
+    }
+
+    /// Returns how a task ended.
+    ///
+    /// **name**: &str - the name of the task.
+    pub fn state(&self, name: &str) -> Option<&TaskState<E>> {
+        self.states
+            .iter()
+            .find(|(task, _)| task == name)
+            .map(|(_, state)| state)
+    }
+
+    /// Returns the names and states of the tasks, in the order they
+    /// were added.
+    pub fn into_states(self) -> Vec<(String, TaskState<E>)> {
+        self.states
+    }
+}
+
+impl WorkerPool {
+    /// Runs the tasks of a graph on the pool, each as soon as its
+    /// dependencies succeeded, and blocks the current thread until the
+    /// graph is done. Calling it from a job of a pool running fewer than
+    /// two workers deadlocks.
+    ///
+    /// **graph**: Graph<E> - the tasks to run. \
+    /// **returns**: the Report of the tasks, or a GraphError if the
+    /// graph is invalid or the pool rejected a task.
+    ///
+    /// ### Examples
+    /// ```
+    /// use rpools::dag::{Graph, GraphError, TaskState};
+    /// use rpools::pool::WorkerPool;
+    ///
+    /// let pool = WorkerPool::new(2);
+    /// let mut graph = Graph::new();
+    /// graph
+    ///     .add_task("migrate", &[], || Err("database is read only"))
+    ///     .add_task("serve", &["migrate"], || Ok(()))
+    ///     .add_task("warm cache", &[], || Ok(()))
+    ///     .fail_fast(false);
+    ///
+    /// let report = pool.run(graph).unwrap();
+    /// assert!(matches!(report.state("migrate"), Some(TaskState::Failed(_))));
+    /// assert!(matches!(report.state("serve"), Some(TaskState::Skipped)));
+    /// assert!(matches!(report.state("warm cache"), Some(TaskState::Succeeded)));
+    ///
+    /// let mut cycle = Graph::<()>::new();
+    /// cycle.add_task("a", &["b"], || Ok(())).add_task("b", &["a"], || Ok(()));
+    /// assert!(matches!(pool.run(cycle), Err(GraphError::Cycle(_))));
+    /// ```
+    pub fn run<E: Send + 'static>(&self, graph: Graph<E>) -> Result<Report<E>, GraphError> {
+        let deps = graph.resolve()?;
+        let dependents = dependents(&deps);
+        let mut pending: Vec<usize> = deps.iter().map(Vec::len).collect();
+        let (names, mut runs): (Vec<String>, Vec<Option<Run<E>>>) = graph
+            .nodes
+            .into_iter()
+            .map(|node| (node.name, Some(node.run)))
+            .unzip();
+        let mut states: Vec<Option<TaskState<E>>> = names.iter().map(|_| None).collect();
+        let (tx, rx) = mpsc::channel();
+
+        let mut running = 0;
+        let mut failed = false;
+        let mut ready: Vec<usize> = (0..names.len()).filter(|&i| pending[i] == 0).collect();
+        loop {
+            for i in ready.drain(..) {
+                if failed && graph.fail_fast {
+                    break;
+                }
+                let run = runs[i].take().expect("a task runs once");
+                let tx = tx.clone();
+                self.execute(move || {
+                    let state = match catch(run) {
+                        Ok(Ok(())) => TaskState::Succeeded,
+                        Ok(Err(err)) => TaskState::Failed(err),
+                        Err(payload) => {
+                            TaskState::Panicked(panic_message(payload.as_ref()).map(str::to_string))
+                        }
+                    };
+                    // the graph stops listening if the pool rejected a task
+                    let _ = tx.send((i, state));
+                })
+                .map_err(GraphError::Execute)?;
+                running += 1;
+            }
+            if running == 0 {
+                break;
+            }
+            let (i, state) = rx.recv().expect("the sender is alive");
+            running -= 1;
+            if matches!(state, TaskState::Succeeded) {
+                for &dependent in &dependents[i] {
+                    pending[dependent] -= 1;
+                    if pending[dependent] == 0 {
+                        ready.push(dependent);
+                    }
+                }
+            } else {
+                failed = true;
+            }
+            states[i] = Some(state);
+        }
+
+        let states = names
+            .into_iter()
+            .zip(states)
+            .map(|(name, state)| (name, state.unwrap_or(TaskState::Skipped)))
+            .collect();
+        Ok(Report { states })
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn graph_should_fail_fast_on_panics_and_reject_invalid_graphs() {
+        let pool = WorkerPool::new(1);
+        let started = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&started);
+        let mut graph = Graph::<()>::new();
+        graph
+            .add_task("boom", &[], || panic!("corrupt state"))
+            .add_task("after", &["boom"], || Ok(()))
+            .add_task("other", &["boom2"], move || {
+                flag.store(true, Ordering::Relaxed);
+                Ok(())
+            })
+            .add_task("boom2", &[], || Err(()));
+        let report = pool.run(graph).unwrap();
+        assert!(!report.is_success());
+        assert!(matches!(
+            report.state("boom"),
+            Some(TaskState::Panicked(Some(message))) if message == "corrupt state"
+        ));
+        assert!(matches!(report.state("after"), Some(TaskState::Skipped)));
+        assert!(!started.load(Ordering::Relaxed));
+        assert_eq!(4, report.into_states().len());
+
+        let mut graph = Graph::<()>::new();
+        graph.add_task("a", &["missing"], || Ok(()));
+        assert_eq!(
+            "the task a depends on the unknown missing",
+            pool.run(graph).unwrap_err().to_string()
+        );
+        let mut graph = Graph::<()>::new();
+        graph
+            .add_task("a", &[], || Ok(()))
+            .add_task("a", &[], || Ok(()));
+        assert_eq!(
+            Err(GraphError::Duplicate("a".to_string())),
+            pool.run(graph).map(drop)
+        );
+    }
+}
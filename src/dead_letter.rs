@@ -0,0 +1,119 @@
+//! ## Dead Letter
+//!
+//! This module has the dead letter sink of a pool, where the jobs that
+//! failed for good are delivered: the jobs that panicked, and the ones
+//! sent with `WorkerPool::execute_with_retry` that failed their last
+//! attempt. Applications install one with `Builder::dead_letters` to log
+//! or persist these jobs instead of losing them silently.
+//!
+//! ### Examples
+//! ```
+//! use rpools::dead_letter::{DeadLetter, Failure};
+//! use rpools::pool::Builder;
+//! use rpools::retry::RetryPolicy;
+//! use std::sync::{mpsc, Arc};
+//! use std::time::Duration;
+//!
+//! let (tx, rx) = mpsc::channel::<DeadLetter>();
+//! let pool = Builder::new(2).dead_letters(Arc::new(tx)).build();
+//!
+//! let policy = RetryPolicy::fixed(2, Duration::from_millis(1));
+//! pool.execute_with_retry(policy, || Err("mailbox unavailable")).unwrap();
+//!
+//! let letter = rx.recv().unwrap();
+//! assert_eq!(2, letter.attempts);
+//! match letter.failure {
+//!     Failure::Exhausted(Some(err)) => {
+//!         assert_eq!(Some(&"mailbox unavailable"), err.downcast_ref::<&str>());
+//!     }
+//!     _ => unreachable!(),
+//! }
+//! ```
+
+use std::{any::Any, sync::mpsc};
+
+/// Why a job failed for good.
+#[derive(Debug)]
+pub enum Failure {
+    /// The job panicked. Holds the panic message, when the payload is a
+    /// string and the job didn't unwind its worker.
+    Panicked(Option<String>),
+    /// The last attempt of a retried job returned an error. Holds the
+    /// error, unless it was given to a JobHandle.
+    Exhausted(Option<Box<dyn Any + Send + 'static>>),
+}
+
+/// A job that failed for good.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct DeadLetter {
+    /// The name of the job, if it was sent with one.
+    pub name: Option<String>,
+    /// The label of the job, if it was sent with one.
+    pub label: Option<String>,
+    /// The trace id of the job.
+    pub trace: u64,
+    /// How many times the job was attempted.
+    pub attempts: usize,
+    /// Why the job failed.
+    pub failure: Failure,
+}
+
+/// Where dead letters are delivered. Sinks are called inline, in the
+/// worker thread that ran the job, so they should be quick. Closures
+/// taking a DeadLetter and channel senders are sinks.
+pub trait DeadLetterSink: Send + Sync {
+    /// Delivers the letter of a job that failed for good.
+    fn deliver(&self, letter: DeadLetter);
+}
+
+impl<F> DeadLetterSink for F
+where
+    F: Fn(DeadLetter) + Send + Sync,
+{
+    fn deliver(&self, letter: DeadLetter) {
+        self(letter)
+    }
+}
+
+impl DeadLetterSink for mpsc::Sender<DeadLetter> {
+    fn deliver(&self, letter: DeadLetter) {
+        // the receiver may have been dropped, nobody reads the letters
+        let _ = self.send(letter);
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::pool::Builder;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn dead_letters_should_get_panicked_jobs() {
+        let letters = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&letters);
+        let pool = Builder::new(1)
+            .dead_letters(Arc::new(move |letter: DeadLetter| {
+                sink.lock().unwrap().push(letter)
+            }))
+            .build();
+        let handle = pool
+            .job(|| -> u8 { panic!("corrupt invoice") })
+            .name("billing")
+            .submit()
+            .unwrap();
+        assert!(handle.join().is_err());
+        pool.execute(|| {}).unwrap();
+        pool.wait();
+
+        let letters = letters.lock().unwrap();
+        assert_eq!(1, letters.len());
+        assert_eq!(Some("billing"), letters[0].name.as_deref());
+        assert_eq!(1, letters[0].attempts);
+        match &letters[0].failure {
+            Failure::Panicked(message) => assert_eq!(Some("corrupt invoice"), message.as_deref()),
+            failure => panic!("unexpected failure {:?}", failure),
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//! ## Fallback
+//!
+//! This module has the executors a saturated pool falls back on. When
+//! the queue of a pool built with `Builder::fallback` stays over its
+//! threshold for longer than the grace period, the jobs sent with
+//! `JobBuilder::critical` are given to the fallback instead of waiting
+//! behind the backlog.
+//!
+//! ### Examples
+//! ```
+//! use rpools::fallback::SpawnThread;
+//! use rpools::pool::Builder;
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//!
+//! let pool = Builder::new(4)
+//!     .fallback(1000, Duration::from_millis(200), Arc::new(SpawnThread))
+//!     .build();
+//!
+//! let handle = pool.job(|| "healthcheck").critical().submit().unwrap();
+//! assert_eq!("healthcheck", handle.join().unwrap());
+//! ```
+
+use std::thread;
+
+/// Runs the jobs a saturated pool spills. These jobs skip the pool, so
+/// they aren't reported to observers nor counted in the metrics, but
+/// in `PoolMetrics::spilled`.
+pub trait Spawn: Send + Sync {
+    /// Runs or schedules a job.
+    fn spawn(&self, job: Box<dyn FnOnce() + Send + 'static>);
+}
+
+/// Runs each spilled job on a new thread.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SpawnThread;
+
+impl Spawn for SpawnThread {
+    fn spawn(&self, job: Box<dyn FnOnce() + Send + 'static>) {
+        thread::spawn(job);
+    }
+}
+
+/// Runs each spilled job inline, in the thread that sent it, which
+/// also slows the senders down while the pool is saturated.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RunInline;
+
+impl Spawn for RunInline {
+    fn spawn(&self, job: Box<dyn FnOnce() + Send + 'static>) {
+        job();
+    }
+}
@@ -0,0 +1,289 @@
+//! ## Future
+//!
+//! This module connects the pool with async runtimes. Blocking or CPU
+//! heavy work can be moved to the pool and awaited from a tokio or
+//! async-std task, without blocking the runtime threads. The pool can
+//! also run futures itself with `WorkerPool::spawn_async`, so it can be
+//! given to libraries that only need somewhere to spawn their tasks.
+//!
+//! It is only available with the `futures` feature.
+//!
+//! ### Examples
+//! ```
+//! use rpools::pool::WorkerPool;
+//!
+//! let pool = WorkerPool::new(2);
+//! let future = pool.spawn_future(|| (1..=10).sum::<u32>()).unwrap();
+//!
+//! // inside an async fn: let sum = future.await;
+//! # drop(future);
+//! ```
+
+use std::{
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Wake, Waker},
+};
+
+use crate::pool::{ExecuteError, WeakPool, WorkerPool};
+
+// A future spawned with spawn_async, taken by the job polling it. It is
+// None once the future completed or panicked.
+type Spawned = Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send + 'static>>>>;
+
+// A future spawned on the pool, and its waker. Waking it queues a job
+// that polls it once, unless such a job is already queued.
+struct AsyncTask {
+    future: Spawned,
+    scheduled: AtomicBool,
+    pool: WeakPool,
+}
+
+impl AsyncTask {
+    // Queues a poll of the future. A pool that was shut down drops it.
+    fn schedule(self: Arc<Self>) {
+        if !self.scheduled.swap(true, Ordering::AcqRel) {
+            let task = Arc::clone(&self);
+            let _ = self.pool.execute(Box::new(move || task.poll()));
+        }
+    }
+
+    // Polls the future once. A panic drops the future, not the worker.
+    fn poll(self: Arc<Self>) {
+        self.scheduled.store(false, Ordering::Release);
+        let mut future = self.future.lock().expect("Cant acquire lock");
+        let Some(pending) = future.as_mut() else {
+            return;
+        };
+        let waker = Waker::from(Arc::clone(&self));
+        let mut cx = Context::from_waker(&waker);
+        let polled = panic::catch_unwind(AssertUnwindSafe(|| pending.as_mut().poll(&mut cx)));
+        if !matches!(polled, Ok(Poll::Pending)) {
+            *future = None;
+        }
+    }
+}
+
+impl Wake for AsyncTask {
+    fn wake(self: Arc<Self>) {
+        self.schedule();
+    }
+}
+
+// Shared state between the job running on the pool and the future
+// polled by the async runtime.
+struct State<T> {
+    value: Option<T>,
+    waker: Option<Waker>,
+    finished: bool,
+}
+
+/// A future that resolves with the value returned by a job
+/// sent to the pool with `WorkerPool::spawn_future`.
+///
+/// If the job panics, polling the future panics too, as the value
+/// will never be produced.
+pub struct JobFuture<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Future for JobFuture<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut state = self.state.lock().expect("Cant acquire lock");
+        if let Some(value) = state.value.take() {
+            return Poll::Ready(value);
+        }
+        if state.finished {
+            panic!("job panicked before producing a value");
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+// Marks the state as finished and wakes the future, even if the
+// job unwinds before storing its value.
+struct Completion<T>(Arc<Mutex<State<T>>>);
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut state = match self.0.lock() {
+                Ok(state) => state,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            state.finished = true;
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl WorkerPool {
+    /// Executes a job in the pool and returns a future that resolves
+    /// with its result. The job runs in a worker thread, so the caller's
+    /// runtime is never blocked.
+    ///
+    /// **f**: A FnOnce closure that produces a value. \
+    /// **returns**: a JobFuture that resolves with the value, or
+    /// ExecuteError::Shutdown.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    ///
+    /// let pool = WorkerPool::new(1);
+    /// let future = pool.spawn_future(|| "done".to_string()).unwrap();
+    /// # drop(future);
+    /// ```
+    pub fn spawn_future<F, T>(&self, f: F) -> Result<JobFuture<T>, ExecuteError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let state = Arc::new(Mutex::new(State {
+            value: None,
+            waker: None,
+            finished: false,
+        }));
+
+        let completion = Completion(Arc::clone(&state));
+        self.execute(move || {
+            let value = f();
+            completion.0.lock().expect("Cant acquire lock").value = Some(value);
+            drop(completion);
+        })?;
+
+        Ok(JobFuture { state })
+    }
+
+    /// Spawns a future that runs on the pool. Each time it is woken, a
+    /// worker polls it once, so the pool serves as the executor of
+    /// libraries that just need to spawn tasks. A future that panics is
+    /// dropped.
+    ///
+    /// **future**: A Future returning nothing. \
+    /// **returns**: Ok if the first poll was queued, or an ExecuteError.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use std::sync::mpsc;
+    ///
+    /// let pool = WorkerPool::new(2);
+    /// let (tx, rx) = mpsc::channel();
+    ///
+    /// let sum = pool.spawn_future(|| (1..=10).sum::<u32>()).unwrap();
+    /// pool.spawn_async(async move {
+    ///     tx.send(sum.await * 2).unwrap();
+    /// })
+    /// .unwrap();
+    ///
+    /// assert_eq!(110, rx.recv().unwrap());
+    /// ```
+    pub fn spawn_async<F>(&self, future: F) -> Result<(), ExecuteError>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let task = Arc::new(AsyncTask {
+            future: Mutex::new(Some(Box::pin(future))),
+            scheduled: AtomicBool::new(true),
+            pool: self.downgrade(),
+        });
+        self.execute(move || task.poll())
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use std::{
+        task::Wake,
+        thread::{self, Thread},
+    };
+
+    // A minimal executor that parks the current thread until woken.
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(value) => return value,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn spawn_future_should_resolve_with_job_value() {
+        let pool = WorkerPool::new(2);
+        let future = pool.spawn_future(|| (1..=10).sum::<u32>()).unwrap();
+        assert_eq!(55, block_on(future));
+    }
+
+    // A future that wakes itself and yields a number of times.
+    struct Yield(usize);
+
+    impl Future for Yield {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 == 0 {
+                return Poll::Ready(());
+            }
+            self.0 -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn spawn_async_should_poll_futures_until_ready() {
+        let pool = WorkerPool::new(2);
+        let (tx, rx) = std::sync::mpsc::channel();
+        for i in 0..10 {
+            let tx = tx.clone();
+            pool.spawn_async(async move {
+                Yield(i).await;
+                tx.send(i).unwrap();
+            })
+            .unwrap();
+        }
+        pool.spawn_async(async { panic!("async boom") }).unwrap();
+        let mut done: Vec<_> = rx.iter().take(10).collect();
+        done.sort_unstable();
+        assert_eq!((0..10).collect::<Vec<_>>(), done);
+        pool.wait();
+        assert_eq!(2, pool.metrics().workers);
+    }
+
+    #[test]
+    fn spawn_future_should_resolve_many_futures() {
+        let pool = WorkerPool::new(3);
+        let futures: Vec<_> = (0..20)
+            .map(|i| pool.spawn_future(move || i * 2).unwrap())
+            .collect();
+        let sum: usize = futures.into_iter().map(block_on).sum();
+        assert_eq!(380, sum);
+    }
+}
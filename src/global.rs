@@ -0,0 +1,69 @@
+//! ## Global
+//!
+//! This module has a process wide pool, built the first time it is
+//! used, with one worker for each CPU the process may use. Small
+//! programs and libraries can send jobs to it with `rpools::spawn` and
+//! `rpools::submit`, without passing a pool around. The pool lives as
+//! long as the process and is never shut down.
+//!
+//! ### Examples
+//! ```
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//! use std::sync::Arc;
+//!
+//! let counter = Arc::new(AtomicUsize::new(0));
+//! for _ in 0..10 {
+//!     let counter = counter.clone();
+//!     rpools::spawn(move || {
+//!         counter.fetch_add(1, Ordering::Relaxed);
+//!     })
+//!     .unwrap();
+//! }
+//! let answer = rpools::submit(|| 6 * 7).unwrap();
+//!
+//! rpools::block_on_all();
+//! assert_eq!(10, counter.load(Ordering::Relaxed));
+//! assert_eq!(42, answer.join().unwrap());
+//! ```
+
+use std::sync::OnceLock;
+
+use crate::pool::{ExecuteError, JobHandle, WorkerPool};
+
+static POOL: OnceLock<WorkerPool> = OnceLock::new();
+
+/// Returns the global pool, building it on the first call.
+pub fn pool() -> &'static WorkerPool {
+    POOL.get_or_init(WorkerPool::default)
+}
+
+/// Executes a job in the global pool, same as `WorkerPool::execute`.
+///
+/// **f**: A FnOnce closure hosted by a Box smart pointer.
+pub fn spawn<J>(f: J) -> Result<(), ExecuteError>
+where
+    J: FnOnce() + Send + 'static,
+{
+    pool().execute(f)
+}
+
+/// Executes a job in the global pool and returns a handle to its
+/// result, same as `WorkerPool::submit`.
+///
+/// **f**: A FnOnce closure that produces a value.
+pub fn submit<F, T>(f: F) -> Result<JobHandle<T>, ExecuteError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    pool().submit(f)
+}
+
+/// Blocks the current thread until every job sent to the global pool
+/// has finished, same as `WorkerPool::wait`. Calling it from inside a
+/// job deadlocks.
+pub fn block_on_all() {
+    if let Some(pool) = POOL.get() {
+        pool.wait();
+    }
+}
@@ -0,0 +1,146 @@
+// ## Histogram
+//
+// A lock free histogram of durations, recorded by the workers and read
+// by whoever asks for percentiles. Buckets are log linear: eight per
+// power of two nanoseconds, so a percentile is off by 12.5% at most,
+// while the histogram keeps a fixed, small size.
+
+use std::{
+    convert::TryFrom,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// The buckets per power of two.
+const SUB_BUCKETS: usize = 8;
+// The values below 8 ns get one bucket each, then 61 powers of two.
+const BUCKETS: usize = SUB_BUCKETS * 62;
+
+pub(crate) struct Histogram {
+    buckets: Vec<AtomicU64>,
+    max: AtomicU64,
+}
+
+impl Histogram {
+    // Constructs a new empty Histogram.
+    pub(crate) fn new() -> Histogram {
+        Histogram {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    // Records a duration.
+    pub(crate) fn record(&self, duration: Duration) {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.buckets[bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    // Returns the recorded durations and the given percentiles of them,
+    // each q between 0 and 1, from a single snapshot of the buckets.
+    pub(crate) fn percentiles<const N: usize>(&self, qs: [f64; N]) -> (u64, [Duration; N]) {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        let max = self.max.load(Ordering::Relaxed);
+        let values = qs.map(|q| {
+            if total == 0 {
+                return Duration::ZERO;
+            }
+            let rank = ((q * total as f64).ceil() as u64).clamp(1, total);
+            let mut seen = 0;
+            let index = counts
+                .iter()
+                .position(|&count| {
+                    seen += count;
+                    seen >= rank
+                })
+                .unwrap_or(BUCKETS - 1);
+            Duration::from_nanos(upper_bound(index).min(max))
+        });
+        (total, values)
+    }
+
+    // Fills counts with how many recorded durations are at most each
+    // bound, then with how many were recorded, from a single snapshot.
+    // A bucket counts toward a bound only if it ends below it, so the
+    // counts are within the precision of the buckets.
+    #[cfg(feature = "metrics-export")]
+    pub(crate) fn cumulative(&self, bounds: &[Duration], counts: &mut [usize]) {
+        let snapshot: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect();
+        for (bound, count) in bounds.iter().zip(counts.iter_mut()) {
+            let nanos = u64::try_from(bound.as_nanos()).unwrap_or(u64::MAX);
+            let below: u64 = (0..BUCKETS)
+                .take_while(|&index| upper_bound(index) <= nanos)
+                .map(|index| snapshot[index])
+                .sum();
+            *count = usize::try_from(below).unwrap_or(usize::MAX);
+        }
+        if let Some(total) = counts.get_mut(bounds.len()) {
+            *total = usize::try_from(snapshot.iter().sum::<u64>()).unwrap_or(usize::MAX);
+        }
+    }
+
+    // Returns the longest recorded duration.
+    pub(crate) fn max(&self) -> Duration {
+        Duration::from_nanos(self.max.load(Ordering::Relaxed))
+    }
+}
+
+// Returns the bucket of a value in nanoseconds.
+fn bucket(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS as u64 {
+        return nanos as usize;
+    }
+    let exponent = 63 - nanos.leading_zeros() as usize;
+    let mantissa = (nanos >> (exponent - 3)) as usize & (SUB_BUCKETS - 1);
+    (exponent - 2) * SUB_BUCKETS + mantissa
+}
+
+// Returns the largest value in nanoseconds that falls in a bucket.
+fn upper_bound(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let exponent = index / SUB_BUCKETS + 2;
+    let mantissa = (index % SUB_BUCKETS) as u128;
+    let bound = ((SUB_BUCKETS as u128 + mantissa + 1) << (exponent - 3)) - 1;
+    u64::try_from(bound).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn histogram_should_report_percentiles_within_its_precision() {
+        let histogram = Histogram::new();
+        assert_eq!((0, [Duration::ZERO]), histogram.percentiles([0.5]));
+
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        histogram.record(Duration::MAX);
+        let (count, [p50, p99, p100]) = histogram.percentiles([0.5, 0.99, 1.0]);
+        assert_eq!(1001, count);
+        for (value, expected) in [(p50, 500.0), (p99, 991.0)] {
+            let micros = value.as_secs_f64() * 1e6;
+            assert!(
+                micros >= expected && micros <= expected * 1.125,
+                "{}",
+                micros
+            );
+        }
+        assert_eq!(Duration::from_nanos(u64::MAX), p100);
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+        assert!((0..BUCKETS).all(|i| bucket(upper_bound(i)) == i));
+    }
+}
@@ -3,32 +3,117 @@
 //! This module contains constructs for dealing with concurrent tasks. It can spawn
 //! any number of worker threads and sync them with other channels.
 //!
-//! ## Examples
-//!
-//! ### Synchronized with other channels
-//!
-//! ```
-//! use rpools::pool::WorkerPool;
-//! use std::sync::mpsc::channel;
-//! use std::sync::{Arc, Mutex};
-//!
-//! let n_workers = 4;
-//! let n_jobs = 8;
-//! let pool = WorkerPool::new(n_workers);
-//!
-//! let (tx, rx) = channel();
-//! let atx = Arc::new(Mutex::new(tx));
-//! for _ in 0..n_jobs {
-//!     let atx = atx.clone();
-//!     pool.execute(move|| {
-//!         let tx = atx.lock().unwrap();
-//!         tx.send(1).expect("channel will be there waiting for the pool");
-//!     });
-//! }
-//!
-//! assert_eq!(rx.iter().take(n_jobs).fold(0, |a, b| a + b), 8);
-//!```
+// The examples build a WorkerPool, so they only exist with std.
+#![cfg_attr(
+    feature = "std",
+    doc = r#"
+## Examples
+
+### Synchronized with other channels
+
+```
+use rpools::pool::WorkerPool;
+use std::sync::mpsc::channel;
+
+let n_workers = 4;
+let n_jobs = 8;
+let pool = WorkerPool::new(n_workers);
+
+let (tx, rx) = channel();
+for _ in 0..n_jobs {
+    let tx = tx.clone();
+    pool.execute(move|| {
+        tx.send(1).expect("channel will be there waiting for the pool");
+    }).unwrap();
+}
+
+assert_eq!(rx.iter().take(n_jobs).fold(0, |a, b| a + b), 8);
+```
+
+### Results as they complete
 
-// Imports and makes pool public.
+```
+use rpools::pool::WorkerPool;
+
+let pool = WorkerPool::new(4);
+let squares = pool.channel_execute(8, |i| i * i).unwrap();
+
+assert_eq!(140, squares.sum::<usize>());
+```
+"#
+)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+// Imports and makes pool public. Everything but the core of the sync
+// module needs std.
+#[cfg(feature = "std")]
+pub mod actor;
+#[cfg(feature = "std")]
+pub mod audit;
+#[cfg(feature = "std")]
+pub mod backend;
+#[cfg(feature = "std")]
+pub mod dag;
+#[cfg(feature = "std")]
+pub mod dead_letter;
+#[cfg(feature = "std")]
+pub mod fallback;
+#[cfg(feature = "std")]
+pub mod global;
+#[cfg(feature = "std")]
+pub mod observer;
+#[cfg(feature = "std")]
+pub mod pipeline;
+#[cfg(feature = "std")]
 pub mod pool;
+#[cfg(feature = "std")]
+pub mod retry;
+#[cfg(feature = "std")]
+pub mod rng;
+#[cfg(feature = "std")]
+pub mod scope;
+#[cfg(feature = "std")]
+pub mod store;
+#[cfg(feature = "std")]
 pub mod sync;
+#[cfg(feature = "std")]
+pub mod task_set;
+
+#[cfg(feature = "std")]
+mod histogram;
+#[cfg(feature = "std")]
+mod queue;
+#[cfg(feature = "std")]
+mod scaling;
+mod sync_core;
+#[cfg(feature = "std")]
+mod timer;
+
+/// ## Sync
+///
+/// The sync primitives that build without std: CancellationToken,
+/// ShardedCounter and SpinLock. Enable the std feature for the pool
+/// and the other primitives.
+#[cfg(not(feature = "std"))]
+pub mod sync {
+    pub use crate::sync_core::{CancellationToken, ShardedCounter, SpinLock, SpinLockGuard};
+}
+
+#[cfg(feature = "std")]
+pub use global::{block_on_all, spawn, submit};
+#[cfg(feature = "std")]
+pub use pool::{select_all, select_any};
+
+#[cfg(feature = "futures")]
+pub mod future;
+
+#[cfg(feature = "schedule")]
+pub mod schedule;
+
+#[cfg(feature = "test-support")]
+pub mod testing;
+
+#[cfg(feature = "test-support")]
+pub mod testkit;
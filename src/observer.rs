@@ -0,0 +1,96 @@
+//! ## Observer
+//!
+//! This module has the hooks called along the lifecycle of each job,
+//! so a pool can be wired into tracing, Prometheus or custom logging.
+//! Observers are installed with `Builder::observer`, and are called
+//! from the submitting thread and from the worker threads.
+//!
+//! ### Examples
+//! ```
+//! use rpools::observer::{JobInfo, PoolObserver};
+//! use rpools::pool::Builder;
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//!
+//! #[derive(Default)]
+//! struct SlowJobs(AtomicUsize);
+//!
+//! impl PoolObserver for SlowJobs {
+//!     fn on_complete(&self, _job: &JobInfo, duration: Duration) {
+//!         if duration > Duration::from_millis(100) {
+//!             self.0.fetch_add(1, Ordering::Relaxed);
+//!         }
+//!     }
+//! }
+//!
+//! let slow = Arc::new(SlowJobs::default());
+//! let pool = Builder::new(2).observer(slow.clone()).build();
+//!
+//! pool.execute(|| {}).unwrap();
+//! pool.wait();
+//! assert_eq!(0, slow.0.load(Ordering::Relaxed));
+//! ```
+
+use std::time::Duration;
+
+/// What an observer gets to know about a job.
+#[derive(Debug, Clone, Copy)]
+pub struct JobInfo<'a> {
+    pub(crate) name: Option<&'a str>,
+    pub(crate) label: Option<&'a str>,
+    pub(crate) trace: u64,
+}
+
+impl JobInfo<'_> {
+    /// Returns the name of the job, if it was sent with one.
+    pub fn name(&self) -> Option<&str> {
+        self.name
+    }
+
+    /// Returns the label of the job, if it was sent with one.
+    pub fn label(&self) -> Option<&str> {
+        self.label
+    }
+
+    /// Returns the trace id of the job, shared with the job that sent it,
+    /// if any.
+    pub fn trace_id(&self) -> u64 {
+        self.trace
+    }
+}
+
+/// Callbacks for the lifecycle of jobs. Every method does nothing by
+/// default, so observers implement only the ones they need.
+///
+/// Callbacks run inline, in the submitting or worker thread, so they
+/// should be quick. They must not panic, as on_panic runs while the
+/// worker may be unwinding.
+pub trait PoolObserver: Send + Sync {
+    /// Called when a job sent to the pool is accepted. Jobs rejected
+    /// with an ExecuteError are not reported. If a worker picks the job
+    /// before the submitting thread tells the observers, the worker
+    /// calls it right before on_start.
+    fn on_submit(&self, _job: &JobInfo) {}
+
+    /// Called in the worker thread right before the job runs. Jobs
+    /// skipped because they were cancelled or missed their deadline
+    /// never start.
+    fn on_start(&self, _job: &JobInfo) {}
+
+    /// Called in the worker thread when the job returns.
+    ///
+    /// **duration**: Duration - how long the job ran.
+    fn on_complete(&self, _job: &JobInfo, _duration: Duration) {}
+
+    /// Called in the worker thread instead of on_complete when the job
+    /// panics.
+    fn on_panic(&self, _job: &JobInfo) {}
+
+    /// Called in the worker thread when it exits unexpectedly, unwound
+    /// by a panic, so services can alarm on lost capacity. The pool
+    /// doesn't replace the worker.
+    ///
+    /// **worker**: usize - the id of the worker.
+    fn on_worker_death(&self, _worker: usize) {}
+}
@@ -0,0 +1,234 @@
+//! ## Pipeline
+//!
+//! This module wires producer, transformer and consumer stages on a
+//! WorkerPool. Each Stage is a closure with its own concurrency limit,
+//! and stages are connected with bounded channels, so a slow stage
+//! holds the ones before it back instead of buffering without limit.
+//!
+//! Each concurrent slot of a stage, and the producer of the source, is
+//! a pool job that lives until the pipeline drains, so the pool needs
+//! at least one more worker than the sum of the stage concurrencies.
+//!
+//! ### Examples
+//! ```
+//! use rpools::pipeline::{Pipeline, Stage};
+//! use rpools::pool::WorkerPool;
+//!
+//! let pool = WorkerPool::new(6);
+//! let lines = vec!["3,4", "10,20", "1,1"];
+//!
+//! let mut sums: Vec<u32> = Pipeline::new(lines)
+//!     .then(Stage::new(1, |line: &str| line.split(',').map(String::from).collect::<Vec<_>>()))
+//!     .then(Stage::new(2, |fields: Vec<String>| {
+//!         fields.iter().map(|f| f.parse::<u32>().unwrap()).sum()
+//!     }))
+//!     .run(&pool)
+//!     .unwrap()
+//!     .collect();
+//!
+//! sums.sort_unstable();
+//! assert_eq!(vec![2, 7, 30], sums);
+//! ```
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    mpsc::{self, Receiver, SyncSender},
+    Arc, Mutex,
+};
+
+use crate::pool::{catch, ExecuteError, WorkerPool};
+
+// The items buffered between two stages, unless the stage sets it.
+const BUFFER: usize = 16;
+
+// Wires the pipeline on a pool, returning the receiver of its last
+// channel. Panicked items are counted in the given counter.
+type Start<T> =
+    Box<dyn FnOnce(&WorkerPool, &Arc<AtomicUsize>) -> Result<Receiver<T>, ExecuteError>>;
+
+/// A stage of a pipeline, turning each In item into an Out item.
+pub struct Stage<In, Out> {
+    concurrency: usize,
+    capacity: usize,
+    f: Arc<dyn Fn(In) -> Out + Send + Sync + 'static>,
+}
+
+impl<In, Out> Stage<In, Out> {
+    /// Constructs a new Stage.
+    ///
+    /// **concurrency**: usize - how many items the stage processes at
+    /// once, at least one. \
+    /// **f**: A Fn closure applied to each item.
+    pub fn new<F>(concurrency: usize, f: F) -> Stage<In, Out>
+    where
+        F: Fn(In) -> Out + Send + Sync + 'static,
+    {
+        Stage {
+            concurrency: concurrency.max(1),
+            capacity: BUFFER,
+            f: Arc::new(f),
+        }
+    }
+
+    /// Sets how many processed items are buffered for the next stage
+    /// before this one blocks.
+    ///
+    /// **capacity**: usize - the bound of the output channel, 0 hands
+    /// each item over directly.
+    pub fn capacity(mut self, capacity: usize) -> Stage<In, Out> {
+        self.capacity = capacity;
+        self
+    }
+}
+
+/// A chain of stages fed from a source, ending in items of type T.
+pub struct Pipeline<T> {
+    start: Start<T>,
+}
+
+impl<T: Send + 'static> Pipeline<T> {
+    /// Constructs a new Pipeline producing the items of source.
+    ///
+    /// **source**: An iterator with the input items.
+    pub fn new<I>(source: I) -> Pipeline<T>
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: Send + 'static,
+    {
+        let source = source.into_iter();
+        Pipeline {
+            start: Box::new(move |pool, _| {
+                let (tx, rx) = mpsc::sync_channel(BUFFER);
+                pool.execute(move || {
+                    for item in source {
+                        if tx.send(item).is_err() {
+                            break;
+                        }
+                    }
+                })?;
+                Ok(rx)
+            }),
+        }
+    }
+
+    /// Appends a stage to the pipeline.
+    ///
+    /// **stage**: Stage<T, Out> - the stage processing the items. \
+    /// **returns**: the Pipeline producing the items of the stage.
+    pub fn then<Out: Send + 'static>(self, stage: Stage<T, Out>) -> Pipeline<Out> {
+        let start = self.start;
+        Pipeline {
+            start: Box::new(move |pool, panicked| {
+                let input = Arc::new(Mutex::new(start(pool, panicked)?));
+                let (tx, rx) = mpsc::sync_channel(stage.capacity);
+                for _ in 0..stage.concurrency {
+                    let slot = Slot {
+                        input: Arc::clone(&input),
+                        output: tx.clone(),
+                        f: Arc::clone(&stage.f),
+                        panicked: Arc::clone(panicked),
+                    };
+                    pool.execute(move || slot.run())?;
+                }
+                Ok(rx)
+            }),
+        }
+    }
+
+    /// Starts every stage on the pool.
+    ///
+    /// **pool**: &WorkerPool - the pool running the stages. \
+    /// **returns**: an Output iterating over the items of the last
+    /// stage, or an ExecuteError if the pool rejected a stage.
+    pub fn run(self, pool: &WorkerPool) -> Result<Output<T>, ExecuteError> {
+        let panicked = Arc::new(AtomicUsize::new(0));
+        let items = (self.start)(pool, &panicked)?;
+        Ok(Output { items, panicked })
+    }
+}
+
+// A concurrent slot of a stage, processing items until its input is
+// drained or its output is dropped.
+struct Slot<In, Out> {
+    input: Arc<Mutex<Receiver<In>>>,
+    output: SyncSender<Out>,
+    f: Arc<dyn Fn(In) -> Out + Send + Sync + 'static>,
+    panicked: Arc<AtomicUsize>,
+}
+
+impl<In, Out> Slot<In, Out> {
+    fn run(self) {
+        loop {
+            let item = self.input.lock().expect("Cant acquire lock").recv();
+            let item = match item {
+                Ok(item) => item,
+                Err(_) => return,
+            };
+            match catch(|| (self.f)(item)) {
+                Ok(out) => {
+                    if self.output.send(out).is_err() {
+                        return;
+                    }
+                }
+                Err(_) => {
+                    self.panicked.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+/// The items coming out of a running pipeline. Dropping it stops the
+/// stages once they finish their current items.
+pub struct Output<T> {
+    items: Receiver<T>,
+    panicked: Arc<AtomicUsize>,
+}
+
+impl<T> Output<T> {
+    /// Returns how many items were dropped so far because a stage
+    /// panicked on them.
+    pub fn panicked(&self) -> usize {
+        self.panicked.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Iterator for Output<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.items.recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn pipeline_should_skip_panicked_items_and_stop_when_dropped() {
+        let pool = WorkerPool::new(4);
+        let mut output = Pipeline::new(0..10)
+            .then(Stage::new(2, |x: u32| {
+                if x == 3 {
+                    panic!("bad record");
+                }
+                x * 2
+            }))
+            .then(Stage::new(1, |x: u32| x + 1).capacity(0))
+            .run(&pool)
+            .unwrap();
+        let mut items: Vec<_> = output.by_ref().collect();
+        items.sort_unstable();
+        assert_eq!(vec![1, 3, 5, 9, 11, 13, 15, 17, 19], items);
+        assert_eq!(1, output.panicked());
+
+        let mut output = Pipeline::new(0..)
+            .then(Stage::new(1, |x: u64| x))
+            .run(&pool)
+            .unwrap();
+        assert_eq!(Some(0), output.next());
+        drop(output);
+        pool.wait();
+    }
+}
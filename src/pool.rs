@@ -1,194 +1,7038 @@
-//! ## Pool
-//!
-//! With this module, we are able to synchronize channels,
-//! start jobs, wait for workers, and many others concurrent
-//! tasks are made easy.
-
-use std::{
-    fmt::Display,
-    sync::{mpsc, Arc, Mutex},
-    thread,
-};
-
-// Basic types for concurrent tasks
-type Job = Box<dyn FnOnce() + Send + Sync + 'static>;
-type JobReceiver = Arc<Mutex<mpsc::Receiver<Job>>>;
-type Handle = thread::JoinHandle<()>;
-
-/// Implements a continuous pool of rust threads thats doesn't stops
-/// unless it gets out of scope.
-///
-/// ### Examples
-/// 
-/// let njobs = 20;
-/// let nworkers = 3;
-/// let pool = pool::WorkerPool::new(nworkers);
-/// let atomic = Arc::new(AtomicUsize::new(0));
-/// let wg = WaitGroup::default();
-/// 
-/// // send the jobs to the pool
-/// for _ in 0..njobs {
-///     let wg = wg.clone();
-///     let atomic = atomic.clone();
-///     pool.execute(move || {
-///         atomic.fetch_add(1, Ordering::Relaxed);
-///         drop(wg);
-///     });
-/// }
-/// 
-/// // wait for the pool finnishes
-/// wg.wait();
-/// assert_eq!(njobs, atomic.load(Ordering::Relaxed));
-pub struct WorkerPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
-}
-
-impl WorkerPool {
-    /// Constructs a new WorkerPool of size x.
-    ///
-    /// **size**: usize - Is the number of workers in WorkerPool object. \
-    /// **returns**: a WorkerPool object.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use rpools::pool::WorkerPool;
-    ///
-    /// let pool = WorkerPool::new(3);
-    ///
-    /// assert_eq!("workers[] = (id: 0)(id: 1)(id: 2)", pool.to_string());
-    /// ```
-    pub fn new(size: usize) -> WorkerPool {
-        let (tx, rx) = mpsc::channel();
-        let mut workers = Vec::<Worker>::with_capacity(size);
-        let rec = Arc::new(Mutex::new(rx));
-
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&rec)));
-        }
-
-        WorkerPool {
-            workers,
-            sender: tx,
-        }
-    }
-
-    /// Executes a job. The job is moved to closure, as this function is FnOnce. \
-    ///
-    /// **f**: A FnOnce closure hosted by a Box smart pointer.
-    /// ## Examples
-    ///
-    /// ```
-    /// use rpools::pool::WorkerPool;
-    /// use std::sync::mpsc;
-    /// use std::sync::{Arc, Mutex};
-    ///
-    /// let njobs = 20;
-    /// let nworkers = 10;
-    ///
-    /// let pool = WorkerPool::new(nworkers);
-    /// let (tx, rx) = mpsc::channel();
-    ///
-    /// let atx = Arc::new(Mutex::new(tx));
-    ///
-    /// for _ in 0 .. njobs {
-    ///     let atx = atx.clone();
-    ///     pool.execute(move || {
-    ///         let tx = atx.lock().unwrap();
-    ///         tx.send(1).unwrap();
-    ///     });
-    /// }
-    ///
-    /// let sum = rx.iter().take(njobs).sum();
-    /// assert_eq!(njobs, sum);
-    /// ```
-    pub fn execute<J>(&self, f: J)
-    where
-        J: FnOnce() + Send + Sync + 'static,
-    {
-        let job = Box::new(f);
-        self.sender.send(job).expect("Cant send job");
-    }
-}
-
-// Implements Display for WorkerPool. This is usefull as we can able
-// to compare and make unit tests more easily.
-impl Display for WorkerPool {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut buffer = String::new();
-        for i in &self.workers {
-            buffer.push_str(&i.to_string());
-        }
-        write!(f, "workers[] = {}", buffer)
-    }
-}
-
-// A structure that holds an id and thread handle.
-//
-// id: usize - An id for worker indentification.\
-// handle: JoinHandle<()> - a handle that has a working thread.
-struct Worker {
-    id: usize,
-    _handle: Handle,
-}
-
-impl Worker {
-    // Constructs a new Worker.
-    //
-    // id: usize - Worker identificator.
-    // handle: JoinHandle<()> - a thread handle.
-    fn new(id: usize, handle: JobReceiver) -> Worker {
-        let handle = thread::spawn(move || loop {
-            let job = match handle.lock().expect("Cant acquire lock").recv() {
-                Ok(data) => data,
-                Err(_) => continue,
-            };
-
-            job();
-        });
-
-        Worker {
-            id,
-            _handle: handle,
-        }
-    }
-}
-
-// Implements Display for Worker as this simplifys test writing.
-impl Display for Worker {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "(id: {})", self.id,)
-    }
-}
-
-// This sections are the beginning of workerpool module unit tests.
-#[cfg(test)]
-mod unit_tests {
-    use super::*;
-
-    #[test]
-    fn worker_should_return_new() {
-        let (_, rx) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(rx));
-        let w = Worker::new(1, Arc::clone(&receiver));
-        assert_eq!("(id: 1)", w.to_string());
-    }
-
-    #[test]
-    fn workerpool_should_return_new() {
-        let expected = "workers[] = (id: 0)(id: 1)(id: 2)".to_string();
-        let pool = WorkerPool::new(3);
-        assert_eq!(expected.to_string(), pool.to_string());
-    }
-
-    #[test]
-    fn workerpool_should_execute_job_succeed() {
-        let pool = WorkerPool::new(1);
-        for _ in 0..10000 {
-            pool.execute(|| {
-                let _sum = 3 + 1;
-            });
-        }
-    }
-}
+//! ## Pool
+//!
+//! With this module, we are able to synchronize channels,
+//! start jobs, wait for workers, and many others concurrent
+//! tasks are made easy.
+
+use std::{
+    any::{self, Any, TypeId},
+    cell::{Cell, RefCell},
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    error::Error,
+    fmt::{Debug, Display},
+    hash::{BuildHasher, Hasher},
+    mem,
+    panic::{self, AssertUnwindSafe},
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Condvar, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    audit::{AuditRecord, AuditSink, Outcome},
+    backend::{JobQueue, PriorityHeap, RoundRobin},
+    dead_letter::{DeadLetter, DeadLetterSink, Failure},
+    fallback::Spawn,
+    histogram::Histogram,
+    observer::{JobInfo, PoolObserver},
+    queue::{Origin, Overflow, Pop, PushError, Queue},
+    rng::WorkerRng,
+    scaling::HillClimber,
+    store::QueueStore,
+    sync::{Broadcast, CancellationToken, RateLimiter, Subscriber, WaitGroup},
+    timer::Timer,
+};
+
+mod handle;
+mod metrics;
+mod subpool;
+
+pub use handle::{select_all, select_any, JobHandle};
+#[cfg(feature = "metrics-export")]
+pub use metrics::JOB_DURATION_BUCKETS;
+pub use metrics::{
+    DrainReport, Health, LatencyStats, Percentiles, PoolDump, PoolMetrics, WorkerDump, WorkerState,
+};
+pub use subpool::{PoolHandle, SubPool};
+
+pub(crate) use handle::handle_channel;
+use handle::with_handle;
+use subpool::Group;
+
+// How long a job joining a sub-job waits for it before looking for
+// queued jobs to run again.
+const HELP_INTERVAL: Duration = Duration::from_millis(1);
+
+// Basic types for concurrent tasks
+/// A job as the pool keeps it, returned by `WorkerPool::shutdown_now`.
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+type Handle = thread::JoinHandle<()>;
+
+// Builds the state of a worker from its id, for Builder::worker_state.
+type StateInit = Arc<dyn Fn(usize) -> Box<dyn Any> + Send + Sync>;
+
+// Called with the id of a worker idle for a while, for Builder::on_idle.
+type IdleCallback = Arc<dyn Fn(usize) + Send + Sync>;
+
+// Makes the storage of a queue lane, for Builder::queue_backend.
+type Backend = Box<dyn Fn() -> Box<dyn JobQueue<QueuedJob>> + Send + Sync>;
+
+// Captures the context of the caller when a job is sent, and returns
+// what enters it on the worker, for Builder::propagate.
+type Capture = Arc<dyn Fn() -> Box<dyn FnOnce() -> Box<dyn Any> + Send> + Send + Sync>;
+
+// The state a worker keeps for a pool.
+type PoolState = (Weak<Shared>, Box<dyn Any>);
+
+thread_local! {
+    // The token of the task running on this worker thread, read by the
+    // jobs sent with a JobContext.
+    static CURRENT_TOKEN: RefCell<Option<CancellationToken>> = const { RefCell::new(None) };
+
+    // Set when a job wrapper caught a panic of the job, so the worker
+    // counts it.
+    static CAUGHT_PANIC: Cell<bool> = const { Cell::new(false) };
+    static CAUGHT_MESSAGE: RefCell<Option<String>> = const { RefCell::new(None) };
+
+    // The attempt of the job running on this worker thread, set by the
+    // retry module.
+    static CURRENT_ATTEMPT: Cell<usize> = const { Cell::new(1) };
+
+    // The generator of this worker thread, seeded when the worker starts
+    // if the pool has a seed, or lazily from a random seed otherwise.
+    static WORKER_RNG: RefCell<Option<WorkerRng>> = const { RefCell::new(None) };
+
+    // The pool and trace id of the task running on this worker thread,
+    // so jobs sent from it join the same trace.
+    static CURRENT_POOL: RefCell<Option<Weak<Shared>>> = const { RefCell::new(None) };
+    static CURRENT_TRACE: Cell<Option<u64>> = const { Cell::new(None) };
+
+    // The slot where this worker thread shows the job it runs, read by
+    // WorkerPool::dump. Threads that aren't workers have none.
+    static CURRENT_JOB: RefCell<Option<Arc<JobSlot>>> = const { RefCell::new(None) };
+
+    // The id of this worker thread, set if its pool attributes panics.
+    static PANIC_WORKER: Cell<Option<usize>> = const { Cell::new(None) };
+
+    // The id of this worker thread, and the states it keeps for the
+    // jobs sent with execute_with_state, one per pool.
+    static WORKER_ID: Cell<Option<usize>> = const { Cell::new(None) };
+    static WORKER_STATES: RefCell<Vec<PoolState>> = const { RefCell::new(Vec::new()) };
+}
+
+// The job a worker runs.
+struct RunningJob {
+    name: Option<Arc<str>>,
+    label: Option<String>,
+    since: Instant,
+}
+
+// The job a worker runs, if any.
+type JobSlot = Mutex<Option<RunningJob>>;
+
+// Shows the job the current worker runs, or that it is idle.
+fn show_job(job: Option<RunningJob>) {
+    CURRENT_JOB.with(|slot| {
+        if let Some(slot) = slot.borrow().as_ref() {
+            *slot.lock().expect("Cant acquire lock") = job;
+        }
+    });
+}
+
+// Installed once, by the first pool built with Builder::attribute_panics.
+static PANIC_HOOK: std::sync::Once = std::sync::Once::new();
+
+// Wraps the panic hook in place, so panics on workers of pools that
+// attribute them are preceded by a line naming the worker and job. The
+// previous hook still runs for every panic.
+fn install_panic_hook() {
+    PANIC_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if let Some(attribution) = panic_attribution() {
+                eprintln!("{}", attribution);
+            }
+            previous(info);
+        }));
+    });
+}
+
+// Describes the worker and job panicking on the current thread, if it
+// is a worker of a pool that attributes panics.
+fn panic_attribution() -> Option<String> {
+    let worker = PANIC_WORKER.with(Cell::get)?;
+    let job = CURRENT_JOB.with(|slot| {
+        let slot = slot.borrow();
+        // try_lock, the panic may come from the code holding the slot
+        let job = slot.as_ref()?.try_lock().ok()?;
+        job.as_ref().map(|job| match (&job.name, &job.label) {
+            (Some(name), Some(label)) => format!("job {} (label {})", name, label),
+            (Some(name), None) => format!("job {}", name),
+            (None, Some(label)) => format!("a job labeled {}", label),
+            (None, None) => "an unnamed job".to_string(),
+        })
+    });
+    Some(format!(
+        "rpools: worker {} panicked running {}",
+        worker,
+        job.as_deref().unwrap_or("no job")
+    ))
+}
+
+// The next trace id given to a job sent from outside the pools.
+static NEXT_TRACE: AtomicU64 = AtomicU64::new(1);
+
+// Returns the trace id of the running job, or a new one when called
+// from outside a job.
+fn inherited_trace() -> u64 {
+    CURRENT_TRACE
+        .with(Cell::get)
+        .unwrap_or_else(|| NEXT_TRACE.fetch_add(1, Ordering::Relaxed))
+}
+
+// Runs f, catching its panic and marking it for the worker metrics and
+// the dead letter.
+pub(crate) fn catch<R>(f: impl FnOnce() -> R) -> Result<R, Box<dyn Any + Send + 'static>> {
+    panic::catch_unwind(AssertUnwindSafe(f)).inspect_err(|payload| {
+        CAUGHT_PANIC.with(|caught| caught.set(true));
+        let message = panic_message(payload.as_ref()).map(str::to_string);
+        CAUGHT_MESSAGE.with(|caught| caught.replace(message));
+    })
+}
+
+// Returns the panic message, when the payload is a string.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> Option<&str> {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+}
+
+// Tells the worker which attempt of a job runs, for the dead letter.
+pub(crate) fn set_attempt(attempt: usize) {
+    CURRENT_ATTEMPT.with(|current| current.set(attempt));
+}
+
+/// A job waiting in the queue of a pool, with the options it was sent
+/// with, as stored by a JobQueue backend.
+// on_cancel runs instead of the job when it is cancelled or misses its
+// deadline. announced is set once the observers were told of the submit.
+pub struct QueuedJob {
+    job: Job,
+    on_cancel: Option<Job>,
+    announced: Option<Arc<AtomicBool>>,
+    name: Option<Arc<str>>,
+    label: Option<String>,
+    tag: Option<Arc<str>>,
+    deadline: Option<Instant>,
+    token: Option<CancellationToken>,
+    trace: u64,
+    submitted_by: thread::ThreadId,
+    submitter: Option<SubmitterId>,
+    queued: Instant,
+}
+
+impl QueuedJob {
+    // Wraps a job in a QueuedJob without options.
+    fn new(job: Job) -> QueuedJob {
+        QueuedJob {
+            job,
+            on_cancel: None,
+            announced: None,
+            name: None,
+            label: None,
+            tag: None,
+            deadline: None,
+            token: None,
+            trace: inherited_trace(),
+            submitted_by: thread::current().id(),
+            submitter: None,
+            queued: Instant::now(),
+        }
+    }
+
+    /// Returns the name, label and trace id of the job.
+    pub fn info(&self) -> JobInfo<'_> {
+        JobInfo {
+            name: self.name.as_deref(),
+            label: self.label.as_deref(),
+            trace: self.trace,
+        }
+    }
+
+    /// Returns the deadline for the job to start, if it was sent with
+    /// one.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Returns when the job was queued.
+    pub fn queued_at(&self) -> Instant {
+        self.queued
+    }
+
+    /// Returns the submitter the job was sent as, if any.
+    pub fn submitter(&self) -> Option<SubmitterId> {
+        self.submitter
+    }
+
+    /// Returns the tag the job was sent with, if any.
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+}
+
+// The info of a task handed over to the queue, kept by the submitting
+// thread to tell the observers once the task is accepted.
+struct Submitted {
+    name: Option<Arc<str>>,
+    label: Option<String>,
+    trace: u64,
+    announced: Arc<AtomicBool>,
+}
+
+/// Identifies a component sending jobs to a pool, made by
+/// `WorkerPool::submitter_id`. In a pool built with `Builder::fair`,
+/// the jobs of each submitter wait in their own FIFO, and workers take
+/// jobs from the submitters in turn.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SubmitterId(u64);
+
+/// The priority of a job. Workers always pick jobs from the highest
+/// priority lane first, and in FIFO order within a lane.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Background jobs, executed when no other job is waiting.
+    Low,
+    /// The priority of jobs sent with `execute`.
+    #[default]
+    Normal,
+    /// Interactive jobs, executed before any other waiting job.
+    High,
+}
+
+impl Priority {
+    // The number of priority lanes in the queue.
+    const LANES: usize = 3;
+
+    // The index of the queue lane for this priority.
+    fn lane(self) -> usize {
+        self as usize
+    }
+}
+
+/// What a pool does when a job sent with `execute` panics.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum PanicPolicy {
+    /// The panic unwinds the worker thread, which exits.
+    #[default]
+    Unwind,
+    /// The panic is caught and counted, and the worker goes on.
+    Catch,
+    /// The process is aborted, for services that can't run on with a
+    /// broken invariant.
+    Abort,
+}
+
+/// When the workers of a pool are spawned, set with `Builder::spawn_mode`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum SpawnMode {
+    /// Every worker is spawned when the pool is built.
+    #[default]
+    Eager,
+    /// Workers are spawned as jobs arrive and find no idle worker, up
+    /// to the size of the pool, and then kept. Short lived tools that
+    /// need one or two workers don't pay for the others.
+    Lazy,
+}
+
+/// What a pool does with a job sent while its queue is at the bound set
+/// with `Builder::queue_bound`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum OverflowPolicy {
+    /// The job is rejected with ExecuteError::QueueFull.
+    #[default]
+    RejectWithError,
+    /// The submitting thread blocks until a worker takes a queued job.
+    /// Blocking inside a job of the same pool may deadlock.
+    Block,
+    /// The job queued first in the lowest priority lane is dropped to
+    /// make room, as if it was cancelled, whatever order the backend of
+    /// the lane picks jobs in.
+    DropOldest,
+    /// The new job is dropped, as if it was cancelled.
+    DropNewest,
+    /// The new job runs on the submitting thread, which slows the
+    /// producer down to the pace of the pool.
+    CallerRuns,
+}
+
+/// The configuration of a pool as plain data, for services that read
+/// it from their configuration files. Start from `PoolConfig::default()`
+/// and build the pool with `WorkerPool::from_config`.
+///
+/// ### Examples
+///
+/// ```
+/// use rpools::pool::{PanicPolicy, PoolConfig, WorkerPool};
+///
+/// let mut config = PoolConfig::default();
+/// config.workers = 2;
+/// config.queue_bound = Some(1000);
+/// config.thread_name = Some("billing".to_string());
+/// config.panic_policy = PanicPolicy::Catch;
+///
+/// let pool = WorkerPool::from_config(&config);
+/// let name = pool.submit(|| std::thread::current().name().map(str::to_string));
+/// assert!(name.unwrap().join().unwrap().unwrap().starts_with("billing-"));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PoolConfig {
+    /// The workers the pool starts with, and keeps while idle.
+    pub workers: usize,
+    /// If set, the pool is elastic and spawns up to this many workers
+    /// while jobs are waiting, see `Builder::elastic`.
+    pub max_workers: Option<usize>,
+    /// How long the extra workers of an elastic pool wait idle before
+    /// exiting.
+    pub keep_alive: Duration,
+    /// The maximum number of queued jobs, or None for no bound.
+    pub queue_bound: Option<usize>,
+    /// What happens with a job sent while the queue is at its bound.
+    pub overflow_policy: OverflowPolicy,
+    /// The prefix of the worker thread names.
+    pub thread_name: Option<String>,
+    /// What happens when a job sent with `execute` panics.
+    pub panic_policy: PanicPolicy,
+}
+
+// Implements Default for PoolConfig as an unbounded pool with one
+// worker for each CPU.
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            workers: parallelism(),
+            max_workers: None,
+            keep_alive: Duration::from_secs(60),
+            queue_bound: None,
+            overflow_policy: OverflowPolicy::RejectWithError,
+            thread_name: None,
+            panic_policy: PanicPolicy::Unwind,
+        }
+    }
+}
+
+/// Implements a continuous pool of rust threads thats doesn't stops
+/// unless it gets out of scope or `shutdown` is called.
+///
+/// ### Examples
+///
+/// let njobs = 20;
+/// let nworkers = 3;
+/// let pool = pool::WorkerPool::new(nworkers);
+/// let atomic = Arc::new(AtomicUsize::new(0));
+/// let wg = WaitGroup::default();
+///
+/// // send the jobs to the pool
+/// for _ in 0..njobs {
+///     let wg = wg.clone();
+///     let atomic = atomic.clone();
+///     pool.execute(move || {
+///         atomic.fetch_add(1, Ordering::Relaxed);
+///         drop(wg);
+///     }).unwrap();
+/// }
+///
+/// // wait for the pool finnishes
+/// wg.wait();
+/// assert_eq!(njobs, atomic.load(Ordering::Relaxed));
+pub struct WorkerPool {
+    shared: Arc<Shared>,
+    scaler: Mutex<Option<Handle>>,
+    timer: Mutex<Option<Handle>>,
+    // the workers of the IO bound jobs, set by Builder::io_workers
+    io: Option<Box<WorkerPool>>,
+}
+
+// The state shared between the pool, its workers and its helper threads.
+struct Shared {
+    queue: Queue<QueuedJob>,
+    labels: HashMap<String, LabelQueue>,
+    workers: Mutex<Vec<Worker>>,
+    next_id: AtomicUsize,
+    retiring: AtomicUsize,
+    completed: AtomicUsize,
+    active: AtomicUsize,
+    panicked: AtomicUsize,
+    busy_nanos: AtomicU64,
+    // the time workers spent blocked waiting for a job
+    parked_nanos: AtomicU64,
+    in_flight: AtomicUsize,
+    idle_lock: Mutex<()>,
+    idle: Condvar,
+    control: Broadcast<Control>,
+    paused: Mutex<bool>,
+    resumed: Condvar,
+    peers: Mutex<Vec<Peer>>,
+    thieves: Mutex<Vec<Weak<Shared>>>,
+    timer: Timer<Delayed>,
+    observers: Vec<Arc<dyn PoolObserver>>,
+    seed: Option<u64>,
+    retries: Arc<RetryQueue>,
+    attribute_panics: bool,
+    keyed: Mutex<HashMap<SerialKey, VecDeque<Job>>>,
+    groups: Mutex<Vec<Weak<Group>>>,
+    audit: Vec<Arc<dyn AuditSink>>,
+    dead_letters: Option<Arc<dyn DeadLetterSink>>,
+    fallback: Option<Fallback>,
+    spilled: AtomicUsize,
+    budget: Option<Duration>,
+    budget_yields: AtomicUsize,
+    // the workers unwound by a panic
+    dead: AtomicUsize,
+    cores: Vec<usize>,
+    // the OS priority of the workers, set by Builder::thread_priority
+    priority: Option<i8>,
+    on_idle: Option<(Duration, IdleCallback)>,
+    worker_state: Option<(TypeId, StateInit)>,
+    // the worker threads running, including the ones asked to retire
+    running: AtomicUsize,
+    elastic: Option<Elastic>,
+    queue_wait: Histogram,
+    execution: Histogram,
+    thread_name: Option<String>,
+    panic_policy: PanicPolicy,
+    overflow_policy: OverflowPolicy,
+    // the jobs rejected or dropped because the queue was at its bound
+    rejected: AtomicUsize,
+    dropped: AtomicUsize,
+    missed_deadlines: AtomicUsize,
+    capture: Option<Capture>,
+    store: Option<Arc<dyn QueueStore>>,
+}
+
+// The bounds of an elastic pool, set by Builder::elastic.
+struct Elastic {
+    core: usize,
+    max: usize,
+    keep_alive: Duration,
+}
+
+// The control messages sent to the helper threads of a pool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Control {
+    // the pool is shutting down, the thread should return
+    Stop,
+}
+
+// A job waiting in the timer. Whoever sets claimed first, the timer
+// thread when the job is due or the handle when cancelling, wins. A
+// recurring job is only claimed by its handle.
+struct Delayed {
+    timed: Timed,
+    claimed: Arc<AtomicBool>,
+}
+
+enum Timed {
+    Once(Job),
+    // run on the timer thread itself when due, for quick jobs that
+    // can't wait behind the queue, like timeouts
+    Inline(Job),
+    // next gives the due time of the run after the one due at due, or
+    // None to stop. running is set while a run is queued or running,
+    // so runs of the same job never overlap.
+    Recurring {
+        job: Arc<dyn Fn() + Send + Sync + 'static>,
+        due: Instant,
+        next: NextDue,
+        running: Arc<AtomicBool>,
+    },
+}
+
+type NextDue = Box<dyn Fn(Instant) -> Option<Instant> + Send + Sync + 'static>;
+
+// A pool whose queued jobs may be run by the idle workers of another
+// pool, with at most cap of them running at once.
+#[derive(Clone)]
+struct Peer {
+    shared: Weak<Shared>,
+    cap: usize,
+    stolen: Arc<AtomicUsize>,
+}
+
+// The turn of a key, held by its running job. When the job finishes, or
+// is dropped without running, the next job waiting for the key is
+// queued, or the key is released if none is.
+struct KeyTurn {
+    shared: Weak<Shared>,
+    key: SerialKey,
+}
+
+// The key serializing a job: one given to execute_keyed, or the mailbox
+// of an actor, so the keys of users and actors never collide.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum SerialKey {
+    User(u64),
+    Actor(u64),
+}
+
+// Queues a job after the jobs of its key, or right away if none waits.
+fn enqueue_serial(shared: &Arc<Shared>, key: SerialKey, job: Job) -> Result<(), ExecuteError> {
+    shared.accepting()?;
+    let mut keyed = shared.keyed.lock().expect("Cant acquire lock");
+    if let Some(waiting) = keyed.get_mut(&key) {
+        waiting.push_back(job);
+        return Ok(());
+    }
+    keyed.insert(key, VecDeque::new());
+    drop(keyed);
+
+    let turn = KeyTurn {
+        shared: Arc::downgrade(shared),
+        key,
+    };
+    shared.enqueue(QueuedJob::new(turn.job(job)), Priority::Normal)
+}
+
+impl KeyTurn {
+    // Wraps a job so it passes the turn of its key on when it ends.
+    fn job(self, job: Job) -> Job {
+        Box::new(move || {
+            let _turn = self;
+            job();
+        })
+    }
+}
+
+impl Drop for KeyTurn {
+    fn drop(&mut self) {
+        let Some(shared) = self.shared.upgrade() else {
+            return;
+        };
+        let next = {
+            let mut keyed = shared.keyed.lock().expect("Cant acquire lock");
+            let next = keyed.get_mut(&self.key).and_then(VecDeque::pop_front);
+            if next.is_none() {
+                keyed.remove(&self.key);
+            }
+            next
+        };
+        if let Some(next) = next {
+            let turn = KeyTurn {
+                shared: Weak::clone(&self.shared),
+                key: self.key,
+            };
+            // a rejected job drops its turn, which drops the next ones
+            let _ = shared.enqueue_follow_up(QueuedJob::new(turn.job(next)));
+        }
+    }
+}
+
+// Counts a job stolen from a peer until it finishes.
+struct Stolen(Arc<AtomicUsize>);
+
+impl Drop for Stolen {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Shared {
+    // Constructs the shared state of a pool without workers.
+    fn new(labels: HashMap<String, LabelQueue>, lane_limits: Vec<Option<usize>>) -> Shared {
+        Shared {
+            queue: Queue::new(lane_limits),
+            labels,
+            workers: Mutex::new(Vec::new()),
+            next_id: AtomicUsize::new(0),
+            retiring: AtomicUsize::new(0),
+            completed: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
+            panicked: AtomicUsize::new(0),
+            busy_nanos: AtomicU64::new(0),
+            parked_nanos: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+            idle_lock: Mutex::new(()),
+            idle: Condvar::new(),
+            control: Broadcast::new(),
+            paused: Mutex::new(false),
+            resumed: Condvar::new(),
+            peers: Mutex::new(Vec::new()),
+            thieves: Mutex::new(Vec::new()),
+            timer: Timer::new(),
+            observers: Vec::new(),
+            seed: None,
+            retries: Arc::new(RetryQueue::new(usize::MAX)),
+            attribute_panics: false,
+            keyed: Mutex::new(HashMap::new()),
+            groups: Mutex::new(Vec::new()),
+            audit: Vec::new(),
+            dead_letters: None,
+            fallback: None,
+            spilled: AtomicUsize::new(0),
+            budget: None,
+            budget_yields: AtomicUsize::new(0),
+            dead: AtomicUsize::new(0),
+            cores: Vec::new(),
+            priority: None,
+            on_idle: None,
+            worker_state: None,
+            running: AtomicUsize::new(0),
+            elastic: None,
+            queue_wait: Histogram::new(),
+            execution: Histogram::new(),
+            thread_name: None,
+            panic_policy: PanicPolicy::Unwind,
+            overflow_policy: OverflowPolicy::RejectWithError,
+            rejected: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+            missed_deadlines: AtomicUsize::new(0),
+            capture: None,
+            store: None,
+        }
+    }
+
+    // Queues a task in the lane of the given priority, respecting the
+    // label and lane limits.
+    fn enqueue(self: &Arc<Self>, task: QueuedJob, priority: Priority) -> Result<(), ExecuteError> {
+        self.admit(task, priority, Origin::New)
+    }
+
+    // Queues the next job of a key or group whose previous job was
+    // accepted, even while the pool is quiesced.
+    fn enqueue_follow_up(self: &Arc<Self>, task: QueuedJob) -> Result<(), ExecuteError> {
+        self.admit(task, Priority::Normal, Origin::FollowUp)
+    }
+
+    // Returns the error for a new job if the pool doesn't accept any.
+    fn accepting(&self) -> Result<(), ExecuteError> {
+        if self.queue.is_closed() {
+            Err(ExecuteError::Shutdown)
+        } else if !self.queue.is_accepting() {
+            Err(ExecuteError::Quiesced)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Queues a task, taking a follow-up even while the pool is quiesced.
+    fn admit(
+        self: &Arc<Self>,
+        mut task: QueuedJob,
+        priority: Priority,
+        origin: Origin,
+    ) -> Result<(), ExecuteError> {
+        self.capture(&mut task);
+        if let Some(label) = &task.label {
+            self.reserve_label(label)?;
+        }
+        let submitted = self.track_submit(&mut task);
+        // counted before the push, so a fast worker can't finish it first
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+        self.push(task, priority, origin)?;
+        self.notify_submit(submitted);
+        Ok(())
+    }
+
+    // Pushes a counted task to the queue, following the overflow policy
+    // when the queue is at its bound.
+    fn push(
+        self: &Arc<Self>,
+        task: QueuedJob,
+        priority: Priority,
+        origin: Origin,
+    ) -> Result<(), ExecuteError> {
+        let overflow = match self.overflow_policy {
+            OverflowPolicy::Block => Overflow::Wait,
+            OverflowPolicy::DropOldest => Overflow::EvictOldest,
+            _ => Overflow::Reject,
+        };
+        let evicted = match self
+            .queue
+            .push_with(priority.lane(), task, overflow, origin)
+        {
+            Err(PushError::AtCapacity(task))
+                if self.overflow_policy == OverflowPolicy::DropNewest =>
+            {
+                self.drop_task(task);
+                return Ok(());
+            }
+            Err(PushError::AtCapacity(task))
+                if self.overflow_policy == OverflowPolicy::CallerRuns =>
+            {
+                self.work(task);
+                return Ok(());
+            }
+            pushed => pushed,
+        };
+        let evicted = evicted.map_err(|err| {
+            let (task, err) = match err {
+                PushError::Closed(task) => (task, ExecuteError::Shutdown),
+                PushError::Full(task) => (task, ExecuteError::LaneFull(priority)),
+                PushError::Rejected(task) => (task, ExecuteError::Quiesced),
+                PushError::AtCapacity(task) => {
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+                    (task, ExecuteError::QueueFull)
+                }
+            };
+            self.release_label(&task);
+            self.finish(1);
+            err
+        })?;
+        if let Some(task) = evicted {
+            self.drop_task(task);
+        }
+        self.wake_thieves(1);
+        self.grow();
+        Ok(())
+    }
+
+    // Drops a counted task to make room in the queue, as if it was
+    // cancelled.
+    fn drop_task(&self, task: QueuedJob) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        self.cancel_task(task);
+    }
+
+    // Takes the jobs waiting for their key or for a slot of their
+    // SubPool, which aren't queued yet.
+    fn take_waiting(&self) -> Vec<Job> {
+        let keyed = mem::take(&mut *self.keyed.lock().expect("Cant acquire lock"));
+        let mut waiting: Vec<Job> = keyed.into_values().flatten().collect();
+        let mut groups = self.groups.lock().expect("Cant acquire lock");
+        groups.retain(|group| group.strong_count() > 0);
+        for group in groups.iter().filter_map(Weak::upgrade) {
+            waiting.extend(group.take_waiting());
+        }
+        waiting
+    }
+
+    // Skips a counted task taken out of the queue before it ran.
+    fn cancel_task(&self, task: QueuedJob) {
+        self.release_label(&task);
+        self.audit(&task, None, Outcome::Skipped);
+        if let Some(on_cancel) = task.on_cancel {
+            on_cancel();
+        }
+        self.finish(1);
+    }
+
+    // Queues a task, or gives it to the fallback executor if it is
+    // critical and the pool has been saturated for too long.
+    fn dispatch(
+        self: &Arc<Self>,
+        task: QueuedJob,
+        priority: Priority,
+        critical: bool,
+    ) -> Result<(), ExecuteError> {
+        match &self.fallback {
+            Some(fallback)
+                if critical
+                    && fallback.saturated(self.queue.len())
+                    && self.queue.is_accepting() =>
+            {
+                self.spilled.fetch_add(1, Ordering::Relaxed);
+                fallback.spawner.spawn(task.job);
+                Ok(())
+            }
+            _ => self.enqueue(task, priority),
+        }
+    }
+
+    // Queues a batch of tasks in the normal lane, with a single lock.
+    fn enqueue_batch(self: &Arc<Self>, mut tasks: Vec<QueuedJob>) -> Result<(), ExecuteError> {
+        let submitted: Vec<_> = tasks
+            .iter_mut()
+            .map(|task| {
+                self.capture(task);
+                self.track_submit(task)
+            })
+            .collect();
+        let count = tasks.len();
+        self.in_flight.fetch_add(count, Ordering::AcqRel);
+        let pushed = match self.queue.push_batch(Priority::Normal.lane(), tasks) {
+            // the batch doesn't fit, each task overflows on its own
+            Err(PushError::AtCapacity(tasks))
+                if self.overflow_policy != OverflowPolicy::RejectWithError =>
+            {
+                return tasks
+                    .into_iter()
+                    .zip(submitted)
+                    .map(|(task, submitted)| {
+                        self.push(task, Priority::Normal, Origin::New)?;
+                        self.notify_submit(submitted);
+                        Ok(())
+                    })
+                    .fold(Ok(()), Result::and);
+            }
+            pushed => pushed,
+        };
+        pushed.map_err(|err| {
+            self.finish(count);
+            match err {
+                PushError::Closed(_) => ExecuteError::Shutdown,
+                PushError::Full(_) => ExecuteError::LaneFull(Priority::Normal),
+                PushError::Rejected(_) => ExecuteError::Quiesced,
+                PushError::AtCapacity(_) => {
+                    self.rejected.fetch_add(count, Ordering::Relaxed);
+                    ExecuteError::QueueFull
+                }
+            }
+        })?;
+        submitted
+            .into_iter()
+            .for_each(|submitted| self.notify_submit(submitted));
+        self.wake_thieves(count);
+        self.grow();
+        Ok(())
+    }
+
+    // Spawns a worker in an elastic pool if more jobs are queued than
+    // workers are idle, unless it already runs its maximum of workers.
+    fn grow(self: &Arc<Self>) {
+        let Some(elastic) = &self.elastic else {
+            return;
+        };
+        let backlog = |running: usize| {
+            let idle = running.saturating_sub(self.active.load(Ordering::Acquire));
+            self.queue.len() > idle
+        };
+        if !backlog(self.running.load(Ordering::Acquire)) {
+            return;
+        }
+        // checked again under the lock, so concurrent jobs can't overshoot
+        let mut workers = self.workers.lock().expect("Cant acquire lock");
+        let running = self.running.load(Ordering::Acquire);
+        if running < elastic.max && backlog(running) {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            workers.push(Worker::new(id, Arc::clone(self)));
+        }
+    }
+
+    // Spawns workers until n are running, within the maximum of an
+    // elastic pool. Returns how many were spawned.
+    fn prestart(self: &Arc<Self>, n: usize) -> usize {
+        let Some(elastic) = &self.elastic else {
+            return 0;
+        };
+        let mut workers = self.workers.lock().expect("Cant acquire lock");
+        let mut spawned = 0;
+        while self.running.load(Ordering::Acquire) < n.min(elastic.max) {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            workers.push(Worker::new(id, Arc::clone(self)));
+            spawned += 1;
+        }
+        spawned
+    }
+
+    // Called by the workers of an elastic pool that waited idle for the
+    // keep alive. Returns true if the worker should exit, as the pool
+    // runs more than its core workers, and then removes it from the pool.
+    fn claim_idle_exit(&self, id: usize) -> bool {
+        let core = self.elastic.as_ref().map_or(usize::MAX, |e| e.core);
+        let claimed = self
+            .running
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n > core).then(|| n - 1)
+            })
+            .is_ok();
+        if claimed {
+            let mut workers = self.workers.lock().expect("Cant acquire lock");
+            workers.retain(|worker| worker.id != id);
+        }
+        claimed
+    }
+
+    // Captures the context of the caller in a task, so the job runs
+    // inside it, when the pool was built with Builder::propagate.
+    fn capture(&self, task: &mut QueuedJob) {
+        if let Some(capture) = &self.capture {
+            let enter = capture();
+            let job = mem::replace(&mut task.job, Box::new(|| {}));
+            task.job = Box::new(move || {
+                let _entered = enter();
+                job();
+            });
+        }
+    }
+
+    // Tells the observers a task was submitted.
+    fn notify_submit(&self, submitted: Option<Submitted>) {
+        if let Some(submitted) = submitted {
+            let info = JobInfo {
+                name: submitted.name.as_deref(),
+                label: submitted.label.as_deref(),
+                trace: submitted.trace,
+            };
+            self.announce(&info, &submitted.announced);
+        }
+    }
+
+    // Keeps what the submitting thread needs to tell the observers of a
+    // task once it is accepted, if the pool has observers.
+    fn track_submit(&self, task: &mut QueuedJob) -> Option<Submitted> {
+        if self.observers.is_empty() {
+            return None;
+        }
+        let announced = Arc::new(AtomicBool::new(false));
+        task.announced = Some(Arc::clone(&announced));
+        Some(Submitted {
+            name: task.name.clone(),
+            label: task.label.clone(),
+            trace: task.trace,
+            announced,
+        })
+    }
+
+    // Tells the observers of a submitted task, unless that was done
+    // already. The submitting thread does it once the task is accepted,
+    // and the worker before starting it, whichever comes first.
+    fn announce(&self, info: &JobInfo, announced: &AtomicBool) {
+        if !announced.swap(true, Ordering::AcqRel) {
+            for observer in &self.observers {
+                observer.on_submit(info);
+            }
+        }
+    }
+
+    // Wakes the idle workers of the pools stealing from this one, so
+    // they can pick the new jobs.
+    fn wake_thieves(&self, count: usize) {
+        let thieves = self.thieves.lock().expect("Cant acquire lock");
+        for thief in thieves.iter().filter_map(Weak::upgrade) {
+            match count {
+                0 => {}
+                1 => thief.queue.wake_one(),
+                _ => thief.queue.wake_all(),
+            }
+        }
+    }
+
+    // Returns true if a peer has queued jobs this pool may steal.
+    fn can_steal(&self) -> bool {
+        let peers = self.peers.lock().expect("Cant acquire lock");
+        peers.iter().any(|peer| {
+            peer.stolen.load(Ordering::Acquire) < peer.cap
+                && peer
+                    .shared
+                    .upgrade()
+                    .is_some_and(|shared| shared.queue.len() > 0 && !shared.is_paused())
+        })
+    }
+
+    // Takes a queued job from the first peer below its cap. The job
+    // still belongs to the peer, which is returned with it.
+    fn steal(&self) -> Option<(Arc<Shared>, QueuedJob, Stolen)> {
+        // the peers are copied, as their queues are locked next
+        let peers = self.peers.lock().expect("Cant acquire lock").clone();
+        peers.into_iter().find_map(|peer| {
+            let shared = peer.shared.upgrade().filter(|s| !s.is_paused())?;
+            peer.stolen
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                    if n < peer.cap {
+                        Some(n + 1)
+                    } else {
+                        None
+                    }
+                })
+                .ok()?;
+            let stolen = Stolen(peer.stolen);
+            let task = shared.queue.try_pop()?;
+            Some((shared, task, stolen))
+        })
+    }
+
+    // Marks n in flight jobs as finished, waking the threads waiting for
+    // the pool to become idle.
+    fn finish(&self, n: usize) {
+        if self.in_flight.fetch_sub(n, Ordering::AcqRel) == n {
+            // taking the lock makes sure a waiter between its check and
+            // its wait doesn't miss the notification
+            drop(self.idle_lock.lock().expect("Cant acquire lock"));
+            self.idle.notify_all();
+        }
+    }
+
+    // Blocks until no job is queued or running.
+    fn wait_idle(&self) {
+        let mut lock = self.idle_lock.lock().expect("Cant acquire lock");
+        while self.in_flight.load(Ordering::Acquire) != 0 {
+            lock = self.idle.wait(lock).expect("Cant block the current thread");
+        }
+    }
+
+    // Blocks until no job is queued or running, or until timeout.
+    // Returns false on timeout.
+    fn wait_idle_timeout(&self, timeout: Duration) -> bool {
+        let lock = self.idle_lock.lock().expect("Cant acquire lock");
+        let (_lock, result) = self
+            .idle
+            .wait_timeout_while(lock, timeout, |_| {
+                self.in_flight.load(Ordering::Acquire) != 0
+            })
+            .expect("Cant block the current thread");
+        !result.timed_out()
+    }
+
+    // Counts a job in its label queue, if the label has a limit.
+    fn reserve_label(&self, label: &str) -> Result<(), ExecuteError> {
+        let queue = match self.labels.get(label) {
+            Some(queue) => queue,
+            None => return Ok(()),
+        };
+        queue
+            .depth
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |depth| {
+                if depth < queue.limit {
+                    Some(depth + 1)
+                } else {
+                    None
+                }
+            })
+            .map(|_| ())
+            .map_err(|_| ExecuteError::LimitReached(label.to_string()))
+    }
+
+    // Removes a task from its label queue, when it leaves the queue.
+    fn release_label(&self, task: &QueuedJob) {
+        if let Some(queue) = task.label.as_ref().and_then(|l| self.labels.get(l)) {
+            queue.depth.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+
+    // Runs a task popped from the queue, unless it was cancelled or
+    // missed its deadline.
+    fn run(&self, mut task: QueuedJob) {
+        self.release_label(&task);
+        let cancelled = task
+            .token
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled);
+        let expired = task
+            .deadline
+            .is_some_and(|deadline| Instant::now() > deadline);
+        if cancelled || expired {
+            if expired {
+                self.missed_deadlines.fetch_add(1, Ordering::Relaxed);
+            }
+            self.audit(&task, None, Outcome::Skipped);
+            if let Some(on_cancel) = task.on_cancel {
+                on_cancel();
+            }
+            return;
+        }
+        let token = CURRENT_TOKEN.with(|current| current.replace(task.token.take()));
+        let trace = CURRENT_TRACE.with(|current| current.replace(Some(task.trace)));
+        let job = mem::replace(&mut task.job, Box::new(|| {}));
+        let busy = Busy::new(self, task);
+        match self.panic_policy {
+            PanicPolicy::Unwind => job(),
+            PanicPolicy::Catch => {
+                let _ = catch(job);
+            }
+            PanicPolicy::Abort => {
+                if catch(job).is_err() {
+                    process::abort();
+                }
+            }
+        }
+        drop(busy);
+        CURRENT_TRACE.with(|current| current.set(trace));
+        CURRENT_TOKEN.with(|current| current.replace(token));
+    }
+
+    // Writes the audit record of a task that ended now, if the pool has
+    // audit sinks.
+    fn audit(&self, task: &QueuedJob, started: Option<SystemTime>, outcome: Outcome) {
+        if self.audit.is_empty() {
+            return;
+        }
+        let record = AuditRecord {
+            submitted_by: task.submitted_by,
+            name: task.name.as_deref().map(str::to_string),
+            label: task.label.clone(),
+            trace: task.trace,
+            started,
+            finished: SystemTime::now(),
+            outcome,
+        };
+        for sink in &self.audit {
+            sink.record(&record);
+        }
+    }
+
+    // Delivers a job that failed for good to the dead letter sink.
+    fn dead_letter(&self, name: Option<&str>, label: Option<&str>, trace: u64, failure: Failure) {
+        if let Some(sink) = &self.dead_letters {
+            sink.deliver(DeadLetter {
+                name: name.map(str::to_string),
+                label: label.map(str::to_string),
+                trace,
+                attempts: CURRENT_ATTEMPT.with(Cell::get),
+                failure,
+            });
+        }
+    }
+
+    // Pauses or resumes the pool, waking the threads waiting to resume.
+    fn set_paused(&self, paused: bool) {
+        *self.paused.lock().expect("Cant acquire lock") = paused;
+        if !paused {
+            self.resumed.notify_all();
+        }
+    }
+
+    // Returns true if the pool is paused.
+    fn is_paused(&self) -> bool {
+        *self.paused.lock().expect("Cant acquire lock")
+    }
+
+    // Runs a task popped from this pool's queue in the current worker,
+    // once the pool isn't paused, and counts it as finished.
+    fn work(self: &Arc<Self>, task: QueuedJob) {
+        let _finish = Finish(self);
+        self.wait_resumed();
+        let pool = CURRENT_POOL.with(|current| current.replace(Some(Arc::downgrade(self))));
+        self.run(task);
+        CURRENT_POOL.with(|current| current.replace(pool));
+        self.completed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Runs a queued task inside the job of this worker, while that job
+    // waits for another one, and restores what the job shows after it.
+    fn help(self: &Arc<Self>, task: QueuedJob) {
+        let job = CURRENT_JOB.with(|slot| {
+            let slot = slot.borrow();
+            slot.as_ref()
+                .and_then(|slot| slot.lock().expect("Cant acquire lock").take())
+        });
+        let attempt = CURRENT_ATTEMPT.with(Cell::get);
+        // a panic of a job sent with execute must not unwind the waiting job
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| self.work(task)));
+        set_attempt(attempt);
+        show_job(job);
+    }
+
+    // Blocks while the pool is paused.
+    fn wait_resumed(&self) {
+        let paused = self.paused.lock().expect("Cant acquire lock");
+        let _paused = self
+            .resumed
+            .wait_while(paused, |paused| *paused)
+            .expect("Cant block the current thread");
+    }
+
+    // Spawns a new worker with the next free id.
+    fn spawn_worker(self: &Arc<Self>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let worker = Worker::new(id, Arc::clone(self));
+        self.workers.lock().expect("Cant acquire lock").push(worker);
+    }
+
+    // Asks one worker to exit, as soon as it is idle.
+    fn retire_worker(&self) {
+        self.retiring.fetch_add(1, Ordering::AcqRel);
+        self.queue.wake_all();
+    }
+
+    // Called by workers to check if they should retire.
+    fn claim_retirement(&self) -> bool {
+        self.retiring
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1))
+            .is_ok()
+    }
+
+    // Joins the workers that already exited and returns how many
+    // workers are still running and not asked to retire.
+    fn live_workers(&self) -> usize {
+        let mut workers = self.workers.lock().expect("Cant acquire lock");
+        let (exited, running): (Vec<_>, Vec<_>) = mem::take(&mut *workers)
+            .into_iter()
+            .partition(|w| w.handle.as_ref().is_none_or(|h| h.is_finished()));
+        *workers = running;
+        let live = workers.len();
+        drop(workers);
+
+        for worker in exited {
+            worker.join();
+        }
+        live.saturating_sub(self.retiring.load(Ordering::Acquire))
+    }
+
+    // Wakes the helper threads, so they return.
+    fn stop(&self) {
+        self.control.send(Control::Stop);
+    }
+}
+
+/// Errors returned when joining a job that didn't produce its value.
+pub enum JobError {
+    /// The job panicked. Holds the panic payload.
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// The job was cancelled by its token, or missed its deadline.
+    Cancelled,
+    /// The pool was shut down and dropped the job before running it.
+    PoolShutdown,
+    /// The job didn't finish within its timeout.
+    Timeout,
+    /// The job was dropped from a full queue by the DropOldest or
+    /// DropNewest overflow policy, before it ran.
+    Dropped,
+}
+
+impl JobError {
+    // Returns the panic message, when the payload is a string.
+    fn message(&self) -> Option<&str> {
+        match self {
+            JobError::Panicked(payload) => panic_message(payload.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl Debug for JobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobError::Panicked(_) => match self.message() {
+                Some(message) => write!(f, "Panicked({:?})", message),
+                None => write!(f, "Panicked(..)"),
+            },
+            JobError::Cancelled => write!(f, "Cancelled"),
+            JobError::PoolShutdown => write!(f, "PoolShutdown"),
+            JobError::Timeout => write!(f, "Timeout"),
+            JobError::Dropped => write!(f, "Dropped"),
+        }
+    }
+}
+
+impl Display for JobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobError::Panicked(_) => match self.message() {
+                Some(message) => write!(f, "the job panicked: {}", message),
+                None => write!(f, "the job panicked"),
+            },
+            JobError::Cancelled => write!(f, "the job was cancelled"),
+            JobError::PoolShutdown => write!(f, "the pool was shut down before the job ran"),
+            JobError::Timeout => write!(f, "the job timed out"),
+            JobError::Dropped => write!(f, "the job was dropped from a full queue"),
+        }
+    }
+}
+
+impl Error for JobError {}
+
+/// Errors returned when a job can't be sent to the pool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecuteError {
+    /// The queue of the given label is full. The job was rejected.
+    LimitReached(String),
+    /// The lane of the given priority is full. The job was rejected.
+    LaneFull(Priority),
+    /// The pool was shut down and doesn't accept jobs anymore.
+    Shutdown,
+    /// The pool is quiesced, and doesn't accept jobs until reopened.
+    Quiesced,
+    /// The queue holds as many jobs as its bound. The job was rejected.
+    QueueFull,
+    /// No worker of the pool has the given id, or it exited.
+    UnknownWorker(usize),
+}
+
+impl Display for ExecuteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExecuteError::LimitReached(label) => {
+                write!(f, "queue limit reached for label {}", label)
+            }
+            ExecuteError::LaneFull(priority) => {
+                write!(f, "queue limit reached for {:?} priority lane", priority)
+            }
+            ExecuteError::Shutdown => write!(f, "the pool is shut down"),
+            ExecuteError::Quiesced => write!(f, "the pool is quiesced"),
+            ExecuteError::QueueFull => write!(f, "the queue is full"),
+            ExecuteError::UnknownWorker(id) => write!(f, "the pool has no worker {}", id),
+        }
+    }
+}
+
+impl Error for ExecuteError {}
+
+/// The error of `WorkerPool::try_execute`, carrying back the job that
+/// the pool didn't accept, so it can be retried or run elsewhere.
+pub struct TryExecuteError<J> {
+    job: J,
+    error: ExecuteError,
+}
+
+impl<J> TryExecuteError<J> {
+    /// Returns why the pool didn't accept the job.
+    pub fn error(&self) -> &ExecuteError {
+        &self.error
+    }
+
+    /// Returns the job that the pool didn't accept.
+    pub fn into_job(self) -> J {
+        self.job
+    }
+}
+
+// Implements Debug for TryExecuteError, without the job.
+impl<J> Debug for TryExecuteError<J> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TryExecuteError")
+            .field("error", &self.error)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<J> Display for TryExecuteError<J> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl<J> Error for TryExecuteError<J> {}
+
+// Tracks how many jobs of a label are waiting in the queue.
+struct LabelQueue {
+    limit: usize,
+    depth: AtomicUsize,
+}
+
+// The executor critical jobs fall back on, once the queue stayed over
+// threshold for longer than grace.
+struct Fallback {
+    threshold: usize,
+    grace: Duration,
+    spawner: Arc<dyn Spawn>,
+    // when a critical job first saw the queue over threshold
+    since: Mutex<Option<Instant>>,
+}
+
+impl Fallback {
+    // Returns true if the queue has been over threshold for too long,
+    // as seen by the critical jobs.
+    fn saturated(&self, queued: usize) -> bool {
+        let mut since = self.since.lock().expect("Cant acquire lock");
+        if queued < self.threshold {
+            *since = None;
+            return false;
+        }
+        since.get_or_insert_with(Instant::now).elapsed() >= self.grace
+    }
+}
+
+// Tracks the retries waiting for their backoff or in the queue, and
+// the ones dropped because the limit was reached.
+struct RetryQueue {
+    limit: usize,
+    depth: AtomicUsize,
+    rejected: AtomicUsize,
+}
+
+impl RetryQueue {
+    fn new(limit: usize) -> RetryQueue {
+        RetryQueue {
+            limit,
+            depth: AtomicUsize::new(0),
+            rejected: AtomicUsize::new(0),
+        }
+    }
+}
+
+// Frees a place in the retry queue when the retry starts, or is
+// dropped without running.
+struct RetrySlot(Arc<RetryQueue>);
+
+impl Drop for RetrySlot {
+    fn drop(&mut self) {
+        self.0.depth.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// Sends the retries of a pool to its retry queue, for the retry
+// module. Weak, so the retries of a job don't keep the pool alive.
+#[derive(Clone)]
+pub(crate) struct Retrier {
+    shared: Weak<Shared>,
+}
+
+impl Retrier {
+    // Delivers the running job to the dead letter sink, as its last
+    // attempt failed.
+    pub(crate) fn dead_letter(&self, error: Option<Box<dyn Any + Send + 'static>>) {
+        let Some(shared) = self.shared.upgrade() else {
+            return;
+        };
+        let (name, label) = CURRENT_JOB.with(|slot| {
+            let slot = slot.borrow();
+            let job = slot
+                .as_ref()
+                .map(|slot| slot.lock().expect("Cant acquire lock"));
+            match job.as_deref() {
+                Some(Some(job)) => (job.name.clone(), job.label.clone()),
+                _ => (None, None),
+            }
+        });
+        let trace = CURRENT_TRACE.with(Cell::get).unwrap_or(0);
+        shared.dead_letter(
+            name.as_deref(),
+            label.as_deref(),
+            trace,
+            Failure::Exhausted(error),
+        );
+    }
+
+    // Queues job after delay, unless the retry queue is full or the
+    // pool was shut down. Returns true if the job was accepted.
+    pub(crate) fn retry_after(&self, delay: Duration, job: Job) -> bool {
+        let Some(shared) = self.shared.upgrade() else {
+            return false;
+        };
+        let retries = &shared.retries;
+        let reserved = retries
+            .depth
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |depth| {
+                (depth < retries.limit).then_some(depth + 1)
+            });
+        if reserved.is_err() {
+            retries.rejected.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        let slot = RetrySlot(Arc::clone(retries));
+        let delayed = Delayed {
+            timed: Timed::Once(Box::new(move || {
+                drop(slot);
+                job();
+            })),
+            claimed: Arc::new(AtomicBool::new(false)),
+        };
+        shared
+            .timer
+            .schedule(Instant::now() + delay, delayed)
+            .is_ok()
+    }
+}
+
+/// The CPU cores the workers are pinned to, with `Builder::pin_workers`.
+#[cfg(feature = "core-affinity")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CoreSelection {
+    /// Every core available to the process, from 0.
+    All,
+    /// The given cores, by index.
+    Cores(Vec<usize>),
+}
+
+/// The OS scheduling priority of the worker threads, set with
+/// `Builder::thread_priority`, so background pools yield the CPU to
+/// latency critical ones. On Linux it is the niceness of each thread,
+/// from 19 for Lowest to -10 for Highest, and on Windows the thread
+/// priority of the same name. Raising the priority above Normal usually
+/// needs privileges, and fails silently without them.
+#[cfg(feature = "thread-priority")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ThreadPriority {
+    /// Runs only when nothing else wants the CPU.
+    Lowest,
+    /// Below the other threads of the process.
+    Low,
+    /// The priority of threads spawned by the OS.
+    #[default]
+    Normal,
+    /// Above the other threads of the process.
+    High,
+    /// Ahead of every other normal thread.
+    Highest,
+}
+
+/// The configuration of workers added with
+/// `WorkerPool::extend_workers_with`, so a pool may mix workers of
+/// different kinds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WorkerConfig {
+    stack_size: Option<usize>,
+    core: Option<usize>,
+    priority: Priority,
+}
+
+impl WorkerConfig {
+    /// Constructs a new WorkerConfig, for workers like the ones spawned
+    /// by the Builder.
+    pub fn new() -> WorkerConfig {
+        WorkerConfig {
+            stack_size: None,
+            core: None,
+            priority: Priority::Low,
+        }
+    }
+
+    /// Sets the stack size of the worker thread, for jobs with deep
+    /// recursion or large locals.
+    ///
+    /// **size**: usize - the stack size in bytes.
+    pub fn stack_size(mut self, size: usize) -> WorkerConfig {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// Pins the worker thread to a CPU core, instead of the cores given
+    /// to `Builder::pin_workers`. Only available with the
+    /// `core-affinity` feature.
+    ///
+    /// **core**: usize - the index of the core.
+    #[cfg(feature = "core-affinity")]
+    pub fn core(mut self, core: usize) -> WorkerConfig {
+        self.core = Some(core);
+        self
+    }
+
+    /// Makes the worker take only the jobs of the given priority and
+    /// above, so they never wait behind background work. Such workers
+    /// don't steal jobs from peer pools either.
+    ///
+    /// **priority**: Priority - the lowest priority the worker runs.
+    pub fn min_priority(mut self, priority: Priority) -> WorkerConfig {
+        self.priority = priority;
+        self
+    }
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        WorkerConfig::new()
+    }
+}
+
+/// A builder to configure a WorkerPool before spawning its workers.
+///
+/// ### Examples
+///
+/// ```
+/// use rpools::pool::Builder;
+///
+/// let pool = Builder::new(2).label_limit("bulk-export", 100).build();
+///
+/// assert_eq!("workers[] = (id: 0)(id: 1)", pool.to_string());
+/// ```
+pub struct Builder {
+    size: usize,
+    label_limits: HashMap<String, usize>,
+    lane_limits: Vec<Option<usize>>,
+    adaptive: Option<(usize, usize, Duration)>,
+    elastic: Option<(usize, usize, Duration)>,
+    queue_bound: Option<usize>,
+    thread_name: Option<String>,
+    panic_policy: PanicPolicy,
+    overflow_policy: OverflowPolicy,
+    spawn_mode: SpawnMode,
+    io_workers: Option<usize>,
+    capture: Option<Capture>,
+    store: Option<Arc<dyn QueueStore>>,
+    backend: Option<Backend>,
+    observers: Vec<Arc<dyn PoolObserver>>,
+    seed: Option<u64>,
+    retry_limit: usize,
+    attribute_panics: bool,
+    audit: Vec<Arc<dyn AuditSink>>,
+    dead_letters: Option<Arc<dyn DeadLetterSink>>,
+    fallback: Option<Fallback>,
+    budget: Option<Duration>,
+    cores: Vec<usize>,
+    // the ThreadPriority of the workers, from -2 to 2
+    priority: Option<i8>,
+    on_idle: Option<(Duration, IdleCallback)>,
+    worker_state: Option<(TypeId, StateInit)>,
+}
+
+impl Builder {
+    /// Constructs a new Builder for a pool of size x.
+    ///
+    /// **size**: usize - Is the number of workers in WorkerPool object.
+    pub fn new(size: usize) -> Builder {
+        Builder {
+            size,
+            label_limits: HashMap::new(),
+            lane_limits: vec![None; Priority::LANES],
+            adaptive: None,
+            elastic: None,
+            queue_bound: None,
+            thread_name: None,
+            panic_policy: PanicPolicy::Unwind,
+            overflow_policy: OverflowPolicy::RejectWithError,
+            spawn_mode: SpawnMode::Eager,
+            io_workers: None,
+            capture: None,
+            store: None,
+            backend: None,
+            observers: Vec::new(),
+            seed: None,
+            retry_limit: usize::MAX,
+            attribute_panics: false,
+            audit: Vec::new(),
+            dead_letters: None,
+            fallback: None,
+            budget: None,
+            cores: Vec::new(),
+            priority: None,
+            on_idle: None,
+            worker_state: None,
+        }
+    }
+
+    /// Installs an observer, called along the lifecycle of each job. It
+    /// can be called more than once, the observers are called in the
+    /// order they were installed.
+    ///
+    /// **observer**: Arc<dyn PoolObserver> - the observer to install.
+    pub fn observer(mut self, observer: Arc<dyn PoolObserver>) -> Builder {
+        self.observers.push(observer);
+        self
+    }
+
+    /// Seeds the generator of each worker from this seed and the worker
+    /// id, so jobs drawing from `JobContext::with_rng` get the same
+    /// numbers run to run on the same worker.
+    ///
+    /// **seed**: u64 - the seed of the pool.
+    pub fn seed(mut self, seed: u64) -> Builder {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Makes panics in the jobs of the pool print a line with the worker
+    /// id and the name and label of the job, before the panic message.
+    /// The panic hook of the application is kept and still called for
+    /// every panic, here and in other threads.
+    pub fn attribute_panics(mut self) -> Builder {
+        self.attribute_panics = true;
+        self
+    }
+
+    /// Gives each worker a state of its own, built from the worker id,
+    /// that the jobs sent with `WorkerPool::execute_with_state` borrow.
+    /// Expensive resources, like connections or scratch buffers, are
+    /// then reused from job to job without sharing them behind a lock.
+    ///
+    /// The state is built on the worker thread, the first time it runs
+    /// one of those jobs. A worker stealing them from a peer pool keeps
+    /// a state for the peer too.
+    ///
+    /// **init**: A Fn closure given the worker id and returning its state.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// use rpools::pool::Builder;
+    ///
+    /// let pool = Builder::new(2)
+    ///     .worker_state(|_id| Vec::<u8>::with_capacity(4096))
+    ///     .build();
+    ///
+    /// for _ in 0..10 {
+    ///     pool.execute_with_state(|scratch: &mut Vec<u8>| {
+    ///         scratch.clear();
+    ///         scratch.extend_from_slice(b"render into the reused buffer");
+    ///     })
+    ///     .unwrap();
+    /// }
+    /// pool.wait();
+    /// ```
+    pub fn worker_state<S, F>(mut self, init: F) -> Builder
+    where
+        S: 'static,
+        F: Fn(usize) -> S + Send + Sync + 'static,
+    {
+        let init: StateInit = Arc::new(move |id| Box::new(init(id)));
+        self.worker_state = Some((TypeId::of::<S>(), init));
+        self
+    }
+
+    /// Limits how many retries, sent by `WorkerPool::execute_with_retry`,
+    /// may wait in the retry queue. Retries over the limit are dropped,
+    /// so failing jobs can't crowd out fresh work.
+    ///
+    /// **limit**: usize - the maximum number of waiting retries.
+    pub fn retry_limit(mut self, limit: usize) -> Builder {
+        self.retry_limit = limit;
+        self
+    }
+
+    /// Configures the executor critical jobs fall back on when the pool
+    /// is saturated, so they degrade gracefully instead of waiting behind
+    /// a huge backlog. The pool is saturated once its queue stays at or
+    /// over threshold for longer than grace. Only jobs sent with
+    /// `JobBuilder::critical` fall back.
+    ///
+    /// **threshold**: usize - the queued jobs that saturate the pool. \
+    /// **grace**: Duration - how long the pool may stay saturated. \
+    /// **spawner**: Arc<dyn Spawn> - the executor to fall back on, see
+    /// the fallback module.
+    pub fn fallback(
+        mut self,
+        threshold: usize,
+        grace: Duration,
+        spawner: Arc<dyn Spawn>,
+    ) -> Builder {
+        self.fallback = Some(Fallback {
+            threshold,
+            grace,
+            spawner,
+            since: Mutex::new(None),
+        });
+        self
+    }
+
+    /// Gives each worker a budget of continuous execution. A worker that
+    /// ran jobs back to back for longer than budget yields its thread and
+    /// checks the pause flag before taking the next job. Jobs themselves
+    /// are never interrupted.
+    ///
+    /// **budget**: Duration - the continuous run time before yielding.
+    pub fn worker_budget(mut self, budget: Duration) -> Builder {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Pins each worker thread to a CPU core, for cache sensitive
+    /// workloads. Workers take the selected cores in turn, so with more
+    /// workers than cores some share a core. Supported on Linux and
+    /// Windows, elsewhere workers aren't pinned. `WorkerPool::dump` tells
+    /// where each worker landed. Only available with the `core-affinity`
+    /// feature.
+    ///
+    /// **cores**: CoreSelection - the cores to pin the workers to.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// use rpools::pool::{Builder, CoreSelection};
+    ///
+    /// let pool = Builder::new(2).pin_workers(CoreSelection::All).build();
+    /// for worker in pool.dump().workers {
+    ///     println!("worker {} on core {:?}", worker.id, worker.core);
+    /// }
+    /// ```
+    #[cfg(feature = "core-affinity")]
+    pub fn pin_workers(mut self, cores: CoreSelection) -> Builder {
+        self.cores = match cores {
+            CoreSelection::All => (0..parallelism()).collect(),
+            CoreSelection::Cores(cores) => cores,
+        };
+        self
+    }
+
+    /// Sets the OS priority of the worker threads, for example to run a
+    /// background pool of indexing or compression jobs below a latency
+    /// critical pool in the same process. Supported on Linux and
+    /// Windows, elsewhere the priority isn't changed. Only available with
+    /// the `thread-priority` feature.
+    ///
+    /// **priority**: ThreadPriority - the priority of the workers.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// use rpools::pool::{Builder, ThreadPriority};
+    ///
+    /// let indexer = Builder::new(2).thread_priority(ThreadPriority::Low).build();
+    /// indexer.execute(|| println!("indexing in the background")).unwrap();
+    /// indexer.wait();
+    /// ```
+    #[cfg(feature = "thread-priority")]
+    pub fn thread_priority(mut self, priority: ThreadPriority) -> Builder {
+        self.priority = Some(priority as i8 - ThreadPriority::Normal as i8);
+        self
+    }
+
+    /// Installs an audit sink, which gets a record of each job when it
+    /// ends, see the audit module. It can be called more than once.
+    ///
+    /// **sink**: Arc<dyn AuditSink> - where the records are written.
+    pub fn audit(mut self, sink: Arc<dyn AuditSink>) -> Builder {
+        self.audit.push(sink);
+        self
+    }
+
+    /// Installs the dead letter sink, which gets the jobs that failed for
+    /// good: the jobs that panicked, and the retried jobs whose last
+    /// attempt failed, see the dead_letter module.
+    ///
+    /// **sink**: Arc<dyn DeadLetterSink> - where the letters are delivered.
+    pub fn dead_letters(mut self, sink: Arc<dyn DeadLetterSink>) -> Builder {
+        self.dead_letters = Some(sink);
+        self
+    }
+
+    /// Installs the queue store, where the jobs sent with
+    /// `WorkerPool::execute_serialized` are saved until they ran, see
+    /// the store module.
+    ///
+    /// **store**: Arc<dyn QueueStore> - where the jobs are persisted.
+    pub fn queue_store(mut self, store: Arc<dyn QueueStore>) -> Builder {
+        self.store = Some(store);
+        self
+    }
+
+    /// Sets the storage of the queue lanes, which decides the order the
+    /// jobs of a lane are picked in, see the backend module. Jobs of a
+    /// higher priority are still picked first.
+    ///
+    /// **backend**: A Fn closure that makes the JobQueue of a lane.
+    pub fn queue_backend<F>(mut self, backend: F) -> Builder
+    where
+        F: Fn() -> Box<dyn JobQueue<QueuedJob>> + Send + Sync + 'static,
+    {
+        self.backend = Some(Box::new(backend));
+        self
+    }
+
+    /// Makes the pool fair between submitters: the jobs sent with a
+    /// SubmitterId wait in a FIFO per submitter, and workers take jobs
+    /// from each submitter in turn, so a chatty component can't starve
+    /// the others. Jobs sent without one share a FIFO of their own.
+    /// Same as a RoundRobin queue_backend keyed by
+    /// `QueuedJob::submitter`.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// use rpools::pool::Builder;
+    ///
+    /// let pool = Builder::new(2).fair().build();
+    /// let (indexer, api) = (pool.submitter_id(), pool.submitter_id());
+    /// for _ in 0..100 {
+    ///     pool.execute_as(indexer, || {}).unwrap();
+    /// }
+    /// pool.execute_as(api, || println!("served next")).unwrap();
+    /// pool.wait();
+    /// ```
+    pub fn fair(self) -> Builder {
+        self.queue_backend(|| Box::new(RoundRobin::new(|job: &QueuedJob| job.submitter)))
+    }
+
+    /// Picks the jobs of each priority lane in deadline order, earliest
+    /// first, and the jobs without a deadline after them in FIFO order.
+    /// Same as a queue_backend keyed by `QueuedJob::deadline`.
+    pub fn earliest_deadline_first(self) -> Builder {
+        self.queue_backend(|| {
+            let key = |job: &QueuedJob| (job.deadline.is_none(), job.deadline);
+            Box::new(PriorityHeap::new(key))
+        })
+    }
+
+    /// Limits how many jobs of a label may wait in the queue. When the
+    /// limit is reached, new jobs of that label are rejected with
+    /// ExecuteError::LimitReached, while jobs of other labels keep flowing.
+    ///
+    /// **label**: &str - the label to limit. \
+    /// **limit**: usize - the maximum number of queued jobs.
+    pub fn label_limit(mut self, label: &str, limit: usize) -> Builder {
+        self.label_limits.insert(label.to_string(), limit);
+        self
+    }
+
+    /// Limits how many jobs of a priority lane may wait in the queue. When
+    /// the limit is reached, new jobs of that priority are rejected with
+    /// ExecuteError::LaneFull, while the other lanes keep flowing.
+    ///
+    /// **priority**: Priority - the lane to limit. \
+    /// **limit**: usize - the maximum number of queued jobs.
+    pub fn lane_limit(mut self, priority: Priority, limit: usize) -> Builder {
+        self.lane_limits[priority.lane()] = Some(limit);
+        self
+    }
+
+    /// Lets the pool adjust its number of workers to maximize throughput.
+    /// Every interval, the completed jobs per second are measured, and a
+    /// hill climbing policy adds or retires one worker, keeping the count
+    /// between min and max. This helps when jobs block on external systems
+    /// and the best concurrency isn't the number of cores.
+    ///
+    /// **min**: usize - the minimum number of workers. \
+    /// **max**: usize - the maximum number of workers. \
+    /// **interval**: Duration - the time between two measurements.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// use rpools::pool::Builder;
+    /// use std::time::Duration;
+    ///
+    /// let pool = Builder::new(4)
+    ///     .adaptive(2, 16, Duration::from_millis(500))
+    ///     .build();
+    ///
+    /// assert_eq!(4, pool.os_thread_ids().len());
+    /// ```
+    pub fn adaptive(mut self, min: usize, max: usize, interval: Duration) -> Builder {
+        self.adaptive = Some((min, max.max(min), interval));
+        self
+    }
+
+    /// Makes the pool elastic, like the core and maximum threads of a
+    /// ThreadPoolExecutor. The pool starts with its core workers, spawns
+    /// one more, up to max, whenever jobs are queued and no worker is
+    /// idle, and retires the extra workers once they are idle for the
+    /// keep alive. So it serves bursts without keeping their threads.
+    ///
+    /// **core**: usize - the workers kept while idle. \
+    /// **max**: usize - the maximum number of workers. \
+    /// **keep_alive**: Duration - how long an extra worker waits for a
+    /// job before it exits.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// use rpools::pool::Builder;
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let pool = Builder::new(1)
+    ///     .elastic(1, 4, Duration::from_millis(50))
+    ///     .build();
+    ///
+    /// for _ in 0..4 {
+    ///     pool.execute(|| thread::sleep(Duration::from_millis(100))).unwrap();
+    /// }
+    /// assert_eq!(4, pool.metrics().workers);
+    ///
+    /// pool.wait();
+    /// thread::sleep(Duration::from_millis(200));
+    /// assert_eq!(1, pool.metrics().workers);
+    /// ```
+    pub fn elastic(mut self, core: usize, max: usize, keep_alive: Duration) -> Builder {
+        self.elastic = Some((core, max.max(core), keep_alive));
+        self
+    }
+
+    /// Sets when the workers are spawned. Lazy elastic pools spawn
+    /// their core workers on demand too. Adaptive pools always start
+    /// their workers eagerly.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// use rpools::pool::{Builder, SpawnMode};
+    ///
+    /// let pool = Builder::new(8).spawn_mode(SpawnMode::Lazy).build();
+    /// assert_eq!(0, pool.metrics().workers);
+    ///
+    /// pool.submit(|| 1 + 1).unwrap().join().unwrap();
+    /// assert_eq!(1, pool.metrics().workers);
+    /// ```
+    ///
+    /// **mode**: SpawnMode - eager or lazy.
+    pub fn spawn_mode(mut self, mode: SpawnMode) -> Builder {
+        self.spawn_mode = mode;
+        self
+    }
+
+    /// Bounds the jobs waiting in the queue, across all priorities. When
+    /// the bound is reached, new jobs are rejected with
+    /// ExecuteError::QueueFull, unless `overflow_policy` says otherwise.
+    ///
+    /// **bound**: usize - the maximum number of queued jobs.
+    pub fn queue_bound(mut self, bound: usize) -> Builder {
+        self.queue_bound = Some(bound);
+        self
+    }
+
+    /// Sets what happens with a job sent while the queue is at the bound
+    /// set with `queue_bound`.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// use rpools::pool::{Builder, OverflowPolicy};
+    /// use std::thread;
+    ///
+    /// let pool = Builder::new(0)
+    ///     .queue_bound(1)
+    ///     .overflow_policy(OverflowPolicy::CallerRuns)
+    ///     .build();
+    ///
+    /// pool.execute(|| {}).unwrap();
+    /// let caller = thread::current().id();
+    /// pool.execute(move || assert_eq!(caller, thread::current().id()))
+    ///     .unwrap();
+    /// assert_eq!(1, pool.metrics().queued);
+    /// ```
+    ///
+    /// **policy**: OverflowPolicy - reject, block, drop or run inline.
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Builder {
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// Gives the pool a second group of workers for IO bound jobs, sent
+    /// with `WorkerPool::execute_io`, so jobs waiting on disks or the
+    /// network never hold the workers of the CPU bound ones. The group
+    /// shares the observers, panic policy and thread names of the pool,
+    /// with `-io` appended to the prefix, and has a queue of its own.
+    /// `wait` and the shutdown methods cover both groups, while the
+    /// other methods only see the CPU group.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// use rpools::pool::Builder;
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let pool = Builder::new(2).io_workers(16).build();
+    ///
+    /// for _ in 0..16 {
+    ///     pool.execute_io(|| thread::sleep(Duration::from_millis(20))).unwrap();
+    /// }
+    /// let sum = pool.submit(|| (1..=100).sum::<u32>()).unwrap();
+    /// assert_eq!(5050, sum.join().unwrap());
+    /// pool.wait();
+    /// ```
+    ///
+    /// **size**: usize - the workers of the IO group.
+    pub fn io_workers(mut self, size: usize) -> Builder {
+        self.io_workers = Some(size);
+        self
+    }
+
+    /// Makes the jobs run inside the context of the code that sent them,
+    /// such as a request id in a thread local, instead of losing it at
+    /// the thread boundary. capture is called when a job is sent, and
+    /// the closure it returns is called on the worker right before the
+    /// job runs; what that returns is dropped when the job ends. The
+    /// crate doesn't depend on tracing, but a `tracing::Span` can be
+    /// captured the same way. Only available with the
+    /// `context-propagation` feature.
+    ///
+    /// **capture**: A Fn closure that returns a FnOnce closure entering
+    /// the captured context.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// use rpools::pool::Builder;
+    /// use std::cell::Cell;
+    ///
+    /// thread_local! {
+    ///     static REQUEST: Cell<u32> = const { Cell::new(0) };
+    /// }
+    ///
+    /// // puts the previous request back when the job ends
+    /// struct Entered(u32);
+    /// impl Drop for Entered {
+    ///     fn drop(&mut self) {
+    ///         REQUEST.with(|request| request.set(self.0));
+    ///     }
+    /// }
+    ///
+    /// let pool = Builder::new(4)
+    ///     .propagate(|| {
+    ///         let request = REQUEST.with(Cell::get);
+    ///         move || Entered(REQUEST.with(|current| current.replace(request)))
+    ///     })
+    ///     .build();
+    ///
+    /// REQUEST.with(|request| request.set(42));
+    /// let seen = pool.submit(|| REQUEST.with(Cell::get)).unwrap();
+    /// assert_eq!(42, seen.join().unwrap());
+    /// ```
+    #[cfg(feature = "context-propagation")]
+    pub fn propagate<C, E, G>(mut self, capture: C) -> Builder
+    where
+        C: Fn() -> E + Send + Sync + 'static,
+        E: FnOnce() -> G + Send + 'static,
+        G: 'static,
+    {
+        self.capture = Some(Arc::new(move || {
+            let enter = capture();
+            Box::new(move || Box::new(enter()) as Box<dyn Any>)
+        }));
+        self
+    }
+
+    /// Installs a callback, called by each worker that waited for a job
+    /// for the given time, once per idle period. Mostly idle daemons can
+    /// use it to release caches or flush buffers while nothing happens.
+    /// Idle workers stay parked until a job comes, without waking up.
+    ///
+    /// **after**: Duration - how long a worker waits before the call. \
+    /// **callback**: A Fn closure that takes the id of the idle worker.
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// use rpools::pool::Builder;
+    /// use std::sync::mpsc;
+    /// use std::time::Duration;
+    ///
+    /// let (tx, rx) = mpsc::channel();
+    /// let pool = Builder::new(1)
+    ///     .on_idle(Duration::from_millis(10), move |worker| {
+    ///         tx.send(worker).unwrap();
+    ///     })
+    ///     .build();
+    /// assert_eq!(0, rx.recv().unwrap());
+    /// assert!(pool.metrics().parked_time >= Duration::from_millis(10));
+    /// ```
+    pub fn on_idle<F>(mut self, after: Duration, callback: F) -> Builder
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.on_idle = Some((after, Arc::new(callback)));
+        self
+    }
+
+    /// Names the worker threads with a prefix and their ids, like
+    /// `prefix-0`, as shown by debuggers, panic messages and `top -H`.
+    ///
+    /// **prefix**: &str - the start of each thread name.
+    pub fn thread_name(mut self, prefix: &str) -> Builder {
+        self.thread_name = Some(prefix.to_string());
+        self
+    }
+
+    /// Sets what happens when a job sent with `execute` panics. Jobs
+    /// sent with `submit` always report their panics to their handle.
+    ///
+    /// **policy**: PanicPolicy - unwind, catch or abort.
+    pub fn panic_policy(mut self, policy: PanicPolicy) -> Builder {
+        self.panic_policy = policy;
+        self
+    }
+
+    /// Constructs a new Builder from a PoolConfig.
+    ///
+    /// **config**: &PoolConfig - the settings of the pool.
+    pub fn from_config(config: &PoolConfig) -> Builder {
+        let mut builder = Builder::new(config.workers)
+            .overflow_policy(config.overflow_policy)
+            .panic_policy(config.panic_policy);
+        if let Some(max) = config.max_workers {
+            builder = builder.elastic(config.workers, max, config.keep_alive);
+        }
+        if let Some(bound) = config.queue_bound {
+            builder = builder.queue_bound(bound);
+        }
+        if let Some(prefix) = &config.thread_name {
+            builder = builder.thread_name(prefix);
+        }
+        builder
+    }
+
+    /// Spawns the workers and returns the configured WorkerPool.
+    pub fn build(self) -> WorkerPool {
+        let io = self.io_workers.map(|size| {
+            let mut io = Builder::new(size).panic_policy(self.panic_policy);
+            if let Some(prefix) = &self.thread_name {
+                io = io.thread_name(&format!("{}-io", prefix));
+            }
+            for observer in &self.observers {
+                io = io.observer(Arc::clone(observer));
+            }
+            io.capture = self.capture.clone();
+            io.priority = self.priority;
+            Box::new(io.build())
+        });
+        let size = match (self.adaptive, self.elastic) {
+            (Some((min, max, _)), _) => self.size.max(min).min(max),
+            (None, Some((core, _, _))) => core,
+            (None, None) => self.size,
+        };
+        // a lazy pool is an elastic one starting without workers, whose
+        // workers never exit
+        let (size, elastic) = match (self.spawn_mode, self.adaptive, self.elastic) {
+            (SpawnMode::Lazy, None, Some(elastic)) => (0, Some(elastic)),
+            (SpawnMode::Lazy, None, None) => (0, Some((size, size, Duration::ZERO))),
+            (_, _, elastic) => (size, elastic),
+        };
+
+        let labels = self
+            .label_limits
+            .into_iter()
+            .map(|(label, limit)| {
+                let queue = LabelQueue {
+                    limit,
+                    depth: AtomicUsize::new(0),
+                };
+                (label, queue)
+            })
+            .collect();
+
+        let mut shared = Shared::new(labels, self.lane_limits);
+        shared.queue.set_capacity(self.queue_bound);
+        if let Some(backend) = &self.backend {
+            shared.queue.set_backend(backend);
+        }
+        shared.thread_name = self.thread_name;
+        shared.panic_policy = self.panic_policy;
+        shared.overflow_policy = self.overflow_policy;
+        shared.capture = self.capture;
+        shared.store = self.store;
+        shared.observers = self.observers;
+        shared.seed = self.seed;
+        shared.retries = Arc::new(RetryQueue::new(self.retry_limit));
+        shared.attribute_panics = self.attribute_panics;
+        shared.audit = self.audit;
+        shared.dead_letters = self.dead_letters;
+        shared.fallback = self.fallback;
+        shared.budget = self.budget;
+        shared.cores = self.cores;
+        shared.priority = self.priority;
+        shared.on_idle = self.on_idle;
+        shared.worker_state = self.worker_state;
+        shared.elastic = elastic.map(|(core, max, keep_alive)| Elastic {
+            core,
+            max,
+            keep_alive,
+        });
+        if self.attribute_panics {
+            install_panic_hook();
+        }
+        let shared = Arc::new(shared);
+        for _ in 0..size {
+            shared.spawn_worker();
+        }
+
+        let scaler = self.adaptive.map(|(min, max, interval)| {
+            let shared = Arc::clone(&shared);
+            // subscribed here, so a stop sent before the thread runs isn't missed
+            let control = shared.control.subscribe();
+            thread::spawn(move || scale(shared, control, HillClimber::new(min, max), interval))
+        });
+
+        WorkerPool {
+            shared,
+            scaler: Mutex::new(scaler),
+            timer: Mutex::new(None),
+            io,
+        }
+    }
+}
+
+// Moves the delayed jobs to the queue as they become due, until the
+// timer is closed. Jobs rejected by the queue are dropped, as nobody
+// waits for them.
+fn tick(shared: Arc<Shared>) {
+    while let Some(delayed) = shared.timer.pop_due() {
+        match delayed.timed {
+            Timed::Once(job) => {
+                if !delayed.claimed.swap(true, Ordering::AcqRel) {
+                    let _ = shared.enqueue(QueuedJob::new(job), Priority::Normal);
+                }
+            }
+            Timed::Inline(job) => {
+                if !delayed.claimed.swap(true, Ordering::AcqRel) {
+                    job();
+                }
+            }
+            Timed::Recurring {
+                job,
+                due,
+                next,
+                running,
+            } => {
+                if delayed.claimed.load(Ordering::Acquire) {
+                    continue;
+                }
+                // a run still going skips this one
+                if !running.swap(true, Ordering::AcqRel) {
+                    // a run rejected by the queue clears the flag too
+                    let (job, guard) = (Arc::clone(&job), Running(Arc::clone(&running)));
+                    let run = Box::new(move || {
+                        let _running = guard;
+                        job();
+                    });
+                    let queued = shared.enqueue(QueuedJob::new(run), Priority::Normal);
+                    if queued == Err(ExecuteError::Shutdown) {
+                        continue;
+                    }
+                }
+                if let Some(due) = next(due) {
+                    let delayed = Delayed {
+                        timed: Timed::Recurring {
+                            job,
+                            due,
+                            next,
+                            running,
+                        },
+                        claimed: delayed.claimed,
+                    };
+                    let _ = shared.timer.schedule(due, delayed);
+                }
+            }
+        }
+    }
+}
+
+// Clears the running flag of a recurring job when its run finishes,
+// even if it panics or never runs.
+struct Running(Arc<AtomicBool>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+// Runs the adaptive scaling loop until the pool is stopped.
+fn scale(
+    shared: Arc<Shared>,
+    control: Subscriber<Control>,
+    mut climber: HillClimber,
+    interval: Duration,
+) {
+    let mut last_completed = shared.completed.load(Ordering::Relaxed);
+    let mut last_sample = Instant::now();
+
+    while let Err(RecvTimeoutError::Timeout) = control.recv_timeout(interval) {
+        let completed = shared.completed.load(Ordering::Relaxed);
+        let elapsed = last_sample.elapsed().as_secs_f64();
+        let throughput = (completed - last_completed) as f64 / elapsed;
+        last_completed = completed;
+        last_sample = Instant::now();
+
+        let current = shared.live_workers();
+        let backlog = shared.queue.len() > 0;
+        let target = climber.next(current, throughput, backlog);
+        for _ in current..target {
+            shared.spawn_worker();
+        }
+        for _ in target..current {
+            shared.retire_worker();
+        }
+    }
+}
+
+impl WorkerPool {
+    /// Constructs a new WorkerPool of size x. A size of 0 is clamped to
+    /// one worker, as jobs would never run otherwise. Use `Builder::new`
+    /// for a pool that starts without workers.
+    ///
+    /// **size**: usize - Is the number of workers in WorkerPool object. \
+    /// **returns**: a WorkerPool object.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    ///
+    /// let pool = WorkerPool::new(3);
+    ///
+    /// assert_eq!("workers[] = (id: 0)(id: 1)(id: 2)", pool.to_string());
+    /// ```
+    pub fn new(size: usize) -> WorkerPool {
+        Builder::new(size.max(1)).build()
+    }
+
+    /// Constructs a new WorkerPool sized from the CPUs the process may
+    /// use, as told by `std::thread::available_parallelism`, times a
+    /// multiplier. CPU bound jobs want a multiplier of 1, while jobs that
+    /// mostly wait on IO keep the CPUs busy with more workers.
+    ///
+    /// **multiplier**: usize - the workers for each CPU, at least 1. \
+    /// **returns**: a WorkerPool object.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    ///
+    /// let cpus = std::thread::available_parallelism().map_or(1, usize::from);
+    /// let pool = WorkerPool::auto(4);
+    ///
+    /// assert_eq!(cpus * 4, pool.dump().workers.len());
+    /// ```
+    pub fn auto(multiplier: usize) -> WorkerPool {
+        WorkerPool::new(parallelism() * multiplier.max(1))
+    }
+
+    /// Spawns workers ahead of demand in a lazy or elastic pool, so the
+    /// first jobs don't wait for threads to start. The extra workers of
+    /// an elastic pool still exit after their keep alive.
+    ///
+    /// **n**: usize - the workers wanted, capped at the size of a lazy
+    /// pool or the maximum of an elastic one. \
+    /// **returns**: how many workers were spawned, 0 for other pools.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rpools::pool::{Builder, SpawnMode};
+    ///
+    /// let pool = Builder::new(4).spawn_mode(SpawnMode::Lazy).build();
+    ///
+    /// assert_eq!(2, pool.prestart(2));
+    /// assert_eq!(2, pool.prestart(10));
+    /// assert_eq!(4, pool.metrics().workers);
+    /// ```
+    pub fn prestart(&self, n: usize) -> usize {
+        self.shared.prestart(n)
+    }
+
+    /// Constructs a new WorkerPool from a PoolConfig, see PoolConfig.
+    ///
+    /// **config**: &PoolConfig - the settings of the pool. \
+    /// **returns**: a WorkerPool object.
+    pub fn from_config(config: &PoolConfig) -> WorkerPool {
+        Builder::from_config(config).build()
+    }
+
+    /// Executes a job. The job is moved to closure, as this function is FnOnce. \
+    ///
+    /// **f**: A FnOnce closure hosted by a Box smart pointer.
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use std::sync::mpsc;
+    ///
+    /// let njobs = 20;
+    /// let nworkers = 10;
+    ///
+    /// let pool = WorkerPool::new(nworkers);
+    /// let (tx, rx) = mpsc::channel();
+    ///
+    /// for _ in 0 .. njobs {
+    ///     let tx = tx.clone();
+    ///     pool.execute(move || {
+    ///         tx.send(1).unwrap();
+    ///     }).unwrap();
+    /// }
+    ///
+    /// let sum = rx.iter().take(njobs).sum();
+    /// assert_eq!(njobs, sum);
+    /// ```
+    pub fn execute<J>(&self, f: J) -> Result<(), ExecuteError>
+    where
+        J: FnOnce() + Send + 'static,
+    {
+        self.job(f).spawn()
+    }
+
+    /// Same as `execute`, but if the pool doesn't accept the job, the
+    /// error gives the job back instead of dropping it.
+    ///
+    /// **f**: A FnOnce closure. \
+    /// **returns**: a TryExecuteError with the job, if it was rejected.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::{ExecuteError, WorkerPool};
+    ///
+    /// let pool = WorkerPool::new(1);
+    /// pool.shutdown();
+    ///
+    /// let err = pool.try_execute(|| 6 * 7).unwrap_err();
+    /// assert_eq!(&ExecuteError::Shutdown, err.error());
+    /// assert_eq!(42, (err.into_job())());
+    /// ```
+    pub fn try_execute<J, T>(&self, f: J) -> Result<(), TryExecuteError<J>>
+    where
+        J: FnOnce() -> T + Send + 'static,
+    {
+        // the job stays reachable from here until a worker takes it
+        let slot = Arc::new(Mutex::new(Some(f)));
+        let job = Arc::clone(&slot);
+        self.execute(move || {
+            let f = job.lock().expect("Cant acquire lock").take();
+            if let Some(f) = f {
+                f();
+            }
+        })
+        .map_err(|error| {
+            let job = slot.lock().expect("Cant acquire lock").take();
+            TryExecuteError {
+                job: job.expect("a rejected job never runs"),
+                error,
+            }
+        })
+    }
+
+    /// Starts building the submission of a job, so options like priority,
+    /// label, deadline and cancellation token can be composed before
+    /// sending it with `spawn` or `submit`.
+    ///
+    /// **f**: A FnOnce closure that may produce a value. \
+    /// **returns**: a JobBuilder for the job.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::{Priority, WorkerPool};
+    /// use std::time::{Duration, Instant};
+    ///
+    /// let pool = WorkerPool::new(2);
+    ///
+    /// pool.job(|| println!("cleanup"))
+    ///     .priority(Priority::Low)
+    ///     .label("maintenance")
+    ///     .deadline(Instant::now() + Duration::from_secs(60))
+    ///     .spawn()
+    ///     .unwrap();
+    ///
+    /// let handle = pool.job(|| 2 + 2).priority(Priority::High).submit().unwrap();
+    /// assert_eq!(4, handle.join().unwrap());
+    /// ```
+    pub fn job<F, T>(&self, f: F) -> JobBuilder<'_, F>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        JobBuilder::new(&self.shared, f)
+    }
+
+    /// Executes a batch of jobs. All jobs are enqueued at once, which
+    /// is cheaper than calling `execute` for each one in fan-out workloads.
+    ///
+    /// **jobs**: An iterator of FnOnce closures.
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use std::sync::mpsc;
+    ///
+    /// let pool = WorkerPool::new(4);
+    /// let (tx, rx) = mpsc::channel();
+    ///
+    /// pool.execute_many((0..100).map(|i| {
+    ///     let tx = tx.clone();
+    ///     move || tx.send(i).unwrap()
+    /// })).unwrap();
+    ///
+    /// assert_eq!(4950, rx.iter().take(100).sum::<i32>());
+    /// ```
+    pub fn execute_many<I, J>(&self, jobs: I) -> Result<(), ExecuteError>
+    where
+        I: IntoIterator<Item = J>,
+        J: FnOnce() + Send + 'static,
+    {
+        let tasks = jobs
+            .into_iter()
+            .map(|f| QueuedJob::new(Box::new(f)))
+            .collect();
+        self.shared.enqueue_batch(tasks)
+    }
+
+    /// Executes f for each index from 0 to n_jobs, as a batch, and
+    /// returns an iterator over the results as they complete. The
+    /// iterator ends once every job is done, so the results of jobs
+    /// that panicked are missing.
+    ///
+    /// **n_jobs**: usize - how many jobs to run. \
+    /// **f**: A Fn closure given the index of the job. \
+    /// **returns**: the results in completion order, or an ExecuteError.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    ///
+    /// let pool = WorkerPool::new(4);
+    /// let results = pool.channel_execute(10, |i| i * 2).unwrap();
+    ///
+    /// assert_eq!(90, results.sum::<usize>());
+    /// ```
+    pub fn channel_execute<T, F>(
+        &self,
+        n_jobs: usize,
+        f: F,
+    ) -> Result<mpsc::IntoIter<T>, ExecuteError>
+    where
+        F: Fn(usize) -> T + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        let f = Arc::new(f);
+        let (tx, rx) = mpsc::channel();
+        self.execute_many((0..n_jobs).map(|i| {
+            let (f, tx) = (Arc::clone(&f), tx.clone());
+            move || {
+                // the receiver may have been dropped, nobody reads the results
+                let _ = tx.send(f(i));
+            }
+        }))?;
+        Ok(rx.into_iter())
+    }
+
+    /// Submits a batch of jobs at once, as `execute_many` does, and
+    /// returns their handles in submission order, for scatter/gather
+    /// workloads. Either every job is queued, or none is.
+    ///
+    /// Only the RejectWithError overflow policy keeps the batch whole
+    /// when it doesn't fit under `Builder::queue_bound`. The other
+    /// policies handle its jobs one at a time:
+    /// - Block queues each job once there is room. If the pool shuts
+    ///   down meanwhile, the jobs queued so far still run, but the
+    ///   ExecuteError is returned in place of their handles.
+    /// - DropOldest queues every job, dropping the oldest queued jobs,
+    ///   which may be jobs of the batch.
+    /// - DropNewest drops the jobs that don't fit, and their handles
+    ///   resolve with JobError::Cancelled.
+    /// - CallerRuns runs the jobs that don't fit on the calling thread
+    ///   before returning.
+    ///
+    /// **jobs**: An iterator of FnOnce closures producing a value. \
+    /// **returns**: a JobHandle for each job, in the order of jobs.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    ///
+    /// let pool = WorkerPool::new(4);
+    /// let handles = pool
+    ///     .submit_all((1..=4).map(|i| move || i * 10))
+    ///     .unwrap();
+    ///
+    /// let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    /// assert_eq!(vec![10, 20, 30, 40], results);
+    /// ```
+    pub fn submit_all<I, F, T>(&self, jobs: I) -> Result<Vec<JobHandle<T>>, ExecuteError>
+    where
+        I: IntoIterator<Item = F>,
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tasks, handles) = jobs
+            .into_iter()
+            .map(|f| {
+                let (job, on_cancel, handle) = with_handle(f);
+                let mut task = QueuedJob::new(job);
+                task.on_cancel = Some(on_cancel);
+                (task, handle)
+            })
+            .unzip();
+        self.shared.enqueue_batch(tasks)?;
+        Ok(handles)
+    }
+
+    /// Executes a batch of jobs at once, as `execute_many` does, and
+    /// returns a Progress counting them as they finish, to drive
+    /// progress bars.
+    ///
+    /// **jobs**: An iterator of FnOnce closures. \
+    /// **returns**: the Progress of the batch, or an ExecuteError.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    ///
+    /// let pool = WorkerPool::new(4);
+    /// let progress = pool
+    ///     .submit_batch_with_progress((0..100).map(|i| move || drop(i * i)))
+    ///     .unwrap();
+    ///
+    /// assert_eq!(100, progress.total());
+    /// progress.wait_until(0.5);
+    /// assert!(progress.fraction() >= 0.5);
+    /// progress.wait_until(1.0);
+    /// assert_eq!(100, progress.completed());
+    /// ```
+    pub fn submit_batch_with_progress<I, J>(&self, jobs: I) -> Result<Progress, ExecuteError>
+    where
+        I: IntoIterator<Item = J>,
+        J: FnOnce() + Send + 'static,
+    {
+        let jobs: Vec<J> = jobs.into_iter().collect();
+        let progress = Progress {
+            state: Arc::new(ProgressState {
+                total: jobs.len(),
+                completed: Mutex::new(0),
+                advanced: Condvar::new(),
+            }),
+        };
+        let tasks = jobs
+            .into_iter()
+            .map(|f| {
+                // counts on drop, so jobs that panic or are dropped count too
+                let tick = Tick(Arc::clone(&progress.state));
+                QueuedJob::new(Box::new(move || {
+                    let _tick = tick;
+                    f()
+                }))
+            })
+            .collect();
+        self.shared.enqueue_batch(tasks)?;
+        Ok(progress)
+    }
+
+    /// Blocks the current thread until every job sent to the pool has
+    /// finished, that is, until no job is queued or running. Unlike a
+    /// WaitGroup, the jobs don't need to carry anything.
+    ///
+    /// Calling it from inside a job deadlocks, as that job is running.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// let pool = WorkerPool::new(3);
+    /// let counter = Arc::new(AtomicUsize::new(0));
+    ///
+    /// for _ in 0..20 {
+    ///     let counter = counter.clone();
+    ///     pool.execute(move || {
+    ///         counter.fetch_add(1, Ordering::Relaxed);
+    ///     }).unwrap();
+    /// }
+    ///
+    /// pool.wait();
+    /// assert_eq!(20, counter.load(Ordering::Relaxed));
+    /// ```
+    pub fn wait(&self) {
+        self.shared.wait_idle();
+        if let Some(io) = &self.io {
+            io.wait();
+        }
+    }
+
+    /// Executes an IO bound job, like a blocking read or a request to a
+    /// service, on the IO workers set with `Builder::io_workers`. Pools
+    /// without IO workers run it like `execute`.
+    ///
+    /// **f**: A FnOnce closure. \
+    /// **returns**: Ok if the job was queued, or an ExecuteError.
+    pub fn execute_io<J>(&self, f: J) -> Result<(), ExecuteError>
+    where
+        J: FnOnce() + Send + 'static,
+    {
+        match &self.io {
+            Some(io) => io.execute(f),
+            None => self.execute(f),
+        }
+    }
+
+    /// Executes a CPU bound job on the general workers, same as
+    /// `execute`, to make the choice explicit next to `execute_io`.
+    ///
+    /// **f**: A FnOnce closure. \
+    /// **returns**: Ok if the job was queued, or an ExecuteError.
+    pub fn execute_cpu<J>(&self, f: J) -> Result<(), ExecuteError>
+    where
+        J: FnOnce() + Send + 'static,
+    {
+        self.execute(f)
+    }
+
+    /// Executes a job and returns a handle to its result. The handle can
+    /// be joined to block until the job finishes. Jobs submitted before
+    /// a shutdown still run, so their handles still resolve.
+    ///
+    /// **f**: A FnOnce closure that produces a value. \
+    /// **returns**: a JobHandle, or an ExecuteError.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    ///
+    /// let pool = WorkerPool::new(2);
+    /// let handle = pool.submit(|| 6 * 7).unwrap();
+    ///
+    /// assert_eq!(42, handle.join().unwrap());
+    /// ```
+    pub fn submit<F, T>(&self, f: F) -> Result<JobHandle<T>, ExecuteError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.job(f).submit()
+    }
+
+    /// Executes a job that can be cancelled with a token. If the token is
+    /// cancelled before a worker picks the job, the job is skipped. A
+    /// running job can keep a clone of the token and poll `is_cancelled`
+    /// to stop early.
+    ///
+    /// **token**: &CancellationToken - the token that cancels the job. \
+    /// **f**: A FnOnce closure hosted by a Box smart pointer.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use rpools::sync::CancellationToken;
+    ///
+    /// let pool = WorkerPool::new(2);
+    /// let token = CancellationToken::new();
+    ///
+    /// for _ in 0..100 {
+    ///     let job_token = token.clone();
+    ///     pool.execute_cancellable(&token, move || {
+    ///         while !job_token.is_cancelled() {
+    ///             // a long task goes here, in small steps
+    /// #           break;
+    ///         }
+    ///     }).unwrap();
+    /// }
+    ///
+    /// // one result makes the rest irrelevant
+    /// token.cancel();
+    /// ```
+    pub fn execute_cancellable<J>(
+        &self,
+        token: &CancellationToken,
+        f: J,
+    ) -> Result<(), ExecuteError>
+    where
+        J: FnOnce() + Send + 'static,
+    {
+        self.job(f).token(token).spawn()
+    }
+
+    /// Returns a new SubmitterId, unique in the process, for the
+    /// components sending jobs to a pool built with `Builder::fair`.
+    pub fn submitter_id(&self) -> SubmitterId {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        SubmitterId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Executes a job as the given submitter, same as
+    /// `WorkerPool::job(f).submitter(submitter).spawn()`.
+    ///
+    /// **submitter**: SubmitterId - who sends the job. \
+    /// **f**: A FnOnce closure hosted by a Box smart pointer.
+    pub fn execute_as<J>(&self, submitter: SubmitterId, f: J) -> Result<(), ExecuteError>
+    where
+        J: FnOnce() + Send + 'static,
+    {
+        self.job(f).submitter(submitter).spawn()
+    }
+
+    /// Executes a job that must start before a deadline, or be skipped,
+    /// as a late result is useless to soft realtime work like audio or
+    /// frame rendering. Pools built with
+    /// `Builder::earliest_deadline_first` pick these jobs in deadline
+    /// order, and `PoolMetrics::missed_deadlines` counts the skipped ones.
+    ///
+    /// **deadline**: Instant - the latest time the job may start. \
+    /// **f**: A FnOnce closure hosted by a Box smart pointer.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::Builder;
+    /// use std::time::{Duration, Instant};
+    ///
+    /// let pool = Builder::new(2).earliest_deadline_first().build();
+    /// let frame = Instant::now() + Duration::from_millis(16);
+    /// pool.execute_with_deadline(frame, || println!("render")).unwrap();
+    /// pool.wait();
+    /// ```
+    pub fn execute_with_deadline<J>(&self, deadline: Instant, f: J) -> Result<(), ExecuteError>
+    where
+        J: FnOnce() + Send + 'static,
+    {
+        self.job(f).deadline(deadline).spawn()
+    }
+
+    /// Executes a job that must finish within a timeout. If it doesn't,
+    /// its handle gets JobError::Timeout right away, and the token of its
+    /// JobContext is cancelled, so the job stops at its next checkpoint,
+    /// or is skipped if it didn't start yet. A job that doesn't check
+    /// its context runs to the end, and its value is dropped. The
+    /// timeout is tracked by the timer thread.
+    ///
+    /// **timeout**: Duration - the time the job has, from now. \
+    /// **f**: A FnOnce closure that takes a &JobContext and produces a
+    /// value. \
+    /// **returns**: a JobHandle to the value, or an ExecuteError.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::checkpoint;
+    /// use rpools::pool::{JobError, WorkerPool};
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let pool = WorkerPool::new(2);
+    /// let handle = pool
+    ///     .execute_with_timeout(Duration::from_millis(20), |ctx| loop {
+    ///         checkpoint!(ctx);
+    ///         thread::sleep(Duration::from_millis(1));
+    ///     })
+    ///     .unwrap();
+    ///
+    /// assert!(matches!(handle.join(), Err(JobError::Timeout)));
+    /// ```
+    pub fn execute_with_timeout<F, T>(
+        &self,
+        timeout: Duration,
+        f: F,
+    ) -> Result<JobHandle<T>, ExecuteError>
+    where
+        F: FnOnce(&JobContext) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let token = CancellationToken::new();
+        let (tx, handle) = handle_channel();
+        // whoever takes the sender first, the job or the timeout, wins
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        let finish = |tx: &Mutex<Option<mpsc::Sender<_>>>, result| {
+            if let Some(tx) = tx.lock().expect("Cant acquire lock").take() {
+                // the handle may have been dropped, nobody waits the result
+                let _ = tx.send(result);
+            }
+        };
+
+        let (expired, cancel) = (Arc::clone(&tx), token.clone());
+        let watchdog = self.schedule(
+            Instant::now() + timeout,
+            Timed::Inline(Box::new(move || {
+                finish(&expired, Err(JobError::Timeout));
+                cancel.cancel();
+            })),
+        )?;
+        let claimed = Arc::clone(&watchdog.claimed);
+        let spawned = self
+            .job_with_context(move |ctx| {
+                let result = catch(|| f(ctx)).map_err(JobError::Panicked);
+                watchdog.cancel();
+                finish(&tx, result);
+            })
+            .token(&token)
+            .spawn();
+        if spawned.is_err() {
+            claimed.store(true, Ordering::Release);
+        }
+        spawned.map(|_| handle)
+    }
+
+    /// Executes a job after a delay. The job waits in a timer, not in a
+    /// worker, and is moved to the queue when it is due. The timer thread
+    /// is started by the first delayed job.
+    ///
+    /// **delay**: Duration - how long to wait before queueing the job. \
+    /// **f**: A FnOnce closure hosted by a Box smart pointer. \
+    /// **returns**: a ScheduledHandle to cancel the job, or
+    /// ExecuteError::Shutdown.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use std::time::Duration;
+    ///
+    /// let pool = WorkerPool::new(2);
+    ///
+    /// pool.execute_after(Duration::from_millis(10), || println!("later")).unwrap();
+    /// let handle = pool.execute_after(Duration::from_secs(60), || {}).unwrap();
+    ///
+    /// assert!(handle.cancel());
+    /// ```
+    pub fn execute_after<J>(&self, delay: Duration, f: J) -> Result<ScheduledHandle, ExecuteError>
+    where
+        J: FnOnce() + Send + 'static,
+    {
+        self.schedule(Instant::now() + delay, Timed::Once(Box::new(f)))
+    }
+
+    /// Executes a job paced by a rate limiter, so a burst of jobs calling
+    /// the same service doesn't overwhelm it. The job is queued at once
+    /// if the limiter has a token, and otherwise waits in the timer until
+    /// its reserved token is due, like `execute_after`, so neither the
+    /// caller nor a worker is blocked.
+    ///
+    /// **limiter**: &RateLimiter - the limiter shared by the jobs. \
+    /// **f**: A FnOnce closure hosted by a Box smart pointer. \
+    /// **returns**: Ok if the job was queued or scheduled, or an
+    /// ExecuteError.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use rpools::sync::RateLimiter;
+    ///
+    /// let pool = WorkerPool::new(8);
+    /// let limiter = RateLimiter::new(50.0, 5);
+    ///
+    /// for page in 0..10 {
+    ///     pool.execute_throttled(&limiter, move || println!("crawling page {}", page))
+    ///         .unwrap();
+    /// }
+    /// ```
+    pub fn execute_throttled<J>(&self, limiter: &RateLimiter, f: J) -> Result<(), ExecuteError>
+    where
+        J: FnOnce() + Send + 'static,
+    {
+        match limiter.reserve() {
+            Duration::ZERO => self.execute(f),
+            wait => self.execute_after(wait, f).map(drop),
+        }
+    }
+
+    /// Executes a job periodically. The first run is queued after
+    /// initial_delay, and the next ones every period after that,
+    /// measured from when each run was due, not from when it finished.
+    /// If a run is still queued or running when the next one is due,
+    /// the next one is skipped, so runs never overlap.
+    ///
+    /// **initial_delay**: Duration - how long to wait for the first run. \
+    /// **period**: Duration - the time between the start of two runs. \
+    /// **f**: A Fn closure called on each run. \
+    /// **returns**: a ScheduledHandle to stop the recurrence, or
+    /// ExecuteError::Shutdown.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use std::time::Duration;
+    ///
+    /// let pool = WorkerPool::new(2);
+    /// let heartbeat = pool
+    ///     .execute_at_fixed_rate(Duration::ZERO, Duration::from_secs(1), || {
+    ///         println!("alive");
+    ///     })
+    ///     .unwrap();
+    ///
+    /// assert!(heartbeat.cancel());
+    /// ```
+    pub fn execute_at_fixed_rate<J>(
+        &self,
+        initial_delay: Duration,
+        period: Duration,
+        f: J,
+    ) -> Result<ScheduledHandle, ExecuteError>
+    where
+        J: Fn() + Send + Sync + 'static,
+    {
+        let due = Instant::now() + initial_delay;
+        self.schedule_recurring(due, move |due| Some(due + period), f)
+    }
+
+    // Schedules a job that runs at due, and then at each time given by
+    // next, until next returns None or the job is cancelled.
+    pub(crate) fn schedule_recurring<N, J>(
+        &self,
+        due: Instant,
+        next: N,
+        f: J,
+    ) -> Result<ScheduledHandle, ExecuteError>
+    where
+        N: Fn(Instant) -> Option<Instant> + Send + Sync + 'static,
+        J: Fn() + Send + Sync + 'static,
+    {
+        let timed = Timed::Recurring {
+            job: Arc::new(f),
+            due,
+            next: Box::new(next),
+            running: Arc::new(AtomicBool::new(false)),
+        };
+        self.schedule(due, timed)
+    }
+
+    // Schedules a job in the timer, starting the timer thread on first
+    // use.
+    fn schedule(&self, due: Instant, timed: Timed) -> Result<ScheduledHandle, ExecuteError> {
+        let claimed = Arc::new(AtomicBool::new(false));
+        let delayed = Delayed {
+            timed,
+            claimed: Arc::clone(&claimed),
+        };
+        self.shared
+            .timer
+            .schedule(due, delayed)
+            .map_err(|_| ExecuteError::Shutdown)?;
+        self.start_timer();
+        Ok(ScheduledHandle { claimed })
+    }
+
+    // Starts the timer thread, if it isn't running yet.
+    fn start_timer(&self) {
+        let mut timer = self.timer.lock().expect("Cant acquire lock");
+        if timer.is_none() {
+            let shared = Arc::clone(&self.shared);
+            *timer = Some(thread::spawn(move || tick(shared)));
+        }
+    }
+
+    // Returns the Retrier of the pool, starting the timer thread the
+    // retries wait in.
+    pub(crate) fn retrier(&self) -> Retrier {
+        self.start_timer();
+        Retrier {
+            shared: Arc::downgrade(&self.shared),
+        }
+    }
+
+    // Returns how many workers the pool has right now.
+    pub(crate) fn worker_count(&self) -> usize {
+        self.shared.workers.lock().expect("Cant acquire lock").len()
+    }
+
+    // Returns the store set by Builder::queue_store.
+    pub(crate) fn queue_store(&self) -> Option<&Arc<dyn QueueStore>> {
+        self.shared.store.as_ref()
+    }
+
+    /// Executes a job that receives a JobContext, so its body can call
+    /// `checkpoint!(ctx)` at loop boundaries to stop when its token is
+    /// cancelled, and to wait while the pool is paused.
+    ///
+    /// **f**: A FnOnce closure that takes a &JobContext.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::checkpoint;
+    /// use rpools::pool::WorkerPool;
+    /// use rpools::sync::CancellationToken;
+    ///
+    /// let pool = WorkerPool::new(2);
+    /// let token = CancellationToken::new();
+    ///
+    /// pool.execute_with_context(|ctx| {
+    ///     for _chunk in 0..1000 {
+    ///         checkpoint!(ctx);
+    ///         // process the chunk
+    ///     }
+    /// }).unwrap();
+    ///
+    /// pool.job_with_context(|ctx| {
+    ///     loop {
+    ///         checkpoint!(ctx);
+    ///     }
+    /// }).token(&token).spawn().unwrap();
+    ///
+    /// token.cancel();
+    /// ```
+    pub fn execute_with_context<J>(&self, f: J) -> Result<(), ExecuteError>
+    where
+        J: FnOnce(&JobContext) + Send + 'static,
+    {
+        self.job_with_context(f).spawn()
+    }
+
+    /// Same as `job`, but the job receives a JobContext bound to the
+    /// token set with `JobBuilder::token`, if any.
+    ///
+    /// **f**: A FnOnce closure that takes a &JobContext and may produce
+    /// a value. \
+    /// **returns**: a JobBuilder for the job.
+    pub fn job_with_context<F, T>(
+        &self,
+        f: F,
+    ) -> JobBuilder<'_, impl FnOnce() -> T + Send + 'static>
+    where
+        F: FnOnce(&JobContext) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.job(with_context(&self.shared, f))
+    }
+
+    /// Pauses the pool. Workers finish the jobs they are running, up to
+    /// their next checkpoint, and don't start new jobs until `resume` is
+    /// called. Jobs can still be sent to a paused pool.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    ///
+    /// let pool = WorkerPool::new(2);
+    ///
+    /// pool.pause();
+    /// let handle = pool.submit(|| 1).unwrap();
+    /// assert!(pool.is_paused());
+    ///
+    /// pool.resume();
+    /// assert_eq!(1, handle.join().unwrap());
+    /// ```
+    pub fn pause(&self) {
+        self.shared.set_paused(true);
+    }
+
+    /// Resumes a paused pool, waking the jobs blocked at a checkpoint.
+    pub fn resume(&self) {
+        self.shared.set_paused(false);
+    }
+
+    /// Returns true if the pool is paused.
+    pub fn is_paused(&self) -> bool {
+        self.shared.is_paused()
+    }
+
+    /// Quiesces the pool. New jobs are rejected with
+    /// ExecuteError::Quiesced, and the call blocks until the queued and
+    /// running jobs finish. Unlike `shutdown`, the worker threads stay
+    /// alive, with their thread local state, so `reopen` resumes service
+    /// instantly. Delayed jobs becoming due meanwhile are dropped. Jobs
+    /// waiting for their key or for a slot of their SubPool were
+    /// accepted already, so they still run.
+    ///
+    /// Calling it from inside a job deadlocks, as that job is running.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::{ExecuteError, WorkerPool};
+    ///
+    /// let pool = WorkerPool::new(2);
+    ///
+    /// pool.quiesce();
+    /// assert_eq!(Err(ExecuteError::Quiesced), pool.execute(|| {}));
+    ///
+    /// pool.reopen();
+    /// assert!(pool.execute(|| {}).is_ok());
+    /// ```
+    pub fn quiesce(&self) {
+        self.shared.queue.set_rejecting(true);
+        self.shared.wait_idle();
+    }
+
+    /// Accepts jobs again after `quiesce`.
+    pub fn reopen(&self) {
+        self.shared.queue.set_rejecting(false);
+    }
+
+    /// Registers peer as a steal peer of this pool. When the workers of
+    /// this pool are idle, they execute the jobs queued in peer, with at
+    /// most cap of them running at once. Call it on both pools to let
+    /// them steal from each other.
+    ///
+    /// **peer**: &WorkerPool - the pool to steal jobs from. \
+    /// **cap**: usize - how many jobs of peer may run here at once.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    ///
+    /// let ingest = WorkerPool::new(2);
+    /// let reports = WorkerPool::new(2);
+    ///
+    /// ingest.steal_from(&reports, 1);
+    /// reports.steal_from(&ingest, 1);
+    ///
+    /// for _ in 0..10 {
+    ///     ingest.execute(|| {}).unwrap();
+    /// }
+    /// ingest.wait();
+    /// ```
+    pub fn steal_from(&self, peer: &WorkerPool, cap: usize) {
+        assert!(
+            !Arc::ptr_eq(&self.shared, &peer.shared),
+            "a pool can't steal from itself"
+        );
+        self.shared
+            .peers
+            .lock()
+            .expect("Cant acquire lock")
+            .push(Peer {
+                shared: Arc::downgrade(&peer.shared),
+                cap,
+                stolen: Arc::new(AtomicUsize::new(0)),
+            });
+        peer.shared
+            .thieves
+            .lock()
+            .expect("Cant acquire lock")
+            .push(Arc::downgrade(&self.shared));
+        // the peer may already have a backlog
+        self.shared.queue.wake_all();
+    }
+
+    /// Adds workers to the pool, each with its own configuration, like
+    /// a pair of workers reserved to urgent jobs next to the general
+    /// ones. The new workers are counted in the size of the pool, and
+    /// may be retired by adaptive scaling like any other.
+    ///
+    /// **count**: usize - how many workers to add. \
+    /// **config**: A FnMut closure given the id of each new worker and
+    /// returning its configuration.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::{Priority, WorkerConfig, WorkerPool};
+    ///
+    /// let pool = WorkerPool::new(6);
+    /// pool.extend_workers_with(2, |_id| WorkerConfig::new().min_priority(Priority::High));
+    ///
+    /// let handle = pool
+    ///     .job(|| "checkout")
+    ///     .priority(Priority::High)
+    ///     .submit()
+    ///     .unwrap();
+    /// assert_eq!("checkout", handle.join().unwrap());
+    /// ```
+    pub fn extend_workers_with<F>(&self, count: usize, mut config: F)
+    where
+        F: FnMut(usize) -> WorkerConfig,
+    {
+        for _ in 0..count {
+            let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+            let worker = Worker::with_config(id, Arc::clone(&self.shared), config(id));
+            self.shared
+                .workers
+                .lock()
+                .expect("Cant acquire lock")
+                .push(worker);
+        }
+    }
+
+    /// Executes a job with the state of the worker that runs it, built by
+    /// `Builder::worker_state`. Panics if the pool has no worker state,
+    /// or if its state isn't a S.
+    ///
+    /// **f**: A FnOnce closure given the state of the worker. \
+    /// **returns**: Ok if the job was queued, or an ExecuteError.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::Builder;
+    /// use std::collections::HashMap;
+    ///
+    /// let pool = Builder::new(2)
+    ///     .worker_state(|_id| HashMap::<u64, u64>::new())
+    ///     .build();
+    ///
+    /// pool.execute_with_state(|cache: &mut HashMap<u64, u64>| {
+    ///     cache.entry(7).or_insert(49);
+    /// })
+    /// .unwrap();
+    /// pool.wait();
+    /// ```
+    pub fn execute_with_state<S, J>(&self, f: J) -> Result<(), ExecuteError>
+    where
+        S: 'static,
+        J: FnOnce(&mut S) + Send + 'static,
+    {
+        let init = match &self.shared.worker_state {
+            Some((state, init)) if *state == TypeId::of::<S>() => Arc::clone(init),
+            _ => panic!(
+                "the pool has no worker state of type {}",
+                any::type_name::<S>()
+            ),
+        };
+        let pool = Arc::downgrade(&self.shared);
+        self.execute(move || with_worker_state(&pool, &init, f))
+    }
+
+    /// Runs a closure once on every worker thread, to flush thread local
+    /// caches, update per worker state or warm allocators. Each worker
+    /// runs it before its next queued job, or as soon as it is idle. The
+    /// closures skip the queue, so they aren't reported to observers nor
+    /// counted in the metrics, and a panic doesn't take the worker down.
+    ///
+    /// **f**: A Fn closure given the id of the worker. \
+    /// **returns**: a WaitGroup that is done once every worker ran f, or
+    /// exited without running it.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// let pool = WorkerPool::new(4);
+    /// let flushed = Arc::new(AtomicUsize::new(0));
+    ///
+    /// let counter = flushed.clone();
+    /// pool.broadcast(move |_id| {
+    ///     counter.fetch_add(1, Ordering::Relaxed);
+    /// })
+    /// .wait();
+    /// assert_eq!(4, flushed.load(Ordering::Relaxed));
+    /// ```
+    pub fn broadcast<F>(&self, f: F) -> WaitGroup
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        let wg = WaitGroup::default();
+        let workers = self.shared.workers.lock().expect("Cant acquire lock");
+        for worker in workers.iter() {
+            let (f, wg, id) = (Arc::clone(&f), wg.clone(), worker.id);
+            // a job the worker never runs drops its WaitGroup too
+            worker.inbox.push(Box::new(move || {
+                f(id);
+                drop(wg);
+            }));
+        }
+        drop(workers);
+        self.shared.queue.wake_all();
+        wg
+    }
+
+    /// Returns the ids of the workers of the pool, for `spawn_pinned`.
+    pub fn worker_ids(&self) -> Vec<usize> {
+        let workers = self.shared.workers.lock().expect("Cant acquire lock");
+        workers.iter().map(|worker| worker.id).collect()
+    }
+
+    /// Executes a job on the given worker thread only, for jobs that use
+    /// a resource owned by that thread, like a !Send value made by
+    /// `Builder::worker_state` or kept in a thread local. The job skips
+    /// the queue, so priorities and backends don't apply, but it is
+    /// reported to observers and awaited by `wait`. The worker runs it
+    /// before its next queued job, or as soon as it is idle, and a panic
+    /// doesn't take the worker down.
+    ///
+    /// **worker**: usize - the id of the worker, from `worker_ids`. \
+    /// **f**: A FnOnce closure hosted by a Box smart pointer. \
+    /// **returns**: Ok, or ExecuteError::UnknownWorker if no worker has
+    /// that id.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use std::sync::mpsc;
+    ///
+    /// let pool = WorkerPool::new(3);
+    /// let (tx, rx) = mpsc::channel();
+    /// let worker = pool.worker_ids()[1];
+    /// for _ in 0..2 {
+    ///     let tx = tx.clone();
+    ///     pool.spawn_pinned(worker, move || tx.send(std::thread::current().id()).unwrap())
+    ///         .unwrap();
+    /// }
+    /// assert_eq!(rx.recv().unwrap(), rx.recv().unwrap());
+    /// ```
+    pub fn spawn_pinned<J>(&self, worker: usize, f: J) -> Result<(), ExecuteError>
+    where
+        J: FnOnce() + Send + 'static,
+    {
+        if !self.shared.queue.is_accepting() {
+            return Err(ExecuteError::Shutdown);
+        }
+        let mut task = QueuedJob::new(Box::new(f));
+        let submitted = self.shared.track_submit(&mut task);
+        // counted before the push, so the worker can't finish it first
+        self.shared.in_flight.fetch_add(1, Ordering::AcqRel);
+        let pinned = Pinned(Some(task), Arc::downgrade(&self.shared));
+        let workers = self.shared.workers.lock().expect("Cant acquire lock");
+        let pushed = workers
+            .iter()
+            .find(|w| w.id == worker)
+            .is_some_and(|w| w.inbox.push(Box::new(move || pinned.run())));
+        drop(workers);
+        if !pushed {
+            return Err(ExecuteError::UnknownWorker(worker));
+        }
+        self.shared.notify_submit(submitted);
+        self.shared.queue.wake_all();
+        Ok(())
+    }
+
+    /// Executes a job with a name. The name is reported to observers and
+    /// panic messages, to tell which logical task failed or is stuck.
+    ///
+    /// **name**: &str - the name of the job. \
+    /// **f**: A FnOnce closure hosted by a Box smart pointer.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    ///
+    /// let pool = WorkerPool::new(2);
+    ///
+    /// for id in 0..3 {
+    ///     let name = format!("resize-image-{}", id);
+    ///     pool.execute_named(&name, move || println!("resizing {}", id)).unwrap();
+    /// }
+    /// ```
+    pub fn execute_named<J>(&self, name: &str, f: J) -> Result<(), ExecuteError>
+    where
+        J: FnOnce() + Send + 'static,
+    {
+        self.job(f).name(name).spawn()
+    }
+
+    /// Executes a job after the jobs sent before with the same key, and
+    /// never while one of them runs. Jobs of different keys run in
+    /// parallel, so work for each user or file is serialized on a
+    /// shared pool. Jobs waiting for their key don't count as queued.
+    ///
+    /// **key**: u64 - the key serializing the job. \
+    /// **f**: A FnOnce closure hosted by a Box smart pointer. \
+    /// **returns**: Ok if the job was queued or waits for its key, or
+    /// an ExecuteError.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let pool = WorkerPool::new(4);
+    /// let log = Arc::new(Mutex::new(Vec::new()));
+    ///
+    /// for step in 0..10 {
+    ///     let log = log.clone();
+    ///     pool.execute_keyed(42, move || log.lock().unwrap().push(step)).unwrap();
+    /// }
+    /// pool.wait();
+    ///
+    /// assert_eq!((0..10).collect::<Vec<_>>(), *log.lock().unwrap());
+    /// ```
+    pub fn execute_keyed<J>(&self, key: u64, f: J) -> Result<(), ExecuteError>
+    where
+        J: FnOnce() + Send + 'static,
+    {
+        enqueue_serial(&self.shared, SerialKey::User(key), Box::new(f))
+    }
+
+    /// Carves groups out of the pool, each with a limit of jobs running
+    /// at once, so every subsystem of a binary gets bounded parallelism
+    /// on the threads of one pool. The jobs of a group beyond its limit
+    /// wait in the group, and don't count as queued.
+    ///
+    /// **groups**: &[(&str, usize)] - the name and the limit of each
+    /// group, at least one job. \
+    /// **returns**: a SubPool for each group, in the same order.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// let pool = WorkerPool::new(6);
+    /// let groups = pool.partition(&[("parsing", 2), ("compress", 4)]);
+    /// let parsing = &groups[0];
+    /// let (running, peak) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    ///
+    /// for _ in 0..20 {
+    ///     let (running, peak) = (running.clone(), peak.clone());
+    ///     parsing.execute(move || {
+    ///         let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+    ///         peak.fetch_max(now, Ordering::SeqCst);
+    ///         std::thread::sleep(std::time::Duration::from_millis(1));
+    ///         running.fetch_sub(1, Ordering::SeqCst);
+    ///     })
+    ///     .unwrap();
+    /// }
+    /// pool.wait();
+    ///
+    /// assert_eq!("parsing", parsing.name());
+    /// assert!(peak.load(Ordering::SeqCst) <= 2);
+    /// ```
+    pub fn partition(&self, groups: &[(&str, usize)]) -> Vec<SubPool> {
+        let subpools: Vec<_> = groups
+            .iter()
+            .map(|&(name, limit)| SubPool::new(&self.shared, name, limit))
+            .collect();
+        // kept so the shutdown methods find the jobs waiting in a group
+        let mut registered = self.shared.groups.lock().expect("Cant acquire lock");
+        registered.extend(subpools.iter().map(SubPool::group));
+        subpools
+    }
+
+    /// Executes a job tagged with a label. If the label has a limit
+    /// configured in the Builder and its queue is full, the job is
+    /// rejected. Labels without a limit are never rejected.
+    ///
+    /// **label**: &str - the label of the job. \
+    /// **f**: A FnOnce closure hosted by a Box smart pointer. \
+    /// **returns**: Ok if the job was queued, or an ExecuteError.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::{Builder, ExecuteError};
+    ///
+    /// let pool = Builder::new(1).label_limit("bulk", 0).build();
+    ///
+    /// assert!(pool.execute_labeled("interactive", || {}).is_ok());
+    /// assert_eq!(
+    ///     Err(ExecuteError::LimitReached("bulk".to_string())),
+    ///     pool.execute_labeled("bulk", || {})
+    /// );
+    /// ```
+    pub fn execute_labeled<J>(&self, label: &str, f: J) -> Result<(), ExecuteError>
+    where
+        J: FnOnce() + Send + 'static,
+    {
+        self.job(f).label(label).spawn()
+    }
+
+    /// Executes a job with a tag, so it can be removed from the queue
+    /// with `cancel_tag`, like the jobs of a client that disconnected.
+    ///
+    /// **tag**: &str - the tag of the job. \
+    /// **f**: A FnOnce closure hosted by a Box smart pointer. \
+    /// **returns**: Ok if the job was queued, or an ExecuteError.
+    pub fn execute_tagged<J>(&self, tag: &str, f: J) -> Result<(), ExecuteError>
+    where
+        J: FnOnce() + Send + 'static,
+    {
+        self.job(f).tag(tag).spawn()
+    }
+
+    /// Removes the jobs of a tag waiting in the queue. They are skipped
+    /// as if they were cancelled: their handles resolve with
+    /// JobError::Cancelled. Jobs of the tag already running go on.
+    ///
+    /// **tag**: &str - the tag of the jobs to remove. \
+    /// **returns**: how many jobs were removed.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use std::sync::mpsc;
+    ///
+    /// let pool = WorkerPool::new(1);
+    /// let (tx, rx) = mpsc::channel::<()>();
+    /// pool.execute(move || drop(rx.recv())).unwrap();
+    ///
+    /// for page in 0..3 {
+    ///     pool.execute_tagged("user:42", move || println!("render {}", page))
+    ///         .unwrap();
+    /// }
+    /// pool.execute_tagged("user:7", || println!("render 0")).unwrap();
+    ///
+    /// // user 42 disconnected
+    /// assert_eq!(3, pool.cancel_tag("user:42"));
+    /// drop(tx);
+    /// pool.wait();
+    /// ```
+    pub fn cancel_tag(&self, tag: &str) -> usize {
+        let tasks = self
+            .shared
+            .queue
+            .remove_where(&mut |task: &QueuedJob| task.tag.as_deref() == Some(tag));
+        let removed = tasks.len();
+        tasks
+            .into_iter()
+            .for_each(|task| self.shared.cancel_task(task));
+        removed + self.io.as_ref().map_or(0, |io| io.cancel_tag(tag))
+    }
+
+    /// Asserts that the pool becomes idle, with no job queued or running,
+    /// within the timeout. Panics with a dump of the pool state otherwise,
+    /// instead of hanging like `wait` would. Only available with the
+    /// `test-support` feature.
+    ///
+    /// **timeout**: Duration - the maximum time to wait.
+    #[cfg(feature = "test-support")]
+    pub fn assert_idle_within(&self, timeout: Duration) {
+        if !self.shared.wait_idle_timeout(timeout) {
+            panic!("pool was not idle within {:?}\nstate: {:#?}", timeout, self);
+        }
+    }
+
+    /// Applies f to every item in parallel and returns the results in
+    /// the same order as the input. Blocks until all items are processed.
+    ///
+    /// **items**: An iterator with the input items. \
+    /// **f**: A Fn closure applied to each item. \
+    /// **returns**: a Vec with the results, in input order, or the
+    /// JobError of the first item that failed. An item dropped by a
+    /// lossy overflow policy fails with JobError::Dropped.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    ///
+    /// let pool = WorkerPool::new(4);
+    /// let squares = pool.map(1..=5, |x| x * x).unwrap();
+    ///
+    /// assert_eq!(vec![1, 4, 9, 16, 25], squares);
+    /// ```
+    pub fn map<I, F, R>(&self, items: I, f: F) -> Result<Vec<R>, JobError>
+    where
+        I: IntoIterator,
+        I::Item: Send + 'static,
+        F: Fn(I::Item) -> R + Send + Sync + 'static,
+        R: Send + 'static,
+    {
+        let f = Arc::new(f);
+        let (tx, rx) = mpsc::channel();
+        let mut count = 0;
+        let tasks: Vec<_> = items
+            .into_iter()
+            .enumerate()
+            .map(|(index, item)| {
+                count += 1;
+                let f = Arc::clone(&f);
+                let (tx, cancel_tx) = (tx.clone(), tx.clone());
+                let mut task = QueuedJob::new(Box::new(move || {
+                    let result = catch(|| f(item));
+                    let _ = tx.send((index, result.map_err(JobError::Panicked)));
+                }));
+                // only an overflow policy drops an untagged job from the queue
+                task.on_cancel = Some(Box::new(move || {
+                    let _ = cancel_tx.send((index, Err(JobError::Dropped)));
+                }));
+                task
+            })
+            .collect();
+        drop(tx);
+        self.shared
+            .enqueue_batch(tasks)
+            .map_err(|_| JobError::PoolShutdown)?;
+
+        let mut results: Vec<Option<Result<R, JobError>>> = (0..count).map(|_| None).collect();
+        for (index, result) in rx.iter().take(count) {
+            results[index] = Some(result);
+        }
+        results
+            .into_iter()
+            .map(|result| result.unwrap_or(Err(JobError::PoolShutdown)))
+            .collect()
+    }
+
+    /// Maps every item and reduces the results in parallel. The input is
+    /// split in one chunk per worker, each chunk is mapped and reduced on
+    /// a worker starting from identity, and the partial results are
+    /// reduced in input order on the current thread, so reduce only has
+    /// to be associative. Blocks until all chunks are processed.
+    ///
+    /// **items**: An iterator with the input items. \
+    /// **map**: A Fn closure applied to each item. \
+    /// **identity**: T - the neutral value of reduce, the result for an
+    /// empty input. \
+    /// **reduce**: A Fn closure combining two values. \
+    /// **returns**: the reduced value, or the JobError of the first
+    /// chunk that failed.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    ///
+    /// let pool = WorkerPool::new(4);
+    /// let words = vec!["map", "reduce", "on", "workers"];
+    /// let letters = pool
+    ///     .map_reduce(words, |word| word.len(), 0, |a, b| a + b)
+    ///     .unwrap();
+    ///
+    /// assert_eq!(18, letters);
+    /// ```
+    pub fn map_reduce<I, M, T, R>(
+        &self,
+        items: I,
+        map: M,
+        identity: T,
+        reduce: R,
+    ) -> Result<T, JobError>
+    where
+        I: IntoIterator,
+        I::Item: Send + 'static,
+        M: Fn(I::Item) -> T + Send + Sync + 'static,
+        T: Clone + Send + Sync + 'static,
+        R: Fn(T, T) -> T + Send + Sync + 'static,
+    {
+        let mut items: Vec<_> = items.into_iter().collect();
+        let size = items.len().div_ceil(self.worker_count().max(1)).max(1);
+        let mut chunks = Vec::new();
+        while !items.is_empty() {
+            let rest = items.split_off(size.min(items.len()));
+            chunks.push(mem::replace(&mut items, rest));
+        }
+
+        let (map, reduce) = (Arc::new(map), Arc::new(reduce));
+        let partials = {
+            let reduce = Arc::clone(&reduce);
+            let identity = identity.clone();
+            self.map(chunks, move |chunk| {
+                chunk
+                    .into_iter()
+                    .fold(identity.clone(), |acc, item| reduce(acc, map(item)))
+            })?
+        };
+        Ok(partials
+            .into_iter()
+            .fold(identity, |acc, partial| reduce(acc, partial)))
+    }
+
+    /// Shuts the pool down. New jobs are rejected with
+    /// ExecuteError::Shutdown, the jobs already queued are executed, and
+    /// then the worker threads are joined. Delayed jobs that aren't due
+    /// yet are dropped. Calling it more than once is
+    /// harmless, and it is called automatically when the pool is dropped.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::{ExecuteError, WorkerPool};
+    ///
+    /// let pool = WorkerPool::new(2);
+    /// let handle = pool.submit(|| 1).unwrap();
+    ///
+    /// pool.shutdown();
+    /// pool.shutdown();
+    ///
+    /// assert_eq!(1, handle.join().unwrap());
+    /// assert_eq!(Err(ExecuteError::Shutdown), pool.execute(|| {}));
+    /// ```
+    pub fn shutdown(&self) {
+        self.close();
+        self.join();
+    }
+
+    /// Shuts the pool down, giving the queued jobs up to timeout to
+    /// finish. New jobs are rejected with ExecuteError::Shutdown. If the
+    /// jobs finish in time, the workers are joined as in `shutdown`.
+    /// Otherwise the jobs still queued are cancelled, as by
+    /// `cancel_tag`, their handles resolving with JobError::Cancelled.
+    /// The jobs waiting for their key or for a slot of their SubPool are
+    /// dropped, their handles resolving with JobError::PoolShutdown. The
+    /// workers exit in the background once their running jobs end.
+    ///
+    /// **timeout**: Duration - the maximum time to wait for the jobs. \
+    /// **returns**: the number of queued and waiting jobs abandoned, 0
+    /// if every job finished. The IO group, if any, shares the deadline,
+    /// and its abandoned jobs are counted too.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let pool = WorkerPool::new(1);
+    /// for _ in 0..5 {
+    ///     pool.execute(|| thread::sleep(Duration::from_millis(50))).unwrap();
+    /// }
+    ///
+    /// let abandoned = pool.shutdown_graceful(Duration::from_millis(10));
+    /// assert!(abandoned >= 3);
+    /// ```
+    pub fn shutdown_graceful(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        self.close();
+        let idle = self.shared.wait_idle_timeout(timeout);
+        // the IO group gets what is left of the same deadline
+        let io_abandoned = self.io.as_ref().map_or(0, |io| {
+            io.shutdown_graceful(deadline.saturating_duration_since(Instant::now()))
+        });
+        if idle {
+            if io_abandoned == 0 {
+                self.join();
+            }
+            return io_abandoned;
+        }
+
+        // taken first, so the turns of the cancelled jobs don't queue them
+        let waiting = self.shared.take_waiting();
+        let abandoned = self.shared.queue.take_all();
+        let count = abandoned.len() + waiting.len();
+        abandoned
+            .into_iter()
+            .for_each(|task| self.shared.cancel_task(task));
+        drop(waiting);
+        count + io_abandoned
+    }
+
+    /// Shuts the pool down at once, and returns the jobs that didn't
+    /// start, so they can be persisted or sent elsewhere. The queued
+    /// jobs come first, by priority, then the jobs waiting for their key
+    /// or for a slot of their SubPool.
+    /// Running a returned job completes its JobHandle, if it has one.
+    /// Jobs already running are not interrupted, and their workers exit
+    /// in the background. Delayed jobs that aren't due yet are dropped.
+    ///
+    /// **returns**: the jobs that didn't start.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::Builder;
+    ///
+    /// let pool = Builder::new(0).build();
+    /// let handle = pool.submit(|| 42).unwrap();
+    ///
+    /// let pending = pool.shutdown_now();
+    /// assert_eq!(1, pending.len());
+    ///
+    /// // run it here instead
+    /// pending.into_iter().for_each(|job| job());
+    /// assert_eq!(42, handle.join().unwrap());
+    /// ```
+    pub fn shutdown_now(&self) -> Vec<Job> {
+        self.close();
+        let tasks = self.shared.queue.take_all();
+        tasks
+            .iter()
+            .for_each(|task| self.shared.release_label(task));
+        self.shared.finish(tasks.len());
+
+        let waiting = self.shared.take_waiting();
+        let io = self.io.as_ref().map(|io| io.shutdown_now());
+        tasks
+            .into_iter()
+            .map(|task| task.job)
+            .chain(waiting)
+            .chain(io.into_iter().flatten())
+            .collect()
+    }
+
+    /// The first phase of a shutdown split in steps: stops the intake.
+    /// New jobs are rejected with ExecuteError::Shutdown, delayed jobs
+    /// that aren't due yet are dropped, and a paused pool is resumed.
+    /// The queued jobs keep running. Follow it with `drain` and `join`,
+    /// with the steps of the application in between.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::{ExecuteError, WorkerPool};
+    ///
+    /// let pool = WorkerPool::new(2);
+    /// for _ in 0..10 {
+    ///     pool.execute(|| {}).unwrap();
+    /// }
+    ///
+    /// pool.close();
+    /// assert_eq!(Err(ExecuteError::Shutdown), pool.execute(|| {}));
+    ///
+    /// let report = pool.drain();
+    /// // flush caches, the jobs are done and no worker runs
+    /// assert_eq!(0, pool.metrics().in_flight);
+    /// assert!(report.completed <= 10);
+    ///
+    /// pool.join();
+    /// ```
+    pub fn close(&self) {
+        self.shared.timer.close();
+        if let Some(timer) = self.timer.lock().expect("Cant acquire lock").take() {
+            let _ = timer.join();
+        }
+        self.shared.queue.close();
+        // paused workers would never drain the queue
+        self.shared.set_paused(false);
+        if let Some(io) = &self.io {
+            io.close();
+        }
+    }
+
+    /// The second phase of a shutdown: blocks until the jobs queued or
+    /// running are finished. Without `close` first, it waits like `wait`.
+    ///
+    /// **returns**: a DrainReport with the jobs finished while draining.
+    pub fn drain(&self) -> DrainReport {
+        let started = Instant::now();
+        let completed = self.shared.completed.load(Ordering::Relaxed);
+        let panicked = self.shared.panicked.load(Ordering::Relaxed);
+        self.shared.wait_idle();
+        if let Some(io) = &self.io {
+            io.wait();
+        }
+        DrainReport {
+            completed: self.shared.completed.load(Ordering::Relaxed) - completed,
+            panicked: self.shared.panicked.load(Ordering::Relaxed) - panicked,
+            elapsed: started.elapsed(),
+        }
+    }
+
+    /// The last phase of a shutdown: joins the worker threads, once
+    /// they run out of jobs. Call it after `close`, or it blocks until
+    /// the pool is closed from elsewhere.
+    pub fn join(&self) {
+        self.shared.stop();
+        if let Some(scaler) = self.scaler.lock().expect("Cant acquire lock").take() {
+            let _ = scaler.join();
+        }
+
+        let workers = mem::take(&mut *self.shared.workers.lock().expect("Cant acquire lock"));
+        for worker in workers {
+            worker.join();
+        }
+        if let Some(io) = &self.io {
+            io.join();
+        }
+    }
+}
+
+// Shuts the pool down when it goes out of scope, so no worker
+// thread outlives it.
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// Implements Default for WorkerPool as one worker for each CPU.
+impl Default for WorkerPool {
+    fn default() -> WorkerPool {
+        WorkerPool::auto(1)
+    }
+}
+
+// A weak reference to a pool, for the wakers of the futures spawned on
+// it and the addresses of its actors, so they don't keep it alive.
+pub(crate) struct WeakPool(Weak<Shared>);
+
+impl WeakPool {
+    // Executes a job in the pool, if it is still alive.
+    #[cfg(feature = "futures")]
+    pub(crate) fn execute(&self, job: Job) -> Result<(), ExecuteError> {
+        match self.0.upgrade() {
+            Some(shared) => shared.enqueue(QueuedJob::new(job), Priority::Normal),
+            None => Err(ExecuteError::Shutdown),
+        }
+    }
+
+    // Executes a job after the jobs of its key, if the pool is still
+    // alive.
+    pub(crate) fn execute_serial(&self, key: SerialKey, job: Job) -> Result<(), ExecuteError> {
+        match self.0.upgrade() {
+            Some(shared) => enqueue_serial(&shared, key, job),
+            None => Err(ExecuteError::Shutdown),
+        }
+    }
+}
+
+impl WorkerPool {
+    // Returns a weak reference to the pool.
+    pub(crate) fn downgrade(&self) -> WeakPool {
+        WeakPool(Arc::downgrade(&self.shared))
+    }
+}
+
+// Returns how many CPUs the process may use, at least one.
+pub(crate) fn parallelism() -> usize {
+    thread::available_parallelism().map_or(1, usize::from)
+}
+
+// Runs f with the generator of the current thread, for JobContext and
+// the retry module.
+pub(crate) fn with_worker_rng<R>(f: impl FnOnce(&mut WorkerRng) -> R) -> R {
+    WORKER_RNG.with(|rng| {
+        let mut rng = rng.borrow_mut();
+        f(rng.get_or_insert_with(|| WorkerRng::new(random_seed())))
+    })
+}
+
+// Wraps a job taking a JobContext into a plain job, building the
+// context on the worker that runs it.
+fn with_context<F, T>(shared: &Arc<Shared>, f: F) -> impl FnOnce() -> T + Send + 'static
+where
+    F: FnOnce(&JobContext) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let shared = Arc::downgrade(shared);
+    move || {
+        let ctx = JobContext {
+            token: CURRENT_TOKEN.with(|current| current.borrow().clone()),
+            trace: inherited_trace(),
+            shared,
+        };
+        f(&ctx)
+    }
+}
+
+// Runs f with the state the current worker keeps for the pool. The state
+// is taken out while f runs, so a job running other jobs inline doesn't
+// borrow it twice.
+fn with_worker_state<S: 'static>(pool: &Weak<Shared>, init: &StateInit, f: impl FnOnce(&mut S)) {
+    let kept = WORKER_STATES.with(|states| {
+        let mut states = states.borrow_mut();
+        let index = states.iter().position(|(owner, _)| owner.ptr_eq(pool))?;
+        Some(states.swap_remove(index).1)
+    });
+    let mut state = kept.unwrap_or_else(|| init(WORKER_ID.with(Cell::get).unwrap_or(0)));
+    f(state
+        .downcast_mut()
+        .expect("the state type is checked when the job is sent"));
+    WORKER_STATES.with(|states| states.borrow_mut().push((pool.clone(), state)));
+}
+
+/// Returns the pool running the current job, or None when called from
+/// outside a worker. Jobs sent through it, like those sent to any pool
+/// from a running job, take the trace id of the current job, so a tree
+/// of work can be grouped in logs.
+///
+/// ## Examples
+///
+/// ```
+/// use rpools::pool::{current_pool, WorkerPool};
+///
+/// let pool = WorkerPool::new(2);
+/// let handle = pool
+///     .job_with_context(|parent| {
+///         let child = current_pool()
+///             .unwrap()
+///             .job_with_context(|child| child.trace_id())
+///             .submit()
+///             .unwrap();
+///         (parent.trace_id(), child.join().unwrap())
+///     })
+///     .submit()
+///     .unwrap();
+///
+/// let (parent, child) = handle.join().unwrap();
+/// assert_eq!(parent, child);
+/// assert!(current_pool().is_none());
+/// ```
+pub fn current_pool() -> Option<CurrentPool> {
+    CURRENT_POOL
+        .with(|current| current.borrow().as_ref().and_then(Weak::upgrade))
+        .map(|shared| CurrentPool { shared })
+}
+
+/// The pool running the current job, given by `current_pool`. It sends
+/// jobs as the WorkerPool does, but doesn't own the pool, so dropping it
+/// doesn't shut the pool down.
+pub struct CurrentPool {
+    shared: Arc<Shared>,
+}
+
+impl CurrentPool {
+    /// Executes a job in the pool, same as `WorkerPool::execute`.
+    ///
+    /// **f**: A FnOnce closure hosted by a Box smart pointer.
+    pub fn execute<J>(&self, f: J) -> Result<(), ExecuteError>
+    where
+        J: FnOnce() + Send + 'static,
+    {
+        self.job(f).spawn()
+    }
+
+    /// Executes a job in the pool and returns a handle to its result,
+    /// same as `WorkerPool::submit`.
+    ///
+    /// **f**: A FnOnce closure that produces a value.
+    pub fn submit<F, T>(&self, f: F) -> Result<JobHandle<T>, ExecuteError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.job(f).submit()
+    }
+
+    /// Starts building the submission of a job, same as `WorkerPool::job`.
+    ///
+    /// **f**: A FnOnce closure that may produce a value.
+    pub fn job<F, T>(&self, f: F) -> JobBuilder<'_, F>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        JobBuilder::new(&self.shared, f)
+    }
+
+    /// Same as `job`, but the job receives a JobContext, as with
+    /// `WorkerPool::job_with_context`.
+    ///
+    /// **f**: A FnOnce closure that takes a &JobContext and may produce
+    /// a value.
+    pub fn job_with_context<F, T>(
+        &self,
+        f: F,
+    ) -> JobBuilder<'_, impl FnOnce() -> T + Send + 'static>
+    where
+        F: FnOnce(&JobContext) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.job(with_context(&self.shared, f))
+    }
+}
+
+/// Composes the options of a job before sending it to the pool. It is
+/// created with `WorkerPool::job` or `CurrentPool::job`.
+pub struct JobBuilder<'a, F> {
+    shared: &'a Arc<Shared>,
+    f: F,
+    priority: Priority,
+    name: Option<Arc<str>>,
+    label: Option<String>,
+    tag: Option<Arc<str>>,
+    deadline: Option<Instant>,
+    token: Option<CancellationToken>,
+    trace: Option<u64>,
+    critical: bool,
+    submitter: Option<SubmitterId>,
+}
+
+impl<'a, F, T> JobBuilder<'a, F>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    // Constructs a JobBuilder with the default options.
+    fn new(shared: &'a Arc<Shared>, f: F) -> JobBuilder<'a, F> {
+        JobBuilder {
+            shared,
+            f,
+            priority: Priority::Normal,
+            name: None,
+            label: None,
+            tag: None,
+            deadline: None,
+            token: None,
+            trace: None,
+            critical: false,
+            submitter: None,
+        }
+    }
+
+    /// Sets the priority lane of the job. The default is Priority::Normal.
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Names the job, so observers, panic messages and diagnostics can
+    /// tell which logical task it is.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(Arc::from(name));
+        self
+    }
+
+    /// Sets the trace id of the job, for example the id of the request
+    /// that caused it. By default, jobs sent from a running job take its
+    /// trace id, and the others get a new one.
+    pub fn trace(mut self, trace: u64) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Sends the job as the given submitter, for the fair scheduling of
+    /// `Builder::fair`.
+    pub fn submitter(mut self, submitter: SubmitterId) -> Self {
+        self.submitter = Some(submitter);
+        self
+    }
+
+    /// Marks the job as critical. When the pool has a fallback executor
+    /// and stays saturated, critical jobs are given to it instead of
+    /// waiting in the queue. See `Builder::fallback`.
+    pub fn critical(mut self) -> Self {
+        self.critical = true;
+        self
+    }
+
+    /// Tags the job with a label, subject to the label limit configured
+    /// in the Builder.
+    pub fn label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    /// Tags the job, so it can be removed from the queue with the other
+    /// jobs of the tag by `WorkerPool::cancel_tag`. Unlike a label, a tag
+    /// has no limit.
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tag = Some(Arc::from(tag));
+        self
+    }
+
+    /// Sets a deadline for the job to start. If no worker picks the job
+    /// before it, the job is skipped.
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Binds the job to a cancellation token. If the token is cancelled
+    /// before a worker picks the job, the job is skipped.
+    pub fn token(mut self, token: &CancellationToken) -> Self {
+        self.token = Some(token.clone());
+        self
+    }
+
+    /// Sends the job to the pool, discarding the value it produces.
+    pub fn spawn(self) -> Result<(), ExecuteError> {
+        let f = self.f;
+        let job = Box::new(move || {
+            f();
+        });
+        self.shared.dispatch(
+            QueuedJob {
+                job,
+                on_cancel: None,
+                announced: None,
+                name: self.name,
+                label: self.label,
+                tag: self.tag,
+                deadline: self.deadline,
+                token: self.token,
+                trace: self.trace.unwrap_or_else(inherited_trace),
+                submitted_by: thread::current().id(),
+                submitter: self.submitter,
+                queued: Instant::now(),
+            },
+            self.priority,
+            self.critical,
+        )
+    }
+
+    /// Sends the job to the pool and returns a handle to its result.
+    /// Panics in the job are caught and reported by the handle.
+    pub fn submit(self) -> Result<JobHandle<T>, ExecuteError> {
+        let (job, on_cancel, handle) = with_handle(self.f);
+        self.shared.dispatch(
+            QueuedJob {
+                job,
+                on_cancel: Some(on_cancel),
+                announced: None,
+                name: self.name,
+                label: self.label,
+                tag: self.tag,
+                deadline: self.deadline,
+                token: self.token,
+                trace: self.trace.unwrap_or_else(inherited_trace),
+                submitted_by: thread::current().id(),
+                submitter: self.submitter,
+                queued: Instant::now(),
+            },
+            self.priority,
+            self.critical,
+        )?;
+        Ok(handle)
+    }
+}
+
+/// A handle to a job scheduled with `WorkerPool::execute_after` or
+/// `WorkerPool::execute_at_fixed_rate`.
+pub struct ScheduledHandle {
+    claimed: Arc<AtomicBool>,
+}
+
+impl ScheduledHandle {
+    /// Cancels the job, if it didn't become due yet. A recurring job is
+    /// stopped, although a run already queued still completes.
+    ///
+    /// **returns**: true if the job was cancelled, false if it was
+    /// already queued or cancelled. For a recurring job, false only if
+    /// it was already cancelled.
+    pub fn cancel(&self) -> bool {
+        !self.claimed.swap(true, Ordering::AcqRel)
+    }
+}
+
+/// The context of a job sent with `WorkerPool::execute_with_context`.
+/// Long running jobs check it at loop boundaries, usually with the
+/// `checkpoint!` macro, to cooperate with cancellation and pausing.
+pub struct JobContext {
+    token: Option<CancellationToken>,
+    trace: u64,
+    // weak, so a queued job doesn't keep its own pool alive
+    shared: Weak<Shared>,
+}
+
+impl JobContext {
+    /// Returns the trace id of the job. Jobs sent while it runs, through
+    /// `current_pool` or any other pool, share it.
+    pub fn trace_id(&self) -> u64 {
+        self.trace
+    }
+
+    /// Returns true if the token of the job was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.token
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    }
+
+    /// Checks if the job should go on. Blocks while the pool is paused,
+    /// then yields to other threads.
+    ///
+    /// **returns**: Ok to go on, or JobError::Cancelled if the token of
+    /// the job was cancelled.
+    pub fn checkpoint(&self) -> Result<(), JobError> {
+        if self.is_cancelled() {
+            return Err(JobError::Cancelled);
+        }
+        if let Some(shared) = self.shared.upgrade() {
+            shared.wait_resumed();
+        }
+        if self.is_cancelled() {
+            return Err(JobError::Cancelled);
+        }
+        thread::yield_now();
+        Ok(())
+    }
+
+    /// Gives f the random generator of the worker running the job. It
+    /// is seeded from `Builder::seed` and the worker id, or randomly if
+    /// the pool has no seed. f must not call with_rng again.
+    ///
+    /// **f**: A FnOnce closure that draws from the generator. \
+    /// **returns**: the value returned by f.
+    pub fn with_rng<R>(&self, f: impl FnOnce(&mut WorkerRng) -> R) -> R {
+        with_worker_rng(f)
+    }
+}
+
+// A seed for generators of unseeded pools, from the random keys std
+// uses for its hash maps.
+fn random_seed() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// Checks a JobContext and returns from the enclosing job if it was
+/// cancelled, after waiting while the pool is paused. The optional
+/// second argument is the value returned, for jobs that produce one.
+///
+/// ### Examples
+/// ```
+/// use rpools::checkpoint;
+/// use rpools::pool::WorkerPool;
+///
+/// let pool = WorkerPool::new(1);
+/// let handle = pool
+///     .job_with_context(|ctx| {
+///         let mut sum = 0;
+///         for i in 0..10 {
+///             checkpoint!(ctx, None);
+///             sum += i;
+///         }
+///         Some(sum)
+///     })
+///     .submit()
+///     .unwrap();
+///
+/// assert_eq!(Some(45), handle.join().unwrap());
+/// ```
+#[macro_export]
+macro_rules! checkpoint {
+    ($ctx:expr) => {
+        if $ctx.checkpoint().is_err() {
+            return;
+        }
+    };
+    ($ctx:expr, $ret:expr) => {
+        if $ctx.checkpoint().is_err() {
+            return $ret;
+        }
+    };
+}
+
+/// The progress of a batch sent with
+/// `WorkerPool::submit_batch_with_progress`. Jobs count as completed
+/// when they finish, even if they panicked or were dropped. Clones
+/// follow the same batch.
+#[derive(Clone)]
+pub struct Progress {
+    state: Arc<ProgressState>,
+}
+
+// The counter of a Progress, with a condvar notified on each job.
+struct ProgressState {
+    total: usize,
+    completed: Mutex<usize>,
+    advanced: Condvar,
+}
+
+// Counts a job of a batch as completed when dropped.
+struct Tick(Arc<ProgressState>);
+
+impl Drop for Tick {
+    fn drop(&mut self) {
+        *self.0.completed.lock().expect("Cant acquire lock") += 1;
+        self.0.advanced.notify_all();
+    }
+}
+
+impl Progress {
+    /// Returns how many jobs of the batch have finished.
+    pub fn completed(&self) -> usize {
+        *self.state.completed.lock().expect("Cant acquire lock")
+    }
+
+    /// Returns how many jobs the batch has.
+    pub fn total(&self) -> usize {
+        self.state.total
+    }
+
+    /// Returns the finished part of the batch, between 0 and 1. An
+    /// empty batch is finished.
+    pub fn fraction(&self) -> f64 {
+        fraction(self.completed(), self.state.total)
+    }
+
+    /// Blocks the current thread until the finished part of the batch
+    /// reaches the given fraction.
+    ///
+    /// **target**: f64 - between 0 and 1, values above 1 wait for the
+    /// whole batch.
+    pub fn wait_until(&self, target: f64) {
+        let target = target.min(1.0);
+        let mut completed = self.state.completed.lock().expect("Cant acquire lock");
+        while fraction(*completed, self.state.total) < target {
+            completed = self
+                .state
+                .advanced
+                .wait(completed)
+                .expect("Cant block the current thread");
+        }
+    }
+}
+
+// Implements Debug for Progress, showing the counters.
+impl Debug for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Progress")
+            .field("completed", &self.completed())
+            .field("total", &self.state.total)
+            .finish()
+    }
+}
+
+// The part of total that is completed.
+fn fraction(completed: usize, total: usize) -> f64 {
+    if total == 0 {
+        1.0
+    } else {
+        completed as f64 / total as f64
+    }
+}
+
+impl WorkerPool {
+    /// Returns the operating system thread id of each worker, in the
+    /// order of their ids. Use it to correlate the pool with `top -H`, perf
+    /// or eBPF tools. The id is None on platforms where it is not available.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    ///
+    /// let pool = WorkerPool::new(2);
+    ///
+    /// assert_eq!(2, pool.os_thread_ids().len());
+    /// ```
+    pub fn os_thread_ids(&self) -> Vec<Option<u64>> {
+        let workers = self.shared.workers.lock().expect("Cant acquire lock");
+        workers.iter().map(|w| w.os_id).collect()
+    }
+
+    /// Refreshes a PoolMetrics owned by the caller with the current
+    /// counters of the pool. It doesn't allocate, so it can be called
+    /// from high frequency sampling loops.
+    ///
+    /// **metrics**: &mut PoolMetrics - the snapshot to overwrite.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::{PoolMetrics, WorkerPool};
+    ///
+    /// let pool = WorkerPool::new(2);
+    /// let mut metrics = PoolMetrics::default();
+    ///
+    /// pool.execute(|| {}).unwrap();
+    /// pool.wait();
+    /// pool.metrics_into(&mut metrics);
+    ///
+    /// assert_eq!(2, metrics.workers);
+    /// assert_eq!(1, metrics.completed);
+    /// ```
+    pub fn metrics_into(&self, metrics: &mut PoolMetrics) {
+        let shared = &self.shared;
+        let workers = shared.workers.lock().expect("Cant acquire lock").len();
+        metrics.workers = workers.saturating_sub(shared.retiring.load(Ordering::Acquire));
+        metrics.queued = shared.queue.len();
+        metrics.scheduled = shared.timer.len();
+        metrics.in_flight = shared.in_flight.load(Ordering::Acquire);
+        metrics.active = shared.active.load(Ordering::Acquire);
+        metrics.completed = shared.completed.load(Ordering::Relaxed);
+        metrics.panicked = shared.panicked.load(Ordering::Relaxed);
+        metrics.busy_time = Duration::from_nanos(shared.busy_nanos.load(Ordering::Relaxed));
+        metrics.parked_time = Duration::from_nanos(shared.parked_nanos.load(Ordering::Relaxed));
+        metrics.retrying = shared.retries.depth.load(Ordering::Acquire);
+        metrics.retries_rejected = shared.retries.rejected.load(Ordering::Relaxed);
+        metrics.spilled = shared.spilled.load(Ordering::Relaxed);
+        metrics.budget_yields = shared.budget_yields.load(Ordering::Relaxed);
+        metrics.rejected = shared.rejected.load(Ordering::Relaxed);
+        metrics.dropped = shared.dropped.load(Ordering::Relaxed);
+        metrics.missed_deadlines = shared.missed_deadlines.load(Ordering::Relaxed);
+        #[cfg(feature = "metrics-export")]
+        shared.execution.cumulative(
+            &JOB_DURATION_BUCKETS.map(Duration::from_secs_f64),
+            &mut metrics.job_durations,
+        );
+    }
+
+    /// Returns a snapshot of the counters of the pool, for capacity
+    /// planning and alerting. See `metrics_into` to reuse a snapshot.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    ///
+    /// let pool = WorkerPool::new(2);
+    /// let _ = pool.submit(|| -> u8 { panic!("boom") }).unwrap().join();
+    /// pool.wait();
+    ///
+    /// let metrics = pool.metrics();
+    /// assert_eq!(0, metrics.queued);
+    /// assert_eq!(0, metrics.active);
+    /// assert_eq!(1, metrics.completed);
+    /// assert_eq!(1, metrics.panicked);
+    /// ```
+    pub fn metrics(&self) -> PoolMetrics {
+        let mut metrics = PoolMetrics::default();
+        self.metrics_into(&mut metrics);
+        metrics
+    }
+
+    /// Returns percentiles of how long jobs waited in the queue and how
+    /// long they ran, since the pool was built, to tell if jobs are slow
+    /// because the pool is saturated or because the jobs themselves are.
+    /// Percentiles are approximated within 12.5%.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let pool = WorkerPool::new(1);
+    /// for _ in 0..5 {
+    ///     pool.execute(|| thread::sleep(Duration::from_millis(2))).unwrap();
+    /// }
+    /// pool.wait();
+    ///
+    /// let stats = pool.latency_stats();
+    /// assert_eq!(5, stats.execution.count);
+    /// assert!(stats.execution.p50 >= Duration::from_millis(2));
+    /// assert!(stats.queue_wait.max >= Duration::from_millis(6));
+    /// ```
+    pub fn latency_stats(&self) -> LatencyStats {
+        LatencyStats {
+            queue_wait: Percentiles::of(&self.shared.queue_wait),
+            execution: Percentiles::of(&self.shared.execution),
+        }
+    }
+
+    /// Returns the health of the pool, so long running services can
+    /// alarm when workers die silently. Observers are told of each death
+    /// with `PoolObserver::on_worker_death`.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::{Health, WorkerPool};
+    ///
+    /// let pool = WorkerPool::new(2);
+    /// assert_eq!(Health::Healthy, pool.health());
+    ///
+    /// pool.execute(|| panic!("escaped")).unwrap();
+    /// pool.wait();
+    /// # while pool.health() == Health::Healthy {
+    /// #     std::thread::yield_now();
+    /// # }
+    /// assert_eq!(Health::Degraded { dead_workers: 1 }, pool.health());
+    ///
+    /// pool.shutdown();
+    /// assert_eq!(Health::Stopped, pool.health());
+    /// ```
+    pub fn health(&self) -> Health {
+        let dead_workers = self.shared.dead.load(Ordering::Relaxed);
+        if self.shared.queue.is_closed() {
+            Health::Stopped
+        } else if dead_workers > 0 {
+            Health::Degraded { dead_workers }
+        } else {
+            Health::Healthy
+        }
+    }
+
+    /// Returns what each worker is doing and how many jobs are queued,
+    /// to diagnose stalled pipelines. The dump is displayed one worker
+    /// per line.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::{WorkerPool, WorkerState};
+    /// use rpools::sync::CountDownLatch;
+    ///
+    /// let pool = WorkerPool::new(1);
+    /// let (started, release) = (CountDownLatch::new(1), CountDownLatch::new(1));
+    /// let (job_started, job_release) = (started.clone(), release.clone());
+    /// pool.job(move || {
+    ///     job_started.count_down();
+    ///     job_release.wait();
+    /// })
+    /// .name("ingest")
+    /// .spawn()
+    /// .unwrap();
+    /// started.wait();
+    ///
+    /// let dump = pool.dump();
+    /// match &dump.workers[0].state {
+    ///     WorkerState::Running { job_name, .. } => {
+    ///         assert_eq!(Some("ingest"), job_name.as_deref())
+    ///     }
+    ///     state => panic!("unexpected state {:?}", state),
+    /// }
+    /// println!("{}", dump);
+    /// release.count_down();
+    /// ```
+    pub fn dump(&self) -> PoolDump {
+        let workers = self.shared.workers.lock().expect("Cant acquire lock");
+        PoolDump {
+            workers: workers.iter().map(Worker::dump).collect(),
+            queued: self.shared.queue.len(),
+        }
+    }
+}
+
+// Implements Debug for WorkerPool, listing the os thread id of each
+// worker and the job counters for diagnostics.
+impl std::fmt::Debug for WorkerPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let workers: Vec<_> = self
+            .shared
+            .workers
+            .lock()
+            .expect("Cant acquire lock")
+            .iter()
+            .map(|w| format!("(id: {}, os_id: {:?})", w.id, w.os_id))
+            .collect();
+        f.debug_struct("WorkerPool")
+            .field("workers", &workers)
+            .field("queued", &self.shared.queue.len())
+            .field("scheduled", &self.shared.timer.len())
+            .field("in_flight", &self.shared.in_flight.load(Ordering::Acquire))
+            .field("completed", &self.shared.completed.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+// Implements Display for WorkerPool. This is usefull as we can able
+// to compare and make unit tests more easily.
+impl Display for WorkerPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let workers = self.shared.workers.lock().expect("Cant acquire lock");
+        let mut buffer = String::new();
+        for i in workers.iter() {
+            buffer.push_str(&i.to_string());
+        }
+        write!(f, "workers[] = {}", buffer)
+    }
+}
+
+// A structure that holds an id and thread handle.
+//
+// id: usize - An id for worker indentification.\
+// os_id: Option<u64> - the thread id given by the operating system.\
+// core: Option<usize> - the CPU core the thread is pinned to.\
+// job: Arc<JobSlot> - the job the worker thread runs.\
+// inbox: Arc<Inbox> - the jobs sent to this worker only.\
+// handle: JoinHandle<()> - a handle that has a working thread.
+struct Worker {
+    id: usize,
+    os_id: Option<u64>,
+    core: Option<usize>,
+    job: Arc<JobSlot>,
+    inbox: Arc<Inbox>,
+    handle: Option<Handle>,
+}
+
+impl Worker {
+    // Constructs a new Worker.
+    //
+    // id: usize - Worker identificator.
+    // shared: Arc<Shared> - the state shared with the pool.
+    fn new(id: usize, shared: Arc<Shared>) -> Worker {
+        Worker::with_config(id, shared, WorkerConfig::default())
+    }
+
+    // Constructs a new Worker, as configured.
+    //
+    // id: usize - Worker identificator.
+    // shared: Arc<Shared> - the state shared with the pool.
+    // config: WorkerConfig - the stack, core and lanes of the worker.
+    fn with_config(id: usize, shared: Arc<Shared>, config: WorkerConfig) -> Worker {
+        let (id_tx, id_rx) = mpsc::channel();
+        let job = Arc::new(Mutex::new(None));
+        let slot = Arc::clone(&job);
+        let inbox = Arc::new(Inbox::new());
+        let closing = Closing(Arc::clone(&inbox));
+        let mut builder = thread::Builder::new();
+        if let Some(size) = config.stack_size {
+            builder = builder.stack_size(size);
+        }
+        if let Some(prefix) = &shared.thread_name {
+            builder = builder.name(format!("{}-{}", prefix, id));
+        }
+        let lowest = config.priority.lane();
+        shared.running.fetch_add(1, Ordering::AcqRel);
+        let spawned = builder.spawn(move || {
+            let mut alive = Alive {
+                id,
+                shared: Arc::clone(&shared),
+                counted: true,
+            };
+            // only the extra workers of an elastic pool can exit
+            let keep_alive = shared
+                .elastic
+                .as_ref()
+                .filter(|e| e.max > e.core)
+                .map(|e| e.keep_alive);
+            let core = match (config.core, shared.cores.as_slice()) {
+                (Some(core), _) => Some(core),
+                (None, []) => None,
+                (None, cores) => Some(cores[id % cores.len()]),
+            }
+            .filter(|&core| pin_thread(core));
+            if let Some(priority) = shared.priority {
+                set_thread_priority(priority);
+            }
+            id_tx
+                .send((os_thread_id(), core))
+                .expect("worker constructor waits for the thread id");
+            CURRENT_JOB.with(|current| *current.borrow_mut() = Some(slot));
+            WORKER_ID.with(|worker| worker.set(Some(id)));
+            if shared.attribute_panics {
+                PANIC_WORKER.with(|worker| worker.set(Some(id)));
+            }
+            let mut budget = Budget {
+                limit: shared.budget,
+                since: None,
+            };
+            if let Some(seed) = shared.seed {
+                WORKER_RNG.with(|rng| *rng.borrow_mut() = Some(WorkerRng::for_worker(seed, id)));
+            }
+            let inbox = &closing.0;
+            let mut idle = Idle::new(shared.on_idle.as_ref().map(|(after, _)| *after));
+            loop {
+                inbox.run();
+                let parked = Instant::now();
+                let popped = shared.queue.pop_from(
+                    lowest,
+                    idle.timeout(keep_alive),
+                    || shared.claim_retirement(),
+                    || inbox.has_jobs() || (lowest == 0 && shared.can_steal()),
+                );
+                shared
+                    .parked_nanos
+                    .fetch_add(parked.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                match popped {
+                    Pop::Item(task) => {
+                        idle.reset();
+                        budget.start();
+                        shared.work(task);
+                        budget.spend(&shared);
+                    }
+                    Pop::Interrupted => {
+                        if inbox.has_jobs() {
+                            continue;
+                        }
+                        if let Some((peer, task, _stolen)) = shared.steal() {
+                            idle.reset();
+                            peer.work(task);
+                        }
+                    }
+                    Pop::Idle => {
+                        if idle.notify() {
+                            if let Some((_, callback)) = &shared.on_idle {
+                                callback(id);
+                            }
+                        }
+                        if idle.kept_alive(keep_alive) {
+                            if shared.claim_idle_exit(id) {
+                                alive.counted = false;
+                                break;
+                            }
+                            idle.idle_since = Instant::now();
+                        }
+                    }
+                    Pop::Closed | Pop::Stopped => break,
+                }
+            }
+        });
+        let handle = spawned.expect("Cant spawn the worker thread");
+
+        let (os_id, core) = id_rx.recv().unwrap_or((None, None));
+        Worker {
+            id,
+            os_id,
+            core,
+            job,
+            inbox,
+            handle: Some(handle),
+        }
+    }
+
+    // Describes what the worker is doing now.
+    fn dump(&self) -> WorkerDump {
+        let exited = self.handle.as_ref().is_none_or(|h| h.is_finished());
+        let state = match self.job.lock().expect("Cant acquire lock").as_ref() {
+            _ if exited => WorkerState::Dead,
+            Some(job) => WorkerState::Running {
+                job_name: job.name.as_deref().map(str::to_string),
+                since: job.since,
+            },
+            None => WorkerState::Idle,
+        };
+        WorkerDump {
+            id: self.id,
+            os_id: self.os_id,
+            core: self.core,
+            state,
+        }
+    }
+
+    // Waits for the worker thread to exit. A job may shut the pool down,
+    // and its worker can't join itself, so the current thread is skipped.
+    fn join(mut self) {
+        if let Some(handle) = self.handle.take() {
+            if handle.thread().id() != thread::current().id() {
+                // a panicking job already killed its worker, nothing to report
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+// Counts a worker thread as running until it exits, unless it was
+// already uncounted when it claimed an idle exit.
+struct Alive {
+    id: usize,
+    shared: Arc<Shared>,
+    counted: bool,
+}
+
+impl Drop for Alive {
+    fn drop(&mut self) {
+        if self.counted {
+            self.shared.running.fetch_sub(1, Ordering::AcqRel);
+        }
+        if thread::panicking() {
+            self.shared.dead.fetch_add(1, Ordering::Relaxed);
+            for observer in &self.shared.observers {
+                observer.on_worker_death(self.id);
+            }
+        }
+    }
+}
+
+// The jobs sent to a single worker with WorkerPool::broadcast. It is
+// closed when the worker exits, so no job is left waiting for it.
+struct Inbox(Mutex<Option<Vec<Job>>>);
+
+impl Inbox {
+    fn new() -> Inbox {
+        Inbox(Mutex::new(Some(Vec::new())))
+    }
+
+    // Queues a job for the worker. Returns false, dropping the job, if
+    // the worker already exited.
+    fn push(&self, job: Job) -> bool {
+        match self.0.lock().expect("Cant acquire lock").as_mut() {
+            Some(jobs) => {
+                jobs.push(job);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn has_jobs(&self) -> bool {
+        let jobs = self.0.lock().expect("Cant acquire lock");
+        jobs.as_ref().is_some_and(|jobs| !jobs.is_empty())
+    }
+
+    // Runs the queued jobs. A panicking job doesn't kill the worker.
+    fn run(&self) {
+        let jobs = match self.0.lock().expect("Cant acquire lock").as_mut() {
+            Some(jobs) => mem::take(jobs),
+            None => return,
+        };
+        for job in jobs {
+            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+        }
+    }
+}
+
+// A job sent to the inbox of a worker with WorkerPool::spawn_pinned.
+// If dropped without running, it is finished, so wait doesn't hang.
+struct Pinned(Option<QueuedJob>, Weak<Shared>);
+
+impl Pinned {
+    fn run(mut self) {
+        if let (Some(task), Some(shared)) = (self.0.take(), self.1.upgrade()) {
+            shared.work(task);
+        }
+    }
+}
+
+impl Drop for Pinned {
+    fn drop(&mut self) {
+        if let (Some(_), Some(shared)) = (self.0.take(), self.1.upgrade()) {
+            shared.finish(1);
+        }
+    }
+}
+
+// Closes the inbox of a worker when its thread exits. The jobs left are
+// run, or dropped if the worker is unwinding.
+struct Closing(Arc<Inbox>);
+
+impl Drop for Closing {
+    fn drop(&mut self) {
+        let jobs = self.0 .0.lock().expect("Cant acquire lock").take();
+        if !thread::panicking() {
+            jobs.into_iter().flatten().for_each(|job| job());
+        }
+    }
+}
+
+// Counts a worker as active while a job runs, and adds the time it
+// took to the busy time of the pool. Also counts the job if it panics,
+// either unwinding the worker or caught by its wrapper. The observers
+// are told when the job starts and ends.
+struct Busy<'a> {
+    shared: &'a Shared,
+    // the task, already without its job
+    task: QueuedJob,
+    started: Instant,
+}
+
+impl Busy<'_> {
+    fn new(shared: &Shared, task: QueuedJob) -> Busy<'_> {
+        shared.active.fetch_add(1, Ordering::AcqRel);
+        shared.queue_wait.record(task.queued.elapsed());
+        CAUGHT_PANIC.with(|caught| caught.set(false));
+        CAUGHT_MESSAGE.with(|caught| caught.replace(None));
+        set_attempt(1);
+        if let Some(announced) = &task.announced {
+            shared.announce(&task.info(), announced);
+        }
+        for observer in &shared.observers {
+            observer.on_start(&task.info());
+        }
+        let started = Instant::now();
+        show_job(Some(RunningJob {
+            name: task.name.clone(),
+            label: task.label.clone(),
+            since: started,
+        }));
+        Busy {
+            shared,
+            task,
+            started,
+        }
+    }
+}
+
+impl Drop for Busy<'_> {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        let shared = self.shared;
+        shared
+            .busy_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        shared.execution.record(elapsed);
+        let panicked = thread::panicking() || CAUGHT_PANIC.with(|caught| caught.replace(false));
+        if panicked {
+            shared.panicked.fetch_add(1, Ordering::Relaxed);
+        }
+        for observer in &shared.observers {
+            if panicked {
+                observer.on_panic(&self.task.info());
+            } else {
+                observer.on_complete(&self.task.info(), elapsed);
+            }
+        }
+        let outcome = if panicked {
+            let message = CAUGHT_MESSAGE.with(RefCell::take);
+            let task = &self.task;
+            shared.dead_letter(
+                task.name.as_deref(),
+                task.label.as_deref(),
+                task.trace,
+                Failure::Panicked(message),
+            );
+            Outcome::Panicked
+        } else {
+            Outcome::Completed
+        };
+        let started = SystemTime::now().checked_sub(elapsed);
+        shared.audit(&self.task, started, outcome);
+        show_job(None);
+        shared.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// Tracks how long a worker runs jobs back to back. Past its limit, the
+// worker yields its thread and checks the pause flag before going on,
+// bounding how stale signals get under heavy load.
+struct Budget {
+    limit: Option<Duration>,
+    since: Option<Instant>,
+}
+
+impl Budget {
+    // Starts counting, unless the worker already runs back to back.
+    fn start(&mut self) {
+        if self.limit.is_some() && self.since.is_none() {
+            self.since = Some(Instant::now());
+        }
+    }
+
+    // Yields past the limit. An empty queue ends the run, as the worker
+    // is about to wait for jobs.
+    fn spend(&mut self, shared: &Shared) {
+        let (Some(limit), Some(since)) = (self.limit, self.since) else {
+            return;
+        };
+        if shared.queue.len() == 0 {
+            self.since = None;
+        } else if since.elapsed() >= limit {
+            self.since = None;
+            shared.budget_yields.fetch_add(1, Ordering::Relaxed);
+            thread::yield_now();
+            shared.wait_resumed();
+        }
+    }
+}
+
+// Marks a job as finished when dropped, even if the job panics and
+// unwinds the worker thread.
+struct Finish<'a>(&'a Shared);
+
+impl Drop for Finish<'_> {
+    fn drop(&mut self) {
+        self.0.finish(1);
+    }
+}
+
+// Returns the id the operating system gave to the current thread, the
+// same id shown by `top -H`, perf and other system tools.
+#[cfg(target_os = "linux")]
+fn os_thread_id() -> Option<u64> {
+    extern "C" {
+        fn gettid() -> i32;
+    }
+    // SAFETY: gettid has no preconditions and can't fail.
+    Some(unsafe { gettid() } as u64)
+}
+
+#[cfg(target_os = "macos")]
+fn os_thread_id() -> Option<u64> {
+    extern "C" {
+        fn pthread_threadid_np(thread: usize, thread_id: *mut u64) -> i32;
+    }
+    let mut tid = 0;
+    // SAFETY: a null thread means the current thread, and tid is a
+    // valid pointer for the call.
+    match unsafe { pthread_threadid_np(0, &mut tid) } {
+        0 => Some(tid),
+        _ => None,
+    }
+}
+
+#[cfg(windows)]
+fn os_thread_id() -> Option<u64> {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentThreadId() -> u32;
+    }
+    // SAFETY: GetCurrentThreadId has no preconditions and can't fail.
+    Some(u64::from(unsafe { GetCurrentThreadId() }))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn os_thread_id() -> Option<u64> {
+    None
+}
+
+// Pins the current thread to a CPU core. Returns false if the core
+// doesn't exist or pinning isn't supported on this system.
+#[cfg(target_os = "linux")]
+fn pin_thread(core: usize) -> bool {
+    // the cpu_set_t of glibc and musl, a mask of 1024 cores
+    #[repr(C)]
+    struct CpuSet([u64; 16]);
+    extern "C" {
+        fn sched_setaffinity(pid: i32, size: usize, set: *const CpuSet) -> i32;
+    }
+    if core >= 1024 {
+        return false;
+    }
+    let mut set = CpuSet([0; 16]);
+    set.0[core / 64] |= 1 << (core % 64);
+    // SAFETY: a zero pid means the current thread, and set is a valid
+    // mask of the given size.
+    unsafe { sched_setaffinity(0, mem::size_of::<CpuSet>(), &set) == 0 }
+}
+
+#[cfg(windows)]
+fn pin_thread(core: usize) -> bool {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentThread() -> isize;
+        fn SetThreadAffinityMask(thread: isize, mask: usize) -> usize;
+    }
+    if core >= usize::BITS as usize {
+        return false;
+    }
+    // SAFETY: the pseudo handle of the current thread is always valid.
+    unsafe { SetThreadAffinityMask(GetCurrentThread(), 1 << core) != 0 }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn pin_thread(_core: usize) -> bool {
+    false
+}
+
+// How long a worker has been waiting for a job, to call the idle
+// callback and to retire the extra workers of an elastic pool.
+struct Idle {
+    idle_since: Instant,
+    // when to call the idle callback, if it wasn't called yet
+    callback_after: Option<Duration>,
+    after: Option<Duration>,
+}
+
+impl Idle {
+    fn new(after: Option<Duration>) -> Idle {
+        Idle {
+            idle_since: Instant::now(),
+            callback_after: after,
+            after,
+        }
+    }
+
+    // Starts a new idle period, after the worker ran a job.
+    fn reset(&mut self) {
+        self.idle_since = Instant::now();
+        self.callback_after = self.after;
+    }
+
+    // Returns how long the worker may wait for a job before it has
+    // something else to do, or None to wait until a job comes.
+    fn timeout(&self, keep_alive: Option<Duration>) -> Option<Duration> {
+        let waited = self.idle_since.elapsed();
+        [self.callback_after, keep_alive]
+            .iter()
+            .flatten()
+            .map(|limit| limit.saturating_sub(waited))
+            .min()
+    }
+
+    // Returns true once per idle period, when the callback is due.
+    fn notify(&mut self) -> bool {
+        let due = self
+            .callback_after
+            .is_some_and(|after| self.idle_since.elapsed() >= after);
+        if due {
+            self.callback_after = None;
+        }
+        due
+    }
+
+    // Returns true if the worker waited for its keep alive.
+    fn kept_alive(&self, keep_alive: Option<Duration>) -> bool {
+        keep_alive.is_some_and(|keep_alive| self.idle_since.elapsed() >= keep_alive)
+    }
+}
+
+// Sets the priority of the current thread, from -2 for the lowest to 2
+// for the highest. Returns false if the OS refused it or setting it
+// isn't supported on this system.
+#[cfg(target_os = "linux")]
+fn set_thread_priority(priority: i8) -> bool {
+    extern "C" {
+        fn setpriority(which: i32, who: u32, prio: i32) -> i32;
+    }
+    let nice = [19, 10, 0, -5, -10][(priority + 2) as usize];
+    // SAFETY: on Linux, PRIO_PROCESS with a zero id sets the niceness of
+    // the calling thread only.
+    unsafe { setpriority(0, 0, nice) == 0 }
+}
+
+#[cfg(windows)]
+fn set_thread_priority(priority: i8) -> bool {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentThread() -> isize;
+        fn SetThreadPriority(thread: isize, priority: i32) -> i32;
+    }
+    // SAFETY: the pseudo handle of the current thread is always valid,
+    // and -2 to 2 are the THREAD_PRIORITY_LOWEST to HIGHEST constants.
+    unsafe { SetThreadPriority(GetCurrentThread(), i32::from(priority)) != 0 }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn set_thread_priority(_priority: i8) -> bool {
+    false
+}
+
+// Implements Display for Worker as this simplifys test writing.
+impl Display for Worker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "(id: {})", self.id,)
+    }
+}
+
+// This sections are the beginning of workerpool module unit tests.
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn worker_should_return_new() {
+        let shared = Arc::new(Shared::new(HashMap::new(), vec![None]));
+        let w = Worker::new(1, Arc::clone(&shared));
+        assert_eq!("(id: 1)", w.to_string());
+        shared.queue.close();
+        w.join();
+    }
+
+    #[test]
+    fn workerpool_should_return_new() {
+        let expected = "workers[] = (id: 0)(id: 1)(id: 2)".to_string();
+        let pool = WorkerPool::new(3);
+        assert_eq!(expected.to_string(), pool.to_string());
+    }
+
+    #[test]
+    fn workerpool_should_execute_many_jobs() {
+        let pool = WorkerPool::new(3);
+        let (tx, rx) = mpsc::channel();
+        pool.execute_many((0..1000).map(|_| {
+            let tx = tx.clone();
+            move || tx.send(1).unwrap()
+        }))
+        .unwrap();
+        assert_eq!(1000, rx.iter().take(1000).sum::<usize>());
+    }
+
+    #[test]
+    fn workerpool_should_reject_jobs_after_shutdown() {
+        let pool = WorkerPool::new(2);
+        pool.shutdown();
+        assert_eq!(Err(ExecuteError::Shutdown), pool.execute(|| {}));
+        assert_eq!(Err(ExecuteError::Shutdown), pool.execute_many(vec![|| {}]));
+        assert!(pool.submit(|| 1).is_err());
+    }
+
+    #[test]
+    fn workerpool_shutdown_should_run_queued_jobs() {
+        let pool = WorkerPool::new(1);
+        let handles: Vec<_> = (0..50).map(|i| pool.submit(move || i).unwrap()).collect();
+        pool.shutdown();
+        let sum: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(1225, sum);
+    }
+
+    #[test]
+    fn workerpool_shutdown_should_be_callable_from_a_job() {
+        let pool = Arc::new(WorkerPool::new(2));
+        let inner = Arc::clone(&pool);
+        let handle = pool.submit(move || inner.shutdown()).unwrap();
+        handle.join().unwrap();
+        pool.shutdown();
+    }
+
+    #[test]
+    fn workerpool_map_should_preserve_input_order() {
+        let pool = WorkerPool::new(4);
+        let input: Vec<u64> = (0..200).collect();
+        let output = pool.map(input.clone(), |x| {
+            // make later items finish first sometimes
+            thread::sleep(std::time::Duration::from_micros(200 - x));
+            x + 1
+        });
+        assert_eq!(
+            input.iter().map(|x| x + 1).collect::<Vec<_>>(),
+            output.unwrap()
+        );
+    }
+
+    #[test]
+    fn workerpool_map_should_handle_empty_input() {
+        let pool = WorkerPool::new(2);
+        let output: Vec<u8> = pool.map(Vec::<u8>::new(), |x| x).unwrap();
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn workerpool_adaptive_should_add_workers_under_backlog() {
+        let pool = Builder::new(1)
+            .adaptive(1, 8, Duration::from_millis(20))
+            .build();
+        let jobs = (0..400).map(|_| || thread::sleep(Duration::from_millis(5)));
+        pool.execute_many(jobs).unwrap();
+
+        let started = Instant::now();
+        let mut grew = false;
+        while !grew && started.elapsed() < Duration::from_secs(2) {
+            grew = pool.os_thread_ids().len() > 1;
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(grew);
+    }
+
+    #[test]
+    fn workerpool_adaptive_should_retire_idle_workers() {
+        let pool = Builder::new(4)
+            .adaptive(1, 4, Duration::from_millis(10))
+            .build();
+
+        let started = Instant::now();
+        while pool.os_thread_ids().len() > 1 && started.elapsed() < Duration::from_secs(2) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(1, pool.os_thread_ids().len());
+        assert_eq!(2, pool.submit(|| 2).unwrap().join().unwrap());
+    }
+
+    #[test]
+    fn workerpool_should_skip_cancelled_jobs() {
+        let pool = WorkerPool::new(1);
+        let token = CancellationToken::new();
+        let (tx, rx) = mpsc::channel::<()>();
+        let gate = Arc::new(Mutex::new(rx));
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        // block the only worker while the jobs are queued
+        let blocker = Arc::clone(&gate);
+        pool.execute(move || {
+            let _ = blocker.lock().unwrap().recv();
+        })
+        .unwrap();
+        for _ in 0..10 {
+            let counter = Arc::clone(&counter);
+            pool.execute_cancellable(&token, move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+            })
+            .unwrap();
+        }
+
+        token.cancel();
+        tx.send(()).unwrap();
+        pool.shutdown();
+        assert_eq!(0, counter.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn workerpool_checkpoint_should_stop_cancelled_jobs() {
+        let pool = WorkerPool::new(1);
+        let token = CancellationToken::new();
+        let (tx, rx) = mpsc::channel();
+        let handle = pool
+            .job_with_context(move |ctx| {
+                let mut steps = 0;
+                loop {
+                    checkpoint!(ctx, steps);
+                    steps += 1;
+                    if steps == 10 {
+                        tx.send(()).unwrap();
+                    }
+                }
+            })
+            .token(&token)
+            .submit()
+            .unwrap();
+
+        rx.recv().unwrap();
+        token.cancel();
+        assert!(handle.join().unwrap() >= 10);
+    }
+
+    #[test]
+    fn workerpool_should_not_start_jobs_while_paused() {
+        let pool = WorkerPool::new(2);
+        let counter = Arc::new(AtomicUsize::new(0));
+        pool.pause();
+        for _ in 0..10 {
+            let counter = Arc::clone(&counter);
+            pool.execute_with_context(move |ctx| {
+                checkpoint!(ctx);
+                counter.fetch_add(1, Ordering::Relaxed);
+            })
+            .unwrap();
+        }
+
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(0, counter.load(Ordering::Relaxed));
+        pool.resume();
+        pool.wait();
+        assert_eq!(10, counter.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn workerpool_should_steal_jobs_from_busy_peer() {
+        let busy = WorkerPool::new(1);
+        let idle = WorkerPool::new(2);
+        idle.steal_from(&busy, 1);
+        let tx = block_worker(&busy);
+        let counter = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            let counter = Arc::clone(&counter);
+            busy.execute(move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+            })
+            .unwrap();
+        }
+
+        // the only worker of busy is still blocked
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while counter.load(Ordering::Relaxed) < 10 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(10, counter.load(Ordering::Relaxed));
+        tx.send(()).unwrap();
+        busy.wait();
+    }
+
+    #[test]
+    fn workerpool_should_not_steal_over_cap() {
+        let busy = WorkerPool::new(1);
+        let idle = WorkerPool::new(3);
+        idle.steal_from(&busy, 1);
+        let tx = block_worker(&busy);
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        for _ in 0..6 {
+            let running = Arc::clone(&running);
+            let peak = Arc::clone(&peak);
+            busy.execute(move || {
+                let now = running.fetch_add(1, Ordering::AcqRel) + 1;
+                peak.fetch_max(now, Ordering::AcqRel);
+                thread::sleep(Duration::from_millis(10));
+                running.fetch_sub(1, Ordering::AcqRel);
+            })
+            .unwrap();
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while busy.shared.in_flight.load(Ordering::Acquire) > 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(1, peak.load(Ordering::Acquire));
+        tx.send(()).unwrap();
+        busy.wait();
+    }
+
+    #[test]
+    fn workerpool_should_execute_delayed_jobs_after_delay() {
+        let pool = WorkerPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        let started = Instant::now();
+        let first = tx.clone();
+        pool.execute_after(Duration::from_millis(40), move || first.send(2).unwrap())
+            .unwrap();
+        pool.execute_after(Duration::from_millis(10), move || tx.send(1).unwrap())
+            .unwrap();
+
+        assert_eq!(vec![1, 2], rx.iter().take(2).collect::<Vec<_>>());
+        assert!(started.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn workerpool_should_not_execute_cancelled_delayed_jobs() {
+        let pool = WorkerPool::new(1);
+        let counter = Arc::new(AtomicUsize::new(0));
+        let job_counter = Arc::clone(&counter);
+        let handle = pool
+            .execute_after(Duration::from_millis(20), move || {
+                job_counter.fetch_add(1, Ordering::Relaxed);
+            })
+            .unwrap();
+
+        assert!(handle.cancel());
+        assert!(!handle.cancel());
+        thread::sleep(Duration::from_millis(60));
+        pool.wait();
+        assert_eq!(0, counter.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn workerpool_should_repeat_fixed_rate_jobs_until_cancelled() {
+        let pool = WorkerPool::new(2);
+        let (tx, rx) = mpsc::channel();
+        let handle = pool
+            .execute_at_fixed_rate(Duration::ZERO, Duration::from_millis(5), move || {
+                let _ = tx.send(());
+            })
+            .unwrap();
+
+        for _ in 0..3 {
+            rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert!(handle.cancel());
+        assert!(!handle.cancel());
+        // a run queued before the cancel may still arrive
+        thread::sleep(Duration::from_millis(30));
+        while rx.try_recv().is_ok() {}
+        thread::sleep(Duration::from_millis(30));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn workerpool_fixed_rate_runs_should_not_overlap() {
+        let pool = WorkerPool::new(4);
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let runs = Arc::new(AtomicUsize::new(0));
+        let (job_running, job_peak, job_runs) =
+            (Arc::clone(&running), Arc::clone(&peak), Arc::clone(&runs));
+        let handle = pool
+            .execute_at_fixed_rate(Duration::ZERO, Duration::from_millis(1), move || {
+                let now = job_running.fetch_add(1, Ordering::AcqRel) + 1;
+                job_peak.fetch_max(now, Ordering::AcqRel);
+                thread::sleep(Duration::from_millis(10));
+                job_running.fetch_sub(1, Ordering::AcqRel);
+                job_runs.fetch_add(1, Ordering::AcqRel);
+            })
+            .unwrap();
+
+        thread::sleep(Duration::from_millis(60));
+        handle.cancel();
+        pool.wait();
+        assert!(runs.load(Ordering::Acquire) >= 2);
+        assert_eq!(1, peak.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn workerpool_metrics_into_should_refresh_the_snapshot() {
+        let pool = WorkerPool::new(1);
+        let mut metrics = PoolMetrics::default();
+        let tx = block_worker(&pool);
+        pool.execute(|| {}).unwrap();
+        pool.execute_after(Duration::from_secs(60), || {}).unwrap();
+
+        pool.metrics_into(&mut metrics);
+        assert_eq!(1, metrics.workers);
+        assert_eq!(1, metrics.queued);
+        assert_eq!(1, metrics.scheduled);
+        assert_eq!(2, metrics.in_flight);
+
+        tx.send(()).unwrap();
+        pool.wait();
+        pool.metrics_into(&mut metrics);
+        assert_eq!(0, metrics.in_flight);
+        assert_eq!(2, metrics.completed);
+    }
+
+    #[test]
+    fn workerpool_metrics_should_count_active_workers_and_busy_time() {
+        let pool = WorkerPool::new(2);
+        let tx = block_worker(&pool);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.metrics().active == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(1, pool.metrics().active);
+
+        thread::sleep(Duration::from_millis(20));
+        tx.send(()).unwrap();
+        let _ = pool.map(0..4, |i: i32| if i == 2 { panic!("boom") } else { i });
+        pool.wait();
+
+        let metrics = pool.metrics();
+        assert_eq!(0, metrics.active);
+        assert_eq!(5, metrics.completed);
+        assert_eq!(1, metrics.panicked);
+        assert!(metrics.busy_time >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn workerpool_should_run_io_jobs_on_their_own_workers() {
+        let pool = Builder::new(1).io_workers(1).thread_name("svc").build();
+        let (tx, rx) = mpsc::channel::<()>();
+        let rx = Mutex::new(rx);
+        let (name_tx, name_rx) = mpsc::channel();
+        pool.execute_io(move || {
+            let _ = name_tx.send(thread::current().name().map(str::to_string));
+            let _ = rx.lock().unwrap().recv();
+        })
+        .unwrap();
+        assert_eq!(Some("svc-io-0".to_string()), name_rx.recv().unwrap());
+        assert_eq!(3, pool.submit(|| 1 + 2).unwrap().join().unwrap());
+        pool.execute_cpu(|| {}).unwrap();
+
+        pool.close();
+        assert_eq!(Err(ExecuteError::Shutdown), pool.execute_io(|| {}));
+        tx.send(()).unwrap();
+        pool.join();
+    }
+
+    #[test]
+    fn workerpool_partition_should_queue_jobs_over_the_limit() {
+        let pool = WorkerPool::new(2);
+        let groups = pool.partition(&[("compress", 1)]);
+        let (tx, rx) = mpsc::channel::<()>();
+        let rx = Mutex::new(rx);
+        groups[0]
+            .execute(move || {
+                let _ = rx.lock().unwrap().recv();
+            })
+            .unwrap();
+        let handle = groups[0].submit(|| "compressed").unwrap();
+        assert_eq!(1, groups[0].waiting());
+        tx.send(()).unwrap();
+        assert_eq!("compressed", handle.join().unwrap());
+        assert_eq!(0, groups[0].waiting());
+
+        pool.shutdown();
+        assert_eq!(Err(ExecuteError::Shutdown), groups[0].execute(|| {}));
+    }
+
+    #[test]
+    fn workerpool_should_report_dead_workers() {
+        struct Deaths(mpsc::Sender<usize>);
+
+        impl PoolObserver for Deaths {
+            fn on_worker_death(&self, worker: usize) {
+                let _ = self.0.send(worker);
+            }
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let deaths = Arc::new(Deaths(tx));
+        let pool = Builder::new(1).observer(deaths).build();
+        pool.execute(|| panic!("escaped the worker")).unwrap();
+        let victim = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(pool.dump().workers[0].id, victim);
+        assert_eq!(Health::Degraded { dead_workers: 1 }, pool.health());
+
+        let caught = Builder::new(1).panic_policy(PanicPolicy::Catch).build();
+        caught.execute(|| panic!("caught")).unwrap();
+        caught.wait();
+        assert_eq!(Health::Healthy, caught.health());
+    }
+
+    #[test]
+    fn workerpool_should_notify_observers_of_job_lifecycle() {
+        #[derive(Default)]
+        struct Events(Mutex<Vec<String>>);
+
+        impl PoolObserver for Events {
+            fn on_submit(&self, job: &JobInfo) {
+                self.push("submit", job);
+            }
+            fn on_start(&self, job: &JobInfo) {
+                self.push("start", job);
+            }
+            fn on_complete(&self, job: &JobInfo, _duration: Duration) {
+                self.push("complete", job);
+            }
+            fn on_panic(&self, job: &JobInfo) {
+                self.push("panic", job);
+            }
+        }
+
+        impl Events {
+            fn push(&self, event: &str, job: &JobInfo) {
+                let label = job.name().or(job.label()).unwrap_or("-");
+                self.0.lock().unwrap().push(format!("{} {}", event, label));
+            }
+        }
+
+        let events = Arc::new(Events::default());
+        let pool = Builder::new(1).observer(events.clone()).build();
+        pool.execute_labeled("ok", || {}).unwrap();
+        pool.wait();
+        let _ = pool
+            .job(|| panic!("boom"))
+            .name("bad")
+            .submit()
+            .unwrap()
+            .join();
+        pool.wait();
+
+        assert_eq!(
+            vec![
+                "submit ok",
+                "start ok",
+                "complete ok",
+                "submit bad",
+                "start bad",
+                "panic bad"
+            ],
+            *events.0.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn workerpool_should_not_notify_observers_of_rejected_jobs() {
+        #[derive(Default)]
+        struct Submits(AtomicUsize);
+
+        impl PoolObserver for Submits {
+            fn on_submit(&self, _job: &JobInfo) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let submits = Arc::new(Submits::default());
+        let pool = Builder::new(1)
+            .label_limit("bulk", 1)
+            .observer(submits.clone())
+            .build();
+        let release = block_worker(&pool);
+        pool.execute_labeled("bulk", || {}).unwrap();
+        assert_eq!(
+            Err(ExecuteError::LimitReached("bulk".to_string())),
+            pool.execute_labeled("bulk", || {})
+        );
+        assert_eq!(
+            Err(ExecuteError::UnknownWorker(usize::MAX)),
+            pool.spawn_pinned(usize::MAX, || {})
+        );
+        release.send(()).unwrap();
+        pool.wait();
+        assert_eq!(2, submits.0.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn workerpool_quiesce_should_finish_jobs_and_keep_workers() {
+        let pool = WorkerPool::new(2);
+        let ids = pool.os_thread_ids();
+        let counter = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            let counter = Arc::clone(&counter);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(1));
+                counter.fetch_add(1, Ordering::Relaxed);
+            })
+            .unwrap();
+        }
+
+        pool.quiesce();
+        assert_eq!(10, counter.load(Ordering::Relaxed));
+        assert_eq!(Err(ExecuteError::Quiesced), pool.execute(|| {}));
+        assert_eq!(ids, pool.os_thread_ids());
+
+        pool.reopen();
+        assert_eq!(1, pool.submit(|| 1).unwrap().join().unwrap());
+    }
+
+    #[test]
+    fn workerpool_quiesce_should_run_jobs_waiting_for_their_key() {
+        let pool = Arc::new(WorkerPool::new(2));
+        let counter = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = mpsc::channel::<()>();
+        pool.execute_keyed(7, move || {
+            let _ = rx.recv();
+        })
+        .unwrap();
+        for _ in 0..3 {
+            let counter = Arc::clone(&counter);
+            pool.execute_keyed(7, move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+            })
+            .unwrap();
+        }
+
+        let quiescing = Arc::clone(&pool);
+        let quiescer = thread::spawn(move || quiescing.quiesce());
+        while pool.execute(|| {}).is_ok() {
+            thread::yield_now();
+        }
+        tx.send(()).unwrap();
+        quiescer.join().unwrap();
+        assert_eq!(3, counter.load(Ordering::Relaxed));
+        assert_eq!(Err(ExecuteError::Quiesced), pool.execute_keyed(7, || {}));
+    }
+
+    // Blocks the only worker of a pool until the returned sender is used.
+    // Returns once the worker picked the blocking job.
+    fn block_worker(pool: &WorkerPool) -> mpsc::Sender<()> {
+        let (tx, rx) = mpsc::channel::<()>();
+        let (started_tx, started_rx) = mpsc::channel::<()>();
+        pool.execute(move || {
+            let _ = started_tx.send(());
+            let _ = rx.recv();
+        })
+        .unwrap();
+        started_rx.recv().unwrap();
+        tx
+    }
+
+    #[test]
+    fn workerpool_should_cancel_the_queued_jobs_of_a_tag() {
+        let pool = WorkerPool::new(1);
+        let release = block_worker(&pool);
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        for (tag, id) in [("user:42", 0), ("user:7", 1), ("user:42", 2)] {
+            let ran = Arc::clone(&ran);
+            pool.execute_tagged(tag, move || ran.lock().unwrap().push(id))
+                .unwrap();
+        }
+        let handle = pool.job(|| 3).tag("user:42").submit().unwrap();
+        assert_eq!(0, pool.cancel_tag("user:1"));
+        assert_eq!(3, pool.cancel_tag("user:42"));
+        assert_eq!(1, pool.metrics().queued);
+
+        drop(release);
+        assert!(matches!(handle.join(), Err(JobError::Cancelled)));
+        pool.wait();
+        assert_eq!(vec![1], *ran.lock().unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "metrics-export")]
+    fn workerpool_should_export_metrics_for_prometheus() {
+        let pool = WorkerPool::new(2);
+        pool.execute(|| thread::sleep(Duration::from_millis(30)))
+            .unwrap();
+        pool.execute(|| {}).unwrap();
+        pool.wait();
+
+        let text = pool.metrics().to_prometheus("");
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&"# TYPE busy_workers gauge"));
+        assert!(lines.contains(&"jobs_completed_total 2"));
+        assert!(lines.contains(&"job_duration_seconds_bucket{le=\"0.005\"} 1"));
+        assert!(lines.contains(&"job_duration_seconds_bucket{le=\"0.025\"} 1"));
+        assert!(lines.contains(&"job_duration_seconds_bucket{le=\"0.05\"} 2"));
+        assert!(lines.contains(&"job_duration_seconds_count 2"));
+        let sum = lines
+            .iter()
+            .find_map(|line| line.strip_prefix("job_duration_seconds_sum "))
+            .unwrap();
+        assert!(sum.parse::<f64>().unwrap() >= 0.03);
+    }
+
+    #[test]
+    #[cfg(feature = "metrics-export")]
+    fn workerpool_should_export_an_empty_histogram_for_prometheus() {
+        let text = WorkerPool::new(1).metrics().to_prometheus("idle");
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&"# TYPE idle_job_duration_seconds histogram"));
+        assert!(lines.contains(&"idle_job_duration_seconds_bucket{le=\"0.005\"} 0"));
+        assert!(lines.contains(&"idle_job_duration_seconds_bucket{le=\"+Inf\"} 0"));
+        assert!(lines.contains(&"idle_job_duration_seconds_sum 0"));
+        assert!(lines.contains(&"idle_job_duration_seconds_count 0"));
+        assert!(lines.contains(&"idle_jobs_completed_total 0"));
+    }
+
+    #[test]
+    fn workerpool_should_accept_jobs_that_are_not_sync() {
+        let pool = WorkerPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        let hits = Cell::new(0);
+        pool.execute(move || {
+            hits.set(hits.get() + 1);
+            tx.send(hits.get()).unwrap();
+        })
+        .unwrap();
+        let counter = RefCell::new(vec![1, 2]);
+        let handle = pool
+            .submit(move || counter.borrow().iter().sum::<i32>())
+            .unwrap();
+        assert_eq!(1, rx.recv().unwrap());
+        assert_eq!(3, handle.join().unwrap());
+    }
+
+    #[test]
+    fn workerpool_should_run_pinned_jobs_on_their_worker() {
+        let pool = WorkerPool::new(2);
+        let ids = pool.worker_ids();
+        assert_eq!(vec![0, 1], ids);
+        let (tx, rx) = mpsc::channel();
+        WORKER_ID.with(|id| assert_eq!(None, id.get()));
+        for _ in 0..4 {
+            let tx = tx.clone();
+            pool.spawn_pinned(1, move || tx.send(WORKER_ID.with(Cell::get)).unwrap())
+                .unwrap();
+        }
+        pool.wait();
+        assert_eq!(vec![Some(1); 4], rx.try_iter().collect::<Vec<_>>());
+        assert_eq!(
+            Err(ExecuteError::UnknownWorker(7)),
+            pool.spawn_pinned(7, || {})
+        );
+        pool.wait();
+        pool.shutdown();
+        assert_eq!(Err(ExecuteError::Shutdown), pool.spawn_pinned(0, || {}));
+    }
+
+    #[test]
+    fn workerpool_should_call_on_idle_once_per_idle_period() {
+        let (tx, rx) = mpsc::channel();
+        let pool = Builder::new(1)
+            .elastic(1, 2, Duration::from_millis(40))
+            .on_idle(Duration::from_millis(5), move |worker| {
+                tx.send(worker).unwrap();
+            })
+            .build();
+        assert_eq!(0, rx.recv().unwrap());
+        assert!(rx.recv_timeout(Duration::from_millis(30)).is_err());
+
+        pool.execute(|| {}).unwrap();
+        assert_eq!(0, rx.recv().unwrap());
+        let metrics = pool.metrics();
+        assert_eq!(1, metrics.workers);
+        assert!(metrics.parked_time >= Duration::from_millis(35));
+    }
+
+    #[test]
+    fn workerpool_should_take_turns_between_submitters() {
+        let pool = Builder::new(1).fair().build();
+        let gate = block_worker(&pool);
+        let (chatty, quiet) = (pool.submitter_id(), pool.submitter_id());
+        assert_ne!(chatty, quiet);
+        let (tx, rx) = mpsc::channel();
+        for (submitter, id) in [(chatty, 0), (chatty, 1), (chatty, 2), (quiet, 3)] {
+            let tx = tx.clone();
+            pool.execute_as(submitter, move || tx.send(id).unwrap())
+                .unwrap();
+        }
+        gate.send(()).unwrap();
+        pool.wait();
+        assert_eq!(vec![0, 3, 1, 2], rx.try_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn workerpool_should_run_jobs_earliest_deadline_first() {
+        let pool = Builder::new(1).earliest_deadline_first().build();
+        let gate = block_worker(&pool);
+        let (tx, rx) = mpsc::channel();
+        let now = Instant::now();
+        for (id, after) in [(0, None), (1, Some(900)), (2, Some(300)), (3, Some(600))] {
+            let tx = tx.clone();
+            let send = move || tx.send(id).unwrap();
+            match after {
+                Some(after) => pool
+                    .execute_with_deadline(now + Duration::from_secs(after), send)
+                    .unwrap(),
+                None => pool.execute(send).unwrap(),
+            }
+        }
+        pool.execute_with_deadline(now, || {}).unwrap();
+        gate.send(()).unwrap();
+        pool.wait();
+
+        assert_eq!(vec![2, 3, 1, 0], rx.try_iter().collect::<Vec<_>>());
+        assert_eq!(1, pool.metrics().missed_deadlines);
+    }
+
+    #[test]
+    fn workerpool_should_submit_all_in_order() {
+        let pool = WorkerPool::new(4);
+        let handles = pool
+            .submit_all((0..8u64).map(|i| {
+                move || {
+                    thread::sleep(Duration::from_millis(8 - i));
+                    i
+                }
+            }))
+            .unwrap();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!((0..8).collect::<Vec<_>>(), results);
+
+        pool.shutdown();
+        let rejected = pool.submit_all(vec![|| 1]);
+        assert!(matches!(rejected, Err(ExecuteError::Shutdown)));
+    }
+
+    #[test]
+    fn workerpool_submit_all_should_split_batches_only_on_lossy_policies() {
+        let bounded = |policy| {
+            Builder::new(1)
+                .queue_bound(2)
+                .overflow_policy(policy)
+                .build()
+        };
+
+        let pool = bounded(OverflowPolicy::RejectWithError);
+        let release = block_worker(&pool);
+        let rejected = pool.submit_all((0..3).map(|i| move || i));
+        assert!(matches!(rejected, Err(ExecuteError::QueueFull)));
+        assert_eq!(0, pool.metrics().queued);
+        release.send(()).unwrap();
+
+        let pool = bounded(OverflowPolicy::DropNewest);
+        let release = block_worker(&pool);
+        let handles = pool.submit_all((0..3).map(|i| move || i)).unwrap();
+        release.send(()).unwrap();
+        let results: Vec<_> = handles.into_iter().map(JobHandle::join).collect();
+        assert!(matches!(
+            results[..],
+            [Ok(0), Ok(1), Err(JobError::Cancelled)]
+        ));
+
+        let pool = bounded(OverflowPolicy::CallerRuns);
+        let release = block_worker(&pool);
+        let caller = thread::current().id();
+        let handles = pool
+            .submit_all((0..3).map(|_| || thread::current().id()))
+            .unwrap();
+        release.send(()).unwrap();
+        let ran_on: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_ne!(caller, ran_on[0]);
+        assert_eq!(caller, ran_on[2]);
+    }
+
+    #[test]
+    fn workerpool_should_attribute_panics_to_worker_and_job() {
+        let pool = Builder::new(1)
+            .label_limit("bulk", 10)
+            .attribute_panics()
+            .build();
+        let attribution = pool
+            .job(panic_attribution)
+            .name("ingest")
+            .label("bulk")
+            .submit()
+            .unwrap();
+        assert_eq!(
+            Some("rpools: worker 0 panicked running job ingest (label bulk)".to_string()),
+            attribution.join().unwrap()
+        );
+
+        let payload = pool
+            .submit(|| -> u8 { panic!("attributed boom") })
+            .unwrap()
+            .join();
+        assert!(matches!(payload, Err(JobError::Panicked(_))));
+        assert_eq!(None, panic_attribution());
+        assert_eq!(
+            None,
+            WorkerPool::new(1)
+                .submit(panic_attribution)
+                .unwrap()
+                .join()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn workerpool_should_serialize_jobs_of_the_same_key() {
+        let pool = WorkerPool::new(4);
+        let running: Arc<Vec<AtomicBool>> =
+            Arc::new((0..3).map(|_| AtomicBool::new(false)).collect());
+        let logs: Arc<Vec<Mutex<Vec<usize>>>> =
+            Arc::new((0..3).map(|_| Mutex::new(Vec::new())).collect());
+        for step in 0..20 {
+            for key in 0..3 {
+                let (running, logs) = (Arc::clone(&running), Arc::clone(&logs));
+                pool.execute_keyed(key as u64, move || {
+                    assert!(
+                        !running[key].swap(true, Ordering::AcqRel),
+                        "overlapping key"
+                    );
+                    thread::sleep(Duration::from_micros(200));
+                    logs[key].lock().unwrap().push(step);
+                    running[key].store(false, Ordering::Release);
+                })
+                .unwrap();
+            }
+        }
+        pool.wait();
+
+        for log in logs.iter() {
+            assert_eq!((0..20).collect::<Vec<_>>(), *log.lock().unwrap());
+        }
+        assert_eq!(0, pool.metrics().panicked);
+        assert!(pool.shared.keyed.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn workerpool_should_shut_down_in_phases() {
+        let pool = WorkerPool::new(1);
+        let release = block_worker(&pool);
+        for _ in 0..4 {
+            pool.execute(|| {}).unwrap();
+        }
+        let panicking = pool.submit(|| -> u8 { panic!("drained boom") }).unwrap();
+
+        pool.close();
+        assert_eq!(Err(ExecuteError::Shutdown), pool.execute(|| {}));
+        assert_eq!(5, pool.metrics().queued);
+
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            release.send(()).unwrap();
+        });
+        let report = pool.drain();
+        releaser.join().unwrap();
+        assert_eq!(6, report.completed);
+        assert_eq!(1, report.panicked);
+        assert_eq!(0, pool.metrics().in_flight);
+        assert!(panicking.join().is_err());
+
+        pool.join();
+        assert!(pool.os_thread_ids().is_empty());
+    }
+
+    #[test]
+    fn workerpool_should_abandon_queued_jobs_after_graceful_timeout() {
+        let pool = WorkerPool::new(1);
+        let release = block_worker(&pool);
+        let handles: Vec<_> = (0..3).map(|i| pool.submit(move || i).unwrap()).collect();
+
+        assert_eq!(3, pool.shutdown_graceful(Duration::from_millis(10)));
+        assert_eq!(Err(ExecuteError::Shutdown), pool.execute(|| {}));
+        for handle in handles {
+            assert!(matches!(handle.join(), Err(JobError::Cancelled)));
+        }
+        assert_eq!(1, pool.metrics().in_flight);
+
+        release.send(()).unwrap();
+        pool.wait();
+        assert_eq!(
+            0,
+            WorkerPool::new(1).shutdown_graceful(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn workerpool_should_abandon_io_jobs_after_graceful_timeout() {
+        let pool = Builder::new(1).io_workers(1).build();
+        let (tx, rx) = mpsc::channel::<()>();
+        let (started_tx, started_rx) = mpsc::channel();
+        pool.execute_io(move || {
+            started_tx.send(()).unwrap();
+            let _ = rx.recv();
+        })
+        .unwrap();
+        started_rx.recv().unwrap();
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..4 {
+            let ran = Arc::clone(&ran);
+            pool.execute_io(move || {
+                ran.fetch_add(1, Ordering::Relaxed);
+            })
+            .unwrap();
+        }
+
+        assert_eq!(4, pool.shutdown_graceful(Duration::from_millis(10)));
+        tx.send(()).unwrap();
+        pool.join();
+        assert_eq!(0, ran.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn workerpool_should_abandon_keyed_and_group_jobs_after_graceful_timeout() {
+        let pool = WorkerPool::new(1);
+        let groups = pool.partition(&[("compress", 1)]);
+        let ran = Arc::new(AtomicUsize::new(0));
+        let release = block_worker(&pool);
+        for _ in 0..3 {
+            let ran = Arc::clone(&ran);
+            pool.execute_keyed(7, move || {
+                ran.fetch_add(1, Ordering::Relaxed);
+            })
+            .unwrap();
+        }
+        let grouped: Vec<_> = (0..2)
+            .map(|i| groups[0].submit(move || i).unwrap())
+            .collect();
+
+        assert_eq!(5, pool.shutdown_graceful(Duration::from_millis(10)));
+        assert_eq!(0, groups[0].waiting());
+        for handle in grouped {
+            assert!(handle.join().is_err());
+        }
+        release.send(()).unwrap();
+        pool.wait();
+        assert_eq!(0, ran.load(Ordering::Relaxed));
+        assert_eq!(0, pool.metrics().in_flight);
+    }
+
+    #[test]
+    fn workerpool_should_spill_critical_jobs_when_saturated() {
+        let pool = Builder::new(1)
+            .fallback(2, Duration::ZERO, Arc::new(crate::fallback::RunInline))
+            .build();
+        let release = block_worker(&pool);
+        let caller = thread::current().id();
+
+        let queued = pool
+            .job(move || thread::current().id())
+            .critical()
+            .submit()
+            .unwrap();
+        pool.execute(|| {}).unwrap();
+        let spilled = pool
+            .job(move || thread::current().id())
+            .critical()
+            .submit()
+            .unwrap();
+        assert_eq!(caller, spilled.join().unwrap());
+        assert_eq!(1, pool.metrics().spilled);
+
+        pool.execute(|| {}).unwrap();
+        assert_eq!(3, pool.metrics().queued);
+        release.send(()).unwrap();
+        assert_ne!(caller, queued.join().unwrap());
+    }
+
+    #[test]
+    fn workerpool_should_return_unstarted_jobs_on_shutdown_now() {
+        let pool = WorkerPool::new(1);
+        let release = block_worker(&pool);
+        let handles: Vec<_> = (0..2).map(|i| pool.submit(move || i).unwrap()).collect();
+        let keyed = Arc::new(Mutex::new(Vec::new()));
+        for step in 0..3 {
+            let keyed = Arc::clone(&keyed);
+            pool.execute_keyed(1, move || keyed.lock().unwrap().push(step))
+                .unwrap();
+        }
+
+        let pending = pool.shutdown_now();
+        assert_eq!(5, pending.len());
+        assert_eq!(0, pool.metrics().queued);
+        assert_eq!(Err(ExecuteError::Shutdown), pool.execute(|| {}));
+
+        pending.into_iter().for_each(|job| job());
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(vec![0, 1], results);
+        assert_eq!(vec![0, 1, 2], *keyed.lock().unwrap());
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn workerpool_should_yield_workers_past_their_budget() {
+        let yields = |pool: WorkerPool| {
+            let release = block_worker(&pool);
+            for _ in 0..20 {
+                pool.execute(|| thread::sleep(Duration::from_millis(1)))
+                    .unwrap();
+            }
+            release.send(()).unwrap();
+            pool.wait();
+            pool.metrics().budget_yields
+        };
+        let budget = Builder::new(1)
+            .worker_budget(Duration::from_millis(5))
+            .build();
+        assert!(yields(budget) >= 2);
+        assert_eq!(0, yields(WorkerPool::new(1)));
+    }
+
+    #[test]
+    #[cfg(feature = "context-propagation")]
+    fn workerpool_should_run_jobs_in_the_context_of_the_caller() {
+        thread_local! {
+            static REQUEST: Cell<u32> = const { Cell::new(0) };
+        }
+        // restores the previous request when the job ends
+        struct Entered(u32);
+        impl Drop for Entered {
+            fn drop(&mut self) {
+                REQUEST.with(|request| request.set(self.0));
+            }
+        }
+
+        let pool = Builder::new(1)
+            .io_workers(1)
+            .propagate(|| {
+                let request = REQUEST.with(Cell::get);
+                move || Entered(REQUEST.with(|current| current.replace(request)))
+            })
+            .build();
+        REQUEST.with(|request| request.set(7));
+        let seen = pool.submit(|| REQUEST.with(Cell::get)).unwrap();
+        let batch = pool.submit_all(vec![|| REQUEST.with(Cell::get)]).unwrap();
+        let (tx, rx) = mpsc::channel();
+        pool.execute_io(move || tx.send(REQUEST.with(Cell::get)).unwrap())
+            .unwrap();
+        assert_eq!(7, seen.join().unwrap());
+        assert_eq!(7, batch.into_iter().next().unwrap().join().unwrap());
+        assert_eq!(7, rx.recv().unwrap());
+        REQUEST.with(|request| request.set(0));
+        let after = pool.submit(|| REQUEST.with(Cell::get)).unwrap();
+        assert_eq!(0, after.join().unwrap());
+    }
+
+    #[test]
+    #[cfg(all(feature = "thread-priority", target_os = "linux"))]
+    fn workerpool_should_lower_the_priority_of_its_workers() {
+        extern "C" {
+            fn getpriority(which: i32, who: u32) -> i32;
+        }
+        // SAFETY: a zero id reads the niceness of the calling thread.
+        let nice = || unsafe { getpriority(0, 0) };
+        let pool = Builder::new(1)
+            .thread_priority(ThreadPriority::Lowest)
+            .build();
+        assert_eq!(19, pool.submit(nice).unwrap().join().unwrap());
+        let normal = WorkerPool::new(1);
+        assert_eq!(nice(), normal.submit(nice).unwrap().join().unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "core-affinity")]
+    fn workerpool_should_pin_workers_to_cores() {
+        let pool = Builder::new(3)
+            .pin_workers(CoreSelection::Cores(vec![0]))
+            .build();
+        let cores: Vec<_> = pool.dump().workers.iter().map(|w| w.core).collect();
+        if cfg!(any(target_os = "linux", windows)) {
+            assert_eq!(vec![Some(0); 3], cores);
+        } else {
+            assert_eq!(vec![None; 3], cores);
+        }
+        let unpinned = Builder::new(1)
+            .pin_workers(CoreSelection::Cores(vec![4096]))
+            .build();
+        assert_eq!(None, unpinned.dump().workers[0].core);
+        assert_eq!(None, WorkerPool::new(1).dump().workers[0].core);
+    }
+
+    #[test]
+    fn workerpool_should_reserve_extended_workers_to_high_priority_jobs() {
+        let pool = WorkerPool::new(1);
+        let release = block_worker(&pool);
+        pool.extend_workers_with(1, |id| {
+            assert_eq!(1, id);
+            WorkerConfig::new()
+                .stack_size(4 << 20)
+                .min_priority(Priority::High)
+        });
+        assert_eq!(2, pool.dump().workers.len());
+
+        let low = pool.job(|| 1).priority(Priority::Low).submit().unwrap();
+        let high = pool.job(|| 2).priority(Priority::High).submit().unwrap();
+        assert_eq!(2, high.join().unwrap());
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(1, pool.dump().queued);
+
+        release.send(()).unwrap();
+        assert_eq!(1, low.join().unwrap());
+    }
+
+    #[test]
+    fn workerpool_should_build_one_state_per_worker() {
+        let inits = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&inits);
+        let pool = Builder::new(2)
+            .worker_state(move |id| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                (id, 0usize)
+            })
+            .build();
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..20 {
+            let tx = tx.clone();
+            pool.execute_with_state(move |state: &mut (usize, usize)| {
+                state.1 += 1;
+                tx.send(*state).unwrap();
+            })
+            .unwrap();
+        }
+        pool.wait();
+
+        let mut runs = HashMap::new();
+        for (worker, count) in rx.try_iter() {
+            let last = runs.entry(worker).or_insert(0);
+            assert_eq!(*last + 1, count);
+            *last = count;
+        }
+        assert_eq!(20, runs.values().sum::<usize>());
+        assert_eq!(runs.len(), inits.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    #[should_panic(expected = "no worker state of type u8")]
+    fn workerpool_should_reject_jobs_with_a_foreign_state_type() {
+        let pool = Builder::new(1).worker_state(|_| 0u64).build();
+        let _ = pool.execute_with_state(|_: &mut u8| {});
+    }
+
+    #[test]
+    fn workerpool_should_pace_throttled_jobs() {
+        let pool = WorkerPool::new(4);
+        let limiter = RateLimiter::new(100.0, 2);
+        let (tx, rx) = mpsc::channel();
+        let start = Instant::now();
+        for _ in 0..6 {
+            let tx = tx.clone();
+            pool.execute_throttled(&limiter, move || {
+                tx.send(start.elapsed()).unwrap();
+            })
+            .unwrap();
+        }
+        let mut started: Vec<_> = rx.iter().take(6).collect();
+        started.sort();
+        // two jobs from the burst, then one every 10 milliseconds
+        assert!(started[2] >= Duration::from_millis(5));
+        assert!(started[5] >= Duration::from_millis(35));
+    }
+
+    #[test]
+    fn workerpool_should_broadcast_to_every_worker_before_queued_jobs() {
+        let pool = WorkerPool::new(3);
+        let release = block_worker(&pool);
+        let (tx, rx) = mpsc::channel();
+        pool.execute(|| {}).unwrap();
+        let wg = pool.broadcast(move |id| tx.send(id).unwrap());
+        let mut ids: Vec<_> = rx.iter().take(2).collect();
+        assert!(!wg.wait_timeout(Duration::from_millis(20)));
+
+        release.send(()).unwrap();
+        wg.wait();
+        ids.extend(rx.try_iter());
+        ids.sort();
+        assert_eq!(vec![0, 1, 2], ids);
+        pool.broadcast(|_| panic!("flush failed")).wait();
+        assert_eq!(3, pool.metrics().workers);
+    }
+
+    #[test]
+    fn workerpool_should_dump_worker_states() {
+        let pool = WorkerPool::new(2);
+        let states = |pool: &WorkerPool| {
+            let mut states: Vec<_> = pool.dump().workers.into_iter().map(|w| w.state).collect();
+            states.sort_by_key(|state| format!("{:?}", state));
+            states
+        };
+        assert_eq!(vec![WorkerState::Idle, WorkerState::Idle], states(&pool));
+
+        let release = block_worker(&pool);
+        pool.execute(|| panic!("dump boom")).unwrap();
+        while !states(&pool).contains(&WorkerState::Dead) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        pool.execute(|| {}).unwrap();
+
+        let dump = pool.dump();
+        assert_eq!(1, dump.queued);
+        let states = states(&pool);
+        assert_eq!(WorkerState::Dead, states[0]);
+        assert!(matches!(
+            states[1],
+            WorkerState::Running { job_name: None, .. }
+        ));
+        assert!(dump.to_string().starts_with("queued: 1\nworker 0: "));
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn workerpool_should_run_higher_priorities_first() {
+        let pool = WorkerPool::new(1);
+        let gate = block_worker(&pool);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for priority in [Priority::Low, Priority::Normal, Priority::High] {
+            let order = Arc::clone(&order);
+            pool.job(move || order.lock().unwrap().push(priority))
+                .priority(priority)
+                .spawn()
+                .unwrap();
+        }
+
+        gate.send(()).unwrap();
+        pool.shutdown();
+        let expected = vec![Priority::High, Priority::Normal, Priority::Low];
+        assert_eq!(expected, *order.lock().unwrap());
+    }
+
+    #[test]
+    fn workerpool_should_reject_jobs_over_lane_limit() {
+        let pool = Builder::new(1).lane_limit(Priority::Low, 1).build();
+        let gate = block_worker(&pool);
+        let low = || pool.job(|| {}).priority(Priority::Low).spawn();
+
+        assert!(low().is_ok());
+        assert_eq!(Err(ExecuteError::LaneFull(Priority::Low)), low());
+        assert!(pool.job(|| {}).priority(Priority::High).spawn().is_ok());
+        gate.send(()).unwrap();
+    }
+
+    #[test]
+    fn workerpool_should_skip_jobs_past_deadline() {
+        let pool = WorkerPool::new(1);
+        let gate = block_worker(&pool);
+        let handle = pool
+            .job(|| 1)
+            .deadline(Instant::now() + Duration::from_millis(10))
+            .submit()
+            .unwrap();
+
+        thread::sleep(Duration::from_millis(20));
+        gate.send(()).unwrap();
+        assert!(matches!(handle.join(), Err(JobError::Cancelled)));
+    }
+
+    #[test]
+    fn job_handle_should_report_panics_and_keep_worker_alive() {
+        let pool = WorkerPool::new(1);
+        let handle = pool.submit(|| -> u8 { panic!("boom") }).unwrap();
+        match handle.join() {
+            Err(err @ JobError::Panicked(_)) => {
+                assert_eq!("the job panicked: boom", err.to_string())
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(3, pool.submit(|| 3).unwrap().join().unwrap());
+    }
+
+    #[test]
+    fn job_handle_join_unwind_should_resume_the_original_payload() {
+        let pool = WorkerPool::new(1);
+        let handle = pool.submit(|| -> u8 { panic::panic_any(42_u32) }).unwrap();
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| handle.join_unwind())).unwrap_err();
+        assert_eq!(Some(&42), payload.downcast_ref::<u32>());
+        assert_eq!(3, pool.submit(|| 3).unwrap().join_unwind());
+    }
+
+    #[test]
+    fn job_handle_should_report_cancelled_jobs() {
+        let pool = WorkerPool::new(1);
+        let token = CancellationToken::new();
+        let gate = block_worker(&pool);
+        let handle = pool.job(|| 1).token(&token).submit().unwrap();
+        token.cancel();
+        gate.send(()).unwrap();
+        assert!(matches!(handle.join(), Err(JobError::Cancelled)));
+    }
+
+    #[test]
+    fn workerpool_map_reduce_should_reduce_in_input_order() {
+        let pool = WorkerPool::new(3);
+        let digits = pool
+            .map_reduce(0..10, |d| d.to_string(), String::new(), |a, b| a + &b)
+            .unwrap();
+        assert_eq!("0123456789", digits);
+        assert_eq!(
+            Ok(7),
+            pool.map_reduce(Vec::<u8>::new(), |_| 1, 7, |a, b| a + b)
+                .map_err(drop)
+        );
+
+        let failed = pool.map_reduce(
+            0..4,
+            |d| if d == 2 { panic!("bad item") } else { d },
+            0,
+            |a, b| a + b,
+        );
+        assert!(matches!(failed, Err(JobError::Panicked(_))));
+    }
+
+    #[test]
+    fn workerpool_should_run_sub_jobs_joined_from_every_worker() {
+        let pool = Arc::new(WorkerPool::new(2));
+        let handles: Vec<_> = (0..2)
+            .map(|i| {
+                let inner = Arc::clone(&pool);
+                pool.submit(move || {
+                    let children: Vec<_> = (0..3)
+                        .map(|j| inner.submit(move || i * 10 + j).unwrap())
+                        .collect();
+                    inner.execute(|| panic!("helped boom")).unwrap();
+                    children
+                        .into_iter()
+                        .map(|c| c.join().unwrap())
+                        .sum::<usize>()
+                })
+                .unwrap()
+            })
+            .collect();
+        let sums: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(vec![3, 33], sums);
+    }
+
+    #[test]
+    fn pool_handle_should_shut_down_on_the_last_drop() {
+        let pool = PoolHandle::from(WorkerPool::new(1));
+        let clone = pool.clone();
+        drop(pool);
+        assert_eq!(1, clone.handles());
+
+        let done = Arc::new(AtomicBool::new(false));
+        let job_done = Arc::clone(&done);
+        clone
+            .execute(move || {
+                thread::sleep(Duration::from_millis(20));
+                job_done.store(true, Ordering::SeqCst);
+            })
+            .unwrap();
+        drop(clone);
+        assert!(done.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn workerpool_should_have_at_least_one_worker() {
+        assert_eq!("workers[] = (id: 0)", WorkerPool::new(0).to_string());
+        assert_eq!(parallelism(), WorkerPool::default().dump().workers.len());
+        assert_eq!(parallelism(), WorkerPool::auto(0).dump().workers.len());
+    }
+
+    #[test]
+    fn workerpool_should_grow_up_to_max_and_retire_idle_workers() {
+        let pool = Builder::new(0)
+            .elastic(0, 2, Duration::from_millis(20))
+            .build();
+        assert_eq!(0, pool.metrics().workers);
+        let handles: Vec<_> = (0..10)
+            .map(|i| {
+                pool.submit(move || {
+                    thread::sleep(Duration::from_millis(5));
+                    i
+                })
+                .unwrap()
+            })
+            .collect();
+        assert_eq!(2, pool.metrics().workers);
+        let sum: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(45, sum);
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(0, pool.metrics().workers);
+        assert_eq!(7, pool.submit(|| 7).unwrap().join().unwrap());
+    }
+
+    #[test]
+    fn workerpool_should_time_out_slow_jobs_only() {
+        let pool = WorkerPool::new(1);
+        let slow = pool
+            .execute_with_timeout(Duration::from_millis(10), |_| {
+                thread::sleep(Duration::from_millis(50))
+            })
+            .unwrap();
+        let queued = pool
+            .execute_with_timeout(Duration::from_millis(10), |_| unreachable!())
+            .unwrap();
+        let fast = pool
+            .execute_with_timeout(Duration::from_secs(5), |ctx| ctx.is_cancelled())
+            .unwrap();
+        assert!(matches!(slow.join(), Err(JobError::Timeout)));
+        assert!(matches!(queued.join(), Err(JobError::Timeout)));
+        assert!(!fast.join().unwrap());
+        assert_eq!("the job timed out", JobError::Timeout.to_string());
+    }
+
+    #[test]
+    fn workerpool_should_follow_its_config() {
+        let config = PoolConfig {
+            workers: 1,
+            queue_bound: Some(1),
+            panic_policy: PanicPolicy::Catch,
+            ..PoolConfig::default()
+        };
+        let pool = WorkerPool::from_config(&config);
+
+        pool.execute(|| panic!("caught boom")).unwrap();
+        pool.wait();
+        assert_eq!(1, pool.metrics().panicked);
+        assert_eq!(1, pool.metrics().workers);
+
+        let release = block_worker(&pool);
+        pool.execute(|| {}).unwrap();
+        assert_eq!(Err(ExecuteError::QueueFull), pool.execute(|| {}));
+        release.send(()).unwrap();
+        pool.wait();
+        assert_eq!(1, pool.metrics().rejected);
+    }
+
+    #[test]
+    fn workerpool_should_drop_jobs_on_overflow() {
+        for (policy, kept) in [
+            (OverflowPolicy::DropOldest, "new"),
+            (OverflowPolicy::DropNewest, "old"),
+        ] {
+            let pool = Builder::new(1)
+                .queue_bound(1)
+                .overflow_policy(policy)
+                .build();
+            let ran = Arc::new(Mutex::new(Vec::new()));
+            let release = block_worker(&pool);
+            for name in ["old", "new"] {
+                let ran = Arc::clone(&ran);
+                pool.execute(move || ran.lock().unwrap().push(name))
+                    .unwrap();
+            }
+            release.send(()).unwrap();
+            pool.wait();
+            assert_eq!(vec![kept], *ran.lock().unwrap());
+            assert_eq!(1, pool.metrics().dropped);
+            assert_eq!(0, pool.metrics().rejected);
+        }
+    }
+
+    #[test]
+    fn workerpool_map_should_report_items_dropped_on_overflow() {
+        for policy in [OverflowPolicy::DropOldest, OverflowPolicy::DropNewest] {
+            let pool = Arc::new(
+                Builder::new(1)
+                    .queue_bound(1)
+                    .overflow_policy(policy)
+                    .build(),
+            );
+            let release = block_worker(&pool);
+            let watcher = Arc::clone(&pool);
+            let releaser = thread::spawn(move || {
+                while watcher.metrics().dropped < 2 {
+                    thread::yield_now();
+                }
+                release.send(()).unwrap();
+            });
+            let result = pool.map(0..3, |x| x);
+            releaser.join().unwrap();
+            assert!(matches!(result, Err(JobError::Dropped)));
+            assert_eq!("Dropped", format!("{:?}", JobError::Dropped));
+        }
+    }
+
+    #[test]
+    fn workerpool_should_select_from_inside_a_job() {
+        let pool = Arc::new(WorkerPool::new(1));
+        let inner = Arc::clone(&pool);
+        let winner = pool
+            .submit(move || {
+                let first = inner.submit(|| 7).unwrap();
+                let second = inner.submit(|| -> u8 { panic!("lost the race") }).unwrap();
+                select_any(vec![first, second])
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(0, winner.0);
+        assert_eq!(7, winner.1.unwrap());
+        let results = select_all(vec![pool.submit(|| 1).unwrap(), pool.submit(|| 2).unwrap()]);
+        assert_eq!(
+            vec![1, 2],
+            results.into_iter().map(Result::unwrap).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn workerpool_map_should_report_panicked_items() {
+        let pool = WorkerPool::new(2);
+        let result = pool.map(0..10, |x| {
+            if x == 5 {
+                panic!("bad item");
+            }
+            x
+        });
+        assert!(matches!(result, Err(JobError::Panicked(_))));
+    }
+
+    #[test]
+    fn workerpool_wait_should_block_until_all_jobs_finish() {
+        let pool = WorkerPool::new(3);
+        let counter = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            let jobs = (0..50).map(|_| {
+                let counter = Arc::clone(&counter);
+                move || {
+                    thread::sleep(Duration::from_micros(100));
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+            pool.execute_many(jobs).unwrap();
+            pool.wait();
+        }
+        assert_eq!(150, counter.load(Ordering::Relaxed));
+        assert_eq!(0, pool.shared.in_flight.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn workerpool_wait_should_count_skipped_and_rejected_jobs() {
+        let pool = Builder::new(1).lane_limit(Priority::Low, 0).build();
+        let token = CancellationToken::new();
+        token.cancel();
+        pool.execute_cancellable(&token, || {}).unwrap();
+        assert!(pool.job(|| {}).priority(Priority::Low).spawn().is_err());
+        pool.wait();
+    }
+
+    #[test]
+    fn workerpool_should_repeat_worker_rng_draws_for_the_same_seed() {
+        let draws = |seed| {
+            let pool = Builder::new(1).seed(seed).build();
+            (0..3)
+                .map(|_| {
+                    pool.job_with_context(|ctx| ctx.with_rng(|rng| rng.next_u64()))
+                        .submit()
+                        .unwrap()
+                        .join()
+                        .unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(draws(42), draws(42));
+        assert_ne!(draws(42), draws(43));
+    }
+
+    #[test]
+    fn workerpool_should_propagate_trace_ids_to_sub_jobs() {
+        // one worker for each level of the tree, as parents wait children
+        let pool = WorkerPool::new(3);
+        let trace = |pool: &WorkerPool| {
+            pool.job_with_context(|ctx| {
+                let child = current_pool()
+                    .unwrap()
+                    .job_with_context(|ctx| {
+                        let grandchild = current_pool()
+                            .unwrap()
+                            .submit(|| CURRENT_TRACE.with(Cell::get))
+                            .unwrap();
+                        (ctx.trace_id(), grandchild.join().unwrap())
+                    })
+                    .submit()
+                    .unwrap();
+                (ctx.trace_id(), child.join().unwrap())
+            })
+            .submit()
+            .unwrap()
+            .join()
+            .unwrap()
+        };
+
+        let (root, (child, grandchild)) = trace(&pool);
+        assert_eq!(root, child);
+        assert_eq!(Some(root), grandchild);
+        assert_ne!(root, trace(&pool).0);
+
+        let explicit = pool
+            .job_with_context(|ctx| ctx.trace_id())
+            .trace(7)
+            .submit()
+            .unwrap();
+        assert_eq!(7, explicit.join().unwrap());
+    }
+
+    #[test]
+    fn workerpool_should_expose_distinct_os_thread_ids() {
+        let pool = WorkerPool::new(3);
+        let ids = pool.os_thread_ids();
+        assert_eq!(3, ids.len());
+
+        if cfg!(any(target_os = "linux", target_os = "macos", windows)) {
+            let mut ids: Vec<u64> = ids.into_iter().map(Option::unwrap).collect();
+            ids.sort_unstable();
+            ids.dedup();
+            assert_eq!(3, ids.len());
+        }
+        assert!(format!("{:?}", pool).contains("os_id"));
+    }
+
+    #[test]
+    fn workerpool_should_reject_jobs_over_label_limit() {
+        let pool = Builder::new(1).label_limit("bulk", 2).build();
+        let (tx, rx) = mpsc::channel::<()>();
+        let gate = Arc::new(Mutex::new(rx));
+
+        // block the only worker, so the next jobs stay in the queue
+        let blocker = Arc::clone(&gate);
+        pool.execute(move || {
+            let _ = blocker.lock().unwrap().recv();
+        })
+        .unwrap();
+
+        assert!(pool.execute_labeled("bulk", || {}).is_ok());
+        assert!(pool.execute_labeled("bulk", || {}).is_ok());
+        assert_eq!(
+            Err(ExecuteError::LimitReached("bulk".to_string())),
+            pool.execute_labeled("bulk", || {})
+        );
+        assert!(pool.execute_labeled("interactive", || {}).is_ok());
+        tx.send(()).unwrap();
+    }
+
+    #[test]
+    fn workerpool_should_execute_job_succeed() {
+        let pool = WorkerPool::new(1);
+        for _ in 0..10000 {
+            pool.execute(|| {
+                let _sum = 3 + 1;
+            })
+            .unwrap();
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! ## Handle
+//!
+//! The handles to the results of submitted jobs, and the functions
+//! waiting for the first or for every job of a set.
+
+use std::{
+    panic,
+    sync::{
+        mpsc::{self, RecvTimeoutError, TryRecvError},
+        Arc, Weak,
+    },
+};
+
+use super::{catch, Job, JobError, Shared, CURRENT_POOL, HELP_INTERVAL};
+
+// Returns a JobHandle and the sender its result is sent with, for jobs
+// whose result comes from later jobs, like retries.
+pub(crate) fn handle_channel<T>() -> (mpsc::Sender<Result<T, JobError>>, JobHandle<T>) {
+    let (tx, rx) = mpsc::channel();
+    (tx, JobHandle { receiver: rx })
+}
+
+// Wraps a job producing a value into a job sending it to a JobHandle,
+// and a job telling the handle it was cancelled.
+pub(super) fn with_handle<F, T>(f: F) -> (Job, Job, JobHandle<T>)
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    let cancel_tx = tx.clone();
+    // the handle may have been dropped, nobody waits the result
+    let job = Box::new(move || {
+        let result = catch(f);
+        let _ = tx.send(result.map_err(JobError::Panicked));
+    });
+    let on_cancel: Job = Box::new(move || {
+        let _ = cancel_tx.send(Err(JobError::Cancelled));
+    });
+    (job, on_cancel, JobHandle { receiver: rx })
+}
+
+/// A handle to the result of a job sent with `WorkerPool::submit`.
+///
+/// Jobs may send sub-jobs to their own pool and join them. A job that
+/// joins runs the queued jobs of its pool while it waits, so jobs
+/// waiting on sub-jobs can't hold every worker and deadlock the pool.
+pub struct JobHandle<T> {
+    receiver: mpsc::Receiver<Result<T, JobError>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Blocks the current thread until the job finishes and returns its
+    /// value, or the JobError telling why it didn't produce one. Called
+    /// from a job, it runs the queued jobs of the pool while it waits.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::{JobError, WorkerPool};
+    ///
+    /// let pool = WorkerPool::new(1);
+    /// let handle = pool.submit(|| -> u8 { panic!("boom") }).unwrap();
+    ///
+    /// match handle.join() {
+    ///     Err(JobError::Panicked(_)) => {}
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
+    pub fn join(self) -> Result<T, JobError> {
+        match CURRENT_POOL.with(|current| current.borrow().as_ref().and_then(Weak::upgrade)) {
+            Some(shared) => self.join_helping(&shared),
+            None => self.receiver.recv().unwrap_or(Err(JobError::PoolShutdown)),
+        }
+    }
+
+    // Waits for the job from inside a job of the pool, running the
+    // queued jobs of the pool meanwhile, which may include this one.
+    fn join_helping(self, shared: &Arc<Shared>) -> Result<T, JobError> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(result) => return result,
+                Err(TryRecvError::Disconnected) => return Err(JobError::PoolShutdown),
+                Err(TryRecvError::Empty) => {}
+            }
+            match shared.queue.try_pop() {
+                Some(task) => shared.help(task),
+                None => match self.receiver.recv_timeout(HELP_INTERVAL) {
+                    Ok(result) => return result,
+                    Err(RecvTimeoutError::Disconnected) => return Err(JobError::PoolShutdown),
+                    Err(RecvTimeoutError::Timeout) => {}
+                },
+            }
+        }
+    }
+
+    /// Same as `join`, but if the job panicked, the original panic
+    /// payload is resumed in the current thread, so code relying on
+    /// panics keeps working when moved to the pool. A job that was
+    /// cancelled or never ran panics with the JobError message.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use std::panic;
+    ///
+    /// let pool = WorkerPool::new(1);
+    /// let handle = pool.submit(|| -> u8 { panic!("boom") }).unwrap();
+    ///
+    /// let payload = panic::catch_unwind(|| handle.join_unwind()).unwrap_err();
+    /// assert_eq!(Some(&"boom"), payload.downcast_ref::<&str>());
+    /// ```
+    pub fn join_unwind(self) -> T {
+        match self.join() {
+            Ok(value) => value,
+            Err(JobError::Panicked(payload)) => panic::resume_unwind(payload),
+            Err(err) => panic!("{}", err),
+        }
+    }
+}
+
+/// Blocks the current thread until the first of a set of jobs finishes,
+/// for hedged requests and races between redundant computations. The
+/// other jobs keep running, and their results are dropped. Called from
+/// a job, it runs the queued jobs of the pool while it waits.
+///
+/// **handles**: Vec<JobHandle<T>> - the jobs to wait for, at least one. \
+/// **returns**: the index of the first job to finish, and its result.
+///
+/// Panics if handles is empty.
+///
+/// ### Examples
+/// ```
+/// use rpools::pool::WorkerPool;
+/// use std::thread;
+/// use std::time::Duration;
+///
+/// let pool = WorkerPool::new(2);
+/// let slow = pool.submit(|| {
+///     thread::sleep(Duration::from_millis(200));
+///     "replica a"
+/// });
+/// let fast = pool.submit(|| "replica b");
+///
+/// let (index, result) = rpools::select_any(vec![slow.unwrap(), fast.unwrap()]);
+/// assert_eq!((1, "replica b"), (index, result.unwrap()));
+/// ```
+pub fn select_any<T>(handles: Vec<JobHandle<T>>) -> (usize, Result<T, JobError>) {
+    assert!(!handles.is_empty(), "select_any needs at least one handle");
+    let pool = CURRENT_POOL.with(|current| current.borrow().as_ref().and_then(Weak::upgrade));
+    loop {
+        for (i, handle) in handles.iter().enumerate() {
+            match handle.receiver.try_recv() {
+                Ok(result) => return (i, result),
+                Err(TryRecvError::Disconnected) => return (i, Err(JobError::PoolShutdown)),
+                Err(TryRecvError::Empty) => {}
+            }
+        }
+        match pool
+            .as_ref()
+            .and_then(|shared| Some((shared, shared.queue.try_pop()?)))
+        {
+            Some((shared, task)) => shared.help(task),
+            None => match handles[0].receiver.recv_timeout(HELP_INTERVAL) {
+                Ok(result) => return (0, result),
+                Err(RecvTimeoutError::Disconnected) => return (0, Err(JobError::PoolShutdown)),
+                Err(RecvTimeoutError::Timeout) => {}
+            },
+        }
+    }
+}
+
+/// Blocks the current thread until every job of a set finishes, and
+/// returns their results in the order of the handles.
+///
+/// **handles**: Vec<JobHandle<T>> - the jobs to wait for. \
+/// **returns**: the result of each job.
+///
+/// ### Examples
+/// ```
+/// use rpools::pool::WorkerPool;
+///
+/// let pool = WorkerPool::new(2);
+/// let handles = (1..=3).map(|i| pool.submit(move || i * i).unwrap()).collect();
+///
+/// let squares: Vec<_> = rpools::select_all(handles).into_iter().map(Result::unwrap).collect();
+/// assert_eq!(vec![1, 4, 9], squares);
+/// ```
+pub fn select_all<T>(handles: Vec<JobHandle<T>>) -> Vec<Result<T, JobError>> {
+    handles.into_iter().map(JobHandle::join).collect()
+}
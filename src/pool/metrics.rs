@@ -0,0 +1,319 @@
+//! ## Metrics
+//!
+//! The snapshots a pool reports about itself: its counters, the latency
+//! of its jobs, its health and the state of each worker.
+
+use std::{
+    convert::TryFrom,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use crate::histogram::Histogram;
+
+/// The health of a pool, returned by `WorkerPool::health`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Health {
+    /// Every worker is alive and the pool accepts jobs.
+    Healthy,
+    /// The pool accepts jobs, but some workers were unwound by a panic
+    /// and the pool runs with less capacity.
+    Degraded {
+        /// The workers that died.
+        dead_workers: usize,
+    },
+    /// The pool was shut down.
+    Stopped,
+}
+
+/// The jobs finished while a pool was drained, returned by
+/// `WorkerPool::drain`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DrainReport {
+    /// The jobs finished while draining, including the ones that
+    /// panicked or were skipped.
+    pub completed: usize,
+    /// The jobs that panicked while draining.
+    pub panicked: usize,
+    /// How long the drain took.
+    pub elapsed: Duration,
+}
+
+/// What a worker is doing, as reported by `WorkerPool::dump`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WorkerState {
+    /// Waiting for a job.
+    Idle,
+    /// Running a job, with its name if it was sent with one, since the
+    /// given instant.
+    Running {
+        job_name: Option<String>,
+        since: Instant,
+    },
+    /// The worker thread exited, killed by a panicking job or retired
+    /// by the adaptive sizing.
+    Dead,
+}
+
+/// The state of one worker in a PoolDump.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WorkerDump {
+    /// The id of the worker in the pool.
+    pub id: usize,
+    /// The thread id given by the operating system, where available.
+    pub os_id: Option<u64>,
+    /// The CPU core the worker is pinned to, if it is.
+    pub core: Option<usize>,
+    /// What the worker is doing.
+    pub state: WorkerState,
+}
+
+/// A snapshot of the workers of a pool, returned by `WorkerPool::dump`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PoolDump {
+    /// The state of each worker, in the order they were spawned.
+    pub workers: Vec<WorkerDump>,
+    /// The jobs waiting in the queue.
+    pub queued: usize,
+}
+
+// Implements Display for PoolDump, one line for the queue and one for
+// each worker, as in "worker 0: running ingest for 1.5s".
+impl Display for PoolDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "queued: {}", self.queued)?;
+        for worker in &self.workers {
+            write!(f, "\nworker {}: ", worker.id)?;
+            match &worker.state {
+                WorkerState::Idle => write!(f, "idle")?,
+                WorkerState::Running { job_name, since } => write!(
+                    f,
+                    "running {} for {:?}",
+                    job_name.as_deref().unwrap_or("unnamed job"),
+                    since.elapsed()
+                )?,
+                WorkerState::Dead => write!(f, "dead")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A snapshot of the counters of a pool, returned by
+/// `WorkerPool::metrics` or filled by `WorkerPool::metrics_into`.
+/// New counters may be added, so build it with
+/// `PoolMetrics::default()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PoolMetrics {
+    /// The number of workers, not counting the ones retiring.
+    pub workers: usize,
+    /// The jobs waiting in the queue.
+    pub queued: usize,
+    /// The delayed and recurring jobs waiting in the timer.
+    pub scheduled: usize,
+    /// The jobs queued or running.
+    pub in_flight: usize,
+    /// The workers running a job.
+    pub active: usize,
+    /// The jobs finished since the pool was built, including the ones
+    /// that panicked or were skipped.
+    pub completed: usize,
+    /// The jobs that panicked since the pool was built.
+    pub panicked: usize,
+    /// The time workers spent running jobs, added across workers.
+    pub busy_time: Duration,
+    /// The retries waiting for their backoff or in the queue.
+    pub retrying: usize,
+    /// The retries dropped because the retry queue was full.
+    pub retries_rejected: usize,
+    /// The critical jobs given to the fallback executor.
+    pub spilled: usize,
+    /// The times workers yielded after running past their budget.
+    pub budget_yields: usize,
+    /// The jobs rejected because the queue was at its bound.
+    pub rejected: usize,
+    /// The jobs dropped because the queue was at its bound, by the
+    /// DropOldest and DropNewest overflow policies.
+    pub dropped: usize,
+    /// The time workers spent parked waiting for a job, added across
+    /// workers.
+    pub parked_time: Duration,
+    /// The jobs skipped because no worker picked them before their
+    /// deadline.
+    pub missed_deadlines: usize,
+    /// The jobs that ran for at most each bound of
+    /// JOB_DURATION_BUCKETS, cumulative, then every job that ran.
+    /// Counts are approximated within 12.5% of the bounds.
+    #[cfg(feature = "metrics-export")]
+    pub job_durations: [usize; JOB_DURATION_BUCKETS.len() + 1],
+}
+
+/// The upper bounds, in seconds, of the buckets of
+/// `PoolMetrics::job_durations`: the default buckets of Prometheus.
+#[cfg(feature = "metrics-export")]
+pub const JOB_DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[cfg(feature = "metrics-export")]
+impl PoolMetrics {
+    /// Formats the metrics in the Prometheus text format, so an HTTP
+    /// handler can expose the health of the pool with one call.
+    ///
+    /// **namespace**: &str - the prefix of the metric names, as in
+    /// "myapp_pool", or "" for none. \
+    /// **returns**: the gauges, counters and the job duration histogram,
+    /// each with its HELP and TYPE lines.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    ///
+    /// let pool = WorkerPool::new(2);
+    /// pool.execute(|| {}).unwrap();
+    /// pool.wait();
+    ///
+    /// let text = pool.metrics().to_prometheus("api_pool");
+    /// assert!(text.contains("# TYPE api_pool_queue_depth gauge\napi_pool_queue_depth 0\n"));
+    /// assert!(text.contains("api_pool_jobs_completed_total 1\n"));
+    /// assert!(text.contains("api_pool_job_duration_seconds_bucket{le=\"+Inf\"} 1\n"));
+    /// ```
+    pub fn to_prometheus(&self, namespace: &str) -> String {
+        use std::fmt::Write;
+
+        let prefix = if namespace.is_empty() {
+            String::new()
+        } else {
+            format!("{}_", namespace)
+        };
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: usize| {
+            let _ = write!(
+                text,
+                "# HELP {p}{n} {h}\n# TYPE {p}{n} {k}\n{p}{n} {v}\n",
+                p = prefix,
+                n = name,
+                h = help,
+                k = kind,
+                v = value
+            );
+        };
+        metric("workers", "gauge", "The workers of the pool.", self.workers);
+        metric(
+            "queue_depth",
+            "gauge",
+            "The jobs waiting in the queue.",
+            self.queued,
+        );
+        metric(
+            "busy_workers",
+            "gauge",
+            "The workers running a job.",
+            self.active,
+        );
+        metric(
+            "in_flight",
+            "gauge",
+            "The jobs queued or running.",
+            self.in_flight,
+        );
+        metric(
+            "scheduled",
+            "gauge",
+            "The delayed and recurring jobs.",
+            self.scheduled,
+        );
+        metric(
+            "jobs_completed_total",
+            "counter",
+            "The jobs finished.",
+            self.completed,
+        );
+        metric(
+            "jobs_panicked_total",
+            "counter",
+            "The jobs that panicked.",
+            self.panicked,
+        );
+        metric(
+            "jobs_rejected_total",
+            "counter",
+            "The jobs rejected by a full queue.",
+            self.rejected,
+        );
+        metric(
+            "jobs_dropped_total",
+            "counter",
+            "The jobs dropped by a full queue.",
+            self.dropped,
+        );
+        metric(
+            "missed_deadlines_total",
+            "counter",
+            "The jobs skipped past their deadline.",
+            self.missed_deadlines,
+        );
+
+        let name = format!("{}job_duration_seconds", prefix);
+        let _ = write!(
+            text,
+            "# HELP {n} The time jobs ran.\n# TYPE {n} histogram\n",
+            n = name
+        );
+        for (bound, count) in JOB_DURATION_BUCKETS.iter().zip(&self.job_durations) {
+            let _ = writeln!(text, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let count = self.job_durations[JOB_DURATION_BUCKETS.len()];
+        let _ = writeln!(text, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(text, "{}_sum {}", name, self.busy_time.as_secs_f64());
+        let _ = writeln!(text, "{}_count {}", name, count);
+        text
+    }
+}
+
+/// The latency of the jobs of a pool, returned by
+/// `WorkerPool::latency_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LatencyStats {
+    /// The time jobs waited from being queued to starting.
+    pub queue_wait: Percentiles,
+    /// The time jobs ran.
+    pub execution: Percentiles,
+}
+
+/// Percentiles of a duration measured for each job.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Percentiles {
+    /// The jobs measured.
+    pub count: usize,
+    /// The median.
+    pub p50: Duration,
+    /// The 95th percentile.
+    pub p95: Duration,
+    /// The 99th percentile.
+    pub p99: Duration,
+    /// The longest duration.
+    pub max: Duration,
+}
+
+impl Percentiles {
+    // Reads the percentiles of a histogram.
+    pub(super) fn of(histogram: &Histogram) -> Percentiles {
+        let (count, [p50, p95, p99]) = histogram.percentiles([0.5, 0.95, 0.99]);
+        Percentiles {
+            count: usize::try_from(count).unwrap_or(usize::MAX),
+            p50,
+            p95,
+            p99,
+            max: histogram.max(),
+        }
+    }
+}
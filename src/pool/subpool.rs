@@ -0,0 +1,235 @@
+//! ## SubPool
+//!
+//! The handles sharing a pool: PoolHandle, a cloneable owner of the
+//! pool, and SubPool, a group of jobs with its own limit running on the
+//! threads of a parent pool.
+
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    ops::Deref,
+    sync::{Arc, Mutex, Weak},
+};
+
+use super::{with_handle, ExecuteError, Job, JobHandle, Priority, QueuedJob, Shared, WorkerPool};
+
+// The jobs of a SubPool: how many run on the parent pool, and the ones
+// waiting for one of them to end.
+pub(super) struct Group {
+    name: String,
+    limit: usize,
+    state: Mutex<GroupState>,
+}
+
+struct GroupState {
+    running: usize,
+    waiting: VecDeque<Job>,
+}
+
+// A slot of a group, held by its running job. When the job finishes, or
+// is dropped without running, the next job waiting in the group is
+// queued with the slot, or the slot is released if none is.
+struct GroupTurn {
+    shared: Weak<Shared>,
+    group: Arc<Group>,
+}
+
+impl Group {
+    // Takes the jobs waiting for a slot, for the shutdown methods.
+    pub(super) fn take_waiting(&self) -> Vec<Job> {
+        let mut state = self.state.lock().expect("Cant acquire lock");
+        state.waiting.drain(..).collect()
+    }
+}
+
+impl GroupTurn {
+    // Wraps a job so it passes the slot on when it ends.
+    fn job(self, job: Job) -> Job {
+        Box::new(move || {
+            let _turn = self;
+            job();
+        })
+    }
+}
+
+impl Drop for GroupTurn {
+    fn drop(&mut self) {
+        let next = {
+            let mut state = self.group.state.lock().expect("Cant acquire lock");
+            let next = state.waiting.pop_front();
+            if next.is_none() {
+                state.running -= 1;
+            }
+            next
+        };
+        let (Some(next), Some(shared)) = (next, self.shared.upgrade()) else {
+            return;
+        };
+        let turn = GroupTurn {
+            shared: Weak::clone(&self.shared),
+            group: Arc::clone(&self.group),
+        };
+        // a rejected job drops its slot, which drops the next ones
+        let _ = shared.enqueue_follow_up(QueuedJob::new(turn.job(next)));
+    }
+}
+
+/// A cheaply cloneable handle to a WorkerPool, to keep in the state of
+/// an application and share between threads without wrapping the pool
+/// in an Arc. It derefs to the pool, and dropping the last handle shuts
+/// the pool down, while earlier drops don't.
+///
+/// ### Examples
+///
+/// ```
+/// use rpools::pool::{PoolHandle, WorkerPool};
+///
+/// let pool = PoolHandle::from(WorkerPool::new(2));
+/// let handlers: Vec<_> = (0..4).map(|_| pool.clone()).collect();
+///
+/// for handler in handlers {
+///     std::thread::spawn(move || handler.execute(|| {}).unwrap())
+///         .join()
+///         .unwrap();
+/// }
+/// assert_eq!(1, pool.handles());
+/// ```
+#[derive(Clone)]
+pub struct PoolHandle {
+    pool: Arc<WorkerPool>,
+}
+
+impl PoolHandle {
+    /// Returns how many handles share the pool.
+    pub fn handles(&self) -> usize {
+        Arc::strong_count(&self.pool)
+    }
+}
+
+impl From<WorkerPool> for PoolHandle {
+    fn from(pool: WorkerPool) -> PoolHandle {
+        PoolHandle {
+            pool: Arc::new(pool),
+        }
+    }
+}
+
+impl Deref for PoolHandle {
+    type Target = WorkerPool;
+
+    fn deref(&self) -> &WorkerPool {
+        &self.pool
+    }
+}
+
+// Implements Debug for PoolHandle as the pool it shares.
+impl Debug for PoolHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&*self.pool, f)
+    }
+}
+
+/// A group of jobs running on the threads of a parent pool, with a
+/// limit of jobs running at once, made by `WorkerPool::partition`.
+/// Clones send to the same group. Once the parent is shut down, jobs
+/// are rejected with ExecuteError::Shutdown.
+#[derive(Clone)]
+pub struct SubPool {
+    shared: Weak<Shared>,
+    group: Arc<Group>,
+}
+
+impl SubPool {
+    // Constructs an empty group running on the pool of shared.
+    pub(super) fn new(shared: &Arc<Shared>, name: &str, limit: usize) -> SubPool {
+        SubPool {
+            shared: Arc::downgrade(shared),
+            group: Arc::new(Group {
+                name: name.to_string(),
+                limit: limit.max(1),
+                state: Mutex::new(GroupState {
+                    running: 0,
+                    waiting: VecDeque::new(),
+                }),
+            }),
+        }
+    }
+
+    // Returns the group, so the pool can take the jobs waiting in it.
+    pub(super) fn group(&self) -> Weak<Group> {
+        Arc::downgrade(&self.group)
+    }
+
+    /// Executes a job on the parent pool, or queues it in the group if
+    /// the group runs as many jobs as its limit.
+    ///
+    /// **f**: A FnOnce closure. \
+    /// **returns**: Ok if the job was queued, or an ExecuteError.
+    pub fn execute<J>(&self, f: J) -> Result<(), ExecuteError>
+    where
+        J: FnOnce() + Send + 'static,
+    {
+        self.push(Box::new(f))
+    }
+
+    /// Executes a job as `execute` does, and returns a handle to its
+    /// result.
+    ///
+    /// **f**: A FnOnce closure that produces a value. \
+    /// **returns**: a JobHandle, or an ExecuteError.
+    pub fn submit<F, T>(&self, f: F) -> Result<JobHandle<T>, ExecuteError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, _, handle) = with_handle(f);
+        self.push(job).map(|()| handle)
+    }
+
+    /// Returns the name of the group.
+    pub fn name(&self) -> &str {
+        &self.group.name
+    }
+
+    /// Returns the most jobs of the group running at once.
+    pub fn limit(&self) -> usize {
+        self.group.limit
+    }
+
+    /// Returns how many jobs of the group wait for a slot.
+    pub fn waiting(&self) -> usize {
+        let state = self.group.state.lock().expect("Cant acquire lock");
+        state.waiting.len()
+    }
+
+    // Queues a job on the parent if the group has a free slot, and in
+    // the group otherwise.
+    fn push(&self, job: Job) -> Result<(), ExecuteError> {
+        let shared = self.shared.upgrade().ok_or(ExecuteError::Shutdown)?;
+        shared.accepting()?;
+        {
+            let mut state = self.group.state.lock().expect("Cant acquire lock");
+            if state.running >= self.group.limit {
+                state.waiting.push_back(job);
+                return Ok(());
+            }
+            state.running += 1;
+        }
+        let turn = GroupTurn {
+            shared: Weak::clone(&self.shared),
+            group: Arc::clone(&self.group),
+        };
+        shared.enqueue(QueuedJob::new(turn.job(job)), Priority::Normal)
+    }
+}
+
+// Implements Debug for SubPool, showing its group.
+impl Debug for SubPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SubPool")
+            .field("name", &self.group.name)
+            .field("limit", &self.group.limit)
+            .field("waiting", &self.waiting())
+            .finish()
+    }
+}
@@ -0,0 +1,563 @@
+// ## Queue
+//
+// The job queue shared by the pool and its workers. It is a mutex
+// protected set of lanes paired with a condvar, so a batch of jobs can
+// be pushed with a single lock acquisition, and idle workers block until
+// a job is available. Items are popped from the highest lane first, and
+// in FIFO order within a lane. A lane may have a limit of queued items,
+// the whole queue a capacity, and a thread may pop only from the lanes
+// above some lane. Each lane stores its items in a JobQueue, FIFO unless
+// a backend is set. The number of queued items is also kept in an
+// atomic, so other pools can peek at it without taking the lock.
+
+use crate::backend::JobQueue;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// The reasons a push may fail. The items are given back.
+#[derive(Debug, PartialEq)]
+pub(crate) enum PushError<T> {
+    Closed(T),
+    Full(T),
+    Rejected(T),
+    // the queue holds its capacity of items, across all lanes
+    AtCapacity(T),
+}
+
+// What a push does when the queue is at its capacity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Overflow {
+    // fails with PushError::AtCapacity
+    Reject,
+    // blocks until an item is popped
+    Wait,
+    // evicts the oldest item of the lowest non empty lane, whatever
+    // order its backend picks items in
+    EvictOldest,
+}
+
+// Who pushes an item: a new submit, or a follow-up of an item accepted
+// before, which a rejecting queue still takes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Origin {
+    New,
+    FollowUp,
+}
+
+// The outcomes of a blocking pop.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Pop<T> {
+    Item(T),
+    // the queue is closed and empty
+    Closed,
+    // the stop condition returned true
+    Stopped,
+    // the queue is empty and the interrupt condition returned true
+    Interrupted,
+    // no item came for the idle timeout
+    Idle,
+}
+
+struct State<T> {
+    lanes: Vec<Box<dyn JobQueue<T>>>,
+    closed: bool,
+    // open for pops, but pushes are rejected
+    rejecting: bool,
+    // the threads waiting for items of the higher lanes only, which a
+    // single wake up may miss
+    picky: usize,
+}
+
+pub(crate) struct Queue<T> {
+    state: Mutex<State<T>>,
+    limits: Vec<Option<usize>>,
+    capacity: Option<usize>,
+    available: Condvar,
+    // notified when an item is popped from a queue with a capacity
+    space: Condvar,
+    queued: AtomicUsize,
+}
+
+impl<T: Send + 'static> Queue<T> {
+    // Constructs a new empty Queue with one lane per limit.
+    pub(crate) fn new(limits: Vec<Option<usize>>) -> Queue<T> {
+        Queue {
+            state: Mutex::new(State {
+                lanes: limits
+                    .iter()
+                    .map(|_| Box::new(VecDeque::new()) as Box<dyn JobQueue<T>>)
+                    .collect(),
+                closed: false,
+                rejecting: false,
+                picky: 0,
+            }),
+            limits,
+            capacity: None,
+            available: Condvar::new(),
+            space: Condvar::new(),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    // Bounds the items queued across all lanes, or unbounds the queue.
+    pub(crate) fn set_capacity(&mut self, capacity: Option<usize>) {
+        self.capacity = capacity;
+    }
+
+    // Replaces the storage of each lane with one made by backend. The
+    // items already queued are moved to the new lanes.
+    pub(crate) fn set_backend(&mut self, backend: &dyn Fn() -> Box<dyn JobQueue<T>>) {
+        let state = self.state.get_mut().expect("Cant acquire lock");
+        for lane in state.lanes.iter_mut() {
+            let mut replacement = backend();
+            while let Some(item) = lane.pop() {
+                replacement.push(item);
+            }
+            *lane = replacement;
+        }
+    }
+
+    // Returns true if count more items would exceed the capacity.
+    fn over_capacity(&self, count: usize) -> bool {
+        let queued = self.queued.load(Ordering::Acquire);
+        self.capacity
+            .is_some_and(|capacity| queued + count > capacity)
+    }
+
+    // Pushes an item to the back of a lane and wakes one worker.
+    #[cfg(test)]
+    pub(crate) fn push(&self, lane: usize, item: T) -> Result<(), PushError<T>> {
+        self.push_with(lane, item, Overflow::Reject, Origin::New)
+            .map(drop)
+    }
+
+    // Pushes an item to the back of a lane and wakes one worker, doing
+    // what overflow says when the queue is at its capacity. Returns the
+    // item evicted to make room, if any.
+    pub(crate) fn push_with(
+        &self,
+        lane: usize,
+        item: T,
+        overflow: Overflow,
+        origin: Origin,
+    ) -> Result<Option<T>, PushError<T>> {
+        let mut state = self.state.lock().expect("Cant acquire lock");
+        let mut evicted = None;
+        loop {
+            if state.closed {
+                return Err(PushError::Closed(item));
+            }
+            if state.rejecting && origin == Origin::New {
+                return Err(PushError::Rejected(item));
+            }
+            if self.limits[lane].is_some_and(|limit| state.lanes[lane].len() >= limit) {
+                return Err(PushError::Full(item));
+            }
+            if !self.over_capacity(1) {
+                break;
+            }
+            match overflow {
+                Overflow::Reject => return Err(PushError::AtCapacity(item)),
+                Overflow::Wait => {
+                    state = self
+                        .space
+                        .wait(state)
+                        .expect("Cant block the current thread");
+                }
+                Overflow::EvictOldest => {
+                    evicted = state.lanes.iter_mut().find_map(|l| l.pop_oldest());
+                    if evicted.is_none() {
+                        return Err(PushError::AtCapacity(item));
+                    }
+                    self.queued.fetch_sub(1, Ordering::Release);
+                    break;
+                }
+            }
+        }
+        state.lanes[lane].push(item);
+        self.queued.fetch_add(1, Ordering::Release);
+        let picky = state.picky > 0;
+        drop(state);
+        self.notify(1, picky);
+        Ok(evicted)
+    }
+
+    // Pushes all items to a lane under a single lock and wakes as many
+    // workers as needed. The batch is accepted or rejected as a whole.
+    pub(crate) fn push_batch(&self, lane: usize, items: Vec<T>) -> Result<(), PushError<Vec<T>>> {
+        let mut state = self.state.lock().expect("Cant acquire lock");
+        if state.closed {
+            return Err(PushError::Closed(items));
+        }
+        if state.rejecting {
+            return Err(PushError::Rejected(items));
+        }
+        let count = items.len();
+        if self.limits[lane].is_some_and(|limit| state.lanes[lane].len() + count > limit) {
+            return Err(PushError::Full(items));
+        }
+        if self.over_capacity(count) {
+            return Err(PushError::AtCapacity(items));
+        }
+        for item in items {
+            state.lanes[lane].push(item);
+        }
+        self.queued.fetch_add(count, Ordering::Release);
+        let picky = state.picky > 0;
+        drop(state);
+        self.notify(count, picky);
+        Ok(())
+    }
+
+    // Wakes one thread for a single item, or all of them for more, or
+    // when a picky thread could take the only wake up.
+    fn notify(&self, items: usize, picky: bool) {
+        match items {
+            0 => {}
+            1 if !picky => self.available.notify_one(),
+            _ => self.available.notify_all(),
+        }
+    }
+
+    // Blocks the current thread until an item is available and pops it.
+    // Returns None once the queue is closed and all items were popped.
+    #[cfg(test)]
+    pub(crate) fn pop(&self) -> Option<T> {
+        match self.pop_until(|| false, || false) {
+            Pop::Item(item) => Some(item),
+            _ => None,
+        }
+    }
+
+    // Pops an item if one is available, without blocking.
+    pub(crate) fn try_pop(&self) -> Option<T> {
+        let mut state = self.state.lock().expect("Cant acquire lock");
+        self.take(&mut state, 0)
+    }
+
+    // Same as pop, but gives up as soon as stop returns true, or when
+    // the queue is empty and interrupt returns true. Both are checked
+    // each time the thread is woken, stop before taking an item.
+    #[cfg(test)]
+    pub(crate) fn pop_until<S, I>(&self, stop: S, interrupt: I) -> Pop<T>
+    where
+        S: Fn() -> bool,
+        I: Fn() -> bool,
+    {
+        self.pop_from(0, None, stop, interrupt)
+    }
+
+    // Same as pop_until, but only pops items from the lowest lane and
+    // the lanes above it, and gives up once it waited idle for so long.
+    pub(crate) fn pop_from<S, I>(
+        &self,
+        lowest: usize,
+        idle: Option<Duration>,
+        stop: S,
+        interrupt: I,
+    ) -> Pop<T>
+    where
+        S: Fn() -> bool,
+        I: Fn() -> bool,
+    {
+        let deadline = idle.map(|idle| Instant::now() + idle);
+        let mut state = self.state.lock().expect("Cant acquire lock");
+        loop {
+            if stop() {
+                return Pop::Stopped;
+            }
+            if let Some(item) = self.take(&mut state, lowest) {
+                return Pop::Item(item);
+            }
+            if state.closed {
+                return Pop::Closed;
+            }
+            if interrupt() {
+                return Pop::Interrupted;
+            }
+            let timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(timeout) if !timeout.is_zero() => Some(timeout),
+                    _ => return Pop::Idle,
+                },
+                None => None,
+            };
+            state.picky += usize::from(lowest > 0);
+            state = match timeout {
+                Some(timeout) => {
+                    self.available
+                        .wait_timeout(state, timeout)
+                        .expect("Cant block the current thread")
+                        .0
+                }
+                None => self
+                    .available
+                    .wait(state)
+                    .expect("Cant block the current thread"),
+            };
+            state.picky -= usize::from(lowest > 0);
+        }
+    }
+
+    // Takes the next item from the highest non empty lane, down to the
+    // lowest one.
+    fn take(&self, state: &mut State<T>, lowest: usize) -> Option<T> {
+        let item = state.lanes[lowest..].iter_mut().rev().find_map(|l| l.pop());
+        if item.is_some() {
+            self.queued.fetch_sub(1, Ordering::Release);
+            if self.capacity.is_some() {
+                self.space.notify_one();
+            }
+        }
+        item
+    }
+
+    // Removes every item waiting in the queue, highest lane first.
+    pub(crate) fn take_all(&self) -> Vec<T> {
+        let mut state = self.state.lock().expect("Cant acquire lock");
+        let mut items = Vec::new();
+        for lane in state.lanes.iter_mut().rev() {
+            items.extend(std::iter::from_fn(|| lane.pop()));
+        }
+        self.queued.fetch_sub(items.len(), Ordering::Release);
+        self.space.notify_all();
+        items
+    }
+
+    // Removes the items for which remove returns true from every lane,
+    // highest lane first, keeping the order of the other items.
+    pub(crate) fn remove_where(&self, remove: &mut dyn FnMut(&T) -> bool) -> Vec<T> {
+        let mut state = self.state.lock().expect("Cant acquire lock");
+        let mut items = Vec::new();
+        for lane in state.lanes.iter_mut().rev() {
+            items.extend(lane.remove_if(remove));
+        }
+        if !items.is_empty() {
+            self.queued.fetch_sub(items.len(), Ordering::Release);
+            self.space.notify_all();
+        }
+        items
+    }
+
+    // Returns true if new items would be accepted, limits aside.
+    pub(crate) fn is_accepting(&self) -> bool {
+        let state = self.state.lock().expect("Cant acquire lock");
+        !state.closed && !state.rejecting
+    }
+
+    // Returns true if the queue was closed.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.state.lock().expect("Cant acquire lock").closed
+    }
+
+    // Returns how many items are waiting in the queue.
+    pub(crate) fn len(&self) -> usize {
+        self.queued.load(Ordering::Acquire)
+    }
+
+    // Wakes all threads blocked in pop, so they check their stop
+    // condition again.
+    pub(crate) fn wake_all(&self) {
+        let _state = self.state.lock().expect("Cant acquire lock");
+        self.available.notify_all();
+    }
+
+    // Wakes one thread blocked in pop, so it checks its interrupt
+    // condition again.
+    pub(crate) fn wake_one(&self) {
+        let _state = self.state.lock().expect("Cant acquire lock");
+        self.available.notify_one();
+    }
+
+    // Starts or stops rejecting new items, without closing the queue,
+    // so the threads blocked in pop keep waiting.
+    pub(crate) fn set_rejecting(&self, rejecting: bool) {
+        self.state.lock().expect("Cant acquire lock").rejecting = rejecting;
+        self.space.notify_all();
+    }
+
+    // Closes the queue. New items are rejected, but the ones already
+    // queued can still be popped. Returns false if it was already closed.
+    pub(crate) fn close(&self) -> bool {
+        let mut state = self.state.lock().expect("Cant acquire lock");
+        let was_open = !state.closed;
+        state.closed = true;
+        drop(state);
+        self.available.notify_all();
+        self.space.notify_all();
+        was_open
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::{Origin, Overflow, Pop, PushError, Queue};
+    use crate::backend::PriorityHeap;
+    use std::{sync::Arc, thread, time::Duration};
+
+    #[test]
+    fn queue_should_pop_in_fifo_order() {
+        let queue = Queue::new(vec![None]);
+        queue.push(0, 1).unwrap();
+        queue.push_batch(0, vec![2, 3]).unwrap();
+        assert_eq!(
+            vec![1, 2, 3],
+            (0..3).map(|_| queue.pop().unwrap()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn queue_should_pop_higher_lanes_first() {
+        let queue = Queue::new(vec![None, None, None]);
+        queue.push(0, "low").unwrap();
+        queue.push(1, "normal").unwrap();
+        queue.push(2, "high").unwrap();
+        assert_eq!(3, queue.len());
+        assert_eq!(Some("high"), queue.pop());
+        assert_eq!(Some("normal"), queue.pop());
+        assert_eq!(Some("low"), queue.pop());
+    }
+
+    #[test]
+    fn queue_should_reject_items_over_lane_limit() {
+        let queue = Queue::new(vec![Some(1), None]);
+        queue.push(0, 1).unwrap();
+        assert_eq!(Err(PushError::Full(2)), queue.push(0, 2));
+        assert_eq!(
+            Err(PushError::Full(vec![2, 3])),
+            queue.push_batch(0, vec![2, 3])
+        );
+        assert!(queue.push_batch(1, vec![2, 3]).is_ok());
+    }
+
+    #[test]
+    fn queue_should_remove_matching_items_from_every_lane() {
+        let queue = Queue::new(vec![None, None]);
+        queue.push_batch(0, vec![1, 2, 3]).unwrap();
+        queue.push_batch(1, vec![4, 5]).unwrap();
+        assert_eq!(vec![4, 1, 3], queue.remove_where(&mut |i| i % 3 != 2));
+        assert_eq!(2, queue.len());
+        assert_eq!(Some(5), queue.try_pop());
+        assert_eq!(Some(2), queue.try_pop());
+    }
+
+    #[test]
+    fn queue_should_reject_items_over_capacity() {
+        let mut queue = Queue::new(vec![None, None]);
+        queue.set_capacity(Some(2));
+        queue.push(0, 1).unwrap();
+        queue.push(1, 2).unwrap();
+        assert_eq!(Err(PushError::AtCapacity(3)), queue.push(0, 3));
+        assert_eq!(
+            Err(PushError::AtCapacity(vec![3])),
+            queue.push_batch(1, vec![3])
+        );
+        assert_eq!(Some(2), queue.try_pop());
+        assert_eq!(Ok(()), queue.push_batch(0, vec![3]));
+    }
+
+    #[test]
+    fn queue_should_wait_or_evict_at_capacity() {
+        let mut queue = Queue::new(vec![None, None]);
+        queue.set_capacity(Some(2));
+        queue.push(1, "high").unwrap();
+        queue.push(0, "old").unwrap();
+        let evicted = queue.push_with(1, "new", Overflow::EvictOldest, Origin::New);
+        assert_eq!(Ok(Some("old")), evicted);
+
+        let queue = Arc::new(queue);
+        let popper = Arc::clone(&queue);
+        let pop = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            popper.try_pop()
+        });
+        assert_eq!(
+            Ok(None),
+            queue.push_with(0, "late", Overflow::Wait, Origin::New)
+        );
+        assert_eq!(Some("high"), pop.join().unwrap());
+        queue.close();
+        assert_eq!(
+            Err(PushError::Closed("shut")),
+            queue.push_with(0, "shut", Overflow::Wait, Origin::New)
+        );
+    }
+
+    #[test]
+    fn queue_should_evict_the_oldest_item_whatever_the_backend() {
+        let mut queue = Queue::new(vec![None]);
+        queue.set_capacity(Some(2));
+        queue.set_backend(&|| Box::new(PriorityHeap::new(|item: &(u8, char)| item.0)));
+        queue.push(0, (2, 'a')).unwrap();
+        queue.push(0, (1, 'b')).unwrap();
+        let evicted = queue.push_with(0, (0, 'c'), Overflow::EvictOldest, Origin::New);
+        assert_eq!(Ok(Some((2, 'a'))), evicted);
+        assert_eq!(Some((0, 'c')), queue.try_pop());
+    }
+
+    #[test]
+    fn queue_should_reject_items_while_rejecting() {
+        let queue = Queue::new(vec![None]);
+        queue.push(0, 1).unwrap();
+        queue.set_rejecting(true);
+        assert_eq!(Err(PushError::Rejected(2)), queue.push(0, 2));
+        assert_eq!(
+            Ok(None),
+            queue.push_with(0, 3, Overflow::Reject, Origin::FollowUp)
+        );
+        assert_eq!(Some(1), queue.try_pop());
+        assert_eq!(Some(3), queue.try_pop());
+        queue.set_rejecting(false);
+        assert!(queue.push(0, 2).is_ok());
+    }
+
+    #[test]
+    fn queue_should_drain_and_reject_after_close() {
+        let queue = Queue::new(vec![None]);
+        queue.push(0, 1).unwrap();
+        assert!(queue.close());
+        assert!(!queue.close());
+        assert_eq!(Err(PushError::Closed(2)), queue.push(0, 2));
+        assert_eq!(Some(1), queue.pop());
+        assert_eq!(None, queue.pop());
+    }
+
+    #[test]
+    fn queue_should_prefer_items_to_interrupts() {
+        let queue = Queue::new(vec![None]);
+        queue.push(0, 1).unwrap();
+        assert_eq!(Pop::Item(1), queue.pop_until(|| false, || true));
+        assert_eq!(Pop::Interrupted, queue.pop_until(|| false, || true));
+        assert_eq!(Pop::Stopped, queue.pop_until(|| true, || true));
+    }
+
+    #[test]
+    fn queue_should_give_up_when_idle_for_too_long() {
+        let queue: Queue<u8> = Queue::new(vec![None]);
+        let idle = Some(Duration::from_millis(10));
+        assert_eq!(Pop::Idle, queue.pop_from(0, idle, || false, || false));
+        queue.push(0, 1).unwrap();
+        assert_eq!(Pop::Item(1), queue.pop_from(0, idle, || false, || false));
+    }
+
+    #[test]
+    fn queue_should_pop_only_from_the_given_lanes() {
+        let queue = Queue::new(vec![None, None, None]);
+        queue.push(0, "low").unwrap();
+        assert_eq!(Pop::Interrupted, queue.pop_from(1, None, || false, || true));
+        queue.push(2, "high").unwrap();
+        assert_eq!(
+            Pop::Item("high"),
+            queue.pop_from(1, None, || false, || true)
+        );
+        assert_eq!(Pop::Item("low"), queue.pop_from(0, None, || false, || true));
+        assert_eq!(None, queue.try_pop());
+        assert_eq!(0, queue.len());
+    }
+}
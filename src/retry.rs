@@ -0,0 +1,364 @@
+//! ## Retry
+//!
+//! This module runs jobs again when they fail. A failed attempt waits
+//! its backoff in the retry queue, apart from the main queue, and is
+//! queued again when due. The retry queue is bounded with
+//! `Builder::retry_limit`, so a failing downstream fills it instead of
+//! crowding out fresh work: retries over the limit are dropped and
+//! counted in `PoolMetrics::retries_rejected`.
+//!
+//! The backoff is fixed or exponential, and may be spread with jitter
+//! so jobs failing together don't retry together. `submit_with_retry`
+//! returns a handle resolving with the result of the last attempt.
+//!
+//! ### Examples
+//! ```
+//! use rpools::pool::WorkerPool;
+//! use rpools::retry::RetryPolicy;
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//!
+//! let pool = WorkerPool::new(2);
+//! let calls = Arc::new(AtomicUsize::new(0));
+//! let counter = calls.clone();
+//!
+//! pool.execute_with_retry(RetryPolicy::new(3, Duration::from_millis(1)), move || {
+//!     match counter.fetch_add(1, Ordering::Relaxed) {
+//!         0 => Err("downstream unavailable"),
+//!         _ => Ok(()),
+//!     }
+//! })
+//! .unwrap();
+//!
+//! while calls.load(Ordering::Relaxed) < 2 {
+//!     std::thread::yield_now();
+//! }
+//! ```
+
+use std::{
+    sync::{mpsc, Arc},
+    time::Duration,
+};
+
+use crate::pool::{
+    catch, handle_channel, set_attempt, with_worker_rng, ExecuteError, JobError, JobHandle,
+    Retrier, WorkerPool,
+};
+
+/// How many times a job is attempted, and how long a failed attempt
+/// waits before the next one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: usize,
+    backoff: Duration,
+    exponential: bool,
+    max_backoff: Duration,
+    jitter: u32,
+}
+
+impl RetryPolicy {
+    /// Constructs a new RetryPolicy. The backoff doubles after each
+    /// failed retry.
+    ///
+    /// **max_attempts**: usize - the attempts in total, the first one
+    /// included. \
+    /// **backoff**: Duration - the wait before the first retry.
+    pub fn new(max_attempts: usize, backoff: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            backoff,
+            exponential: true,
+            max_backoff: Duration::MAX,
+            jitter: 0,
+        }
+    }
+
+    /// Constructs a new RetryPolicy waiting the same backoff before
+    /// each retry.
+    ///
+    /// **max_attempts**: usize - the attempts in total, the first one
+    /// included. \
+    /// **backoff**: Duration - the wait before each retry.
+    pub fn fixed(max_attempts: usize, backoff: Duration) -> RetryPolicy {
+        RetryPolicy {
+            exponential: false,
+            ..RetryPolicy::new(max_attempts, backoff)
+        }
+    }
+
+    /// Caps the backoff, so an exponential backoff stops growing.
+    ///
+    /// **max_backoff**: Duration - the longest wait before a retry.
+    pub fn max_backoff(mut self, max_backoff: Duration) -> RetryPolicy {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Shortens each wait by a random share, up to percent of it, so
+    /// jobs that failed together don't retry together. The numbers are
+    /// drawn from the generator of the worker, see `Builder::seed`.
+    ///
+    /// **percent**: u32 - the most a wait is shortened, up to 100.
+    pub fn jitter(mut self, percent: u32) -> RetryPolicy {
+        self.jitter = percent.min(100);
+        self
+    }
+
+    // The wait after the given failed attempt, counting from 1, before
+    // the jitter.
+    fn delay(&self, attempt: usize) -> Duration {
+        let delay = if self.exponential {
+            let doublings = attempt.saturating_sub(1).min(31) as u32;
+            self.backoff.saturating_mul(1 << doublings)
+        } else {
+            self.backoff
+        };
+        delay.min(self.max_backoff)
+    }
+
+    // The wait after the given failed attempt, with the jitter.
+    fn jittered(&self, attempt: usize) -> Duration {
+        let delay = self.delay(attempt);
+        if self.jitter == 0 {
+            return delay;
+        }
+        let cut = with_worker_rng(|rng| rng.next_f64()) * f64::from(self.jitter) / 100.0;
+        delay.mul_f64(1.0 - cut)
+    }
+}
+
+// One attempt of a job, which sends the next one to the retry queue
+// when it fails. The last attempt sends its result to the handle, if
+// there is one.
+struct Attempt<J, T, E> {
+    f: Arc<J>,
+    policy: RetryPolicy,
+    retrier: Retrier,
+    number: usize,
+    done: Option<mpsc::Sender<Result<Result<T, E>, JobError>>>,
+}
+
+impl<J, T, E> Attempt<J, T, E>
+where
+    J: Fn() -> Result<T, E> + Send + Sync + 'static,
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    fn run(self) {
+        set_attempt(self.number);
+        let result = match &self.done {
+            // a panic ends the job, it is not retried
+            Some(done) => match catch(|| (self.f)()) {
+                Ok(result) => result,
+                Err(payload) => {
+                    let _ = done.send(Err(JobError::Panicked(payload)));
+                    return;
+                }
+            },
+            None => (self.f)(),
+        };
+        let err = match result {
+            Err(err) if self.number < self.policy.max_attempts => err,
+            result => return self.finish(result),
+        };
+        let delay = self.policy.jittered(self.number);
+        let next = Attempt {
+            f: Arc::clone(&self.f),
+            policy: self.policy,
+            retrier: self.retrier.clone(),
+            number: self.number + 1,
+            done: self.done.clone(),
+        };
+        // a dropped retry is counted by the retry queue, and the job
+        // ends with the error of this attempt
+        if !self
+            .retrier
+            .retry_after(delay, Box::new(move || next.run()))
+        {
+            self.finish(Err(err));
+        }
+    }
+
+    // Sends the result of the last attempt to the handle, and a failed
+    // job to the dead letter sink.
+    fn finish(self, result: Result<T, E>) {
+        match (self.done, result) {
+            (Some(done), result) => {
+                if result.is_err() {
+                    self.retrier.dead_letter(None);
+                }
+                // the handle may have been dropped, nobody waits the result
+                let _ = done.send(Ok(result));
+            }
+            (None, Err(err)) => self.retrier.dead_letter(Some(Box::new(err))),
+            (None, Ok(_)) => {}
+        }
+    }
+}
+
+impl WorkerPool {
+    /// Executes a job, and runs it again when it returns an error, as
+    /// allowed by the policy. Retries wait in the retry queue and are
+    /// dropped when it is full. The error of the last attempt goes to
+    /// the dead letter sink of the pool, see `Builder::dead_letters`.
+    ///
+    /// **policy**: RetryPolicy - the attempts and backoff. \
+    /// **f**: A Fn closure called on each attempt. \
+    /// **returns**: Ok if the first attempt was queued, or the
+    /// ExecuteError that rejected it.
+    pub fn execute_with_retry<J, E>(&self, policy: RetryPolicy, f: J) -> Result<(), ExecuteError>
+    where
+        J: Fn() -> Result<(), E> + Send + Sync + 'static,
+        E: Send + 'static,
+    {
+        let attempt = Attempt {
+            f: Arc::new(f),
+            policy,
+            retrier: self.retrier(),
+            number: 1,
+            done: None,
+        };
+        self.execute(move || attempt.run())
+    }
+
+    /// Same as `execute_with_retry`, but returns a handle resolving with
+    /// the result of the last attempt: the first Ok, or the error of the
+    /// last attempt allowed by the policy, or of the attempt whose retry
+    /// was dropped. A panicking attempt isn't retried, and the handle
+    /// reports the panic.
+    ///
+    /// **policy**: RetryPolicy - the attempts and backoff. \
+    /// **f**: A Fn closure called on each attempt. \
+    /// **returns**: a JobHandle to the final result, or the ExecuteError
+    /// that rejected the first attempt.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use rpools::retry::RetryPolicy;
+    /// use std::time::Duration;
+    ///
+    /// let pool = WorkerPool::new(2);
+    /// let policy = RetryPolicy::fixed(3, Duration::from_millis(1)).jitter(50);
+    ///
+    /// let handle = pool
+    ///     .submit_with_retry(policy, || "10.0.0.1:80".parse::<std::net::SocketAddr>())
+    ///     .unwrap();
+    /// assert_eq!(80, handle.join().unwrap().unwrap().port());
+    /// ```
+    pub fn submit_with_retry<J, T, E>(
+        &self,
+        policy: RetryPolicy,
+        f: J,
+    ) -> Result<JobHandle<Result<T, E>>, ExecuteError>
+    where
+        J: Fn() -> Result<T, E> + Send + Sync + 'static,
+        T: Send + 'static,
+        E: Send + 'static,
+    {
+        let (done, handle) = handle_channel();
+        let attempt = Attempt {
+            f: Arc::new(f),
+            policy,
+            retrier: self.retrier(),
+            number: 1,
+            done: Some(done),
+        };
+        self.execute(move || attempt.run())?;
+        Ok(handle)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::pool::Builder;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn retry_policy_should_double_the_backoff() {
+        let policy = RetryPolicy::new(5, Duration::from_millis(10));
+        assert_eq!(Duration::from_millis(10), policy.delay(1));
+        assert_eq!(Duration::from_millis(40), policy.delay(3));
+        assert_eq!(Duration::MAX, RetryPolicy::new(99, Duration::MAX).delay(40));
+
+        let capped = policy.max_backoff(Duration::from_millis(25));
+        assert_eq!(Duration::from_millis(25), capped.delay(3));
+        let fixed = RetryPolicy::fixed(5, Duration::from_millis(10));
+        assert_eq!(Duration::from_millis(10), fixed.delay(4));
+        for attempt in 1..50 {
+            let jittered = fixed.jitter(30).jittered(attempt);
+            assert!(jittered > Duration::from_millis(6) && jittered <= fixed.delay(attempt));
+        }
+    }
+
+    #[test]
+    fn workerpool_should_retry_until_max_attempts() {
+        let pool = WorkerPool::new(2);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        pool.execute_with_retry(RetryPolicy::new(3, Duration::from_millis(1)), move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            Err(())
+        })
+        .unwrap();
+
+        while pool.metrics().retrying > 0 || calls.load(Ordering::Relaxed) < 3 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        pool.wait();
+        assert_eq!(3, calls.load(Ordering::Relaxed));
+        assert_eq!(0, pool.metrics().retries_rejected);
+    }
+
+    #[test]
+    fn submit_with_retry_should_resolve_with_the_last_result() {
+        let pool = WorkerPool::new(2);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let policy = RetryPolicy::fixed(3, Duration::from_millis(1));
+        let handle = pool
+            .submit_with_retry(policy, move || {
+                match counter.fetch_add(1, Ordering::Relaxed) {
+                    2 => Ok("third time lucky"),
+                    n => Err(n),
+                }
+            })
+            .unwrap();
+        assert_eq!(Ok("third time lucky"), handle.join().unwrap());
+
+        let failing = pool.submit_with_retry(policy, || Err::<(), _>("down"));
+        assert_eq!(Err("down"), failing.unwrap().join().unwrap());
+
+        let panicking = pool.submit_with_retry(policy, || -> Result<(), ()> { panic!("boom") });
+        assert!(matches!(
+            panicking.unwrap().join(),
+            Err(JobError::Panicked(_))
+        ));
+    }
+
+    #[test]
+    fn submit_with_retry_should_end_with_the_error_of_a_dropped_retry() {
+        let pool = Builder::new(1).retry_limit(0).build();
+        let policy = RetryPolicy::new(5, Duration::from_secs(60));
+        let handle = pool.submit_with_retry(policy, || Err::<(), _>(7)).unwrap();
+        assert_eq!(Err(7), handle.join().unwrap());
+        assert_eq!(1, pool.metrics().retries_rejected);
+    }
+
+    #[test]
+    fn workerpool_should_drop_retries_over_the_retry_limit() {
+        let pool = Builder::new(2).retry_limit(1).build();
+        let policy = RetryPolicy::new(2, Duration::from_secs(60));
+        for _ in 0..3 {
+            pool.execute_with_retry(policy, || Err(())).unwrap();
+        }
+        pool.wait();
+
+        let metrics = pool.metrics();
+        assert_eq!(1, metrics.retrying);
+        assert_eq!(2, metrics.retries_rejected);
+    }
+}
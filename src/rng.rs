@@ -0,0 +1,110 @@
+//! ## Rng
+//!
+//! This module has the random number generator each worker owns. With
+//! `Builder::seed`, the generator of each worker is seeded from the pool
+//! seed and the worker id, so the numbers drawn on a worker are the same
+//! run to run. Jobs reach it with `JobContext::with_rng`.
+//!
+//! Which worker runs which job is still up to the scheduler, so for
+//! fully reproducible results, draw numbers in a pool of one worker, or
+//! seed a generator per job with `WorkerRng::new`.
+//!
+//! ### Examples
+//! ```
+//! use rpools::pool::Builder;
+//!
+//! let pool = Builder::new(1).seed(42).build();
+//! let estimate = pool
+//!     .job_with_context(|ctx| {
+//!         let inside = (0..10_000)
+//!             .filter(|_| {
+//!                 ctx.with_rng(|rng| {
+//!                     let (x, y) = (rng.next_f64(), rng.next_f64());
+//!                     x * x + y * y <= 1.0
+//!                 })
+//!             })
+//!             .count();
+//!         4.0 * inside as f64 / 10_000.0
+//!     })
+//!     .submit()
+//!     .unwrap()
+//!     .join()
+//!     .unwrap();
+//!
+//! assert!((estimate - std::f64::consts::PI).abs() < 0.1);
+//! ```
+
+use std::ops::Range;
+
+/// A small and fast pseudo random generator, after SplitMix64. It is
+/// not suitable for cryptography.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerRng {
+    state: u64,
+}
+
+impl WorkerRng {
+    /// Constructs a new generator from a seed.
+    ///
+    /// **seed**: u64 - the same seed gives the same numbers.
+    pub fn new(seed: u64) -> WorkerRng {
+        WorkerRng { state: seed }
+    }
+
+    // Constructs the generator of a worker, mixing the pool seed with
+    // the worker id so neighbour workers get unrelated sequences.
+    pub(crate) fn for_worker(seed: u64, worker: usize) -> WorkerRng {
+        let mut mixer = WorkerRng::new(seed ^ (worker as u64).rotate_left(32));
+        WorkerRng::new(mixer.next_u64())
+    }
+
+    /// Returns the next random u64.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a random f64 in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        // the 53 high bits fill the mantissa
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns a random u64 in the range. Panics if it is empty.
+    ///
+    /// **range**: Range<u64> - the values to pick from.
+    pub fn gen_range(&mut self, range: Range<u64>) -> u64 {
+        assert!(range.start < range.end, "empty range");
+        let span = range.end - range.start;
+        let scaled = (self.next_u64() as u128 * span as u128) >> 64;
+        range.start + scaled as u64
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::WorkerRng;
+
+    #[test]
+    fn worker_rng_should_repeat_sequences_for_the_same_seed() {
+        let draw = |mut rng: WorkerRng| (0..4).map(|_| rng.next_u64()).collect::<Vec<_>>();
+        assert_eq!(draw(WorkerRng::new(7)), draw(WorkerRng::new(7)));
+        assert_ne!(draw(WorkerRng::new(7)), draw(WorkerRng::new(8)));
+        assert_ne!(
+            draw(WorkerRng::for_worker(7, 0)),
+            draw(WorkerRng::for_worker(7, 1))
+        );
+    }
+
+    #[test]
+    fn worker_rng_should_stay_in_range() {
+        let mut rng = WorkerRng::new(1);
+        for _ in 0..1000 {
+            assert!((10..20).contains(&rng.gen_range(10..20)));
+            assert!((0.0..1.0).contains(&rng.next_f64()));
+        }
+    }
+}
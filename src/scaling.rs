@@ -0,0 +1,84 @@
+// ## Scaling
+//
+// Policies that decide how many workers a pool should run. They are
+// pure state machines fed with periodic samples, so the worker
+// management itself stays in the pool module.
+
+// A hill climbing policy that looks for the worker count with the
+// best throughput. After each sample it keeps moving in the same
+// direction while completions per second improve, and turns around
+// when they get worse. With an empty queue, the throughput only
+// reflects the submission rate, so it shrinks towards the minimum.
+pub(crate) struct HillClimber {
+    min: usize,
+    max: usize,
+    step: isize,
+    last: Option<f64>,
+}
+
+impl HillClimber {
+    // Constructs a new HillClimber bounded by min and max workers.
+    pub(crate) fn new(min: usize, max: usize) -> HillClimber {
+        HillClimber {
+            min,
+            max,
+            step: 1,
+            last: None,
+        }
+    }
+
+    // Returns the next worker count given the current one, the
+    // completions per second measured with it, and if jobs were
+    // waiting in the queue.
+    pub(crate) fn next(&mut self, current: usize, throughput: f64, backlog: bool) -> usize {
+        if !backlog {
+            self.last = None;
+            self.step = 1;
+            return current.saturating_sub(1).max(self.min);
+        }
+
+        if let Some(last) = self.last {
+            if throughput < last {
+                self.step = -self.step;
+            }
+        }
+        self.last = Some(throughput);
+
+        let next = current as isize + self.step;
+        let next = next.max(self.min as isize).min(self.max as isize) as usize;
+        if next == current {
+            // bounced on a limit, explore the other direction next time
+            self.step = -self.step;
+        }
+        next
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::HillClimber;
+
+    #[test]
+    fn hill_climber_should_grow_while_throughput_improves() {
+        let mut climber = HillClimber::new(1, 8);
+        assert_eq!(3, climber.next(2, 10.0, true));
+        assert_eq!(4, climber.next(3, 15.0, true));
+        assert_eq!(5, climber.next(4, 20.0, true));
+    }
+
+    #[test]
+    fn hill_climber_should_turn_around_when_throughput_drops() {
+        let mut climber = HillClimber::new(1, 8);
+        assert_eq!(5, climber.next(4, 20.0, true));
+        assert_eq!(4, climber.next(5, 12.0, true));
+        assert_eq!(3, climber.next(4, 15.0, true));
+    }
+
+    #[test]
+    fn hill_climber_should_respect_bounds_and_shrink_when_idle() {
+        let mut climber = HillClimber::new(2, 3);
+        assert_eq!(3, climber.next(3, 10.0, true));
+        assert_eq!(2, climber.next(3, 10.0, true));
+        assert_eq!(2, climber.next(2, 0.0, false));
+    }
+}
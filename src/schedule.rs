@@ -0,0 +1,401 @@
+//! ## Schedule
+//!
+//! This module runs jobs in the pool from cron expressions, so services
+//! can embed periodic maintenance jobs. Expressions have six fields,
+//! `second minute hour day-of-month month day-of-week`, or five without
+//! the seconds, which are then 0. Each field accepts `*`, numbers,
+//! ranges `a-b`, steps `*/n` or `a-b/n`, and lists of those separated by
+//! commas. Days of the week go from 0 (Sunday) to 7 (Sunday again).
+//! Times are matched in UTC.
+//!
+//! It is only available with the `schedule` feature.
+//!
+//! ### Examples
+//! ```
+//! use rpools::pool::WorkerPool;
+//! use rpools::schedule::CronSchedule;
+//!
+//! let pool = WorkerPool::new(2);
+//! let every_five_minutes: CronSchedule = "0 */5 * * * *".parse().unwrap();
+//!
+//! let handle = pool
+//!     .execute_cron(&every_five_minutes, || println!("vacuum"))
+//!     .unwrap();
+//! # handle.cancel();
+//! ```
+
+use std::{
+    error::Error,
+    fmt::Display,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::pool::{ExecuteError, ScheduledHandle, WorkerPool};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+// Every matching time repeats within this many years, the longest gap
+// being between two February 29 around a century.
+const HORIZON_YEARS: i64 = 9;
+
+/// Errors returned when parsing a cron expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CronError {
+    /// The expression doesn't have 5 or 6 fields. Holds how many it has.
+    FieldCount(usize),
+    /// A field can't be parsed or is out of range. Holds the field.
+    InvalidField(String),
+    /// The expression is valid but matches no date, like February 30.
+    NeverMatches,
+}
+
+impl Display for CronError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CronError::FieldCount(count) => {
+                write!(f, "a cron expression has 5 or 6 fields, not {}", count)
+            }
+            CronError::InvalidField(field) => write!(f, "invalid cron field {}", field),
+            CronError::NeverMatches => write!(f, "the cron expression matches no date"),
+        }
+    }
+}
+
+impl Error for CronError {}
+
+/// A parsed cron expression. Each field is kept as a bit set of the
+/// values it matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    seconds: u64,
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    // with both day fields restricted, a day matching either one matches
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl CronSchedule {
+    /// Parses a cron expression.
+    ///
+    /// **expression**: &str - the cron expression. \
+    /// **returns**: a CronSchedule, or the CronError found.
+    pub fn parse(expression: &str) -> Result<CronSchedule, CronError> {
+        let fields: Vec<_> = expression.split_whitespace().collect();
+        let fields = match fields.len() {
+            6 => fields,
+            5 => [&["0"], &fields[..]].concat(),
+            count => return Err(CronError::FieldCount(count)),
+        };
+
+        let mut days_of_week = parse_field(fields[5], 0, 7)?;
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week |= 1;
+        }
+        let schedule = CronSchedule {
+            seconds: parse_field(fields[0], 0, 59)?,
+            minutes: parse_field(fields[1], 0, 59)?,
+            hours: parse_field(fields[2], 0, 23)?,
+            days_of_month: parse_field(fields[3], 1, 31)?,
+            months: parse_field(fields[4], 1, 12)?,
+            days_of_week,
+            any_day_of_month: fields[3] == "*",
+            any_day_of_week: fields[5] == "*",
+        };
+        if schedule.next_after(UNIX_EPOCH).is_none() {
+            return Err(CronError::NeverMatches);
+        }
+        Ok(schedule)
+    }
+
+    /// Returns the first time matching the schedule strictly after time,
+    /// with a resolution of one second.
+    ///
+    /// **time**: SystemTime - the time to search from. \
+    /// **returns**: the next matching time, or None before 1970.
+    ///
+    /// ### Examples
+    /// ```
+    /// use rpools::schedule::CronSchedule;
+    /// use std::time::{Duration, UNIX_EPOCH};
+    ///
+    /// let hourly = CronSchedule::parse("0 * * * *").unwrap();
+    /// let next = hourly.next_after(UNIX_EPOCH).unwrap();
+    ///
+    /// assert_eq!(UNIX_EPOCH + Duration::from_secs(3600), next);
+    /// ```
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let mut t = time.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64 + 1;
+        let limit = civil_from_days(t.div_euclid(SECONDS_PER_DAY)).0 + HORIZON_YEARS;
+        loop {
+            let days = t.div_euclid(SECONDS_PER_DAY);
+            let (year, month, day) = civil_from_days(days);
+            if year > limit {
+                return None;
+            }
+            let rem = t.rem_euclid(SECONDS_PER_DAY);
+            let (hour, minute, second) = (rem / 3600, rem % 3600 / 60, rem % 60);
+
+            t = if !matches(self.months, month) {
+                let (year, month) = if month == 12 {
+                    (year + 1, 1)
+                } else {
+                    (year, month + 1)
+                };
+                days_from_civil(year, month, 1) * SECONDS_PER_DAY
+            } else if !self.matches_day(day, (days + 4).rem_euclid(7)) {
+                (days + 1) * SECONDS_PER_DAY
+            } else if !matches(self.hours, hour) {
+                days * SECONDS_PER_DAY + (hour + 1) * 3600
+            } else if !matches(self.minutes, minute) {
+                days * SECONDS_PER_DAY + hour * 3600 + (minute + 1) * 60
+            } else if !matches(self.seconds, second) {
+                t + 1
+            } else {
+                return Some(UNIX_EPOCH + Duration::from_secs(t as u64));
+            };
+        }
+    }
+
+    // Checks the day fields, with 0 as Sunday for the weekday.
+    fn matches_day(&self, day: i64, weekday: i64) -> bool {
+        let by_month = matches(self.days_of_month, day);
+        let by_week = matches(self.days_of_week, weekday);
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (false, false) => by_month || by_week,
+            _ => by_month && by_week,
+        }
+    }
+}
+
+impl FromStr for CronSchedule {
+    type Err = CronError;
+
+    fn from_str(expression: &str) -> Result<CronSchedule, CronError> {
+        CronSchedule::parse(expression)
+    }
+}
+
+// Returns true if value is in the bit set.
+fn matches(set: u64, value: i64) -> bool {
+    set & (1 << value) != 0
+}
+
+// Parses a field into a bit set of the values between min and max it
+// matches.
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, CronError> {
+    let invalid = || CronError::InvalidField(field.to_string());
+    let number = |text: &str| {
+        text.parse::<u64>()
+            .ok()
+            .filter(|n| (min..=max).contains(n))
+            .ok_or_else(invalid)
+    };
+
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step = step.parse::<u64>().ok().filter(|&s| s > 0);
+                (range, Some(step.ok_or_else(invalid)?))
+            }
+            None => (part, None),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (number(first)?, number(last)?),
+            // a single value with a step runs up to the maximum
+            None if step.is_some() => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if first > last {
+            return Err(invalid());
+        }
+        for value in (first..=last).step_by(step.unwrap_or(1) as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+// Converts a date of the proleptic Gregorian calendar to days since
+// 1970-01-01, after Howard Hinnant's algorithm.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = (month + 9) % 12;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// Converts days since 1970-01-01 to a (year, month, day) date, the
+// inverse of days_from_civil.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Converts a wall clock time to an Instant, for the pool timer.
+fn to_instant(time: SystemTime) -> Instant {
+    let delay = time
+        .duration_since(SystemTime::now())
+        .unwrap_or(Duration::ZERO);
+    Instant::now() + delay
+}
+
+impl WorkerPool {
+    /// Executes a job each time the wall clock matches a cron schedule.
+    /// As with `execute_at_fixed_rate`, a run still queued or running
+    /// makes the next one be skipped.
+    ///
+    /// **schedule**: &CronSchedule - when to run the job. \
+    /// **f**: A Fn closure called on each run. \
+    /// **returns**: a ScheduledHandle to stop the schedule, or
+    /// ExecuteError::Shutdown.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use rpools::schedule::CronSchedule;
+    ///
+    /// let pool = WorkerPool::new(1);
+    /// let nightly = CronSchedule::parse("30 2 * * 1-5").unwrap();
+    ///
+    /// let handle = pool.execute_cron(&nightly, || println!("backup")).unwrap();
+    /// assert!(handle.cancel());
+    /// ```
+    pub fn execute_cron<J>(
+        &self,
+        schedule: &CronSchedule,
+        f: J,
+    ) -> Result<ScheduledHandle, ExecuteError>
+    where
+        J: Fn() + Send + Sync + 'static,
+    {
+        let schedule = schedule.clone();
+        let first = schedule
+            .next_after(SystemTime::now())
+            .expect("a parsed schedule matches within its horizon");
+        // the wall clock may lag behind the timer, so the search starts
+        // from the last due time at least, never running a time twice
+        let last = Mutex::new(first);
+        let next = move |_| {
+            let mut last = last.lock().expect("Cant acquire lock");
+            let from = SystemTime::now().max(*last);
+            let due = schedule.next_after(from)?;
+            *last = due;
+            Some(to_instant(due))
+        };
+        self.schedule_recurring(to_instant(first), next, f)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    // Returns the time of a UTC date.
+    fn utc(year: i64, month: i64, day: i64, hour: u64, minute: u64, second: u64) -> SystemTime {
+        let days = days_from_civil(year, month, day) as u64;
+        let seconds = days * 86400 + hour * 3600 + minute * 60 + second;
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn cron_should_convert_dates_both_ways() {
+        assert_eq!(0, days_from_civil(1970, 1, 1));
+        assert_eq!((2024, 2, 29), civil_from_days(days_from_civil(2024, 2, 29)));
+        assert_eq!((2000, 3, 1), civil_from_days(days_from_civil(2000, 3, 1)));
+    }
+
+    #[test]
+    fn cron_should_reject_invalid_expressions() {
+        assert_eq!(Err(CronError::FieldCount(3)), CronSchedule::parse("* * *"));
+        assert_eq!(
+            Err(CronError::InvalidField("60".to_string())),
+            CronSchedule::parse("60 * * * * *")
+        );
+        assert_eq!(
+            Err(CronError::InvalidField("*/0".to_string())),
+            CronSchedule::parse("*/0 * * * *")
+        );
+        assert_eq!(
+            Err(CronError::InvalidField("5-1".to_string())),
+            CronSchedule::parse("5-1 * * * *")
+        );
+        assert_eq!(
+            Err(CronError::NeverMatches),
+            CronSchedule::parse("0 0 30 2 *")
+        );
+    }
+
+    #[test]
+    fn cron_should_find_next_matching_times() {
+        let every_five: CronSchedule = "0 */5 * * * *".parse().unwrap();
+        assert_eq!(
+            Some(utc(2024, 1, 1, 0, 5, 0)),
+            every_five.next_after(utc(2024, 1, 1, 0, 0, 0))
+        );
+
+        let monday_noon: CronSchedule = "0 0 12 * * 1".parse().unwrap();
+        assert_eq!(
+            Some(utc(2024, 1, 8, 12, 0, 0)),
+            monday_noon.next_after(utc(2024, 1, 1, 13, 0, 0))
+        );
+
+        let leap_day: CronSchedule = "0 0 29 2 *".parse().unwrap();
+        assert_eq!(
+            Some(utc(2024, 2, 29, 0, 0, 0)),
+            leap_day.next_after(utc(2023, 3, 1, 0, 0, 0))
+        );
+
+        // restricted day fields match on either one
+        let first_or_sunday: CronSchedule = "0 0 1 * 0".parse().unwrap();
+        assert_eq!(
+            Some(utc(2024, 1, 7, 0, 0, 0)),
+            first_or_sunday.next_after(utc(2024, 1, 1, 0, 0, 0))
+        );
+        assert_eq!(
+            Some(utc(2023, 12, 31, 0, 0, 0)),
+            "0 0 * * 7"
+                .parse::<CronSchedule>()
+                .unwrap()
+                .next_after(utc(2023, 12, 30, 0, 0, 0))
+        );
+    }
+
+    #[test]
+    fn workerpool_should_execute_cron_jobs() {
+        let pool = WorkerPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        let every_second = CronSchedule::parse("* * * * * *").unwrap();
+        let handle = pool
+            .execute_cron(&every_second, move || {
+                let _ = tx.send(SystemTime::now());
+            })
+            .unwrap();
+
+        let first = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let second = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(second.duration_since(first).unwrap() >= Duration::from_millis(500));
+        handle.cancel();
+    }
+}
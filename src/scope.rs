@@ -0,0 +1,451 @@
+//! ## Scope
+//!
+//! This module lets jobs borrow data from the stack of the thread that
+//! sends them. A scope doesn't return before every job spawned in it has
+//! finished, so the borrows can't outlive the data. While waiting, the
+//! calling thread runs pending jobs of the scope too, so a scope opened
+//! from inside a job can't starve the pool.
+//!
+//! ### Examples
+//! ```
+//! use rpools::pool::WorkerPool;
+//!
+//! let pool = WorkerPool::new(4);
+//! let mut chunks = vec![vec![1, 2], vec![3, 4], vec![5, 6]];
+//!
+//! pool.scope(|s| {
+//!     for chunk in chunks.iter_mut() {
+//!         s.spawn(move || chunk.iter_mut().for_each(|x| *x *= 10));
+//!     }
+//! });
+//!
+//! assert_eq!(vec![vec![10, 20], vec![30, 40], vec![50, 60]], chunks);
+//! ```
+
+use std::{
+    any::Any,
+    collections::VecDeque,
+    marker::PhantomData,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+};
+
+use crate::pool::WorkerPool;
+
+// The jobs of a scope, erased to 'static. The scope outlives them.
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// The order in which the pending jobs of a scope are started.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ScopeOrder {
+    /// The oldest pending job first, for fairness in streaming scopes.
+    #[default]
+    Fifo,
+    /// The newest pending job first, for recursive scopes that split
+    /// work, keeping the recently split data hot in cache.
+    Lifo,
+}
+
+struct Jobs {
+    pending: VecDeque<Job>,
+    // spawned and not finished yet, including the running ones
+    unfinished: usize,
+    panic: Option<Box<dyn Any + Send + 'static>>,
+}
+
+// The state shared between a scope and the pool jobs that run its jobs.
+struct State {
+    order: ScopeOrder,
+    jobs: Mutex<Jobs>,
+    finished: Condvar,
+}
+
+impl State {
+    // Takes the next pending job, in the order of the scope.
+    fn pop(jobs: &mut Jobs, order: ScopeOrder) -> Option<Job> {
+        match order {
+            ScopeOrder::Fifo => jobs.pending.pop_front(),
+            ScopeOrder::Lifo => jobs.pending.pop_back(),
+        }
+    }
+
+    // Runs the next pending job, if any. Each spawn sends one call of
+    // this to the pool, so the pool runs the jobs in the scope order,
+    // whichever of them were already run by the waiting thread.
+    fn run_next(&self) {
+        let job = {
+            let mut jobs = self.jobs.lock().expect("Cant acquire lock");
+            State::pop(&mut jobs, self.order)
+        };
+        if let Some(job) = job {
+            self.run(job);
+        }
+    }
+
+    // Runs a job, keeping the first panic to resume it in the scope.
+    fn run(&self, job: Job) {
+        let result = panic::catch_unwind(AssertUnwindSafe(job));
+        let mut jobs = self.jobs.lock().expect("Cant acquire lock");
+        if let Err(payload) = result {
+            jobs.panic.get_or_insert(payload);
+        }
+        jobs.unfinished -= 1;
+        if jobs.unfinished == 0 {
+            self.finished.notify_all();
+        }
+    }
+
+    // Blocks until every job finished, running pending ones meanwhile.
+    fn wait(&self) {
+        let mut jobs = self.jobs.lock().expect("Cant acquire lock");
+        while jobs.unfinished > 0 {
+            match State::pop(&mut jobs, self.order) {
+                Some(job) => {
+                    drop(jobs);
+                    self.run(job);
+                    jobs = self.jobs.lock().expect("Cant acquire lock");
+                }
+                None => {
+                    jobs = self
+                        .finished
+                        .wait(jobs)
+                        .expect("Cant block the current thread");
+                }
+            }
+        }
+    }
+}
+
+/// A scope to spawn jobs that borrow data living for 'env. It is
+/// created with `WorkerPool::scope` or `WorkerPool::scope_with_order`.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'env WorkerPool,
+    state: Arc<State>,
+    // invariant lifetimes, as in std::thread::Scope
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Spawns a job in the scope. The job may borrow anything that
+    /// outlives the scope, including the scope itself to spawn more jobs.
+    /// If the pool is shut down, the job runs on the current thread.
+    ///
+    /// **f**: A FnOnce closure that may borrow from the scope.
+    pub fn spawn<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(f);
+        // SAFETY: the scope waits for every job it spawned before
+        // returning, so the job never outlives what it borrows.
+        let job = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+        {
+            let mut jobs = self.state.jobs.lock().expect("Cant acquire lock");
+            jobs.pending.push_back(job);
+            jobs.unfinished += 1;
+        }
+
+        let state = Arc::clone(&self.state);
+        if self.pool.execute(move || state.run_next()).is_err() {
+            self.state.run_next();
+        }
+    }
+}
+
+impl WorkerPool {
+    /// Opens a scope where jobs can borrow local data, and waits until
+    /// all of them finish. Pending jobs start in FIFO order. If a job or
+    /// the closure panics, the panic is resumed here once all jobs end.
+    ///
+    /// **f**: A FnOnce closure that spawns jobs with the given Scope. \
+    /// **returns**: the value returned by f.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// let pool = WorkerPool::new(2);
+    /// let counter = AtomicUsize::new(0);
+    ///
+    /// pool.scope(|s| {
+    ///     for _ in 0..10 {
+    ///         s.spawn(|| {
+    ///             counter.fetch_add(1, Ordering::Relaxed);
+    ///         });
+    ///     }
+    /// });
+    ///
+    /// assert_eq!(10, counter.into_inner());
+    /// ```
+    pub fn scope<'env, F, R>(&'env self, f: F) -> R
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        self.scope_with_order(ScopeOrder::Fifo, f)
+    }
+
+    /// Same as `scope`, but the pending jobs of the scope start in the
+    /// given order, independent of the pool.
+    ///
+    /// **order**: ScopeOrder - FIFO or LIFO. \
+    /// **f**: A FnOnce closure that spawns jobs with the given Scope. \
+    /// **returns**: the value returned by f.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    /// use rpools::scope::{Scope, ScopeOrder};
+    ///
+    /// let pool = WorkerPool::new(4);
+    /// let mut data = vec![1; 1024];
+    ///
+    /// fn split<'scope>(s: &'scope Scope<'scope, '_>, data: &'scope mut [i32]) {
+    ///     if data.len() <= 64 {
+    ///         data.iter_mut().for_each(|x| *x *= 2);
+    ///         return;
+    ///     }
+    ///     let (left, right) = data.split_at_mut(data.len() / 2);
+    ///     s.spawn(move || split(s, left));
+    ///     s.spawn(move || split(s, right));
+    /// }
+    ///
+    /// pool.scope_with_order(ScopeOrder::Lifo, |s| split(s, &mut data));
+    /// assert!(data.iter().all(|&x| x == 2));
+    /// ```
+    pub fn scope_with_order<'env, F, R>(&'env self, order: ScopeOrder, f: F) -> R
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        let scope = Scope {
+            pool: self,
+            state: Arc::new(State {
+                order,
+                jobs: Mutex::new(Jobs {
+                    pending: VecDeque::new(),
+                    unfinished: 0,
+                    panic: None,
+                }),
+                finished: Condvar::new(),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.state.wait();
+
+        let panic = scope
+            .state
+            .jobs
+            .lock()
+            .expect("Cant acquire lock")
+            .panic
+            .take();
+        if let Some(payload) = panic {
+            panic::resume_unwind(payload);
+        }
+        result.unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+
+    /// Runs two closures, potentially in parallel, and returns both
+    /// results. a runs on the current thread while b is sent to the
+    /// pool, and if no worker took b when a returns, the current thread
+    /// runs b too. So fork_join can be nested in jobs, to split work
+    /// recursively, without waiting on a busy pool. If a closure panics,
+    /// the panic is resumed here once both end.
+    ///
+    /// **a**: A FnOnce closure run on the current thread. \
+    /// **b**: A FnOnce closure that may run on a worker. \
+    /// **returns**: the values returned by a and b.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    ///
+    /// fn sum(pool: &WorkerPool, data: &[u64]) -> u64 {
+    ///     if data.len() <= 1000 {
+    ///         return data.iter().sum();
+    ///     }
+    ///     let (left, right) = data.split_at(data.len() / 2);
+    ///     let (l, r) = pool.fork_join(|| sum(pool, left), || sum(pool, right));
+    ///     l + r
+    /// }
+    ///
+    /// let pool = WorkerPool::new(2);
+    /// let data: Vec<u64> = (1..=100_000).collect();
+    /// assert_eq!(5_000_050_000, sum(&pool, &data));
+    /// ```
+    pub fn fork_join<A, B, RA, RB>(&self, a: A, b: B) -> (RA, RB)
+    where
+        A: FnOnce() -> RA,
+        B: FnOnce() -> RB + Send,
+        RB: Send,
+    {
+        let rb = Mutex::new(None);
+        let ra = self.scope(|s| {
+            let rb = &rb;
+            s.spawn(move || *rb.lock().expect("Cant acquire lock") = Some(b()));
+            a()
+        });
+        let rb = rb.into_inner().expect("Cant acquire lock");
+        (ra, rb.expect("the scope ran b"))
+    }
+
+    /// Splits a slice in chunks and calls f on each chunk in parallel,
+    /// in a scope, so the slice is mutated in place without being moved
+    /// into the jobs. Panics of f are resumed here once all chunks end.
+    ///
+    /// **data**: &mut [T] - the slice to process. \
+    /// **chunk_size**: usize - the items in each chunk, the last one may
+    /// have less. With 0, the slice is split in one chunk per worker. \
+    /// **f**: A Fn closure called with each chunk.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rpools::pool::WorkerPool;
+    ///
+    /// let pool = WorkerPool::new(4);
+    /// let mut pixels = vec![100u8; 1920 * 1080];
+    ///
+    /// pool.for_each_chunk(&mut pixels, 0, |chunk| {
+    ///     chunk.iter_mut().for_each(|p| *p = 255 - *p);
+    /// });
+    ///
+    /// assert!(pixels.iter().all(|&p| p == 155));
+    /// ```
+    pub fn for_each_chunk<T, F>(&self, data: &mut [T], chunk_size: usize, f: F)
+    where
+        T: Send,
+        F: Fn(&mut [T]) + Sync,
+    {
+        let chunk_size = match chunk_size {
+            0 => data.len().div_ceil(self.worker_count().max(1)).max(1),
+            size => size,
+        };
+        let f = &f;
+        self.scope(|s| {
+            for chunk in data.chunks_mut(chunk_size) {
+                s.spawn(move || f(chunk));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::pool::Builder;
+    use std::cell::Cell;
+
+    // A pool without workers, so the scope runs every job while waiting
+    // and the order is deterministic.
+    fn spawn_order(order: ScopeOrder) -> Vec<usize> {
+        let pool = Builder::new(0).build();
+        let started = Mutex::new(Vec::new());
+        pool.scope_with_order(order, |s| {
+            for i in 0..4 {
+                let started = &started;
+                s.spawn(move || started.lock().unwrap().push(i));
+            }
+        });
+        started.into_inner().unwrap()
+    }
+
+    #[test]
+    fn scope_should_start_jobs_in_fifo_order() {
+        assert_eq!(vec![0, 1, 2, 3], spawn_order(ScopeOrder::Fifo));
+    }
+
+    #[test]
+    fn scope_should_start_jobs_in_lifo_order() {
+        assert_eq!(vec![3, 2, 1, 0], spawn_order(ScopeOrder::Lifo));
+    }
+
+    #[test]
+    fn scope_should_wait_for_nested_jobs() {
+        let pool = WorkerPool::new(2);
+        let counter = Mutex::new(0);
+        let total = &counter;
+        pool.scope(|s| {
+            for _ in 0..5 {
+                s.spawn(move || {
+                    for _ in 0..5 {
+                        s.spawn(move || *total.lock().unwrap() += 1);
+                    }
+                });
+            }
+        });
+        assert_eq!(25, counter.into_inner().unwrap());
+    }
+
+    #[test]
+    fn for_each_chunk_should_visit_every_item_once() {
+        let pool = WorkerPool::new(3);
+        let chunks = Mutex::new(Vec::new());
+        let mut data: Vec<usize> = (0..10).collect();
+        pool.for_each_chunk(&mut data, 4, |chunk| {
+            chunks.lock().unwrap().push(chunk.len());
+            chunk.iter_mut().for_each(|x| *x += 1);
+        });
+        assert_eq!((1..=10).collect::<Vec<_>>(), data);
+        let mut chunks = chunks.into_inner().unwrap();
+        chunks.sort_unstable();
+        assert_eq!(vec![2, 4, 4], chunks);
+
+        let calls = Mutex::new(0);
+        pool.for_each_chunk(&mut data, 0, |_| *calls.lock().unwrap() += 1);
+        assert_eq!(3, calls.into_inner().unwrap());
+        pool.for_each_chunk(&mut [0u8; 0], 0, |_| unreachable!());
+    }
+
+    #[test]
+    fn for_each_chunk_should_accept_items_that_are_not_sync() {
+        let pool = WorkerPool::new(2);
+        let mut cells: Vec<_> = (0..6).map(Cell::new).collect();
+        pool.for_each_chunk(&mut cells, 2, |chunk| {
+            chunk.iter().for_each(|cell| cell.set(cell.get() * 2));
+        });
+        assert_eq!(
+            vec![0, 2, 4, 6, 8, 10],
+            cells.iter().map(Cell::get).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn fork_join_should_not_deadlock_when_nested_in_every_worker() {
+        fn fib(pool: &WorkerPool, n: u64) -> u64 {
+            if n < 2 {
+                return n;
+            }
+            let (a, b) = pool.fork_join(|| fib(pool, n - 1), || fib(pool, n - 2));
+            a + b
+        }
+
+        let pool = Arc::new(WorkerPool::new(2));
+        let (tx, rx) = std::sync::mpsc::channel();
+        for _ in 0..2 {
+            let (inner, tx) = (Arc::clone(&pool), tx.clone());
+            pool.execute(move || tx.send(fib(&inner, 15)).unwrap())
+                .unwrap();
+        }
+        assert_eq!(vec![610, 610], rx.iter().take(2).collect::<Vec<_>>());
+        assert_eq!((1, "b"), Builder::new(0).build().fork_join(|| 1, || "b"));
+    }
+
+    #[test]
+    #[should_panic(expected = "scoped boom")]
+    fn scope_should_resume_job_panics() {
+        let pool = WorkerPool::new(2);
+        pool.scope(|s| {
+            s.spawn(|| panic!("scoped boom"));
+            s.spawn(|| {});
+        });
+    }
+}
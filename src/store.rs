@@ -0,0 +1,310 @@
+//! ## Store
+//!
+//! This module persists queued jobs, so the work queued when a process
+//! crashes can be queued again after a restart. A job sent with
+//! `WorkerPool::execute_serialized` is a name and a payload of bytes: it
+//! is saved in the QueueStore installed with `Builder::queue_store`
+//! before it is queued, and removed once it ran. On startup,
+//! `WorkerPool::recover` queues the jobs left in the store again. A
+//! Registry maps the names to the handlers that run the payloads.
+//!
+//! ### Examples
+//! ```
+//! use rpools::pool::Builder;
+//! use rpools::store::{MemoryStore, QueueStore, Registry};
+//! use std::sync::{mpsc, Arc};
+//!
+//! let (tx, rx) = mpsc::channel();
+//! let mut registry = Registry::new();
+//! registry.handler("email", move |payload: &[u8]| {
+//!     let to = String::from_utf8_lossy(payload).into_owned();
+//!     tx.send(to).unwrap();
+//! });
+//!
+//! let store = Arc::new(MemoryStore::new());
+//! let pool = Builder::new(2).queue_store(store.clone()).build();
+//! pool.execute_serialized("email", b"ana@example.com".to_vec(), &registry)
+//!     .unwrap();
+//! assert_eq!("ana@example.com", rx.recv().unwrap());
+//! pool.wait();
+//!
+//! // jobs left in the store by a crash run again on the next start
+//! store.save("email", b"bob@example.com").unwrap();
+//! let pool = Builder::new(2).queue_store(store.clone()).build();
+//! assert_eq!(1, pool.recover(&registry).unwrap());
+//! assert_eq!("bob@example.com", rx.recv().unwrap());
+//! pool.wait();
+//! assert!(store.load_pending().unwrap().is_empty());
+//! ```
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io,
+    sync::{Arc, Mutex},
+};
+
+use crate::pool::{ExecuteError, WorkerPool};
+
+// The handler of the jobs of a name.
+type Handler = Arc<dyn Fn(&[u8]) + Send + Sync + 'static>;
+
+/// A job saved in a QueueStore.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct StoredJob {
+    /// The id the store gave to the job.
+    pub id: u64,
+    /// The name of the handler of the job.
+    pub name: String,
+    /// The serialized arguments of the job.
+    pub payload: Vec<u8>,
+}
+
+/// Where the queued jobs are persisted. A store is called in the thread
+/// sending a job, to save it, and in the worker that ran it, to remove
+/// it, so it should be quick.
+pub trait QueueStore: Send + Sync {
+    /// Saves a job before it is queued.
+    ///
+    /// **job_name**: &str - the name of the handler of the job. \
+    /// **payload**: &[u8] - the serialized arguments of the job. \
+    /// **returns**: the id of the saved job, or the error of the store.
+    fn save(&self, job_name: &str, payload: &[u8]) -> io::Result<u64>;
+
+    /// Removes a job once it ran, whether it finished or panicked.
+    ///
+    /// **id**: u64 - the id returned by save.
+    fn remove(&self, id: u64) -> io::Result<()>;
+
+    /// Returns the jobs saved and not removed, in the order they were
+    /// saved.
+    fn load_pending(&self) -> io::Result<Vec<StoredJob>>;
+}
+
+/// A QueueStore keeping the jobs in memory, for tests and for processes
+/// where the store only outlives a pool, not a crash.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    state: Mutex<(u64, Vec<StoredJob>)>,
+}
+
+impl MemoryStore {
+    /// Constructs a new empty MemoryStore.
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl QueueStore for MemoryStore {
+    fn save(&self, job_name: &str, payload: &[u8]) -> io::Result<u64> {
+        let mut state = self.state.lock().expect("Cant acquire lock");
+        let (next, jobs) = &mut *state;
+        *next += 1;
+        jobs.push(StoredJob {
+            id: *next,
+            name: job_name.to_string(),
+            payload: payload.to_vec(),
+        });
+        Ok(*next)
+    }
+
+    fn remove(&self, id: u64) -> io::Result<()> {
+        let mut state = self.state.lock().expect("Cant acquire lock");
+        state.1.retain(|job| job.id != id);
+        Ok(())
+    }
+
+    fn load_pending(&self) -> io::Result<Vec<StoredJob>> {
+        Ok(self.state.lock().expect("Cant acquire lock").1.clone())
+    }
+}
+
+/// The handlers of the serialized jobs, by name.
+#[derive(Default)]
+pub struct Registry {
+    handlers: HashMap<String, Handler>,
+}
+
+impl Registry {
+    /// Constructs a new empty Registry.
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// Registers the handler of the jobs of a name, replacing the one
+    /// registered before.
+    ///
+    /// **name**: &str - the name of the jobs. \
+    /// **f**: A Fn closure that runs a job given its payload.
+    pub fn handler<F>(&mut self, name: &str, f: F) -> &mut Registry
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        self.handlers.insert(name.to_string(), Arc::new(f));
+        self
+    }
+
+    // Returns the handler of a name.
+    fn get(&self, name: &str) -> Result<Handler, StoreError> {
+        self.handlers
+            .get(name)
+            .cloned()
+            .ok_or_else(|| StoreError::UnknownJob(name.to_string()))
+    }
+}
+
+/// Errors returned when a serialized job can't be sent or recovered.
+#[derive(Debug)]
+pub enum StoreError {
+    /// No handler is registered with the name of the job.
+    UnknownJob(String),
+    /// The store failed to save or load the jobs.
+    Store(io::Error),
+    /// The pool didn't accept the job.
+    Execute(ExecuteError),
+}
+
+// Implements Display for StoreError with a summary of the problem.
+impl Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::UnknownJob(name) => write!(f, "no handler for the job {}", name),
+            StoreError::Store(err) => write!(f, "the queue store failed: {}", err),
+            StoreError::Execute(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl WorkerPool {
+    /// Sends a serialized job to the pool. The job is saved in the store
+    /// of the pool, if it has one, before it is queued, and removed once
+    /// it ran.
+    ///
+    /// **name**: &str - the name of the handler of the job. \
+    /// **payload**: Vec<u8> - the serialized arguments of the job. \
+    /// **registry**: &Registry - the handlers of the jobs. \
+    /// **returns**: Ok if the job was queued, or a StoreError. A job
+    /// that the pool didn't accept is removed from the store.
+    pub fn execute_serialized(
+        &self,
+        name: &str,
+        payload: Vec<u8>,
+        registry: &Registry,
+    ) -> Result<(), StoreError> {
+        let handler = registry.get(name)?;
+        let stored = match self.queue_store() {
+            Some(store) => {
+                let id = store.save(name, &payload).map_err(StoreError::Store)?;
+                Some((id, Arc::clone(store)))
+            }
+            None => None,
+        };
+        let queued = self.queue_stored(stored.clone(), handler, payload);
+        if queued.is_err() {
+            drop(Stored(stored));
+        }
+        queued
+    }
+
+    /// Queues the jobs left in the store of the pool, by a crash or a
+    /// pool dropped before its jobs ran. Call it once on startup, before
+    /// sending new serialized jobs.
+    ///
+    /// **registry**: &Registry - the handlers of the jobs. \
+    /// **returns**: how many jobs were queued, or a StoreError. Jobs
+    /// without a handler are left in the store.
+    pub fn recover(&self, registry: &Registry) -> Result<usize, StoreError> {
+        let store = match self.queue_store() {
+            Some(store) => store,
+            None => return Ok(0),
+        };
+        let mut queued = 0;
+        for job in store.load_pending().map_err(StoreError::Store)? {
+            if let Ok(handler) = registry.get(&job.name) {
+                let stored = Some((job.id, Arc::clone(store)));
+                self.queue_stored(stored, handler, job.payload)?;
+                queued += 1;
+            }
+        }
+        Ok(queued)
+    }
+
+    // Queues a serialized job, removing it from the store once it ran.
+    // A job dropped before it started stays in the store.
+    fn queue_stored(
+        &self,
+        stored: Option<(u64, Arc<dyn QueueStore>)>,
+        handler: Handler,
+        payload: Vec<u8>,
+    ) -> Result<(), StoreError> {
+        self.execute(move || {
+            let _done = Stored(stored);
+            handler(&payload);
+        })
+        .map_err(StoreError::Execute)
+    }
+}
+
+// Removes a job from the store when dropped, after the job ran or
+// unwound.
+struct Stored(Option<(u64, Arc<dyn QueueStore>)>);
+
+impl Drop for Stored {
+    fn drop(&mut self) {
+        if let Some((id, store)) = self.0.take() {
+            // a job left in the store runs again on recover
+            let _ = store.remove(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::pool::Builder;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn store_should_keep_jobs_until_they_run() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&runs);
+        let mut registry = Registry::new();
+        registry.handler("count", move |payload: &[u8]| {
+            counter.fetch_add(payload.len(), Ordering::SeqCst);
+        });
+        let store = Arc::new(MemoryStore::new());
+
+        let pool = Builder::new(1).queue_store(store.clone()).build();
+        assert!(matches!(
+            pool.execute_serialized("missing", vec![], &registry),
+            Err(StoreError::UnknownJob(_))
+        ));
+        pool.execute_serialized("count", vec![1, 2], &registry)
+            .unwrap();
+        pool.wait();
+        assert_eq!(2, runs.load(Ordering::SeqCst));
+        assert!(store.load_pending().unwrap().is_empty());
+
+        store.save("count", &[1]).unwrap();
+        store.save("missing", &[1]).unwrap();
+        pool.shutdown();
+        assert!(matches!(
+            pool.recover(&registry),
+            Err(StoreError::Execute(ExecuteError::Shutdown))
+        ));
+        assert_eq!(2, store.load_pending().unwrap().len());
+
+        let pool = Builder::new(1).queue_store(store.clone()).build();
+        assert_eq!(1, pool.recover(&registry).unwrap());
+        pool.wait();
+        assert_eq!(3, runs.load(Ordering::SeqCst));
+        let pending = store.load_pending().unwrap();
+        assert_eq!(
+            vec!["missing"],
+            pending.iter().map(|job| &job.name[..]).collect::<Vec<_>>()
+        );
+    }
+}
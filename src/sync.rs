@@ -2,7 +2,20 @@
 //!
 //! This module has data structures used to synchronize
 //! threads. WaitGroup is used to make a thread to wait
-//! others.
+//! others, CountDownLatch to wait for a fixed number of
+//! events, Event to signal a one-off condition, Barrier and
+//! Phaser to run jobs in lockstep phases,
+//! CancellationToken to stop jobs cooperatively, Broadcast to
+//! fan messages out to many threads, RateLimiter to pace
+//! calls to a downstream service, ShardedCounter to count
+//! from many threads without contending on a single atomic,
+//! Collector to gather the results of jobs, Promise and
+//! OnceResult to hand a single value to any number of readers,
+//! and SpinLock and RwLock to guard small state shared by jobs.
+//!
+//! CancellationToken, ShardedCounter and SpinLock only need atomics
+//! and an allocator: without the std feature, they are all this module
+//! has, for the targets without threads of their own.
 //!
 //! ### Examples
 //! ```
@@ -23,7 +36,7 @@
 //!     pool.execute(move || {
 //!         atomic.fetch_add(1, Ordering::Relaxed);
 //!         drop(wg);
-//!     });
+//!     }).unwrap();
 //! }
 //!
 //! // wait for the pool finnishes
@@ -31,41 +44,308 @@
 //! assert_eq!(njobs, atomic.load(Ordering::Relaxed));
 //! ```
 
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc, Condvar, Mutex,
+pub use crate::sync_core::{CancellationToken, ShardedCounter, SpinLock, SpinLockGuard};
+
+use std::{
+    cell::UnsafeCell,
+    ffi::c_void,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, RecvError, RecvTimeoutError, TryRecvError},
+        Arc, Condvar, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 /// A data struct to store a counter, a mutex and a condvar.
 /// It is responsible and serves as semaphore to synchronize threads.
+/// The mutex guards the generation, bumped each time the counter
+/// reaches 0, so a waiter is released by the end of its own round even
+/// if the next one already started when it wakes up.
 #[derive(Default)]
 struct Wg {
     counter: AtomicUsize,
-    mu: Mutex<bool>,
+    mu: Mutex<u64>,
     condvar: Condvar,
 }
 
+impl Wg {
+    /// Adds n to the counter, panicking instead of wrapping around. An
+    /// increment publishes nothing, so it can be relaxed, like the one
+    /// of an Arc clone.
+    fn add(&self, n: usize) {
+        self.counter
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| c.checked_add(n))
+            .expect("WaitGroup counter overflow");
+    }
+
+    /// Subtracts one from the counter, and wakes every waiting thread
+    /// when it reaches 0. Returns false if the counter was already 0.
+    /// The decrement is a release, so the writes of the task before it
+    /// are published to the threads that load the counter with acquire.
+    fn done(&self) -> bool {
+        let mut current = self.counter.load(Ordering::Relaxed);
+        loop {
+            match current {
+                0 => return false,
+                1 => {
+                    // the last decrement and the new generation happen
+                    // under the lock, so a waiter sees both or neither
+                    let mut generation = self.mu.lock().expect("Cant get the lock");
+                    match self
+                        .counter
+                        .compare_exchange(1, 0, Ordering::Release, Ordering::Relaxed)
+                    {
+                        Ok(_) => {
+                            *generation += 1;
+                            self.condvar.notify_all();
+                            return true;
+                        }
+                        Err(c) => current = c,
+                    }
+                }
+                c => match self.counter.compare_exchange_weak(
+                    c,
+                    c - 1,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return true,
+                    Err(c) => current = c,
+                },
+            }
+        }
+    }
+
+    /// Blocks until the counter is 0, or until the round running when
+    /// called ends, or until the deadline passes. Returns false on
+    /// timeout.
+    fn wait_until(&self, deadline: Option<Instant>) -> bool {
+        let mut generation = self.mu.lock().expect("Cant get the lock");
+        let round = *generation;
+        // the counter is loaded with acquire on every check, also when a
+        // new round made it non zero again, to see the writes of the tasks
+        while self.counter.load(Ordering::Acquire) != 0 && *generation == round {
+            generation = match deadline {
+                None => self
+                    .condvar
+                    .wait(generation)
+                    .expect("Cant block the current thread"),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.condvar
+                        .wait_timeout(generation, deadline - now)
+                        .expect("Cant block the current thread")
+                        .0
+                }
+            };
+        }
+        true
+    }
+}
+
 /// A public wrapper above Wg. This data structure is responsible
 /// to do the logics of the semaphore, block the target thread and
 /// wait for signals to continue processing.
+///
+/// The counter can be driven by clones, where each clone counts one
+/// until it is dropped, or Go style with `add` and `done`, when the
+/// number of tasks isn't known when the clones are made.
+///
+/// Everything a task did before dropping its clone or calling `done`
+/// happens before `wait` or `wait_timeout` return true, and before
+/// `is_done` returns true, so the results of the tasks can be read
+/// with relaxed loads or without locks after waiting.
+///
+/// Any number of threads can wait at once, sharing the WaitGroup in an
+/// Arc or by reference, and all of them are released when the counter
+/// reaches 0. Don't wait through a clone: it counts itself, so it would
+/// wait for its own drop forever.
+///
+/// A WaitGroup can be reused for rounds of tasks: once `wait` returns,
+/// the next `add` or clone starts a new round, and a later `wait` only
+/// waits for the tasks of that round. A thread still waking up from the
+/// previous round returns even if the next one already started.
+///
+/// ```
+/// use rpools::pool::WorkerPool;
+/// use rpools::sync::WaitGroup;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::sync::Arc;
+///
+/// let pool = WorkerPool::new(2);
+/// let wg = WaitGroup::default();
+/// let done = Arc::new(AtomicUsize::new(0));
+///
+/// for round in 1..=3 {
+///     for _ in 0..4 {
+///         let (wg, done) = (wg.clone(), Arc::clone(&done));
+///         pool.execute(move || {
+///             done.fetch_add(1, Ordering::Relaxed);
+///             drop(wg);
+///         })
+///         .unwrap();
+///     }
+///     wg.wait();
+///     assert_eq!(round * 4, done.load(Ordering::Relaxed));
+/// }
+/// ```
+///
+/// ### Examples
+/// ```
+/// use rpools::pool::WorkerPool;
+/// use rpools::sync::WaitGroup;
+///
+/// let pool = WorkerPool::new(2);
+/// let wg = WaitGroup::default();
+///
+/// wg.add(10);
+/// for _ in 0..10 {
+///     let wg = wg.clone();
+///     pool.execute(move || wg.done()).unwrap();
+/// }
+///
+/// wg.wait();
+/// ```
 #[derive(Default)]
-pub struct WaitGroup(Arc<Wg>);
+pub struct WaitGroup {
+    inner: Arc<Wg>,
+    // clones count one until dropped, the original doesn't
+    counted: bool,
+}
 
 impl WaitGroup {
+    /// Adds n to the counter. Each unit must be matched by a call to
+    /// `done`.
+    ///
+    /// **n**: usize - how many tasks to wait for.
+    ///
+    /// Panics if the counter overflows.
+    pub fn add(&self, n: usize) {
+        self.inner.add(n);
+    }
+
+    /// Subtracts one from the counter, marking a task added with `add`
+    /// as finished.
+    ///
+    /// Panics if the counter is already 0, as done was called more
+    /// times than tasks were added.
+    pub fn done(&self) {
+        if !self.inner.done() {
+            panic!("WaitGroup counter underflow: done called more times than add");
+        }
+    }
+
     /// Blocks the current thread and waits until counter becomes 0. If
     /// counter is 0, start processing again.
     pub fn wait(&self) {
-        let mut mutex = self.0.mu.lock().expect("Cant get the lock");
-        loop {
-            if self.0.counter.load(Ordering::Relaxed) == 0 {
-                break;
-            }
-            mutex = self
-                .0
-                .condvar
-                .wait(mutex)
-                .expect("Cant block the current thread");
-        }
+        self.inner.wait_until(None);
+    }
+
+    /// Blocks the current thread until counter becomes 0, or until the
+    /// timeout elapses, so callers can bail out of a hung set of jobs.
+    ///
+    /// **timeout**: Duration - the maximum time to wait. \
+    /// **returns**: true if the counter became 0, false on timeout.
+    ///
+    /// ### Examples
+    /// ```
+    /// use rpools::sync::WaitGroup;
+    /// use std::time::Duration;
+    ///
+    /// let wg = WaitGroup::default();
+    /// let pending = wg.clone();
+    ///
+    /// assert!(!wg.wait_timeout(Duration::from_millis(10)));
+    /// drop(pending);
+    /// assert!(wg.wait_timeout(Duration::from_millis(10)));
+    /// ```
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.inner.wait_until(Some(Instant::now() + timeout))
+    }
+
+    /// Returns the current value of the counter: the clones alive plus
+    /// the tasks added and not done yet. It may change as soon as it is
+    /// read, so use it for progress reports, not to synchronize.
+    ///
+    /// ### Examples
+    /// ```
+    /// use rpools::sync::WaitGroup;
+    ///
+    /// let wg = WaitGroup::default();
+    /// let pending = wg.clone();
+    /// wg.add(2);
+    /// assert_eq!(3, wg.count());
+    ///
+    /// wg.done();
+    /// drop(pending);
+    /// assert_eq!(1, wg.count());
+    /// assert!(!wg.is_done());
+    /// ```
+    pub fn count(&self) -> usize {
+        self.inner.counter.load(Ordering::Acquire)
+    }
+
+    /// Returns true if the counter is 0, so `wait` wouldn't block.
+    pub fn is_done(&self) -> bool {
+        self.count() == 0
+    }
+}
+
+impl WaitGroup {
+    /// Counts one task completed by foreign code, and returns it as an
+    /// opaque pointer. Hand it through FFI together with
+    /// `rpools_wait_group_done`, which the foreign callback calls once
+    /// to mark the task as done, so jobs finishing in C callbacks still
+    /// release `wait`.
+    ///
+    /// **returns**: the token, to be passed to `rpools_wait_group_done`
+    /// exactly once. A token never completed keeps `wait` blocked.
+    ///
+    /// ### Examples
+    /// ```
+    /// use rpools::sync::{rpools_wait_group_done, WaitGroup};
+    /// use std::ffi::c_void;
+    ///
+    /// // a C library taking a callback and its user data
+    /// fn register(callback: unsafe extern "C" fn(*mut c_void), data: *mut c_void) {
+    ///     unsafe { callback(data) }
+    /// }
+    ///
+    /// let wg = WaitGroup::default();
+    /// register(rpools_wait_group_done, wg.ffi_token());
+    /// wg.wait();
+    /// ```
+    pub fn ffi_token(&self) -> *mut c_void {
+        Box::into_raw(Box::new(self.clone())) as *mut c_void
+    }
+}
+
+/// Marks the task of a token made by `WaitGroup::ffi_token` as done.
+/// Null tokens are ignored.
+///
+/// # Safety
+///
+/// token must come from `WaitGroup::ffi_token`, and must not be used
+/// again after this call.
+#[no_mangle]
+pub unsafe extern "C" fn rpools_wait_group_done(token: *mut c_void) {
+    if !token.is_null() {
+        drop(Box::from_raw(token as *mut WaitGroup));
+    }
+}
+
+// Implements Debug for WaitGroup, showing the counter for diagnostics.
+impl std::fmt::Debug for WaitGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WaitGroup")
+            .field("counter", &self.count())
+            .finish()
     }
 }
 
@@ -74,8 +354,11 @@ impl Clone for WaitGroup {
     /// For each clone of this struct, increments the
     /// counter in one.
     fn clone(&self) -> Self {
-        self.0.counter.fetch_add(1, Ordering::Relaxed);
-        Self(self.0.clone())
+        self.inner.add(1);
+        Self {
+            inner: self.inner.clone(),
+            counted: true,
+        }
     }
 }
 
@@ -84,18 +367,1575 @@ impl Drop for WaitGroup {
     /// When a shared reference goes out of scope,
     /// decrement the counter in one.
     fn drop(&mut self) {
-        self.0.counter.fetch_sub(1, Ordering::Relaxed);
-        self.0.condvar.notify_one();
+        if self.counted && !self.inner.done() && !std::thread::panicking() {
+            panic!("WaitGroup counter underflow: done called more times than add");
+        }
+    }
+}
+
+/// A one-shot latch initialized with a fixed count. Threads calling
+/// `wait` block until `count_down` was called count times. Unlike a
+/// WaitGroup, the count never increments, and once it reaches 0 the
+/// latch stays open. Clones share the same count.
+///
+/// ### Examples
+/// ```
+/// use rpools::pool::WorkerPool;
+/// use rpools::sync::CountDownLatch;
+///
+/// let pool = WorkerPool::new(3);
+/// let ready = CountDownLatch::new(3);
+///
+/// for _ in 0..3 {
+///     let ready = ready.clone();
+///     pool.execute(move || {
+///         // warm up caches, open connections...
+///         ready.count_down();
+///     }).unwrap();
+/// }
+///
+/// ready.wait();
+/// assert_eq!(0, ready.count());
+/// ```
+#[derive(Clone, Debug)]
+pub struct CountDownLatch {
+    inner: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl CountDownLatch {
+    /// Constructs a new latch that opens after count calls to
+    /// `count_down`.
+    ///
+    /// **count**: usize - the number of events to wait for.
+    pub fn new(count: usize) -> CountDownLatch {
+        CountDownLatch {
+            inner: Arc::new((Mutex::new(count), Condvar::new())),
+        }
+    }
+
+    /// Decrements the count, waking the waiting threads when it
+    /// reaches 0. Does nothing if the count is already 0.
+    pub fn count_down(&self) {
+        let (count, condvar) = &*self.inner;
+        let mut count = count.lock().expect("Cant get the lock");
+        if *count > 0 {
+            *count -= 1;
+            if *count == 0 {
+                condvar.notify_all();
+            }
+        }
+    }
+
+    /// Returns the current count.
+    pub fn count(&self) -> usize {
+        *self.inner.0.lock().expect("Cant get the lock")
+    }
+
+    /// Blocks the current thread until the count reaches 0.
+    pub fn wait(&self) {
+        let (count, condvar) = &*self.inner;
+        let count = count.lock().expect("Cant get the lock");
+        let _count = condvar
+            .wait_while(count, |count| *count > 0)
+            .expect("Cant block the current thread");
+    }
+
+    /// Blocks the current thread until the count reaches 0, or until
+    /// the timeout elapses.
+    ///
+    /// **timeout**: Duration - the maximum time to wait. \
+    /// **returns**: true if the count reached 0, false on timeout.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (count, condvar) = &*self.inner;
+        let count = count.lock().expect("Cant get the lock");
+        let (_count, result) = condvar
+            .wait_timeout_while(count, timeout, |count| *count > 0)
+            .expect("Cant block the current thread");
+        !result.timed_out()
+    }
+}
+
+/// A manual reset event. Once `set`, every waiting thread is released
+/// and later waits return at once, until the event is `reset`. Clones
+/// share the same event.
+///
+/// ### Examples
+/// ```
+/// use rpools::pool::WorkerPool;
+/// use rpools::sync::Event;
+/// use std::time::Duration;
+///
+/// let pool = WorkerPool::new(2);
+/// let warmed_up = Event::new();
+///
+/// let event = warmed_up.clone();
+/// pool.execute(move || {
+///     // load the model...
+///     event.set();
+/// }).unwrap();
+///
+/// assert!(warmed_up.wait_timeout(Duration::from_secs(5)));
+/// assert!(warmed_up.is_set());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Event {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Event {
+    /// Constructs a new event, not set.
+    pub fn new() -> Event {
+        Event::default()
+    }
+
+    /// Sets the event, waking all the waiting threads.
+    pub fn set(&self) {
+        let (set, condvar) = &*self.inner;
+        *set.lock().expect("Cant get the lock") = true;
+        condvar.notify_all();
+    }
+
+    /// Resets the event, so the next waits block until it is set again.
+    pub fn reset(&self) {
+        *self.inner.0.lock().expect("Cant get the lock") = false;
+    }
+
+    /// Returns true if the event is set.
+    pub fn is_set(&self) -> bool {
+        *self.inner.0.lock().expect("Cant get the lock")
+    }
+
+    /// Blocks the current thread until the event is set.
+    pub fn wait(&self) {
+        let (set, condvar) = &*self.inner;
+        let set = set.lock().expect("Cant get the lock");
+        let _set = condvar
+            .wait_while(set, |set| !*set)
+            .expect("Cant block the current thread");
+    }
+
+    /// Blocks the current thread until the event is set, or until the
+    /// timeout elapses.
+    ///
+    /// **timeout**: Duration - the maximum time to wait. \
+    /// **returns**: true if the event was set, false on timeout.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (set, condvar) = &*self.inner;
+        let set = set.lock().expect("Cant get the lock");
+        let (set, _) = condvar
+            .wait_timeout_while(set, timeout, |set| !*set)
+            .expect("Cant block the current thread");
+        *set
+    }
+}
+
+/// A reusable barrier for a fixed number of parties. Threads calling
+/// `wait` block until all parties arrived, then the barrier opens and
+/// starts over for the next generation. An optional action runs once
+/// per generation, in the last thread to arrive, before the others are
+/// released. Clones share the same barrier.
+///
+/// ### Examples
+/// ```
+/// use rpools::pool::WorkerPool;
+/// use rpools::sync::Barrier;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::sync::Arc;
+///
+/// let pool = WorkerPool::new(3);
+/// let steps = Arc::new(AtomicUsize::new(0));
+/// let counter = steps.clone();
+/// let barrier = Barrier::with_action(3, move || {
+///     counter.fetch_add(1, Ordering::Relaxed);
+/// });
+///
+/// for _ in 0..3 {
+///     let barrier = barrier.clone();
+///     pool.execute(move || {
+///         for _ in 0..4 {
+///             // simulate one step of the particles of this job
+///             barrier.wait();
+///         }
+///     }).unwrap();
+/// }
+///
+/// pool.wait();
+/// assert_eq!(4, steps.load(Ordering::Relaxed));
+/// assert_eq!(4, barrier.generation());
+/// ```
+#[derive(Clone)]
+pub struct Barrier {
+    inner: Arc<BarrierInner>,
+}
+
+// The state of a Barrier, shared by its clones.
+struct BarrierInner {
+    parties: usize,
+    // the parties arrived in this generation, and the generation
+    state: Mutex<(usize, u64)>,
+    condvar: Condvar,
+    action: Option<Box<dyn Fn() + Send + Sync>>,
+}
+
+impl Barrier {
+    /// Constructs a new barrier for parties threads.
+    ///
+    /// **parties**: usize - the threads that must call `wait` for the
+    /// barrier to open. 0 is treated as 1.
+    pub fn new(parties: usize) -> Barrier {
+        Barrier::build(parties, None)
+    }
+
+    /// Constructs a new barrier for parties threads, that runs action
+    /// each time it opens.
+    ///
+    /// **parties**: usize - the threads that must call `wait` for the
+    /// barrier to open. 0 is treated as 1. \
+    /// **action**: A Fn closure called once per generation.
+    pub fn with_action<F>(parties: usize, action: F) -> Barrier
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        Barrier::build(parties, Some(Box::new(action)))
+    }
+
+    // Constructs a new barrier with an optional action.
+    fn build(parties: usize, action: Option<Box<dyn Fn() + Send + Sync>>) -> Barrier {
+        Barrier {
+            inner: Arc::new(BarrierInner {
+                parties: parties.max(1),
+                state: Mutex::new((0, 0)),
+                condvar: Condvar::new(),
+                action,
+            }),
+        }
+    }
+
+    /// Blocks the current thread until all parties arrived.
+    ///
+    /// **returns**: true for the last thread to arrive, which ran the
+    /// action, false for the others.
+    pub fn wait(&self) -> bool {
+        let inner = &*self.inner;
+        let mut state = inner.state.lock().expect("Cant get the lock");
+        let generation = state.1;
+        state.0 += 1;
+        if state.0 == inner.parties {
+            if let Some(action) = &inner.action {
+                action();
+            }
+            *state = (0, generation.wrapping_add(1));
+            inner.condvar.notify_all();
+            return true;
+        }
+        let _state = inner
+            .condvar
+            .wait_while(state, |state| state.1 == generation)
+            .expect("Cant block the current thread");
+        false
+    }
+
+    /// Returns how many times the barrier opened.
+    pub fn generation(&self) -> u64 {
+        self.inner.state.lock().expect("Cant get the lock").1
+    }
+}
+
+impl std::fmt::Debug for Barrier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (arrived, generation) = *self.inner.state.lock().expect("Cant get the lock");
+        f.debug_struct("Barrier")
+            .field("parties", &self.inner.parties)
+            .field("arrived", &arrived)
+            .field("generation", &generation)
+            .finish()
+    }
+}
+
+/// A reusable barrier whose parties may change between phases. Parties
+/// `register` to take part, `arrive_and_wait` at the end of each phase,
+/// and `arrive_and_deregister` when they are done, so jobs can join
+/// and leave a computation while it runs. Clones share the same phaser.
+///
+/// ### Examples
+/// ```
+/// use rpools::pool::WorkerPool;
+/// use rpools::sync::Phaser;
+///
+/// let pool = WorkerPool::new(4);
+/// // the main thread takes part, so the jobs can't finish a phase alone
+/// let phaser = Phaser::new(1);
+///
+/// for rounds in 1..=3 {
+///     let phaser = phaser.clone();
+///     phaser.register();
+///     pool.execute(move || {
+///         for _ in 0..rounds {
+///             phaser.arrive_and_wait();
+///         }
+///         phaser.arrive_and_deregister();
+///     }).unwrap();
+/// }
+///
+/// phaser.arrive_and_deregister();
+/// pool.wait();
+/// assert_eq!(0, phaser.parties());
+/// ```
+#[derive(Clone, Debug)]
+pub struct Phaser {
+    inner: Arc<(Mutex<PhaserState>, Condvar)>,
+}
+
+// The parties of a Phaser, the ones arrived in this phase, and the phase.
+#[derive(Debug)]
+struct PhaserState {
+    parties: usize,
+    arrived: usize,
+    phase: u64,
+}
+
+impl PhaserState {
+    // Starts the next phase, waking the waiting parties, if every party
+    // arrived.
+    fn advance(&mut self, condvar: &Condvar) {
+        if self.parties > 0 && self.arrived == self.parties {
+            self.arrived = 0;
+            self.phase = self.phase.wrapping_add(1);
+            condvar.notify_all();
+        }
+    }
+}
+
+impl Phaser {
+    /// Constructs a new phaser at phase 0.
+    ///
+    /// **parties**: usize - the parties registered from the start.
+    pub fn new(parties: usize) -> Phaser {
+        let state = PhaserState {
+            parties,
+            arrived: 0,
+            phase: 0,
+        };
+        Phaser {
+            inner: Arc::new((Mutex::new(state), Condvar::new())),
+        }
+    }
+
+    /// Adds a party, which takes part from the current phase on.
+    ///
+    /// **returns**: the current phase.
+    pub fn register(&self) -> u64 {
+        let mut state = self.inner.0.lock().expect("Cant get the lock");
+        state.parties += 1;
+        state.phase
+    }
+
+    /// Arrives at the end of the current phase without waiting for the
+    /// other parties.
+    ///
+    /// **returns**: the phase arrived at.
+    pub fn arrive(&self) -> u64 {
+        let (state, condvar) = &*self.inner;
+        let mut state = state.lock().expect("Cant get the lock");
+        let phase = state.phase;
+        state.arrived += 1;
+        state.advance(condvar);
+        phase
+    }
+
+    /// Arrives at the end of the current phase, and blocks the current
+    /// thread until every party arrived.
+    ///
+    /// **returns**: the phase that starts.
+    pub fn arrive_and_wait(&self) -> u64 {
+        let (state, condvar) = &*self.inner;
+        let mut state = state.lock().expect("Cant get the lock");
+        let phase = state.phase;
+        state.arrived += 1;
+        state.advance(condvar);
+        let state = condvar
+            .wait_while(state, |state| state.phase == phase)
+            .expect("Cant block the current thread");
+        state.phase
+    }
+
+    /// Removes a party without waiting, which may end the current phase
+    /// for the remaining ones.
+    ///
+    /// **returns**: the current phase, before it advances.
+    pub fn arrive_and_deregister(&self) -> u64 {
+        let (state, condvar) = &*self.inner;
+        let mut state = state.lock().expect("Cant get the lock");
+        let phase = state.phase;
+        state.parties = state.parties.saturating_sub(1);
+        state.advance(condvar);
+        phase
+    }
+
+    /// Returns the current phase.
+    pub fn phase(&self) -> u64 {
+        self.inner.0.lock().expect("Cant get the lock").phase
+    }
+
+    /// Returns how many parties are registered.
+    pub fn parties(&self) -> usize {
+        self.inner.0.lock().expect("Cant get the lock").parties
+    }
+}
+
+/// A channel where every subscriber receives each message, to push
+/// control messages, like a config reload or a cache invalidation, to
+/// many threads at once. Subscribers only get the messages sent after
+/// they subscribed. Clones send to the same subscribers.
+///
+/// ### Examples
+/// ```
+/// use rpools::pool::WorkerPool;
+/// use rpools::sync::Broadcast;
+///
+/// let pool = WorkerPool::new(2);
+/// let reloads = Broadcast::new();
+///
+/// for _ in 0..2 {
+///     let config = reloads.subscribe();
+///     pool.execute(move || {
+///         while let Ok(version) = config.recv() {
+///             println!("reloading config v{}", version);
+///         }
+///     }).unwrap();
+/// }
+///
+/// assert_eq!(2, reloads.send(2u32));
+/// drop(reloads);
+/// pool.wait();
+/// ```
+#[derive(Debug)]
+pub struct Broadcast<T> {
+    subscribers: Arc<Mutex<Vec<mpsc::Sender<T>>>>,
+}
+
+impl<T: Clone> Broadcast<T> {
+    /// Constructs a new Broadcast, without subscribers.
+    pub fn new() -> Broadcast<T> {
+        Broadcast {
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Adds a subscriber, which receives the messages sent from now on.
+    pub fn subscribe(&self) -> Subscriber<T> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().expect("Cant get the lock").push(tx);
+        Subscriber {
+            receiver: Mutex::new(rx),
+        }
+    }
+
+    /// Sends a copy of the message to each subscriber. Dropped
+    /// subscribers are removed.
+    ///
+    /// **message**: T - the message to send. \
+    /// **returns**: how many subscribers got the message.
+    pub fn send(&self, message: T) -> usize {
+        let mut subscribers = self.subscribers.lock().expect("Cant get the lock");
+        subscribers.retain(|subscriber| subscriber.send(message.clone()).is_ok());
+        subscribers.len()
+    }
+
+    /// Returns how many subscribers were alive at the last send.
+    pub fn subscribers(&self) -> usize {
+        self.subscribers.lock().expect("Cant get the lock").len()
+    }
+}
+
+impl<T: Clone> Default for Broadcast<T> {
+    fn default() -> Self {
+        Broadcast::new()
+    }
+}
+
+impl<T> Clone for Broadcast<T> {
+    fn clone(&self) -> Self {
+        Broadcast {
+            subscribers: Arc::clone(&self.subscribers),
+        }
+    }
+}
+
+/// The receiving side of a Broadcast. Once every clone of the Broadcast
+/// is dropped, the receive methods return an error.
+#[derive(Debug)]
+pub struct Subscriber<T> {
+    // behind a mutex, so subscribers can move into pool jobs
+    receiver: Mutex<mpsc::Receiver<T>>,
+}
+
+impl<T> Subscriber<T> {
+    /// Blocks the current thread until a message is sent.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.receiver.lock().expect("Cant get the lock").recv()
+    }
+
+    /// Returns the next message if one was sent, without blocking.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.receiver.lock().expect("Cant get the lock").try_recv()
+    }
+
+    /// Blocks the current thread until a message is sent, or until the
+    /// timeout elapses.
+    ///
+    /// **timeout**: Duration - the maximum time to wait.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.receiver
+            .lock()
+            .expect("Cant get the lock")
+            .recv_timeout(timeout)
+    }
+}
+
+/// Gathers the results of jobs on the parent thread. Each job gets a
+/// Sink, a cheap clone of the sending side, so no job has to lock a
+/// shared sender.
+///
+/// ### Examples
+/// ```
+/// use rpools::pool::WorkerPool;
+/// use rpools::sync::Collector;
+/// use std::time::Duration;
+///
+/// let pool = WorkerPool::new(4);
+/// let collector = Collector::new();
+///
+/// for i in 0..8 {
+///     let sink = collector.sink();
+///     pool.execute(move || sink.send(i * i)).unwrap();
+/// }
+/// let first = collector.collect(2);
+/// assert_eq!(2, first.len());
+///
+/// let rest = collector.collect_timeout(10, Duration::from_millis(200));
+/// assert_eq!(6, rest.len());
+/// assert_eq!(140, first.iter().chain(&rest).sum::<i32>());
+/// ```
+#[derive(Debug)]
+pub struct Collector<T> {
+    sender: mpsc::Sender<T>,
+    receiver: mpsc::Receiver<T>,
+}
+
+impl<T: Send> Collector<T> {
+    /// Constructs a new Collector, without results.
+    pub fn new() -> Collector<T> {
+        let (sender, receiver) = mpsc::channel();
+        Collector { sender, receiver }
+    }
+
+    /// Returns a new sink, to move into a job.
+    pub fn sink(&self) -> Sink<T> {
+        Sink(self.sender.clone())
+    }
+
+    /// Blocks the current thread until n results are sent.
+    ///
+    /// **n**: usize - how many results to gather. \
+    /// **returns**: the results, in the order they were sent.
+    pub fn collect(&self, n: usize) -> Vec<T> {
+        // the collector holds a sender, so recv never fails
+        self.receiver.iter().take(n).collect()
+    }
+
+    /// Blocks the current thread until n results are sent, or until the
+    /// timeout elapses.
+    ///
+    /// **n**: usize - how many results to gather. \
+    /// **timeout**: Duration - the maximum time to wait, for all of
+    /// them. \
+    /// **returns**: the results sent in time, maybe fewer than n.
+    pub fn collect_timeout(&self, n: usize, timeout: Duration) -> Vec<T> {
+        let deadline = Instant::now() + timeout;
+        let mut results = Vec::with_capacity(n);
+        while results.len() < n {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.receiver.recv_timeout(left) {
+                Ok(result) => results.push(result),
+                Err(_) => break,
+            }
+        }
+        results
+    }
+
+    /// Blocks the current thread until every sink is dropped, that is,
+    /// until the jobs holding them are done, and returns all results.
+    /// A sink kept alive forever blocks it forever.
+    ///
+    /// **returns**: the results, in the order they were sent.
+    pub fn collect_all(self) -> Vec<T> {
+        drop(self.sender);
+        self.receiver.iter().collect()
+    }
+}
+
+impl<T: Send> Default for Collector<T> {
+    fn default() -> Self {
+        Collector::new()
+    }
+}
+
+/// The sending side of a Collector, given to a job. Clones send to the
+/// same Collector.
+#[derive(Debug)]
+pub struct Sink<T>(mpsc::Sender<T>);
+
+impl<T> Sink<T> {
+    /// Sends a result to the Collector. The result is dropped if the
+    /// Collector was dropped, as nobody waits for it anymore.
+    ///
+    /// **result**: T - the result of the job.
+    pub fn send(&self, result: T) {
+        let _ = self.0.send(result);
+    }
+}
+
+impl<T> Clone for Sink<T> {
+    fn clone(&self) -> Self {
+        Sink(self.0.clone())
+    }
+}
+
+/// A value computed once, read by any number of threads. The value is
+/// given by a Promise, made with `OnceResult::promise`, usually moved
+/// into a job, and readers wait for it or peek at it. Clones share the
+/// same value, so it also caches an expensive initialization done on a
+/// pool.
+///
+/// ### Examples
+/// ```
+/// use rpools::pool::WorkerPool;
+/// use rpools::sync::OnceResult;
+///
+/// let pool = WorkerPool::new(2);
+/// let config = OnceResult::new();
+/// let promise = config.promise();
+/// pool.execute(move || {
+///     // parse the config file...
+///     promise.fulfill(String::from("threads = 4")).unwrap();
+/// }).unwrap();
+///
+/// for _ in 0..3 {
+///     let config = config.clone();
+///     pool.execute(move || assert!(config.wait().is_some())).unwrap();
+/// }
+/// assert_eq!(Some(&String::from("threads = 4")), config.wait());
+/// pool.wait();
+/// ```
+pub struct OnceResult<T> {
+    inner: Arc<PromiseInner<T>>,
+}
+
+/// The writing side of a OnceResult. It fulfills it at most once;
+/// clones share the right to do it. When every promise is dropped
+/// without fulfilling it, the readers stop waiting.
+pub struct Promise<T> {
+    inner: Arc<PromiseInner<T>>,
+}
+
+// The state shared by a OnceResult and its promises.
+struct PromiseInner<T> {
+    value: OnceLock<T>,
+    // the live promises
+    promises: Mutex<usize>,
+    condvar: Condvar,
+}
+
+impl<T> OnceResult<T> {
+    /// Constructs a new OnceResult, without a value.
+    pub fn new() -> OnceResult<T> {
+        OnceResult {
+            inner: Arc::new(PromiseInner {
+                value: OnceLock::new(),
+                promises: Mutex::new(0),
+                condvar: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Returns a new promise, to move into the job that computes the
+    /// value.
+    pub fn promise(&self) -> Promise<T> {
+        *self.inner.promises.lock().expect("Cant get the lock") += 1;
+        Promise {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    /// Returns the value, if it was given already.
+    pub fn try_get(&self) -> Option<&T> {
+        self.inner.value.get()
+    }
+
+    /// Blocks the current thread until the value is given.
+    ///
+    /// **returns**: the value, or None if every promise was dropped
+    /// without giving it.
+    pub fn wait(&self) -> Option<&T> {
+        let promises = self.inner.promises.lock().expect("Cant get the lock");
+        let _promises = self
+            .inner
+            .condvar
+            .wait_while(promises, |promises| {
+                self.inner.value.get().is_none() && *promises > 0
+            })
+            .expect("Cant block the current thread");
+        self.inner.value.get()
+    }
+
+    /// Blocks the current thread until the value is given, or until the
+    /// timeout elapses.
+    ///
+    /// **timeout**: Duration - the maximum time to wait. \
+    /// **returns**: the value, or None on timeout or if every promise
+    /// was dropped without giving it.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<&T> {
+        let promises = self.inner.promises.lock().expect("Cant get the lock");
+        let _promises = self
+            .inner
+            .condvar
+            .wait_timeout_while(promises, timeout, |promises| {
+                self.inner.value.get().is_none() && *promises > 0
+            })
+            .expect("Cant block the current thread");
+        self.inner.value.get()
+    }
+}
+
+impl<T> Promise<T> {
+    /// Gives the value, waking all the waiting readers.
+    ///
+    /// **value**: T - the value. \
+    /// **returns**: Ok, or the value back if it was given already.
+    pub fn fulfill(&self, value: T) -> Result<(), T> {
+        self.inner.value.set(value)?;
+        // taken so a reader can't miss the wake up between its check
+        // and its wait
+        let _promises = self.inner.promises.lock().expect("Cant get the lock");
+        self.inner.condvar.notify_all();
+        Ok(())
+    }
+
+    /// Returns true if the value was given already.
+    pub fn is_fulfilled(&self) -> bool {
+        self.inner.value.get().is_some()
+    }
+}
+
+impl<T> Default for OnceResult<T> {
+    fn default() -> Self {
+        OnceResult::new()
+    }
+}
+
+impl<T> Clone for OnceResult<T> {
+    fn clone(&self) -> Self {
+        OnceResult {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+// Implements Debug for OnceResult showing the value, if given.
+impl<T: std::fmt::Debug> std::fmt::Debug for OnceResult<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OnceResult")
+            .field("value", &self.try_get())
+            .finish()
+    }
+}
+
+impl<T> Clone for Promise<T> {
+    fn clone(&self) -> Self {
+        *self.inner.promises.lock().expect("Cant get the lock") += 1;
+        Promise {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+// Implements Drop for Promise, waking the readers when the last one is
+// dropped without giving the value.
+impl<T> Drop for Promise<T> {
+    fn drop(&mut self) {
+        let mut promises = self.inner.promises.lock().expect("Cant get the lock");
+        *promises -= 1;
+        if *promises == 0 {
+            self.inner.condvar.notify_all();
+        }
+    }
+}
+
+/// A token bucket limiting how often an operation may run. The bucket
+/// holds up to burst tokens, refilled at a steady rate, and each
+/// operation takes one. Clones share the same bucket, so one limiter
+/// can pace every job calling the same service.
+///
+/// ### Examples
+/// ```
+/// use rpools::pool::WorkerPool;
+/// use rpools::sync::RateLimiter;
+///
+/// let pool = WorkerPool::new(4);
+/// let limiter = RateLimiter::new(100.0, 10);
+///
+/// for id in 0..20 {
+///     pool.execute_throttled(&limiter, move || println!("calling the api for {}", id))
+///         .unwrap();
+/// }
+/// assert!(!limiter.try_acquire());
+/// ```
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+// The tokens of a RateLimiter, negative while reservations are pending.
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    // Adds the tokens earned since the last refill.
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let earned = now.duration_since(self.refilled).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + earned).min(self.burst);
+        self.refilled = now;
+    }
+}
+
+impl RateLimiter {
+    /// Constructs a new limiter with a full bucket. Panics if the rate
+    /// isn't positive.
+    ///
+    /// **per_second**: f64 - the tokens added to the bucket each second. \
+    /// **burst**: usize - the most tokens the bucket holds, so the most
+    /// operations that may run at once after a quiet period.
+    pub fn new(per_second: f64, burst: usize) -> RateLimiter {
+        assert!(
+            per_second > 0.0,
+            "the rate of a RateLimiter must be positive"
+        );
+        let burst = burst.max(1) as f64;
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate: per_second,
+                burst,
+                tokens: burst,
+                refilled: Instant::now(),
+            })),
+        }
+    }
+
+    /// Takes a token if one is available, without blocking.
+    ///
+    /// **returns**: true if the operation may run now.
+    pub fn try_acquire(&self) -> bool {
+        let mut bucket = self.bucket.lock().expect("Cant get the lock");
+        bucket.refill();
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Reserves a token, even if the bucket is empty, and returns how
+    /// long to wait before using it. Reservations are served in order,
+    /// so callers that wait as told never exceed the rate.
+    ///
+    /// **returns**: the wait, zero if a token was available.
+    pub fn reserve(&self) -> Duration {
+        let mut bucket = self.bucket.lock().expect("Cant get the lock");
+        bucket.refill();
+        bucket.tokens -= 1.0;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / bucket.rate)
+        }
+    }
+
+    /// Blocks the current thread until a token is available, and takes
+    /// it.
+    pub fn acquire(&self) {
+        let wait = self.reserve();
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Which side of a RwLock goes first when both readers and writers are
+/// waiting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RwPreference {
+    /// New readers share the lock with the current ones even when a
+    /// writer waits, for mostly read state. Writers may wait for as
+    /// long as readers keep coming.
+    Read,
+    /// New readers wait while a writer waits, so writes are never
+    /// starved by a steady flow of readers.
+    Write,
+}
+
+/// A reader writer lock whose preference between readers and writers
+/// is chosen when it is made, where std RwLock leaves it to the OS.
+/// Any number of readers, or a single writer, hold the lock at a time.
+/// Like SpinLock, it isn't poisoned when a holder panics.
+///
+/// ### Examples
+/// ```
+/// use rpools::pool::WorkerPool;
+/// use rpools::sync::{RwLock, RwPreference};
+/// use std::sync::Arc;
+///
+/// let pool = WorkerPool::new(4);
+/// let routes = Arc::new(RwLock::new(vec!["/"], RwPreference::Write));
+///
+/// for _ in 0..10 {
+///     let routes = Arc::clone(&routes);
+///     pool.execute(move || assert!(routes.read().contains(&"/"))).unwrap();
+/// }
+/// routes.write().push("/health");
+/// pool.wait();
+/// assert_eq!(2, routes.read().len());
+/// ```
+pub struct RwLock<T> {
+    state: Mutex<RwState>,
+    readers: Condvar,
+    writers: Condvar,
+    preference: RwPreference,
+    value: UnsafeCell<T>,
+}
+
+// The holders and the waiting writers of a RwLock.
+#[derive(Default)]
+struct RwState {
+    readers: usize,
+    writer: bool,
+    waiting_writers: usize,
+}
+
+// RwLock hands out shared references to many threads, and the value to
+// one thread at a time, like std RwLock.
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+/// The shared access to the value of a RwLock, released when dropped.
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+/// The exclusive access to the value of a RwLock, released when
+/// dropped.
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    // shared across threads only if T is Sync
+    _value: PhantomData<&'a mut T>,
+}
+
+impl<T> RwLock<T> {
+    /// Constructs a new unlocked RwLock.
+    ///
+    /// **value**: T - the value to protect. \
+    /// **preference**: RwPreference - which side goes first when both
+    /// wait.
+    pub fn new(value: T, preference: RwPreference) -> RwLock<T> {
+        RwLock {
+            state: Mutex::new(RwState::default()),
+            readers: Condvar::new(),
+            writers: Condvar::new(),
+            preference,
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Takes the lock for reading, blocking the current thread while a
+    /// writer holds it, or, preferring writes, while one waits.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let state = self.state.lock().expect("Cant get the lock");
+        let mut state = self
+            .readers
+            .wait_while(state, |state| !self.can_read(state))
+            .expect("Cant block the current thread");
+        state.readers += 1;
+        RwLockReadGuard { lock: self }
+    }
+
+    /// Takes the lock for reading if it can be taken without blocking.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let mut state = self.state.lock().expect("Cant get the lock");
+        if !self.can_read(&state) {
+            return None;
+        }
+        state.readers += 1;
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    /// Takes the lock for writing, blocking the current thread while
+    /// other threads hold it.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let mut state = self.state.lock().expect("Cant get the lock");
+        state.waiting_writers += 1;
+        let mut state = self
+            .writers
+            .wait_while(state, |state| state.writer || state.readers > 0)
+            .expect("Cant block the current thread");
+        state.waiting_writers -= 1;
+        state.writer = true;
+        RwLockWriteGuard {
+            lock: self,
+            _value: PhantomData,
+        }
+    }
+
+    /// Takes the lock for writing if no other thread holds it.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let mut state = self.state.lock().expect("Cant get the lock");
+        if state.writer || state.readers > 0 {
+            return None;
+        }
+        state.writer = true;
+        Some(RwLockWriteGuard {
+            lock: self,
+            _value: PhantomData,
+        })
+    }
+
+    /// Returns the preference the lock was made with.
+    pub fn preference(&self) -> RwPreference {
+        self.preference
+    }
+
+    /// Returns a mutable reference to the value, without locking, as
+    /// the borrow proves no other thread holds the lock.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Consumes the lock and returns the value.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    // Returns true if a new reader may take the lock.
+    fn can_read(&self, state: &RwState) -> bool {
+        !state.writer && (self.preference == RwPreference::Read || state.waiting_writers == 0)
+    }
+}
+
+// Implements Debug for RwLock, showing the preference.
+impl<T> std::fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RwLock")
+            .field("preference", &self.preference)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> std::ops::Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // no writer holds the lock while a reader does
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+// Implements Drop for RwLockReadGuard, waking a writer when the last
+// reader leaves.
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock().expect("Cant get the lock");
+        state.readers -= 1;
+        if state.readers == 0 && state.waiting_writers > 0 {
+            self.lock.writers.notify_one();
+        }
+    }
+}
+
+impl<T> std::ops::Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // the guard owns the lock
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> std::ops::DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // the guard owns the lock
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+// Implements Drop for RwLockWriteGuard, handing the lock to the next
+// writer when writes are preferred, or else to the waiting readers.
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock().expect("Cant get the lock");
+        state.writer = false;
+        let writer_next = self.lock.preference == RwPreference::Write && state.waiting_writers > 0;
+        if writer_next {
+            self.lock.writers.notify_one();
+        } else {
+            self.lock.readers.notify_all();
+            if state.waiting_writers > 0 {
+                self.lock.writers.notify_one();
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod mod_wait_group_tests {
     use super::WaitGroup;
+    use std::time::{Duration, Instant};
 
     #[test]
     fn test_if_zero_count_must_not_block() {
         let wg = WaitGroup::default();
         wg.wait();
     }
+
+    #[test]
+    fn test_if_wait_timeout_must_return_when_counter_reaches_zero() {
+        let wg = WaitGroup::default();
+        let pending = wg.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            drop(pending);
+        });
+        assert!(wg.wait_timeout(Duration::from_secs(5)));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_if_wait_timeout_must_give_up_on_hung_jobs() {
+        let wg = WaitGroup::default();
+        let _hung = wg.clone();
+        let started = Instant::now();
+        assert!(!wg.wait_timeout(Duration::from_millis(30)));
+        assert!(started.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn test_if_add_and_done_must_release_wait() {
+        let wg = WaitGroup::default();
+        wg.add(3);
+        let worker = wg.clone();
+        let handle = std::thread::spawn(move || {
+            for _ in 0..3 {
+                worker.done();
+            }
+        });
+        wg.wait();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_if_ffi_token_must_release_wait_when_completed() {
+        let wg = WaitGroup::default();
+        let token = wg.ffi_token() as usize;
+        assert!(!wg.wait_timeout(Duration::from_millis(10)));
+        let handle = std::thread::spawn(move || unsafe {
+            super::rpools_wait_group_done(token as *mut std::ffi::c_void);
+            super::rpools_wait_group_done(std::ptr::null_mut());
+        });
+        wg.wait();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_if_count_must_follow_clones_and_add() {
+        let wg = WaitGroup::default();
+        assert!(wg.is_done());
+        let pending = wg.clone();
+        wg.add(2);
+        assert_eq!(3, wg.count());
+        assert_eq!("WaitGroup { counter: 3 }", format!("{:?}", wg));
+        wg.done();
+        wg.done();
+        drop(pending);
+        assert!(wg.is_done());
+    }
+
+    #[test]
+    fn test_if_wait_must_see_the_writes_of_the_tasks() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        for _ in 0..200 {
+            let slots: Arc<Vec<AtomicUsize>> = Arc::new((0..4).map(AtomicUsize::new).collect());
+            let wg = WaitGroup::default();
+            let handles: Vec<_> = (0..4)
+                .map(|i| {
+                    let (slots, wg) = (Arc::clone(&slots), wg.clone());
+                    std::thread::spawn(move || {
+                        slots[i].store(i + 1, Ordering::Relaxed);
+                        drop(wg);
+                    })
+                })
+                .collect();
+            wg.wait();
+            for (i, slot) in slots.iter().enumerate() {
+                assert_eq!(i + 1, slot.load(Ordering::Relaxed));
+            }
+            handles.into_iter().for_each(|h| h.join().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_if_every_waiter_must_be_released() {
+        use std::sync::{mpsc, Arc};
+
+        let wg = Arc::new(WaitGroup::default());
+        wg.add(1);
+        let (tx, rx) = mpsc::channel();
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let (wg, tx) = (Arc::clone(&wg), tx.clone());
+                std::thread::spawn(move || {
+                    wg.wait();
+                    tx.send(()).unwrap();
+                })
+            })
+            .collect();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(rx.try_recv().is_err());
+        wg.done();
+        for _ in 0..4 {
+            rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        handles.into_iter().for_each(|h| h.join().unwrap());
+    }
+
+    #[test]
+    fn test_if_waiters_must_be_released_by_their_own_round() {
+        use std::sync::{mpsc, Arc};
+
+        for _ in 0..5 {
+            let wg = Arc::new(WaitGroup::default());
+            wg.add(1);
+            let (tx, rx) = mpsc::channel();
+            let waiter = {
+                let wg = Arc::clone(&wg);
+                std::thread::spawn(move || {
+                    wg.wait();
+                    tx.send(()).unwrap();
+                })
+            };
+            std::thread::sleep(Duration::from_millis(20));
+            // the next round starts before the waiter can wake up
+            wg.done();
+            wg.add(1);
+            rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert!(!wg.wait_timeout(Duration::from_millis(1)));
+            wg.done();
+            wg.wait();
+            waiter.join().unwrap();
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "WaitGroup counter underflow")]
+    fn test_if_done_without_add_must_panic() {
+        let wg = WaitGroup::default();
+        wg.done();
+    }
+
+    #[test]
+    #[should_panic(expected = "WaitGroup counter overflow")]
+    fn test_if_add_past_max_must_panic() {
+        let wg = WaitGroup::default();
+        wg.add(usize::MAX);
+        wg.add(1);
+    }
+}
+
+#[cfg(test)]
+mod mod_count_down_latch_tests {
+    use super::CountDownLatch;
+    use std::time::Duration;
+
+    #[test]
+    fn test_if_zero_count_must_not_block() {
+        let latch = CountDownLatch::new(0);
+        latch.wait();
+    }
+
+    #[test]
+    fn test_if_count_down_must_open_latch_for_all_waiters() {
+        let latch = CountDownLatch::new(2);
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let latch = latch.clone();
+                std::thread::spawn(move || latch.wait())
+            })
+            .collect();
+        latch.count_down();
+        assert!(!latch.wait_timeout(Duration::from_millis(10)));
+        latch.count_down();
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_if_count_must_not_go_below_zero() {
+        let latch = CountDownLatch::new(1);
+        latch.count_down();
+        latch.count_down();
+        assert_eq!(0, latch.count());
+        assert!(latch.wait_timeout(Duration::from_millis(10)));
+    }
+}
+
+#[cfg(test)]
+mod mod_event_tests {
+    use super::Event;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_if_set_must_release_all_waiters_until_reset() {
+        let event = Event::new();
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let event = event.clone();
+                thread::spawn(move || event.wait())
+            })
+            .collect();
+        assert!(!event.wait_timeout(Duration::from_millis(10)));
+        event.set();
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        assert!(event.wait_timeout(Duration::ZERO));
+
+        event.reset();
+        assert!(!event.is_set());
+        assert!(!event.wait_timeout(Duration::from_millis(10)));
+    }
+}
+
+#[cfg(test)]
+mod mod_barrier_tests {
+    use super::Barrier;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_if_barrier_must_open_once_per_generation() {
+        let actions = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&actions);
+        let barrier = Barrier::with_action(3, move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let leaders: usize = (0..3)
+            .map(|_| {
+                let barrier = barrier.clone();
+                thread::spawn(move || (0..5).filter(|_| barrier.wait()).count())
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|party| party.join().unwrap())
+            .sum();
+        assert_eq!(5, leaders);
+        assert_eq!(5, actions.load(Ordering::Relaxed));
+        assert_eq!(5, barrier.generation());
+    }
+
+    #[test]
+    fn test_if_single_party_barrier_must_not_block() {
+        let barrier = Barrier::new(0);
+        assert!(barrier.wait());
+        assert!(barrier.wait());
+        assert_eq!(2, barrier.generation());
+    }
+}
+
+#[cfg(test)]
+mod mod_phaser_tests {
+    use super::Phaser;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_if_phaser_must_wait_for_registered_parties() {
+        let phaser = Phaser::new(1);
+        assert_eq!(0, phaser.register());
+        let party = {
+            let phaser = phaser.clone();
+            thread::spawn(move || phaser.arrive_and_wait())
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(0, phaser.phase());
+        assert_eq!(0, phaser.arrive());
+        assert_eq!(1, party.join().unwrap());
+        assert_eq!(1, phaser.phase());
+    }
+
+    #[test]
+    fn test_if_deregister_must_end_the_phase_for_the_others() {
+        let phaser = Phaser::new(2);
+        let party = {
+            let phaser = phaser.clone();
+            thread::spawn(move || phaser.arrive_and_wait())
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(0, phaser.arrive_and_deregister());
+        assert_eq!(1, party.join().unwrap());
+        assert_eq!(1, phaser.parties());
+    }
+}
+
+#[cfg(test)]
+mod mod_broadcast_tests {
+    use super::Broadcast;
+    use std::sync::mpsc::TryRecvError;
+
+    #[test]
+    fn test_if_each_subscriber_must_get_each_message() {
+        let broadcast = Broadcast::new();
+        let early = broadcast.subscribe();
+        assert_eq!(1, broadcast.send("flush"));
+        let late = broadcast.clone().subscribe();
+        assert_eq!(2, broadcast.send("reload"));
+
+        assert_eq!(Ok("flush"), early.try_recv());
+        assert_eq!(Ok("reload"), early.try_recv());
+        assert_eq!(Ok("reload"), late.try_recv());
+        drop(early);
+        assert_eq!(1, broadcast.send("stop"));
+        drop(broadcast);
+        assert_eq!(Ok("stop"), late.try_recv());
+        assert_eq!(Err(TryRecvError::Disconnected), late.try_recv());
+    }
+}
+
+#[cfg(test)]
+mod mod_rate_limiter_tests {
+    use super::RateLimiter;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_if_burst_must_be_served_then_refused() {
+        let limiter = RateLimiter::new(1.0, 3);
+        assert!((0..3).all(|_| limiter.try_acquire()));
+        assert!(!limiter.clone().try_acquire());
+    }
+
+    #[test]
+    fn test_if_reservations_must_be_spaced_by_the_rate() {
+        let limiter = RateLimiter::new(100.0, 1);
+        assert_eq!(Duration::ZERO, limiter.reserve());
+        let second = limiter.reserve();
+        let third = limiter.reserve();
+        assert!(second > Duration::from_millis(5) && second <= Duration::from_millis(10));
+        assert!(third > second + Duration::from_millis(5));
+
+        let start = Instant::now();
+        limiter.acquire();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+}
+
+#[cfg(test)]
+mod mod_promise_tests {
+    use super::OnceResult;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn test_if_promise_must_be_fulfilled_once_for_every_reader() {
+        let result = OnceResult::new();
+        let promise = result.promise();
+        assert_eq!(None, result.try_get());
+        assert_eq!(None, result.wait_timeout(Duration::from_millis(5)));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let result = result.clone();
+                thread::spawn(move || result.wait().copied())
+            })
+            .collect();
+        assert_eq!(Ok(()), promise.clone().fulfill(7));
+        assert_eq!(Err(8), promise.fulfill(8));
+        assert!(readers.into_iter().all(|r| r.join().unwrap() == Some(7)));
+        assert_eq!(Some(&7), result.try_get());
+    }
+
+    #[test]
+    fn test_if_readers_must_stop_waiting_when_promises_are_dropped() {
+        let result = OnceResult::<u8>::new();
+        let promise = result.promise();
+        let reader = {
+            let result = result.clone();
+            thread::spawn(move || result.wait().copied())
+        };
+        drop(promise.clone());
+        thread::sleep(Duration::from_millis(5));
+        drop(promise);
+        assert_eq!(None, reader.join().unwrap());
+    }
+}
+
+#[cfg(test)]
+mod mod_rw_lock_tests {
+    use super::{RwLock, RwPreference};
+    use std::{sync::Arc, thread, time::Duration};
+
+    // Holds a read guard, starts a writer, and returns whether a new
+    // reader got in while the writer waited.
+    fn reader_gets_in(preference: RwPreference) -> bool {
+        let lock = Arc::new(RwLock::new(0, preference));
+        let reader = lock.read();
+        let writer = {
+            let lock = Arc::clone(&lock);
+            thread::spawn(move || *lock.write() += 1)
+        };
+        thread::sleep(Duration::from_millis(20));
+        let got_in = lock.try_read().is_some();
+        drop(reader);
+        writer.join().unwrap();
+        assert_eq!(1, *lock.read());
+        got_in
+    }
+
+    #[test]
+    fn test_if_preference_must_decide_who_goes_first() {
+        assert!(reader_gets_in(RwPreference::Read));
+        assert!(!reader_gets_in(RwPreference::Write));
+    }
+
+    #[test]
+    fn test_if_readers_must_share_and_writers_must_exclude() {
+        let lock = RwLock::new(vec![1], RwPreference::Read);
+        let (first, second) = (lock.read(), lock.read());
+        assert!(lock.try_write().is_none());
+        assert_eq!(first.len(), second.len());
+        drop((first, second));
+        let mut writer = lock.try_write().unwrap();
+        writer.push(2);
+        assert!(lock.try_read().is_none());
+        drop(writer);
+        assert_eq!(vec![1, 2], lock.into_inner());
+    }
 }
@@ -0,0 +1,424 @@
+// ## Sync Core
+//
+// The sync primitives that only need atomics and an allocator:
+// CancellationToken, ShardedCounter and SpinLock. They build without
+// std, so they are the whole `rpools::sync` module when the std feature
+// is off, and are re-exported by it otherwise. With std, SpinLock parks
+// the threads that spun for too long, and ShardedCounter gives each
+// thread its own shard; without it, SpinLock keeps spinning and
+// ShardedCounter picks the shard from the stack of the caller.
+
+use alloc::sync::Arc;
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/// A token used to cancel jobs cooperatively. Clones share the same
+/// state, so cancelling one clone cancels all of them. Jobs sent with
+/// `WorkerPool::execute_cancellable` are skipped if the token was
+/// cancelled before they start, and running jobs can poll `is_cancelled`.
+///
+/// ### Examples
+/// ```
+/// use rpools::sync::CancellationToken;
+///
+/// let token = CancellationToken::new();
+/// let clone = token.clone();
+///
+/// token.cancel();
+/// assert!(clone.is_cancelled());
+/// ```
+#[derive(Clone, Default, Debug)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Constructs a new token, not cancelled.
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Cancels the token and all its clones.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    /// Returns true if the token was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// A counter striped across cache line padded cells, for counting from
+/// many threads at once, like the progress of millions of small jobs,
+/// where a single AtomicUsize bounces between cores. Each thread adds
+/// to its own cell, and reads sum every cell, so a read is slower than
+/// the one of an atomic and may miss additions running concurrently.
+/// Clones share the same cells.
+///
+/// ### Examples
+/// ```
+/// use rpools::sync::ShardedCounter;
+///
+/// let processed = ShardedCounter::new();
+///
+/// std::thread::scope(|scope| {
+///     for _ in 0..4 {
+///         scope.spawn(|| (0..250).for_each(|_| processed.increment()));
+///     }
+/// });
+/// assert_eq!(1000, processed.sum());
+/// ```
+#[derive(Clone)]
+pub struct ShardedCounter {
+    shards: Arc<[Shard]>,
+}
+
+// A cell of a ShardedCounter, alone in its cache line. 128 bytes
+// covers the adjacent line prefetch of x86 and the lines of Apple CPUs.
+#[derive(Default)]
+#[repr(align(128))]
+struct Shard(AtomicUsize);
+
+#[cfg(feature = "std")]
+std::thread_local! {
+    // the shard of this thread, assigned round robin on first use
+    static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed);
+}
+
+// The next shard to assign to a thread.
+#[cfg(feature = "std")]
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+// The shards of ShardedCounter::new without std, where the CPUs can't
+// be counted.
+#[cfg(not(feature = "std"))]
+const DEFAULT_SHARDS: usize = 32;
+
+// Returns the shard of the calling thread.
+#[cfg(feature = "std")]
+fn current_shard() -> usize {
+    SHARD.with(|shard| *shard)
+}
+
+// Returns the shard of the calling thread, from the 4 KiB page of its
+// stack: the stacks of threads are apart, so threads rarely share one.
+#[cfg(not(feature = "std"))]
+fn current_shard() -> usize {
+    let marker = 0u8;
+    (&marker as *const u8 as usize) >> 12
+}
+
+impl ShardedCounter {
+    /// Constructs a new counter at 0, with four shards for each CPU the
+    /// process may use, or 32 shards without std.
+    pub fn new() -> ShardedCounter {
+        #[cfg(feature = "std")]
+        let shards = crate::pool::parallelism() * 4;
+        #[cfg(not(feature = "std"))]
+        let shards = DEFAULT_SHARDS;
+        ShardedCounter::with_shards(shards)
+    }
+
+    /// Constructs a new counter at 0.
+    ///
+    /// **shards**: usize - the cells to stripe additions across, at
+    /// least one.
+    pub fn with_shards(shards: usize) -> ShardedCounter {
+        ShardedCounter {
+            shards: (0..shards.max(1)).map(|_| Shard::default()).collect(),
+        }
+    }
+
+    /// Adds n to the counter, wrapping around on overflow.
+    ///
+    /// **n**: usize - the amount to add.
+    pub fn add(&self, n: usize) {
+        self.shards[current_shard() % self.shards.len()]
+            .0
+            .fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Adds one to the counter.
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    /// Returns the sum of every shard.
+    pub fn sum(&self) -> usize {
+        self.shards.iter().fold(0, |sum, shard| {
+            sum.wrapping_add(shard.0.load(Ordering::Relaxed))
+        })
+    }
+}
+
+// Implements Default for ShardedCounter, same as ShardedCounter::new.
+impl Default for ShardedCounter {
+    fn default() -> ShardedCounter {
+        ShardedCounter::new()
+    }
+}
+
+// Implements Debug for ShardedCounter, showing the sum and the shards.
+impl fmt::Debug for ShardedCounter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShardedCounter")
+            .field("sum", &self.sum())
+            .field("shards", &self.shards.len())
+            .finish()
+    }
+}
+
+// How many times SpinLock::lock tries to take the lock, with a growing
+// pause between the tries, before parking the thread.
+const SPINS: u32 = 10;
+
+/// A mutex for very short critical sections, like bumping a few fields
+/// shared by jobs. A thread wanting the lock spins for a moment, which
+/// is cheaper than sleeping when the holder is about to release it, and
+/// parks only if the lock is still taken. Without std, it spins until
+/// the lock is free. Unlike std Mutex, the lock isn't poisoned when a
+/// holder panics.
+///
+/// ### Examples
+/// ```
+/// use rpools::sync::SpinLock;
+///
+/// let stats = SpinLock::new((0, 0));
+///
+/// std::thread::scope(|scope| {
+///     for size in 0..100 {
+///         let stats = &stats;
+///         scope.spawn(move || {
+///             let mut stats = stats.lock();
+///             stats.0 += 1;
+///             stats.1 += size;
+///         });
+///     }
+/// });
+/// assert_eq!((100, 4950), *stats.lock());
+/// ```
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    #[cfg(feature = "std")]
+    parker: Parker,
+    value: UnsafeCell<T>,
+}
+
+// SpinLock hands out the value to one thread at a time, like a Mutex.
+unsafe impl<T: Send> Send for SpinLock<T> {}
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+/// The access to the value of a SpinLock, releasing it when dropped.
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    // shared across threads only if T is Sync
+    _value: PhantomData<&'a mut T>,
+}
+
+// The threads of a SpinLock parked after spinning.
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct Parker {
+    // the threads parked, or about to park, on the lock
+    parked: AtomicUsize,
+    mu: std::sync::Mutex<()>,
+    condvar: std::sync::Condvar,
+}
+
+impl<T> SpinLock<T> {
+    /// Constructs a new unlocked SpinLock.
+    ///
+    /// **value**: T - the value to protect.
+    pub fn new(value: T) -> SpinLock<T> {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            #[cfg(feature = "std")]
+            parker: Parker::default(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Takes the lock, spinning and then parking the current thread
+    /// while another one holds it.
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        for spin in 0..SPINS {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            for _ in 0..1 << spin {
+                core::hint::spin_loop();
+            }
+        }
+        self.lock_slow()
+    }
+
+    // Parks the current thread until it takes the lock.
+    #[cfg(feature = "std")]
+    fn lock_slow(&self) -> SpinLockGuard<'_, T> {
+        let parker = &self.parker;
+        let mut mu = parker.mu.lock().expect("Cant get the lock");
+        loop {
+            // counted before the try, so an unlock after a failed try
+            // sees the parked thread and wakes it
+            parker.parked.fetch_add(1, Ordering::SeqCst);
+            let guard = self.try_lock();
+            if guard.is_none() {
+                mu = parker
+                    .condvar
+                    .wait(mu)
+                    .expect("Cant block the current thread");
+            }
+            parker.parked.fetch_sub(1, Ordering::SeqCst);
+            if let Some(guard) = guard {
+                return guard;
+            }
+        }
+    }
+
+    // Spins until the current thread takes the lock.
+    #[cfg(not(feature = "std"))]
+    fn lock_slow(&self) -> SpinLockGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Takes the lock if it is free, without blocking.
+    ///
+    /// **returns**: the guard, or None if another thread holds the lock.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::Relaxed)
+            .ok()
+            .map(|_| SpinLockGuard {
+                lock: self,
+                _value: PhantomData,
+            })
+    }
+
+    /// Returns a mutable reference to the value, without locking, as
+    /// the borrow proves no other thread holds the lock.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Consumes the lock and returns the value.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    fn default() -> Self {
+        SpinLock::new(T::default())
+    }
+}
+
+// Implements Debug for SpinLock, showing the value if it isn't locked.
+impl<T: fmt::Debug> fmt::Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("SpinLock");
+        match self.try_lock() {
+            Some(guard) => debug.field("value", &*guard),
+            None => debug.field("value", &"<locked>"),
+        };
+        debug.finish()
+    }
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // the guard owns the lock
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // the guard owns the lock
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+// Implements Drop for SpinLockGuard, releasing the lock and waking a
+// parked thread, if any.
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::SeqCst);
+        #[cfg(feature = "std")]
+        {
+            let parker = &self.lock.parker;
+            if parker.parked.load(Ordering::SeqCst) > 0 {
+                drop(parker.mu.lock().expect("Cant get the lock"));
+                parker.condvar.notify_one();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod mod_cancellation_token_tests {
+    use super::CancellationToken;
+
+    #[test]
+    fn test_if_cancel_is_shared_by_clones() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+        clone.cancel();
+        assert!(token.is_cancelled());
+    }
+}
+
+#[cfg(test)]
+mod mod_spin_lock_tests {
+    use super::SpinLock;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn test_if_spin_lock_must_serialize_the_threads() {
+        let lock = Arc::new(SpinLock::new(0));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let lock = Arc::clone(&lock);
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let mut value = lock.lock();
+                        let read = *value;
+                        thread::yield_now();
+                        *value = read + 1;
+                    }
+                })
+            })
+            .collect();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        assert_eq!(8000, *lock.lock());
+    }
+
+    #[test]
+    fn test_if_spin_lock_must_be_released_by_a_panic() {
+        let lock = Arc::new(SpinLock::new(vec![1]));
+        let guard = lock.lock();
+        assert!(lock.try_lock().is_none());
+        assert_eq!("SpinLock { value: \"<locked>\" }", format!("{:?}", lock));
+        drop(guard);
+
+        let holder = Arc::clone(&lock);
+        assert!(thread::spawn(move || {
+            holder.lock().push(2);
+            panic!("the job failed");
+        })
+        .join()
+        .is_err());
+        assert_eq!(vec![1, 2], *lock.try_lock().unwrap());
+    }
+}
@@ -0,0 +1,175 @@
+//! ## Task Set
+//!
+//! This module ties jobs to the lifetime of a value. A TaskSet owns the
+//! handles of the jobs spawned through it, and when it goes out of
+//! scope, on an early return or a panic as well, it cancels the jobs
+//! that didn't start and waits for the running ones, so no background
+//! job outlives the code that spawned it.
+//!
+//! ### Examples
+//! ```
+//! use rpools::pool::WorkerPool;
+//!
+//! fn checksums(pool: &WorkerPool, files: &[&'static str]) -> Result<Vec<usize>, String> {
+//!     let mut set = pool.task_set();
+//!     for &file in files {
+//!         set.spawn(move || file.len()).map_err(|err| err.to_string())?;
+//!     }
+//!     set.join_all()
+//!         .into_iter()
+//!         .map(|result| result.map_err(|err| err.to_string()))
+//!         .collect()
+//! }
+//!
+//! let pool = WorkerPool::new(2);
+//! assert_eq!(Ok(vec![5, 3]), checksums(&pool, &["a.txt", "b.c"]));
+//! ```
+
+use crate::{
+    pool::{ExecuteError, JobContext, JobError, JobHandle, WorkerPool},
+    sync::CancellationToken,
+};
+
+/// A set of jobs owned by the code that spawned them, made by
+/// `WorkerPool::task_set`. Dropping it cancels the jobs that didn't
+/// start and blocks until the running ones finish.
+pub struct TaskSet<'pool, T> {
+    pool: &'pool WorkerPool,
+    token: CancellationToken,
+    handles: Vec<JobHandle<T>>,
+}
+
+impl<'pool, T: Send + 'static> TaskSet<'pool, T> {
+    /// Spawns a job in the pool, owned by the set.
+    ///
+    /// **f**: A FnOnce closure that produces a value. \
+    /// **returns**: Ok if the job was queued, or an ExecuteError.
+    pub fn spawn<F>(&mut self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let handle = self.pool.job(f).token(&self.token).submit()?;
+        self.handles.push(handle);
+        Ok(())
+    }
+
+    /// Spawns a job given a JobContext, so a running job can stop at
+    /// its next checkpoint once the set is aborted.
+    ///
+    /// **f**: A FnOnce closure that takes a &JobContext and produces a
+    /// value. \
+    /// **returns**: Ok if the job was queued, or an ExecuteError.
+    pub fn spawn_with_context<F>(&mut self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce(&JobContext) -> T + Send + 'static,
+    {
+        let handle = self.pool.job_with_context(f).token(&self.token).submit()?;
+        self.handles.push(handle);
+        Ok(())
+    }
+
+    /// Returns how many jobs the set owns.
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    /// Returns true if the set owns no job.
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    /// Blocks the current thread until every job of the set finishes,
+    /// and returns their results in the order they were spawned.
+    pub fn join_all(mut self) -> Vec<Result<T, JobError>> {
+        self.handles.drain(..).map(JobHandle::join).collect()
+    }
+
+    /// Cancels the jobs of the set. The ones that didn't start are
+    /// skipped and resolve with JobError::Cancelled, and the running
+    /// ones see the cancellation at their next checkpoint.
+    pub fn abort_all(&self) {
+        self.token.cancel();
+    }
+}
+
+// Implements Drop for TaskSet, cancelling and waiting for its jobs.
+impl<'pool, T> Drop for TaskSet<'pool, T> {
+    fn drop(&mut self) {
+        self.token.cancel();
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl WorkerPool {
+    /// Constructs a new empty TaskSet spawning its jobs in this pool.
+    ///
+    /// ### Examples
+    /// ```
+    /// use rpools::checkpoint;
+    /// use rpools::pool::{JobError, WorkerPool};
+    /// use std::time::Duration;
+    ///
+    /// let pool = WorkerPool::new(1);
+    /// let mut set = pool.task_set();
+    /// set.spawn_with_context(|ctx| loop {
+    ///     checkpoint!(ctx, 0);
+    ///     std::thread::sleep(Duration::from_millis(1));
+    /// })
+    /// .unwrap();
+    /// set.spawn(|| 1).unwrap();
+    ///
+    /// set.abort_all();
+    /// let results = set.join_all();
+    /// assert!(matches!(results[1], Err(JobError::Cancelled)));
+    /// ```
+    pub fn task_set<T: Send + 'static>(&self) -> TaskSet<'_, T> {
+        TaskSet {
+            pool: self,
+            token: CancellationToken::new(),
+            handles: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc, Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn task_set_should_cancel_and_wait_on_drop() {
+        let pool = WorkerPool::new(1);
+        let (finished, skipped_ran) = (
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(AtomicBool::new(false)),
+        );
+        {
+            let mut set = pool.task_set();
+            let flag = Arc::clone(&finished);
+            let (started, has_started) = mpsc::channel();
+            set.spawn(move || {
+                started.send(()).unwrap();
+                thread::sleep(Duration::from_millis(30));
+                flag.store(true, Ordering::SeqCst);
+            })
+            .unwrap();
+            let flag = Arc::clone(&skipped_ran);
+            set.spawn(move || flag.store(true, Ordering::SeqCst))
+                .unwrap();
+            assert_eq!(2, set.len());
+            has_started.recv().unwrap();
+        }
+        assert!(finished.load(Ordering::SeqCst));
+        pool.wait();
+        assert!(!skipped_ran.load(Ordering::SeqCst));
+    }
+}
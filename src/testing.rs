@@ -0,0 +1,120 @@
+//! ## Testing
+//!
+//! Helpers for tests that synchronize with the pool. When the
+//! synchronization breaks, they fail with a dump of the pool or
+//! WaitGroup state, instead of hanging the test suite forever.
+//!
+//! It is only available with the `test-support` feature.
+//!
+//! ### Examples
+//! ```
+//! use rpools::assert_completes_within;
+//! use rpools::pool::WorkerPool;
+//! use rpools::sync::WaitGroup;
+//! use std::time::Duration;
+//!
+//! let pool = WorkerPool::new(2);
+//! let wg = WaitGroup::default();
+//! for _ in 0..10 {
+//!     let wg = wg.clone();
+//!     pool.execute(move || drop(wg)).unwrap();
+//! }
+//!
+//! assert_completes_within!(Duration::from_secs(5), || wg.wait(), &wg);
+//! pool.assert_idle_within(Duration::from_secs(5));
+//! ```
+
+use std::{
+    fmt::Debug,
+    process,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+/// Runs op on the current thread and returns its value. If op doesn't
+/// complete within timeout, prints what was being waited for and the
+/// state, then exits the process with a failure status, as the blocked
+/// thread can't be interrupted.
+///
+/// **timeout**: Duration - the maximum time op may take. \
+/// **what**: &str - a description of op for the failure message. \
+/// **state**: &dyn Debug - the state to dump on failure. \
+/// **op**: A FnOnce closure, usually a blocking wait.
+pub fn completes_within<S, F, R>(timeout: Duration, what: &str, state: &S, op: F) -> R
+where
+    S: Debug + Sync + ?Sized,
+    F: FnOnce() -> R,
+{
+    thread::scope(|s| {
+        let (tx, rx) = mpsc::channel::<()>();
+        s.spawn(move || {
+            // a finished or panicking op drops the sender
+            if let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(timeout) {
+                eprintln!(
+                    "`{}` did not complete within {:?}\nstate: {:#?}",
+                    what, timeout, state
+                );
+                process::exit(101);
+            }
+        });
+        let value = op();
+        drop(tx);
+        value
+    })
+}
+
+/// Asserts that an operation completes within a timeout, dumping an
+/// optional state if it doesn't. See `testing::completes_within`.
+///
+/// ### Examples
+/// ```
+/// use rpools::assert_completes_within;
+/// use std::time::Duration;
+///
+/// let sum = assert_completes_within!(Duration::from_secs(1), || 2 + 2);
+/// assert_eq!(4, sum);
+/// ```
+#[macro_export]
+macro_rules! assert_completes_within {
+    ($timeout:expr, $op:expr) => {
+        $crate::testing::completes_within($timeout, stringify!($op), &"<no state>", $op)
+    };
+    ($timeout:expr, $op:expr, $state:expr) => {
+        $crate::testing::completes_within($timeout, stringify!($op), $state, $op)
+    };
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::pool::WorkerPool;
+
+    #[test]
+    fn completes_within_should_return_the_op_value() {
+        let value = assert_completes_within!(Duration::from_secs(1), || 21 * 2);
+        assert_eq!(42, value);
+    }
+
+    #[test]
+    #[should_panic(expected = "pool was not idle within")]
+    fn assert_idle_within_should_panic_with_state_on_hung_jobs() {
+        let pool = WorkerPool::new(1);
+        let (tx, rx) = mpsc::channel::<()>();
+        let gate = std::sync::Mutex::new(rx);
+        pool.execute(move || {
+            let _ = gate.lock().unwrap().recv();
+        })
+        .unwrap();
+
+        // release the job while unwinding, so the pool can shut down
+        struct Release(mpsc::Sender<()>);
+        impl Drop for Release {
+            fn drop(&mut self) {
+                let _ = self.0.send(());
+            }
+        }
+        let _release = Release(tx);
+        pool.assert_idle_within(Duration::from_millis(20));
+    }
+}
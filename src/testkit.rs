@@ -0,0 +1,265 @@
+//! ## Testkit
+//!
+//! A harness that hammers a pool with a synthetic workload, from many
+//! producer threads at once, and reports what happened to every job.
+//! It validates a pool configuration before production: the report
+//! asserts that no job was lost and that jobs started in time.
+//!
+//! Jobs are sent with `submit`, so a synthetic panic doesn't take its
+//! worker down. The workload is drawn from a seed, so the same seed sends
+//! the same jobs, while the interleaving is left to the scheduler.
+//!
+//! It is only available with the `test-support` feature.
+//!
+//! ### Examples
+//! ```
+//! use rpools::pool::WorkerPool;
+//! use rpools::testkit::Workload;
+//! use std::time::Duration;
+//!
+//! let pool = WorkerPool::new(4);
+//! let report = Workload::new(3, 50)
+//!     .durations(Duration::ZERO..Duration::from_millis(2))
+//!     .panic_rate(0.1)
+//!     .bursts(10, Duration::from_millis(5))
+//!     .seed(7)
+//!     .run(&pool);
+//!
+//! report
+//!     .assert_no_lost_jobs()
+//!     .assert_latency_within(Duration::from_secs(5));
+//! assert_eq!(150, report.submitted);
+//! ```
+
+use std::{
+    convert::TryFrom,
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    pool::{JobError, WorkerPool},
+    rng::WorkerRng,
+};
+
+/// A synthetic workload, sent by a number of producer threads.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Workload {
+    producers: usize,
+    jobs: usize,
+    durations: Range<Duration>,
+    panic_rate: f64,
+    burst: usize,
+    pause: Duration,
+    seed: u64,
+}
+
+impl Workload {
+    /// Constructs a new Workload of instant jobs, sent as fast as the
+    /// pool takes them.
+    ///
+    /// **producers**: usize - the threads sending jobs at once. \
+    /// **jobs**: usize - the jobs each producer sends.
+    pub fn new(producers: usize, jobs: usize) -> Workload {
+        Workload {
+            producers,
+            jobs,
+            durations: Duration::ZERO..Duration::ZERO,
+            panic_rate: 0.0,
+            burst: 0,
+            pause: Duration::ZERO,
+            seed: 0,
+        }
+    }
+
+    /// Sets how long each job runs, drawn uniformly from the range.
+    ///
+    /// **durations**: Range<Duration> - the shortest and longest jobs.
+    pub fn durations(mut self, durations: Range<Duration>) -> Workload {
+        self.durations = durations;
+        self
+    }
+
+    /// Sets the share of jobs that panic instead of returning.
+    ///
+    /// **rate**: f64 - from 0, no job panics, to 1, every job panics.
+    pub fn panic_rate(mut self, rate: f64) -> Workload {
+        self.panic_rate = rate;
+        self
+    }
+
+    /// Sends the jobs of each producer in bursts, with a pause between
+    /// them.
+    ///
+    /// **size**: usize - the jobs in each burst. \
+    /// **pause**: Duration - the wait between two bursts.
+    pub fn bursts(mut self, size: usize, pause: Duration) -> Workload {
+        self.burst = size;
+        self.pause = pause;
+        self
+    }
+
+    /// Sets the seed the durations and panics are drawn from.
+    ///
+    /// **seed**: u64 - the same seed gives the same workload.
+    pub fn seed(mut self, seed: u64) -> Workload {
+        self.seed = seed;
+        self
+    }
+
+    /// Sends the workload to the pool and waits for every job that was
+    /// accepted.
+    ///
+    /// **pool**: &WorkerPool - the pool under test. \
+    /// **returns**: a Report of what happened to the jobs.
+    pub fn run(&self, pool: &WorkerPool) -> Report {
+        let latency = Arc::new(AtomicU64::new(0));
+        let started = Instant::now();
+        let mut report = thread::scope(|s| {
+            let producers: Vec<_> = (0..self.producers)
+                .map(|producer| {
+                    let latency = Arc::clone(&latency);
+                    s.spawn(move || self.produce(pool, producer, latency))
+                })
+                .collect();
+            producers
+                .into_iter()
+                .map(|producer| producer.join().expect("a testkit producer panicked"))
+                .fold(Report::default(), |total, report| total.merge(report))
+        });
+        report.max_latency = Duration::from_nanos(latency.load(Ordering::Relaxed));
+        report.elapsed = started.elapsed();
+        report
+    }
+
+    // Sends the jobs of a producer and joins them.
+    fn produce(&self, pool: &WorkerPool, producer: usize, latency: Arc<AtomicU64>) -> Report {
+        let mut rng = WorkerRng::for_worker(self.seed, producer);
+        let mut report = Report::default();
+        let mut handles = Vec::with_capacity(self.jobs);
+        for job in 0..self.jobs {
+            if self.burst > 0 && job > 0 && job % self.burst == 0 {
+                thread::sleep(self.pause);
+            }
+            let duration = self.draw_duration(&mut rng);
+            let panics = rng.next_f64() < self.panic_rate;
+            let latency = Arc::clone(&latency);
+            let sent = Instant::now();
+            report.submitted += 1;
+            let submitted = pool.submit(move || {
+                let waited = u64::try_from(sent.elapsed().as_nanos()).unwrap_or(u64::MAX);
+                latency.fetch_max(waited, Ordering::Relaxed);
+                thread::sleep(duration);
+                if panics {
+                    panic!("testkit: synthetic panic");
+                }
+            });
+            match submitted {
+                Ok(handle) => handles.push(handle),
+                Err(_) => report.rejected += 1,
+            }
+        }
+        for handle in handles {
+            match handle.join() {
+                Ok(()) => report.completed += 1,
+                Err(JobError::Panicked(_)) => report.panicked += 1,
+                Err(_) => report.lost += 1,
+            }
+        }
+        report
+    }
+
+    // Draws the duration of a job from the range.
+    fn draw_duration(&self, rng: &mut WorkerRng) -> Duration {
+        let Range { start, end } = self.durations;
+        match u64::try_from(end.saturating_sub(start).as_nanos()) {
+            Ok(0) => start,
+            Ok(span) => start + Duration::from_nanos(rng.gen_range(0..span)),
+            Err(_) => start,
+        }
+    }
+}
+
+/// What happened to the jobs of a workload.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Report {
+    /// The jobs sent to the pool.
+    pub submitted: usize,
+    /// The jobs the pool rejected with an ExecuteError.
+    pub rejected: usize,
+    /// The jobs that returned.
+    pub completed: usize,
+    /// The jobs that panicked.
+    pub panicked: usize,
+    /// The jobs accepted by the pool that never ran.
+    pub lost: usize,
+    /// The longest a job waited from being sent to starting.
+    pub max_latency: Duration,
+    /// How long the whole workload took.
+    pub elapsed: Duration,
+}
+
+impl Report {
+    /// Asserts that each accepted job either returned or panicked.
+    /// Panics with the report otherwise.
+    pub fn assert_no_lost_jobs(&self) -> &Report {
+        let ended = self.rejected + self.completed + self.panicked;
+        assert!(
+            self.lost == 0 && ended == self.submitted,
+            "jobs were lost: {:#?}",
+            self
+        );
+        self
+    }
+
+    /// Asserts that no job waited longer than bound to start. Panics
+    /// with the report otherwise.
+    ///
+    /// **bound**: Duration - the longest acceptable wait.
+    pub fn assert_latency_within(&self, bound: Duration) -> &Report {
+        assert!(
+            self.max_latency <= bound,
+            "jobs waited over {:?} to start: {:#?}",
+            bound,
+            self
+        );
+        self
+    }
+
+    // Adds the counts of another report to this one.
+    fn merge(mut self, other: Report) -> Report {
+        self.submitted += other.submitted;
+        self.rejected += other.rejected;
+        self.completed += other.completed;
+        self.panicked += other.panicked;
+        self.lost += other.lost;
+        self
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn workload_should_account_for_every_job() {
+        let pool = WorkerPool::new(2);
+        let workload = Workload::new(4, 25).panic_rate(0.5).seed(3);
+        let report = workload.run(&pool);
+        report.assert_no_lost_jobs();
+        assert_eq!(100, report.submitted);
+        assert!(report.panicked > 0 && report.completed > 0);
+        assert_eq!(report.panicked, workload.run(&pool).panicked);
+
+        pool.shutdown();
+        let report = Workload::new(1, 5).run(&pool);
+        report.assert_no_lost_jobs();
+        assert_eq!(5, report.rejected);
+    }
+}
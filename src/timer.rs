@@ -0,0 +1,160 @@
+// ## Timer
+//
+// The queue of delayed jobs. Items are kept in a heap ordered by their
+// due time, and in scheduling order for the same due time. A single
+// timer thread blocks in pop_due until the earliest item is due, and
+// then moves it to the job queue, so no worker sleeps waiting for it.
+
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    mem,
+    sync::{Condvar, Mutex},
+    time::Instant,
+};
+
+struct Entry<T> {
+    due: Instant,
+    seq: u64,
+    item: T,
+}
+
+// BinaryHeap is a max heap, entries are ordered backwards so the
+// earliest one is on top.
+impl<T> Ord for Entry<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.due.cmp(&self.due).then(other.seq.cmp(&self.seq))
+    }
+}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+struct State<T> {
+    heap: BinaryHeap<Entry<T>>,
+    next_seq: u64,
+    closed: bool,
+}
+
+pub(crate) struct Timer<T> {
+    state: Mutex<State<T>>,
+    changed: Condvar,
+}
+
+impl<T> Timer<T> {
+    // Constructs a new Timer without items.
+    pub(crate) fn new() -> Timer<T> {
+        Timer {
+            state: Mutex::new(State {
+                heap: BinaryHeap::new(),
+                next_seq: 0,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    // Schedules an item to be popped at due. The item is given back if
+    // the timer is closed.
+    pub(crate) fn schedule(&self, due: Instant, item: T) -> Result<(), T> {
+        let mut state = self.state.lock().expect("Cant acquire lock");
+        if state.closed {
+            return Err(item);
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.heap.push(Entry { due, seq, item });
+        drop(state);
+        // the new item may be due before the one the thread waits for
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    // Blocks the current thread until the earliest item is due and pops
+    // it. Returns None once the timer is closed.
+    pub(crate) fn pop_due(&self) -> Option<T> {
+        let mut state = self.state.lock().expect("Cant acquire lock");
+        loop {
+            if state.closed {
+                return None;
+            }
+            let now = Instant::now();
+            state = match state.heap.peek().map(|entry| entry.due) {
+                Some(due) if due <= now => {
+                    return state.heap.pop().map(|entry| entry.item);
+                }
+                Some(due) => {
+                    self.changed
+                        .wait_timeout(state, due - now)
+                        .expect("Cant block the current thread")
+                        .0
+                }
+                None => self
+                    .changed
+                    .wait(state)
+                    .expect("Cant block the current thread"),
+            };
+        }
+    }
+
+    // Returns how many items are waiting to be due.
+    pub(crate) fn len(&self) -> usize {
+        self.state.lock().expect("Cant acquire lock").heap.len()
+    }
+
+    // Closes the timer and drops the items that weren't due yet.
+    pub(crate) fn close(&self) {
+        let mut state = self.state.lock().expect("Cant acquire lock");
+        state.closed = true;
+        let pending = mem::take(&mut state.heap);
+        drop(state);
+        self.changed.notify_all();
+        // dropped outside the lock, items may run code when dropped
+        drop(pending);
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::Timer;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn timer_should_pop_items_in_due_order() {
+        let timer = Timer::new();
+        let now = Instant::now();
+        timer
+            .schedule(now + Duration::from_millis(20), "late")
+            .unwrap();
+        timer.schedule(now, "first").unwrap();
+        timer.schedule(now, "second").unwrap();
+        assert_eq!(3, timer.len());
+        assert_eq!(Some("first"), timer.pop_due());
+        assert_eq!(Some("second"), timer.pop_due());
+        assert_eq!(Some("late"), timer.pop_due());
+        assert!(now.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn timer_should_reject_items_after_close() {
+        let timer = Timer::new();
+        timer
+            .schedule(Instant::now() + Duration::from_secs(60), 1)
+            .unwrap();
+        timer.close();
+        assert_eq!(0, timer.len());
+        assert_eq!(Err(2), timer.schedule(Instant::now(), 2));
+        assert_eq!(None, timer.pop_due());
+    }
+}